@@ -0,0 +1,2 @@
+-- Original position of the command in the shell history (the N in !N) when known
+ALTER TABLE history ADD COLUMN history_no BIGINT;
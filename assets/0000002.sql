@@ -0,0 +1,2 @@
+-- Name of the machine the command was run on (the IP address changes with DHCP)
+ALTER TABLE history ADD COLUMN hostname TEXT;
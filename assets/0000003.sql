@@ -0,0 +1,2 @@
+-- Shell session the command was run in so related commands can be grouped (see dejacmd sessions)
+ALTER TABLE history ADD COLUMN session_id TEXT;
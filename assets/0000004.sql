@@ -0,0 +1,3 @@
+-- Top level directory of the git working tree the command was run in and its branch (see git::GitContext)
+ALTER TABLE history ADD COLUMN git_repo TEXT;
+ALTER TABLE history ADD COLUMN git_branch TEXT;
@@ -0,0 +1,3 @@
+-- MySQL can only index a prefix of TEXT columns and doesn't support CREATE INDEX IF NOT EXISTS
+CREATE INDEX idx_history_command ON history (command(255));
+CREATE INDEX idx_history_cwd ON history (cwd(255));
@@ -0,0 +1,12 @@
+-- A trigram index lets command LIKE '%...%' searches use the index. pg_trgm ships with PostgreSQL but creating it
+-- needs the CREATE privilege on the database, so without it fall back to an expression index on the start of the
+-- command (a plain btree index fails for values over about 2700 bytes, which long commands can be).
+DO $$
+BEGIN
+   CREATE EXTENSION IF NOT EXISTS pg_trgm;
+   CREATE INDEX IF NOT EXISTS idx_history_command ON history USING gin (command gin_trgm_ops);
+EXCEPTION WHEN OTHERS THEN
+   CREATE INDEX IF NOT EXISTS idx_history_command ON history (LEFT(command, 255));
+END
+$$;
+CREATE INDEX IF NOT EXISTS idx_history_cwd ON history (cwd);
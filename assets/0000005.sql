@@ -0,0 +1,3 @@
+-- Indexes for searches on command and the per-directory filters on cwd. See the .postgres and .mysql variants.
+CREATE INDEX IF NOT EXISTS idx_history_command ON history (command);
+CREATE INDEX IF NOT EXISTS idx_history_cwd ON history (cwd);
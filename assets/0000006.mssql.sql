@@ -0,0 +1,11 @@
+-- Starred commands (see dejacmd bookmark and 0000006.sql)
+IF OBJECT_ID(N'bookmarks', N'U') IS NULL
+CREATE TABLE bookmarks
+(
+   id NVARCHAR(255) PRIMARY KEY,
+   bookmarked_timestamp NVARCHAR(32) NOT NULL,
+   command NVARCHAR(MAX) NOT NULL,
+   cwd NVARCHAR(MAX),
+   hostname NVARCHAR(255),
+   note NVARCHAR(MAX)
+);
@@ -0,0 +1,10 @@
+-- Starred commands (see dejacmd bookmark and 0000006.sql)
+CREATE TABLE IF NOT EXISTS bookmarks
+(
+   id VARCHAR(255) PRIMARY KEY,
+   bookmarked_timestamp VARCHAR(32) NOT NULL,
+   command TEXT NOT NULL,
+   cwd TEXT,
+   hostname VARCHAR(255),
+   note TEXT
+) CHARACTER SET utf8mb4;
@@ -0,0 +1,11 @@
+-- Starred commands (see dejacmd bookmark). The id is the id of the bookmarked history row and the command is copied
+-- so a bookmark outlives the row being pruned or deleted.
+CREATE TABLE IF NOT EXISTS bookmarks
+(
+   id VARCHAR(255) PRIMARY KEY,
+   bookmarked_timestamp VARCHAR(32) NOT NULL,
+   command TEXT NOT NULL,
+   cwd TEXT,
+   hostname VARCHAR(255),
+   note TEXT
+);
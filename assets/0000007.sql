@@ -0,0 +1,2 @@
+-- Wall clock time the command took in milliseconds, measured by the shell hooks from dejacmd init
+ALTER TABLE history ADD COLUMN duration_ms BIGINT;
@@ -0,0 +1,4 @@
+-- Address of the machine an SSH session was opened from (from SSH_CONNECTION or SSH_CLIENT) and the terminal the
+-- command was run on, to tell commands run over SSH from those at the console
+ALTER TABLE history ADD COLUMN ssh_origin TEXT;
+ALTER TABLE history ADD COLUMN tty TEXT;
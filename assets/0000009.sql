@@ -0,0 +1,2 @@
+-- Tag of the project a command was run in, from the tag in a .dejacmd.toml in its directory or one above it
+ALTER TABLE history ADD COLUMN project TEXT;
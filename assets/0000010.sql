@@ -0,0 +1,2 @@
+-- Terminal multiplexer the command was run in e.g tmux:debug:1.0 (session:window.pane) or screen:12345.debug:2
+ALTER TABLE history ADD COLUMN multiplexer TEXT;
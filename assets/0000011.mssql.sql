@@ -0,0 +1,7 @@
+-- Summaries of command output (see 0000011.sql)
+IF OBJECT_ID(N'history_output', N'U') IS NULL
+CREATE TABLE history_output
+(
+   id NVARCHAR(255) PRIMARY KEY,
+   output NVARCHAR(MAX)
+);
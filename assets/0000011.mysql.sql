@@ -0,0 +1,6 @@
+-- Summaries of command output (see 0000011.sql)
+CREATE TABLE IF NOT EXISTS history_output
+(
+   id VARCHAR(255) PRIMARY KEY,
+   output TEXT
+) CHARACTER SET utf8mb4;
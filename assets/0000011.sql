@@ -0,0 +1,7 @@
+-- Summaries of command output captured by the hooks from dejacmd init --capture-output (see output::summarize). The
+-- id is the id of the history row, output is kept apart from the history as it is only recorded when opted into.
+CREATE TABLE IF NOT EXISTS history_output
+(
+   id VARCHAR(255) PRIMARY KEY,
+   output TEXT
+);
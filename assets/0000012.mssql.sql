@@ -0,0 +1,2 @@
+-- When a bookmark was removed (see 0000012.sql)
+ALTER TABLE bookmarks ADD removed_timestamp NVARCHAR(32);
@@ -0,0 +1,3 @@
+-- When a bookmark was removed (see dejacmd bookmark --remove). Removed bookmarks are kept so dejacmd sync copies the
+-- removal to the other database instead of copying the bookmark back.
+ALTER TABLE bookmarks ADD COLUMN removed_timestamp VARCHAR(32);
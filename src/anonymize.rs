@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use hmac::{Hmac, Mac};
+use regex::{Captures, Match, Regex};
+use secrecy::zeroize::Zeroizing;
+use sha2::Sha256;
+
+use crate::HistoryEntry;
+
+/// Home directories (/home/NAME, /Users/NAME or C:\Users\NAME), the second group is the user name
+static HOME_DIR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(/home/|/Users/|\\Users\\)([^/\\\s'":;]+)"#).unwrap());
+
+static IPV4: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b\d{1,3}(?:\.\d{1,3}){3}\b").unwrap());
+
+/// Runs of hex digits and colons that may be IPv6 addresses (see is_ipv6)
+static IPV6: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[0-9A-Fa-f:]*:[0-9A-Fa-f:]*:[0-9A-Fa-f:]*").unwrap());
+
+/// User, host, project and session pseudonyms, which are left as they are when anonymized history is anonymized again
+static PSEUDONYM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?:user|host|project|session)-[0-9a-f]{8}$").unwrap());
+
+/// Pseudonyms for IPv4 addresses are in the reserved 240.0.0.0/8 range and for IPv6 addresses in the 2001:db8::/32
+/// documentation range, so they can't be mistaken for real addresses
+const IPV4_PSEUDONYM_PREFIX: &str = "240.";
+const IPV6_PSEUDONYM_PREFIX: &str = "2001:db8:";
+
+/// Pseudonyms for user ids are this or more
+pub const USER_ID_PSEUDONYM_BASE: i64 = 2_000_000_000;
+
+/// User and host names shorter than this are only replaced in the user_name and hostname columns, not wherever they
+/// appear as a word in commands and directories
+pub const MIN_SCRUBBED_NAME_LEN: usize = 3;
+
+/// Rewrites the user names, user ids, hostnames, IP addresses (v4 and v6), home directories, projects and sessions in
+/// history entries with pseudonyms (user-1f0c3a9e, host-7d2e51b0, 240.18.3.77, /home/user-1f0c3a9e) so a history can
+/// be shared without identifying anyone. Each value is given the same pseudonym everywhere it appears, including in
+/// commands, directories, git remotes and branches, tmux locations and ttys. Pseudonyms are a keyed hash (HMAC-SHA256)
+/// of the value, so the same key always gives a value the same pseudonym whatever else is anonymized with it and they
+/// can't be reversed by hashing likely names without the key. root, localhost, loopback addresses and pseudonyms
+/// aren't identifying so are kept.
+#[derive(Debug)]
+pub struct Anonymizer
+{
+   key: Zeroizing<Vec<u8>>,
+   users: HashMap<String, String>,
+   hosts: HashMap<String, String>,
+   ips: HashMap<String, String>,
+   names: Option<Regex>,
+}
+
+impl Anonymizer
+{
+   /// An anonymizer for the identities found in entries, hashing them with key (e.g Settings::get_anonymize_key)
+   pub fn new(entries: &[HistoryEntry], key: &str) -> Self
+   //-----------------------------------------------------
+   {
+      let mut anonymizer = Anonymizer
+      {
+         key: Zeroizing::new(key.as_bytes().to_vec()),
+         users: HashMap::new(),
+         hosts: HashMap::new(),
+         ips: HashMap::new(),
+         names: None,
+      };
+      for entry in entries
+      {
+         anonymizer.learn(entry);
+      }
+      // Longest first so a host's full name is replaced rather than just its first label
+      let mut names: Vec<&String> = anonymizer.users.keys().chain(anonymizer.hosts.keys())
+         .filter(|name| name.len() >= MIN_SCRUBBED_NAME_LEN)
+         .collect();
+      names.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+      if !names.is_empty()
+      {
+         let alternatives: Vec<String> = names.iter().map(|name| regex::escape(name)).collect();
+         anonymizer.names = Regex::new(&format!(r"\b(?:{})\b", alternatives.join("|"))).ok();
+      }
+      anonymizer
+   }
+
+   /// Number of distinct users, hosts and IP addresses given pseudonyms
+   pub fn counts(&self) -> (usize, usize, usize)
+   //-------------------------------------------
+   {
+      (self.users.len(), self.hosts.values().collect::<HashSet<_>>().len(), self.ips.len())
+   }
+
+   /// entry with its identifying values replaced
+   pub fn anonymize(&self, entry: &HistoryEntry) -> HistoryEntry
+   //------------------------------------------------------------
+   {
+      let mut anonymized = entry.clone();
+      let lookup = |names: &HashMap<String, String>, value: &Option<String>|
+         value.as_ref().map(|v| names.get(v).cloned().unwrap_or_else(|| self.scrub(v)));
+      let scrub = |value: &Option<String>| value.as_deref().map(|v| self.scrub(v));
+      let pseudonym = |kind: &str, value: &Option<String>|
+         value.as_ref().map(|v| if is_kept(v) { v.clone() } else { self.pseudonym(kind, v) });
+      anonymized.user_name = lookup(&self.users, &entry.user_name);
+      anonymized.hostname = lookup(&self.hosts, &entry.hostname);
+      anonymized.ip = lookup(&self.ips, &entry.ip);
+      anonymized.ssh_origin = lookup(&self.ips, &entry.ssh_origin);
+      anonymized.user_id = entry.user_id.map(|id| self.user_id(id));
+      anonymized.cwd = scrub(&entry.cwd);
+      anonymized.command = scrub(&entry.command);
+      anonymized.git_repo = scrub(&entry.git_repo);
+      anonymized.git_branch = scrub(&entry.git_branch);
+      anonymized.tty = scrub(&entry.tty);
+      anonymized.multiplexer = scrub(&entry.multiplexer);
+      anonymized.project = pseudonym("project", &entry.project);
+      anonymized.session_id = pseudonym("session", &entry.session_id);
+      anonymized
+   }
+
+   /// text with home directories, IP addresses and the known user and host names replaced
+   pub fn scrub(&self, text: &str) -> String
+   //---------------------------------------
+   {
+      let text = HOME_DIR.replace_all(text, |caps: &Captures|
+         format!("{}{}", &caps[1], self.users.get(&caps[2]).map(String::as_str).unwrap_or(&caps[2])));
+      let text = IPV4.replace_all(&text, |caps: &Captures|
+         self.ips.get(&caps[0]).cloned().unwrap_or_else(|| caps[0].to_string())).into_owned();
+      let text = IPV6.replace_all(&text, |caps: &Captures|
+      {
+         let ip = caps.get(0).filter(|m| is_ipv6(&text, m)).and_then(|m| self.ips.get(m.as_str()));
+         ip.cloned().unwrap_or_else(|| caps[0].to_string())
+      }).into_owned();
+      match &self.names
+      {
+         | Some(names) => names.replace_all(&text, |caps: &Captures|
+              self.users.get(&caps[0]).or_else(|| self.hosts.get(&caps[0])).cloned().unwrap_or_else(|| caps[0].to_string()))
+              .into_owned(),
+         | None => text,
+      }
+   }
+
+   fn learn(&mut self, entry: &HistoryEntry)
+   //----------------------------------------
+   {
+      if let Some(user) = &entry.user_name
+      {
+         self.add_user(user);
+      }
+      if let Some(host) = entry.hostname.as_deref().filter(|host| !is_kept(host))
+      {
+         let pseudonym = self.pseudonym("host", host);
+         self.hosts.entry(host.to_string()).or_insert_with(|| pseudonym.clone());
+         // The short name of a fully qualified host is also replaced in commands e.g ssh web01
+         if let Some((short, _)) = host.split_once('.')
+         {
+            self.hosts.entry(short.to_string()).or_insert(pseudonym);
+         }
+      }
+      for ip in [&entry.ip, &entry.ssh_origin].into_iter().flatten()
+      {
+         self.add_ip(ip);
+      }
+      for text in [&entry.cwd, &entry.command, &entry.git_repo, &entry.git_branch, &entry.multiplexer].into_iter().flatten()
+      {
+         for caps in HOME_DIR.captures_iter(text)
+         {
+            self.add_user(&caps[2]);
+         }
+         for ip in IPV4.find_iter(text)
+         {
+            self.add_ip(ip.as_str());
+         }
+         for ip in IPV6.find_iter(text).filter(|m| is_ipv6(text, m))
+         {
+            self.add_ip(ip.as_str());
+         }
+      }
+   }
+
+   fn add_user(&mut self, user: &str)
+   //---------------------------------
+   {
+      if !is_kept(user) && !self.users.contains_key(user)
+      {
+         self.users.insert(user.to_string(), self.pseudonym("user", user));
+      }
+   }
+
+   fn add_ip(&mut self, ip: &str)
+   //-----------------------------
+   {
+      if is_kept(ip) || self.ips.contains_key(ip)
+      {
+         return;
+      }
+      let [a, b, c, d] = self.hash("ip", ip).to_be_bytes();
+      let pseudonym = match ip.contains(':')
+      {
+         | true => format!("{}{:x}:{:x}::1", IPV6_PSEUDONYM_PREFIX, u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d])),
+         | false => format!("{}{}.{}.{}", IPV4_PSEUDONYM_PREFIX, b, c, d),
+      };
+      self.ips.insert(ip.to_string(), pseudonym);
+   }
+
+   /// A user id other than root's (0) mapped to USER_ID_PSEUDONYM_BASE or more
+   fn user_id(&self, id: i64) -> i64
+   //--------------------------------
+   {
+      match id
+      {
+         | 0 => 0,
+         | id if id >= USER_ID_PSEUDONYM_BASE => id,
+         | id => USER_ID_PSEUDONYM_BASE + (self.hash("uid", &id.to_string()) % 1_000_000_000) as i64,
+      }
+   }
+
+   /// kind-XXXXXXXX where XXXXXXXX is the keyed hash of value
+   fn pseudonym(&self, kind: &str, value: &str) -> String
+   //-----------------------------------------------------
+   {
+      format!("{}-{:08x}", kind, self.hash(kind, value))
+   }
+
+   /// The first 32 bits of the HMAC-SHA256 of kind and value
+   fn hash(&self, kind: &str, value: &str) -> u32
+   //---------------------------------------------
+   {
+      let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes a key of any length");
+      mac.update(kind.as_bytes());
+      mac.update(b"\0");
+      mac.update(value.as_bytes());
+      let hash = mac.finalize().into_bytes();
+      u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+   }
+}
+
+/// Whether a match of IPV6 in text is an IPv6 address rather than e.g a time, a MAC address or a path such as
+/// std::fs (which must be preceded by a letter so doesn't start at a word boundary)
+fn is_ipv6(text: &str, m: &Match) -> bool
+//---------------------------------------
+{
+   let is_word = |c: char| c.is_alphanumeric() || c == '_';
+   !text[..m.start()].chars().next_back().is_some_and(|c| is_word(c) || c == '.')
+      && !text[m.end()..].chars().next().is_some_and(is_word)
+      && m.as_str().chars().any(|c| c.is_ascii_digit())
+      && m.as_str().parse::<std::net::Ipv6Addr>().is_ok()
+}
+
+/// Values that don't identify anyone
+fn is_kept(value: &str) -> bool
+//-----------------------------
+{
+   value.is_empty() || value == "root" || value == "localhost" || value == "::1" || value == "0.0.0.0" || value.starts_with("127.")
+      || value.starts_with(IPV4_PSEUDONYM_PREFIX) || value.starts_with(IPV6_PSEUDONYM_PREFIX) || PSEUDONYM.is_match(value)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn entry(user: &str, host: &str, ip: &str, cwd: &str, command: &str) -> HistoryEntry
+   {
+      let mut entry = crate::store::imported_entry(command, cwd, 1767000000, "bash", 0, None).unwrap();
+      entry.user_name = Some(user.to_string());
+      entry.user_id = Some(if user == "root" { 0 } else { 501 });
+      entry.hostname = Some(host.to_string());
+      entry.ip = Some(ip.to_string());
+      entry
+   }
+
+   const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+   #[test]
+   fn test_anonymize()
+   {
+      let mut entries = vec![
+         entry("alice", "web01.example.com", "192.168.1.20", "/home/alice/src", "ssh bob@web01 'tail /home/bob/log'"),
+         entry("root", "db01", "192.168.1.21", "/root", "psql -h 192.168.1.20 -U alice"),
+         entry("alice", "db01", "127.0.0.1", "/Users/alice", "cd /dev/null; ls"),
+         entry("alice", "db01", "fe80::1c2:3ff:fe4a:5b6", "/root", "ping6 fe80::1c2:3ff:fe4a:5b6 # at 12:30:45 see std::fs"),
+      ];
+      entries[0].git_branch = Some("alice/fix".to_string());
+      entries[0].tty = Some("/dev/pts/3".to_string());
+      entries[0].multiplexer = Some("tmux:web01:0.1".to_string());
+      entries[0].project = Some("payroll".to_string());
+      entries[0].session_id = Some("3f2c9a".to_string());
+      let anonymizer = Anonymizer::new(&entries, KEY);
+      assert_eq!(anonymizer.counts(), (2, 2, 3));
+      let anonymized: Vec<_> = entries.iter().map(|e| anonymizer.anonymize(e)).collect();
+
+      let alice = anonymized[0].user_name.clone().unwrap();
+      let web01 = anonymized[0].hostname.clone().unwrap();
+      let ip = anonymized[0].ip.clone().unwrap();
+      assert!(PSEUDONYM.is_match(&alice) && alice.starts_with("user-"), "{}", alice);
+      assert!(PSEUDONYM.is_match(&web01) && web01.starts_with("host-"), "{}", web01);
+      assert!(ip.starts_with("240.") && ip.parse::<std::net::Ipv4Addr>().is_ok(), "{}", ip);
+      assert!(anonymized[0].user_id.unwrap() >= USER_ID_PSEUDONYM_BASE);
+      assert_eq!(anonymized[0].cwd, Some(format!("/home/{}/src", alice)));
+      let bob = anonymizer.users["bob"].clone();
+      assert_eq!(anonymized[0].command, Some(format!("ssh {}@{} 'tail /home/{}/log'", bob, web01, bob)));
+      assert_eq!(anonymized[0].git_branch, Some(format!("{}/fix", alice)));
+      assert_eq!(anonymized[0].tty.as_deref(), Some("/dev/pts/3"));
+      assert_eq!(anonymized[0].multiplexer, Some(format!("tmux:{}:0.1", web01)));
+      assert!(anonymized[0].project.as_deref().is_some_and(|p| p.starts_with("project-")));
+      assert!(anonymized[0].session_id.as_deref().is_some_and(|s| s.starts_with("session-")));
+      assert_eq!(anonymized[1].user_name.as_deref(), Some("root"), "root isn't identifying");
+      assert_eq!(anonymized[1].user_id, Some(0));
+      assert_eq!(anonymized[1].command, Some(format!("psql -h {} -U {}", ip, alice)));
+      assert_eq!(anonymized[2].ip.as_deref(), Some("127.0.0.1"));
+      assert_eq!(anonymized[2].cwd, Some(format!("/Users/{}", alice)));
+      assert_eq!(anonymized[2].command.as_deref(), Some("cd /dev/null; ls"));
+      assert_eq!(anonymized[2].id, entries[2].id);
+      let ipv6 = anonymized[3].ip.clone().unwrap();
+      assert!(ipv6.starts_with("2001:db8:") && ipv6.parse::<std::net::Ipv6Addr>().is_ok(), "{}", ipv6);
+      assert_eq!(anonymized[3].command, Some(format!("ping6 {} # at 12:30:45 see std::fs", ipv6)), "Times and paths aren't addresses");
+
+      let again = Anonymizer::new(&entries[2..3], KEY);
+      assert_eq!(again.anonymize(&entries[2]), anonymized[2], "Pseudonyms don't depend on the other entries");
+      let other = Anonymizer::new(&entries, "another key");
+      assert_ne!(other.anonymize(&entries[0]).user_name, Some(alice), "Pseudonyms depend on the key");
+      let twice: Vec<_> = anonymized.iter().map(|e| Anonymizer::new(&anonymized, KEY).anonymize(e)).collect();
+      assert_eq!(twice, anonymized, "Pseudonyms are kept");
+   }
+}
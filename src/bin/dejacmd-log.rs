@@ -1,22 +1,57 @@
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
 use crossbeam::atomic::AtomicCell;
 
 use regex::Regex;
+use secrecy::SecretString;
 use clap::Parser;
 use colored::Colorize;
 use short_uuid::ShortUuid;
-use include_dir::{include_dir, Dir};
+use tracing::{Level, info};
 
+use dejacmd::ddl;
+use dejacmd::exclusions::{CentralExclusions, IgnoreRules};
+use dejacmd::formats::line::{HistoryLine, parse_history};
+use dejacmd::hooks::Hooks;
+use dejacmd::error::DejacmdError;
+use dejacmd::git::GitContext;
+use dejacmd::logging::{Rotation, RotatingFile, SystemLog};
+use dejacmd::migrations::{apply_sql_update, update_files, update_sql};
+use dejacmd::output::DEFAULT_OUTPUT_SUMMARY_LINES;
+use dejacmd::project::ProjectConfig;
+use dejacmd::remote::{RemoteCentral, RemoteError, is_remote_url};
 use dejacmd::settings::Settings;
-use dejacmd::{CREATE_INDEX_SQL, CREATE_TABLE_SQL, INSERT_HISTORY_SQL, connections, fix_placeholders, get_database};
+use dejacmd::{HistoryEntry, PoolSettings, connections_with_options, get_database_with_options, upsert_history_sql};
+use dejacmd::logd::{self, HistoryWriter, is_connection_error};
+use dejacmd::spool;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args
 {
-   history: String,
+   #[arg(required_unless_present_any = ["command", "is_batch"], conflicts_with = "command",
+         help = "Last line of the shell history (bash/zsh with the time, fish --show-time or nushell JSON)")]
+   history: Option<String>,
+
+   #[arg(long = "batch", conflicts_with_all = ["history", "command"],
+         help = "Read history lines or JSON lines (entries as exported or spooled) from stdin and write them in one transaction")]
+   pub is_batch: bool,
+
+   #[arg(short = 'c', long = "command", allow_hyphen_values = true,
+         help = "Command to log instead of a history line (e.g. from a PowerShell prompt function)")]
+   pub command: Option<String>,
+
+   #[arg(short = 't', long = "timestamp", requires = "command",
+         help = "When --command was started (yyyy-MM-dd HH:mm:ss or ISO 8601, defaults to now)")]
+   pub timestamp: Option<String>,
+
+   #[arg(short = 'n', long = "history-no", requires = "command", help = "History number of --command")]
+   pub history_no: Option<i64>,
+
+   #[arg(long = "cwd", help = "Working directory of the command (defaults to that of the shell process)")]
+   pub cwd: Option<PathBuf>,
 
    #[arg(short = 's' ,long = "status", default_value_t = -1,
          help = "Exit status of invoked command")]
@@ -26,82 +61,210 @@ struct Args
          help = "Process ID of invoked command")]
    pub pid: i64,
 
+   #[arg(short = 'd' ,long = "duration", default_value_t = -1,
+         help = "Time the invoked command took in milliseconds")]
+   pub duration: i64,
+
    #[arg(short = 'l' ,long = "log", default_value = "stderr",
-         help = r#"Log errors (path to file or "stderr" or "stdout")"#)]
+         help = r#"Log errors (path to file or "stderr", "stdout", "syslog", "journald" or "eventlog")"#)]
    pub log_destination: String,
+
+   #[arg(long = "log-max-size", value_name = "MB", default_value_t = 10,
+         help = "Size in megabytes at which a log file is rotated (0 never rotates)")]
+   pub log_max_size: u64,
+
+   #[arg(long = "log-files", value_name = "N", default_value_t = 3, help = "Number of rotated log files to keep")]
+   pub log_files: u32,
+
+   #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count,
+         help = "Increase diagnostic output written to the log destination (-v info, -vv debug, -vvv trace)")]
+   pub verbose: u8,
+
+   #[arg(long = "debug", help = "Write all diagnostics including source locations to the log destination (overrides -v)")]
+   pub is_debug: bool,
+
+   #[arg(long = "no-daemon", help = "Write to the databases even if dejacmd-logd is running")]
+   pub is_no_daemon: bool,
+
+   #[arg(long = "output-file", value_name = "PATH", conflicts_with = "is_batch",
+         help = "Terminal recording (from script) holding the command's output, see dejacmd init --capture-output")]
+   pub output_file: Option<PathBuf>,
+
+   #[arg(long = "output-offset", value_name = "BYTES", default_value_t = 0, requires = "output_file",
+         help = "Size of --output-file when the command started, its output is what was recorded after it")]
+   pub output_offset: u64,
+
+   #[arg(long = "timeout", value_name = "MS",
+         help = "Spool the command for the databases it hasn't been written to and exit if writing it takes longer, for the next dejacmd-log or dejacmd-logd to write (a .dejacmd.toml database isn't spooled: the command is lost and the exit status is 1) (overrides log_timeout_ms, 0 for no limit)")]
+   pub timeout: Option<u64>,
 }
 
-pub(crate) static ASSETS_DIR: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/assets");
 
-const REGEX: &str = r"^\s*(\d+)\s+(\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2})\s+(.+)$";
 const EMPTY_REGEX: &str = r"^\s*'\d+.*";
 
+/// Rotation of a --log file, set from the arguments
+static LOG_ROTATION: OnceLock<Rotation> = OnceLock::new();
+
 #[tokio::main]
 async fn main() -> std::process::ExitCode
 //----------------------------------
 {
+   let start = tokio::time::Instant::now();
    let args = Args::parse();
+   let rotation = Rotation::new(args.log_max_size, args.log_files);
+   let _ = LOG_ROTATION.set(rotation);
+   dejacmd::logging::init_logging(args.verbose, args.is_debug, &args.log_destination, &rotation);
 
-   sqlx::any::install_default_drivers(); // According to sqlx/src/any/install_drivers_note.md to prevent panic
-   apply_database_updates(&args.log_destination).await;
+   dejacmd::install_drivers(); // According to sqlx/src/any/install_drivers_note.md to prevent panic
 
-   // 66774  2026-01-13 17:45:51 ls -ltrh
-   let re = Regex::new(REGEX).unwrap();
-
-   let text = args.history;
-   let command_date: String;
-   let command: String;
-   if let Some(capture) = re.captures(&text)
-   {
-      // let num = &capture[1];
-      command_date = capture[2].to_string();
-      command = capture[3].to_string();
-   }
-   else
+   if dejacmd::pause::paused().is_some()
    {
-      let re = Regex::new(EMPTY_REGEX).unwrap();
-      if ! re.is_match(&text)
-      {
-         // log(&args.log_destination, format!("{} '{}'", "dejacmd-log: Failed to parse history line:", &text));
-         return std::process::ExitCode::from(1);
-      }
       return std::process::ExitCode::from(0);
    }
-   let ip = match localip::get_local_ip()
+   if args.is_batch
    {
-      Ok(i) => i.to_string(),
-      Err(_) => "".to_string()
-   };
-
-   let settings_file = match Settings::get_settings_path()
+      return log_batch(&args).await;
+   }
+   let text = args.history.clone().unwrap_or_default();
+   let parsed = match &args.command
    {
-      Ok(p) => p.display().to_string(),
-      Err(_e) => "".to_string()
+      Some(command) if command.trim().is_empty() => return std::process::ExitCode::from(0),
+      Some(command) => match command_line(command, args.timestamp.as_deref(), args.history_no)
+      {
+         Ok(line) => Some(line),
+         Err(e) =>
+         {
+            log(&args.log_destination, format!("{} {}", "dejacmd-log:", e));
+            return std::process::ExitCode::from(1);
+         }
+      },
+      None => parse_history(&text),
    };
-   let mut settings = Settings::new();
-   settings = settings.get_settings_or_default();
-   let settings = match settings.get_settings()
+   let HistoryLine { history_no, command_date, separator, command, shell: history_shell, exit_status: history_status, duration_ms, cwd: history_cwd } =
+   match parsed
    {
-      Ok(s) => s,
-      Err(e) =>
+      Some(line) => line,
+      None =>
       {
-         log(&args.log_destination,
-            format!("{} {} [{}] - {}", "dejacmd-log: Error loading settings file ", settings_file, e,
-               "Creating/using default settings with SQLite database."));
-         _ = Settings::write_default_settings();
-         Settings::default()
+         let re = Regex::new(EMPTY_REGEX).unwrap();
+         if ! re.is_match(&text)
+         {
+            // log(&args.log_destination, format!("{} '{}'", "dejacmd-log: Failed to parse history line:", &text));
+            return std::process::ExitCode::from(1);
+         }
+         return std::process::ExitCode::from(0);
       }
    };
+   let exit_status = if args.status == -1 { history_status.unwrap_or(-1) } else { args.status };
+   let duration_ms = if args.duration >= 0 { Some(args.duration) } else { duration_ms };
+   let ip = dejacmd::get_local_ip();
+   let hostname = dejacmd::get_hostname();
+   let session_id = dejacmd::get_session_id(shell_pid(args.pid));
+   let session_id = if session_id.is_empty() { None } else { Some(session_id) };
+   let ssh_origin = dejacmd::get_ssh_origin();
+   let tty = dejacmd::get_tty(shell_pid(args.pid));
+   let multiplexer = dejacmd::get_multiplexer();
+
+   let mut settings = load_settings(&args.log_destination);
 
    // println!("local database URL: {}", settings.get_local_database_url().yellow());
 
-   let (shell, os_user_id, os_user, cwd) = get_process_info().await;
+   let (shell, os_user_id, os_user, cwd) = get_process_info(&settings.get_shells()).await;
+   // The format wins over the process tree, e.g. for nushell started from bash
+   let shell = history_shell.map(|s| s.to_string()).unwrap_or(shell);
+   let cwd = args.cwd.clone().or(history_cwd.map(PathBuf::from)).unwrap_or(cwd);
+   let project = find_project(&cwd, &args.log_destination);
+   if let Some(project) = &project
+   {
+      project.apply(&mut settings);
+   }
+   let project_tag = project.as_ref().and_then(|p| p.tag.clone());
+   // dejacmd-logd and apply_database_updates only know the databases in the settings file
+   let is_project_database = project.as_ref().is_some_and(|p| p.database_url().is_some());
+   match IgnoreRules::from_settings(&settings)
+   {
+      Ok(rules) if rules.is_ignored(&command, is_leading_space(&shell, separator)) => return std::process::ExitCode::from(0),
+      Ok(_) => {},
+      Err(e) => log(&args.log_destination, format!("{} {}", "dejacmd-log: Error in ignore_commands setting:", e)),
+   }
+   let pool_settings = PoolSettings::for_logging(&settings);
+   let git = GitContext::from_dir(&cwd);
+   let (git_repo, git_branch) = (git.as_ref().map(|g| g.repo.clone()), git.and_then(|g| g.branch));
    let id = ShortUuid::generate();
    let mut local_error_messages: Vec<String> = vec![];
    let mut central_error_messages: Vec<String> = vec![];
    let mut local_location = 0;
    let mut central_location = 0;
-   let os = std::env::consts::OS.to_string();
+   // Whether the entry is in the central database, so it isn't spooled when the time is up
+   let mut is_central_written = false;
+   // Whether the entry was written as a new row (rather than already having been written by dejacmd-logd), for the
+   // post-insert hooks
+   let mut is_new_local = false;
+   let mut is_new_central = false;
+   // Why a database couldn't be connected to, whose kind of error is the exit status
+   let mut local_connect_error: Option<DejacmdError> = None;
+   let mut central_connect_error: Option<DejacmdError> = None;
+   let os = dejacmd::get_os();
+   // Written to both databases (and the spool when the central database can't be reached) after the pre-insert hooks
+   let central_entry = HistoryEntry
+   {
+      id: id.to_string(),
+      command_timestamp: command_date.clone(),
+      cwd: Some(cwd.display().to_string()),
+      shell: Some(shell.clone()),
+      user_id: Some(os_user_id as i64),
+      user_name: Some(os_user.clone()),
+      ip: Some(ip.clone()),
+      os: Some(os.clone()),
+      exit_status: Some(exit_status),
+      command: Some(command.clone()),
+      history_no,
+      hostname: Some(hostname.clone()),
+      session_id: session_id.clone(),
+      git_repo: git_repo.clone(),
+      git_branch: git_branch.clone(),
+      duration_ms,
+      ssh_origin: ssh_origin.clone(),
+      tty: tty.clone(),
+      project: project_tag.clone(),
+      multiplexer: multiplexer.clone(),
+      runs: None,
+   };
+   let hooks = Hooks::from_settings(&settings);
+   let central_entry = match hooks.pre_insert(central_entry)
+   {
+      Ok(Some(entry)) => entry,
+      Ok(None) => return std::process::ExitCode::from(0),
+      Err(e) =>
+      {
+         log(&args.log_destination, format!("{} {}", "dejacmd-log: Not logging command, pre-insert hook failed:", e));
+         return std::process::ExitCode::from(1);
+      }
+   };
+   // Summaries of output are only kept in the local database
+   let output = args.output_file.as_ref().and_then(|path| match dejacmd::output::read_capture(path, args.output_offset)
+   {
+      Ok(text) => dejacmd::output::summarize(&text, settings.output_summary_lines.unwrap_or(DEFAULT_OUTPUT_SUMMARY_LINES))
+                     .and_then(|summary| hooks.pre_insert_output(&central_entry, summary)),
+      Err(e) =>
+      {
+         log(&args.log_destination, format!("{} {}", "dejacmd-log:", e));
+         None
+      }
+   });
+   let deadline = args.timeout.or(settings.log_timeout_ms).filter(|ms| *ms > 0).map(|ms| start + Duration::from_millis(ms));
+   // A running dejacmd-logd already has the databases open (and their schema updated)
+   if !args.is_no_daemon && !is_project_database && output.is_none()
+   {
+      let send_timeout = deadline.map_or(logd::SEND_TIMEOUT, |d| d.saturating_duration_since(tokio::time::Instant::now())
+                                                                   .clamp(Duration::from_millis(1), logd::SEND_TIMEOUT));
+      match logd::socket_path().and_then(|path| logd::send(&path, &central_entry, send_timeout))
+      {
+         // dejacmd-logd runs the post-insert hooks once it has written the entry
+         Ok(_) => return std::process::ExitCode::from(0),
+         Err(e) => tracing::debug!("dejacmd-log: Not using dejacmd-logd: {}", e),
+      }
+   }
    let local_queries = async
    {
       let url = settings.get_local_database_url();
@@ -109,59 +272,81 @@ async fn main() -> std::process::ExitCode
       {
          return Ok(sqlx::any::AnyQueryResult::default());
       }
+      // The global credentials are never sent to a database from a .dejacmd.toml
       let (user, password) = match settings.get_credentials(true)
       {
-         Ok((u, p)) => (u, p),
-         Err(_e) => ("".to_string(), "".to_string())
+         Ok((u, p)) if !is_project_database => (u, p),
+         _ => ("".to_string(), SecretString::default())
       };
       local_location = 1;
-      let (local_pool, local_scheme) = match get_database(&url, &user, &password).await
+      let (local_pool, local_scheme) = match get_database_with_options(&url, &user, &password, &pool_settings).await
       {
          Ok((pool, scheme)) => (pool, scheme),
          Err(e) =>
          {
             let errmsg = format!("{} {}", "dejacmd-log: Error connecting to local database:", e);
             local_error_messages.push(errmsg);
+            local_connect_error = Some(e);
             return Ok(sqlx::any::AnyQueryResult::default());
          }
       };
       if let Some(pool) = local_pool.as_ref()
       {
          local_location = 2;
-         let mut result =  sqlx::query( CREATE_TABLE_SQL ).execute(pool).await;
+         let mut result =  sqlx::query( ddl::create_table_sql(&local_scheme) ).execute(pool).await;
          if result.is_err()
          {
-            local_error_messages.push(format!("{} {}", "dejacmd-log: Error creating table in local database:", result.as_ref().err().unwrap().to_string()));
+            local_error_messages.push(format!("{} {}", "dejacmd-log: Error creating table in local database:", result.as_ref().err().unwrap()));
             return result;
          }
          local_location = 3;
-         result = sqlx::query( CREATE_INDEX_SQL ).execute(pool).await;
+         if let Some(index_sql) = ddl::create_index_sql(&local_scheme)
+         {
+            result = sqlx::query( index_sql ).execute(pool).await;
+         }
          if result.is_err()
          {
-            local_error_messages.push(format!("{} {}", "dejacmd-log: Error creating index in local database:", result.as_ref().err().unwrap().to_string()));
+            local_error_messages.push(format!("{} {}", "dejacmd-log: Error creating index in local database:", result.as_ref().err().unwrap()));
             return result;
          }
+         if is_project_database && let Err(e) = dejacmd::migrations::apply_updates(pool, &local_scheme).await
+         {
+            local_error_messages.push(format!("{} {}", "dejacmd-log: Error updating project database:", e));
+            return Ok(sqlx::any::AnyQueryResult::default());
+         }
          local_location = 4;
-         let sql = fix_placeholders(INSERT_HISTORY_SQL, &local_scheme);
-         result = sqlx::query( &sql )
-         .bind(id.to_string())
-         .bind(&command_date)
-         .bind( cwd.display().to_string() )
-         .bind( shell.clone() )
-         .bind( os_user_id ) //os_user_id!= -1 { Some(user_id) } else { None } )
-         .bind( os_user.clone() )
-         .bind( ip.clone() )
-         .bind( os.clone() )
-         .bind( args.status )
-         .bind( command.clone() )
-         .execute(pool).await;
+         let sql = upsert_history_sql(&local_scheme);
+         result = write_direct(&central_entry, pool, &local_scheme).await.map(|is_new|
+         {
+            is_new_local = is_new;
+            sqlx::any::AnyQueryResult::default()
+         });
          if result.is_err()
          {
             let values = format!("VALUES ( {}, {}, {}, {}, {}, {}, {}, {}, {} )",
-               id, command_date.clone(), cwd.display(), shell.clone(), os_user_id, os_user.clone(),
-               ip.clone(), args.status, command.clone() );
+               central_entry.id, central_entry.command_timestamp, central_entry.cwd.as_deref().unwrap_or_default(), shell, os_user_id, os_user, ip,
+               exit_status, central_entry.command.as_deref().unwrap_or_default());
             local_error_messages.push(format!("{}: {} {}", "dejacmd-log: Error inserting command into local database:", sql, values));
          }
+         else
+         {
+            if is_new_local
+               && let Some(output) = &output
+               && let Err(e) = dejacmd::output::insert_output(pool, &local_scheme, &central_entry.id, output).await
+            {
+               local_error_messages.push(format!("{} {}", "dejacmd-log: Error saving command output:", e));
+            }
+            // Commands spooled when an earlier run timed out belong to the local database in the settings file
+            if !is_project_database
+            {
+               match spool::flush_local(pool, &local_scheme).await
+               {
+                  Ok(0) => {},
+                  Ok(n) => info!("dejacmd-log: Flushed {} commands spooled for the local database", n),
+                  Err(e) => local_error_messages.push(format!("{} {}", "dejacmd-log: Error flushing commands spooled for the local database:", e)),
+               }
+            }
+         }
          result
       }
       else
@@ -176,19 +361,79 @@ async fn main() -> std::process::ExitCode
       {
          return Ok(sqlx::any::AnyQueryResult::default());
       }
+      match CentralExclusions::from_settings(&settings)
+      {
+         Ok(exclusions) if exclusions.is_excluded_entry(&central_entry) => return Ok(sqlx::any::AnyQueryResult::default()),
+         Ok(_) => {},
+         Err(e) =>
+         {
+            // Rather not log to central than risk leaking a command that should have been excluded
+            central_error_messages.push(format!("{} {}", "dejacmd-log: Not logging to central database:", e));
+            return Ok(sqlx::any::AnyQueryResult::default());
+         }
+      }
       let (user, password) = match settings.get_credentials(false)
       {
          Ok((u, p)) => (u, p),
-         Err(_e) => ("".to_string(), "".to_string())
+         Err(_e) => ("".to_string(), SecretString::default())
       };
+      if is_remote_url(&url)
+      {
+         central_location = 5;
+         let remote = match RemoteCentral::with_connect_timeout(&url, password, pool_settings.connect_timeout)
+         {
+            Ok(r) => r,
+            Err(e) =>
+            {
+               central_error_messages.push(format!("{} {}", "dejacmd-log: Error connecting to central server:", e));
+               return Ok(sqlx::any::AnyQueryResult::default());
+            }
+         };
+         match remote.insert(std::slice::from_ref(&central_entry)).await
+         {
+            Ok(inserted) =>
+            {
+               is_central_written = true;
+               is_new_central = inserted > 0;
+               match spool::flush_remote(&remote).await
+               {
+                  Ok(0) => {},
+                  Ok(n) => info!("dejacmd-log: Flushed {} spooled commands to the central server", n),
+                  Err(e) => central_error_messages.push(format!("{} {}", "dejacmd-log: Error flushing spooled commands:", e)),
+               }
+            },
+            Err(RemoteError::Unreachable(e)) =>
+            {
+               match spool::append(&central_entry)
+               {
+                  Ok(_) => info!("dejacmd-log: Central server unavailable ({}), command spooled for later", e),
+                  Err(spool_error) =>
+                  {
+                     let errmsg = format!("{} {} ({})", "dejacmd-log: Error connecting to central server:", e, spool_error);
+                     central_error_messages.push(errmsg);
+                  }
+               }
+            },
+            Err(e) => central_error_messages.push(format!("{} {}", "dejacmd-log: Error inserting command into central server:", e)),
+         }
+         return Ok(sqlx::any::AnyQueryResult::default());
+      }
       central_location = 1;
-      let (central_pool, central_scheme) = match get_database(&url, &user, &password).await
+      let (central_pool, central_scheme) = match get_database_with_options(&url, &user, &password, &pool_settings).await
       {
          Ok((pool, scheme)) => (pool, scheme),
          Err(e) =>
          {
-            let errmsg = format!("{} {}", "dejacmd-log: Error connecting to central database:", e);
-            central_error_messages.push(errmsg);
+            match spool::append(&central_entry)
+            {
+               Ok(_) => info!("dejacmd-log: Central database unavailable ({}), command spooled for later", e),
+               Err(spool_error) =>
+               {
+                  let errmsg = format!("{} {} ({})", "dejacmd-log: Error connecting to central database:", e, spool_error);
+                  central_error_messages.push(errmsg);
+                  central_connect_error = Some(e);
+               }
+            }
             return Ok(sqlx::any::AnyQueryResult::default());
          }
       };
@@ -196,39 +441,53 @@ async fn main() -> std::process::ExitCode
       central_location = 2;
       if let Some(pool) = central_pool.as_ref()
       {
-         let mut result =  sqlx::query( CREATE_TABLE_SQL ).execute(pool).await;
+         let mut result =  sqlx::query( ddl::create_table_sql(&central_scheme) ).execute(pool).await;
          if result.is_err()
          {
-            central_error_messages.push(format!("{} {}", "dejacmd-log: Error creating table in central database:", result.as_ref().err().unwrap().to_string()));
+            central_error_messages.push(format!("{} {}", "dejacmd-log: Error creating table in central database:", result.as_ref().err().unwrap()));
             return result;
          }
          central_location = 3;
-         result = sqlx::query( CREATE_INDEX_SQL ).execute(pool).await;
+         if let Some(index_sql) = ddl::create_index_sql(&central_scheme)
+         {
+            result = sqlx::query( index_sql ).execute(pool).await;
+         }
          if result.is_err()
          {
-            central_error_messages.push(format!("{} {}", "dejacmd-log: Error creating index in central database:", result.as_ref().err().unwrap().to_string()));
+            central_error_messages.push(format!("{} {}", "dejacmd-log: Error creating index in central database:", result.as_ref().err().unwrap()));
             return result;
          }
          central_location = 4;
-         let sql = fix_placeholders(INSERT_HISTORY_SQL, &central_scheme);
-         result = sqlx::query( &sql )
-         .bind(id.to_string())
-         .bind(&command_date)
-         .bind( cwd.display().to_string() )
-         .bind( shell.clone() )
-         .bind( os_user_id ) //os_user_id!= -1 { Some(user_id) } else { None } )
-         .bind( os_user.clone() )
-         .bind( ip.clone() )
-         .bind( os.clone() )
-         .bind( args.status )
-         .bind( command.clone() )
-         .execute(pool).await;
-         if result.is_err()
+         let sql = upsert_history_sql(&central_scheme);
+         result = write_direct(&central_entry, pool, &central_scheme).await.map(|is_new|
          {
-            let values = format!("VALUES ( {}, {}, {}, {}, {}, {}, {}, {}, {} )",
-               id, command_date.clone(), cwd.display(), shell.clone(), os_user_id, os_user.clone(),
-               ip.clone(), args.status, command.clone() );
-            central_error_messages.push(format!("{}: {} {}", "dejacmd-log: Error inserting command into central database:", sql, values));
+            is_new_central = is_new;
+            sqlx::any::AnyQueryResult::default()
+         });
+         match &result
+         {
+            Ok(_) =>
+            {
+               is_central_written = true;
+               match spool::flush(pool, &central_scheme).await
+               {
+                  Ok(0) => {},
+                  Ok(n) => info!("dejacmd-log: Flushed {} spooled commands to the central database", n),
+                  Err(e) => central_error_messages.push(format!("{} {}", "dejacmd-log: Error flushing spooled commands:", e)),
+               }
+            },
+            Err(e) if is_connection_error(e) && spool::append(&central_entry).is_ok() =>
+            {
+               info!("dejacmd-log: Central database unavailable ({}), command spooled for later", e);
+               return Ok(sqlx::any::AnyQueryResult::default());
+            },
+            Err(_) =>
+            {
+               let values = format!("VALUES ( {}, {}, {}, {}, {}, {}, {}, {}, {} )",
+                  central_entry.id, central_entry.command_timestamp, central_entry.cwd.as_deref().unwrap_or_default(), shell, os_user_id, os_user, ip,
+                  exit_status, central_entry.command.as_deref().unwrap_or_default());
+               central_error_messages.push(format!("{}: {} {}", "dejacmd-log: Error inserting command into central database:", sql, values));
+            }
          }
          result
       }
@@ -238,29 +497,59 @@ async fn main() -> std::process::ExitCode
       }
    };
 
-   let (local_result, central_result) = tokio::join!(local_queries, central_queries);
+   let (local_result, central_result) = async
+   {
+      let _ = before_deadline(deadline, apply_database_updates(&args.log_destination)).await;
+      tokio::join!(before_deadline(deadline, local_queries), before_deadline(deadline, central_queries))
+   }.await;
+   let is_local_lost = local_result.is_none() && !spool_local_on_timeout(&central_entry, &settings, is_project_database, &args.log_destination);
+   let local_result = local_result.unwrap_or(Ok(sqlx::any::AnyQueryResult::default()));
+   let central_result = central_result.unwrap_or_else(||
+   {
+      if !is_central_written
+      {
+         spool_on_timeout(&central_entry, &settings, &args.log_destination);
+      }
+      Ok(sqlx::any::AnyQueryResult::default())
+   });
 
+   // Only once the entry has been written to one of the databases, and not again if dejacmd-logd had written it
+   if is_new_local || is_new_central
+   {
+      for e in hooks.post_insert(std::slice::from_ref(&central_entry))
+      {
+         log(&args.log_destination, format!("{} {}", "dejacmd-log:", e));
+      }
+   }
    let mut status = 0;
    if local_result.is_err()
    {
       log(&args.log_destination,
-         format!("{} ({}) {}", "dejacmd-log: Error inserting command into local history database:", local_location, local_result.err().unwrap().to_string()));
+         format!("{} ({}) {}", "dejacmd-log: Error inserting command into local history database:", local_location, local_result.err().unwrap()));
+      status |= 1;
+   }
+   if is_local_lost
+   {
       status |= 1;
    }
    if central_result.is_err()
    {
       log(&args.log_destination,
-         format!("{} ({}) {}", "dejacmd-log: Error inserting command into central history database:", central_location, central_result.err().unwrap().to_string()));
+         format!("{} ({}) {}", "dejacmd-log: Error inserting command into central history database:", central_location, central_result.err().unwrap()));
       status |= 2;
    }
-   if local_error_messages.len() > 0
+   if let Some(e) = local_connect_error.or(central_connect_error)
+   {
+      status = e.exit_code();
+   }
+   if !local_error_messages.is_empty()
    {
       for msg in local_error_messages
       {
-         log(&args.log_destination, format!("{}", msg));
+         log(&args.log_destination, msg);
       }
    }
-   if central_error_messages.len() > 0
+   if !central_error_messages.is_empty()
    {
       for msg in central_error_messages
       {
@@ -270,6 +559,233 @@ async fn main() -> std::process::ExitCode
    std::process::ExitCode::from(status)
 }
 
+/// Write entry when dejacmd-logd didn't acknowledge it. dejacmd-logd may have written it all the same (when its reply
+/// came too late or only its write to the other database failed), so a row that is already there is left as it is and
+/// false is returned, then the post-insert hooks dejacmd-logd ran aren't run again.
+async fn write_direct(entry: &HistoryEntry, pool: &sqlx::Pool<sqlx::Any>, scheme: &str) -> Result<bool, sqlx::Error>
+//-------------------------------------------------------------------------------------------------------------------
+{
+   entry.upsert(pool, scheme).await
+}
+
+fn load_settings(log_destination: &str) -> Settings
+//-------------------------------------------------
+{
+   let settings_file = match Settings::get_settings_path()
+   {
+      Ok(p) => p.display().to_string(),
+      Err(_e) => "".to_string()
+   };
+   let mut settings = Settings::new();
+   settings = settings.get_settings_or_default();
+   match settings.get_settings()
+   {
+      Ok(s) => s,
+      Err(e) =>
+      {
+         log(log_destination,
+            format!("{} {} [{}] - {}", "dejacmd-log: Error loading settings file ", settings_file, e,
+               "Creating/using default settings with SQLite database."));
+         _ = Settings::write_default_settings();
+         Settings::default()
+      }
+   }
+}
+
+/// --batch: Log the history lines or JSON entries read from stdin, each database written in a single transaction.
+/// Lines that can't be parsed are logged and skipped.
+async fn log_batch(args: &Args) -> std::process::ExitCode
+//-------------------------------------------------------
+{
+   let input = match std::io::read_to_string(std::io::stdin())
+   {
+      Ok(input) => input,
+      Err(e) =>
+      {
+         log(&args.log_destination, format!("{} {}", "dejacmd-log: Error reading stdin:", e));
+         return std::process::ExitCode::from(1);
+      }
+   };
+   let settings = load_settings(&args.log_destination);
+   // The project tag and ignore rules for each directory
+   let mut projects: HashMap<PathBuf, (Option<String>, Option<IgnoreRules>)> = HashMap::new();
+   let (shell, os_user_id, os_user, cwd) = get_process_info(&settings.get_shells()).await;
+   let cwd = args.cwd.clone().unwrap_or(cwd);
+   let (ip, hostname, ssh_origin, tty) = (dejacmd::get_local_ip(), dejacmd::get_hostname(), dejacmd::get_ssh_origin(),
+                                          dejacmd::get_tty(shell_pid(args.pid)));
+   let multiplexer = dejacmd::get_multiplexer();
+   let session_id = Some(dejacmd::get_session_id(shell_pid(args.pid))).filter(|s| !s.is_empty());
+   let os = dejacmd::get_os();
+   let mut status = 0;
+   let mut entries = Vec::new();
+   let empty = Regex::new(EMPTY_REGEX).unwrap();
+   for text in input.lines().filter(|l| !l.trim().is_empty() && !empty.is_match(l))
+   {
+      // An entry as exported with -E jsonl or spooled for the central database is logged as it is
+      if let Ok(entry) = serde_json::from_str::<HistoryEntry>(text)
+      {
+         entries.push(entry);
+         continue;
+      }
+      let Some(line) = parse_history(text) else
+      {
+         log(&args.log_destination, format!("{} '{}'", "dejacmd-log: Failed to parse history line:", text));
+         status = 1;
+         continue;
+      };
+      let shell = line.shell.map(|s| s.to_string()).unwrap_or(shell.clone());
+      let cwd = line.cwd.map(PathBuf::from).unwrap_or(cwd.clone());
+      let (project_tag, rules) = projects.entry(cwd.clone())
+         .or_insert_with(|| project_rules(&cwd, &settings, &args.log_destination));
+      if rules.as_ref().is_some_and(|rules| rules.is_ignored(&line.command, is_leading_space(&shell, line.separator)))
+      {
+         continue;
+      }
+      let git = GitContext::from_dir(&cwd);
+      entries.push(HistoryEntry
+      {
+         id: ShortUuid::generate().to_string(),
+         command_timestamp: line.command_date,
+         cwd: Some(cwd.display().to_string()),
+         shell: Some(shell),
+         user_id: Some(os_user_id as i64),
+         user_name: Some(os_user.clone()),
+         ip: Some(ip.clone()),
+         os: Some(os.clone()),
+         exit_status: Some(line.exit_status.unwrap_or(args.status)),
+         command: Some(line.command),
+         history_no: line.history_no,
+         hostname: Some(hostname.clone()),
+         session_id: session_id.clone(),
+         git_repo: git.as_ref().map(|g| g.repo.clone()),
+         git_branch: git.and_then(|g| g.branch),
+         duration_ms: line.duration_ms,
+         ssh_origin: ssh_origin.clone(),
+         tty: tty.clone(),
+         project: project_tag.clone(),
+         multiplexer: multiplexer.clone(),
+         runs: None,
+      });
+   }
+   let hooks = Hooks::from_settings(&settings);
+   let (entries, mut errors) = hooks.pre_insert_all(entries);
+   let (mut writer, connect_errors) = HistoryWriter::connect(&settings).await;
+   errors.extend(connect_errors);
+   let write_errors = writer.write_batch(&entries).await;
+   writer.close().await;
+   if write_errors.is_empty()
+   {
+      errors.extend(hooks.post_insert(&entries));
+   }
+   errors.extend(write_errors);
+   for e in &errors
+   {
+      log(&args.log_destination, format!("{} {}", "dejacmd-log:", e));
+   }
+   info!("dejacmd-log: Logged {} commands", entries.len());
+   if errors.is_empty() { std::process::ExitCode::from(status) } else { std::process::ExitCode::from(1) }
+}
+
+/// The .dejacmd.toml for commands run in cwd, logging any error in it
+fn find_project(cwd: &Path, log_destination: &str) -> Option<ProjectConfig>
+//-------------------------------------------------------------------------
+{
+   match ProjectConfig::find(cwd)
+   {
+      Ok(project) => project,
+      Err(e) =>
+      {
+         log(log_destination, format!("{} {}", "dejacmd-log: Ignoring project configuration:", e));
+         None
+      }
+   }
+}
+
+/// The project tag and ignore rules for commands run in cwd in --batch. Project databases aren't used in --batch, the
+/// commands are written to the databases in the settings.
+fn project_rules(cwd: &Path, settings: &Settings, log_destination: &str) -> (Option<String>, Option<IgnoreRules>)
+//---------------------------------------------------------------------------------------------------------------
+{
+   let mut settings = settings.clone();
+   let project = find_project(cwd, log_destination);
+   if let Some(project) = &project
+   {
+      project.apply(&mut settings);
+   }
+   let rules = match IgnoreRules::from_settings(&settings)
+   {
+      Ok(rules) => Some(rules),
+      Err(e) =>
+      {
+         log(log_destination, format!("{} {}", "dejacmd-log: Error in ignore_commands setting:", e));
+         None
+      }
+   };
+   (project.and_then(|p| p.tag), rules)
+}
+
+/// Run a database write, or None if it doesn't finish by the deadline
+async fn before_deadline<T>(deadline: Option<tokio::time::Instant>, write: impl Future<Output = T>) -> Option<T>
+//-------------------------------------------------------------------------------------------------------------
+{
+   match deadline
+   {
+      Some(deadline) => tokio::time::timeout_at(deadline, write).await.ok(),
+      None => Some(write.await),
+   }
+}
+
+/// Spool the entry for the central database when writing it there took too long, unless it is excluded from the
+/// central database.
+fn spool_on_timeout(entry: &HistoryEntry, settings: &Settings, log_destination: &str)
+//-----------------------------------------------------------------------------------
+{
+   if settings.get_central_database_url().trim().is_empty()
+   {
+      info!("dejacmd-log: Timed out writing command {}", entry.id);
+      return;
+   }
+   match CentralExclusions::from_settings(settings)
+   {
+      Ok(exclusions) if !exclusions.is_excluded_entry(entry) => match spool::append(entry)
+      {
+         Ok(_) => info!("dejacmd-log: Timed out writing command {}, spooled for later", entry.id),
+         Err(e) => log(log_destination, format!("{} {}", "dejacmd-log: Timed out writing command and couldn't spool it:", e)),
+      },
+      _ => info!("dejacmd-log: Timed out writing command {}", entry.id),
+   }
+}
+
+/// Spool the entry for the local database when writing it there took too long, to be written by the next dejacmd-log
+/// or dejacmd-logd that writes to it. A project database isn't the one they write to, so the entry is lost and logged
+/// as an error. Returns whether the entry was written or spooled.
+fn spool_local_on_timeout(entry: &HistoryEntry, settings: &Settings, is_project_database: bool, log_destination: &str) -> bool
+//------------------------------------------------------------------------------------------------------------------------
+{
+   if settings.get_local_database_url().trim().is_empty()
+   {
+      return true;
+   }
+   if is_project_database
+   {
+      log(log_destination, format!("{} {}", "dejacmd-log: Timed out writing command to the project database:", entry.id));
+      return false;
+   }
+   match spool::append_local(entry)
+   {
+      Ok(_) =>
+      {
+         info!("dejacmd-log: Timed out writing command {} to the local database, spooled for later", entry.id);
+         true
+      },
+      Err(e) =>
+      {
+         log(log_destination, format!("{} {}", "dejacmd-log: Timed out writing command to the local database and couldn't spool it:", e));
+         false
+      },
+   }
+}
+
 async fn apply_database_updates(log_destination: &str)
 //----------------------------------------------------------------------------------------------------------------------
 {
@@ -283,7 +799,7 @@ async fn apply_database_updates(log_destination: &str)
       Ok(s) => s,
       Err(e) =>
       {
-         log(&log_destination,
+         log(log_destination,
             format!("{} {} [{}] - {}", "dejacmd-log: Error loading settings file ", settings_file, e,
                "Creating/using default settings with SQLite database."));
          _ = Settings::write_default_settings();
@@ -294,17 +810,7 @@ async fn apply_database_updates(log_destination: &str)
    let last_central_update = settings.last_central_update_file.clone().unwrap_or_else(|| "0000000.sql".to_string());
 
    // Collect and sort SQL update files
-   let mut sql_files: Vec<_> = ASSETS_DIR.files()
-      .filter(|file| {
-         let path_str = file.path().to_string_lossy();
-         path_str.ends_with(".sql") &&
-         path_str.chars().take(7).all(|c| c.is_ascii_digit() || c == '/')
-      })
-      .collect();
-
-   sql_files.sort_by_key(|file| {
-      file.path().file_name().and_then(|n| n.to_str()).unwrap_or("")
-   });
+   let sql_files = update_files();
    let last_file = sql_files.last()
       .and_then(|file| file.path().file_name().and_then(|n| n.to_str()))
       .unwrap_or("");
@@ -315,7 +821,7 @@ async fn apply_database_updates(log_destination: &str)
 
    let new_last_local_update: AtomicCell<String> = AtomicCell::new("".to_string());
    let new_last_central_update: AtomicCell<String> = AtomicCell::new("".to_string());
-   let (local_pool_opt, local_scheme, central_pool_opt, central_scheme) = match connections(&settings, false, false).await
+   let (local_pool_opt, local_scheme, central_pool_opt, central_scheme) = match connections_with_options(&settings, &PoolSettings::for_logging(&settings), true, false).await
    {
       Ok(c) => c,
       Err(e) =>
@@ -342,19 +848,19 @@ async fn apply_database_updates(log_destination: &str)
          {
             if local_pool_opt.is_none()
             {
-               return Ok(sqlx::any::AnyQueryResult::default());
+               return Ok(0);
             }
             if filename <= last_local_update.as_str()
             {
-               return Ok(sqlx::any::AnyQueryResult::default());
+               return Ok(0);
             }
 
             let pool = local_pool_opt.as_ref().unwrap();
-            let sql = dejacmd::fix_placeholders(sql_content, &local_scheme);
-            let result =  sqlx::query(&sql).execute(pool).await;
+            let sql = update_sql(file, &local_scheme).unwrap_or(sql_content);
+            let result = apply_sql_update(pool, &local_scheme, filename, sql).await;
             if result.is_err()
             {
-               local_error_messages.push(format!("dejacmd-log: Failed to execute update {}: {}", filename, result.as_ref().err().unwrap().to_string()));
+               local_error_messages.push(format!("dejacmd-log: Failed to execute update {}: {}", filename, result.as_ref().err().unwrap()));
             }
             else
             {
@@ -367,18 +873,18 @@ async fn apply_database_updates(log_destination: &str)
          {
             if central_pool_opt.is_none()
             {
-               return Ok(sqlx::any::AnyQueryResult::default());
+               return Ok(0);
             }
             if filename <= last_central_update.as_str()
             {
-               return Ok(sqlx::any::AnyQueryResult::default());
+               return Ok(0);
             }
             let pool = central_pool_opt.as_ref().unwrap();
-            let sql = dejacmd::fix_placeholders(sql_content, &central_scheme);
-            let result =  sqlx::query(&sql).execute(pool).await;
+            let sql = update_sql(file, &central_scheme).unwrap_or(sql_content);
+            let result = apply_sql_update(pool, &central_scheme, filename, sql).await;
             if result.is_err()
             {
-               central_error_messages.push(format!("dejacmd-log: Failed to execute update {}: {}", filename, result.as_ref().err().unwrap().to_string()));
+               central_error_messages.push(format!("dejacmd-log: Failed to execute update {}: {}", filename, result.as_ref().err().unwrap()));
             }
             else
             {
@@ -444,23 +950,19 @@ fn log(destination: &str, message: String)
    {
       println!("{}", message);
    }
+   else if let Some(system_log) = SystemLog::from_destination(destination)
+   {
+      if let Err(e) = system_log.write("dejacmd-log", Level::ERROR, &message)
+      {
+         eprintln!("{} [{}]", "dejacmd-log: Error writing to system log:".red(), e.bright_red());
+         eprintln!("Log message was: {}", message);
+      }
+   }
    else
    {
       let log_path = Path::new(&destination);
-      let mut file = match OpenOptions::new()
-         .create(true)
-         .append(true)
-         .open(log_path)
-      {
-         Ok(f) => f,
-         Err(e) =>
-         {
-            eprintln!("{} {} [{}]", "dejacmd-log: Error opening log file:".red(), destination.red(), e.to_string().bright_red());
-            eprintln!("Log message was: {}", message);
-            return;
-         }
-      };
-      if let Err(e) = writeln!(file, "{}", message)
+      let file = RotatingFile::new(log_path, &LOG_ROTATION.get().copied().unwrap_or_default());
+      if let Err(e) = file.append(format!("{}\n", message).as_bytes())
       {
          eprintln!("{} {} [{}]", "dejacmd-log: Error writing to log file:".red(), destination.red(), e.to_string().bright_red());
          eprintln!("Log message was: {}", message);
@@ -468,8 +970,34 @@ fn log(destination: &str, message: String)
    }
 }
 
+/// True if the command was typed with a leading space, from the number of spaces between the time and the command in
+/// the history line. bash (with HISTTIMEFORMAT="%F %T ") separates them with one and zsh (fc -t '%Y-%m-%d %T') two.
+fn is_leading_space(shell: &str, separator: usize) -> bool
+//---------------------------------------------------------
+{
+   separator > if shell.contains("zsh") { 2 } else { 1 }
+}
+
+/// Process id of the shell, given by -p $$ in the shell hook or otherwise the parent of this process
+fn shell_pid(pid: i64) -> i64
+//---------------------------
+{
+   if pid > 0
+   {
+      return pid;
+   }
+   #[cfg(unix)]
+   {
+      std::os::unix::process::parent_id() as i64
+   }
+   #[cfg(not(unix))]
+   {
+      -1
+   }
+}
+
 #[allow(unused)]
-async fn get_process_info() -> (String, i32, String, PathBuf)
+async fn get_process_info(shells: &[String]) -> (String, i32, String, PathBuf)
 //------------------------------------------------------------------------------------------------------
 {
    let _my_pid = std::process::id();
@@ -491,7 +1019,7 @@ async fn get_process_info() -> (String, i32, String, PathBuf)
          if let Ok(uid) = p.loginuid() {
             user_id = uid as i32;
          }
-         let (sh, sh_cwd) = find_linux_shell(&p);
+         let (sh, sh_cwd) = find_linux_shell(&p, shells);
          // println!("Found shell: {} at {}", sh, sh_cwd.display());
          shell = sh;
          if !sh_cwd.as_os_str().is_empty() { cwd = sh_cwd; }
@@ -516,12 +1044,22 @@ async fn get_process_info() -> (String, i32, String, PathBuf)
       {
          user = u.name;
       }
-      shell = std::env::var("SHELL").unwrap_or("".to_string());
+      let (sh, sh_cwd) = find_bsd_shell(shells);
+      shell = sh;
+      if !sh_cwd.as_os_str().is_empty() { cwd = sh_cwd; }
+      if shell.is_empty()
+      {
+         shell = std::env::var("SHELL").unwrap_or("".to_string());
+      }
    }
 
    #[cfg(target_os = "windows")]
    {
-      shell = std::env::var("COMSPEC").unwrap_or("".to_string());
+      shell = find_windows_shell().unwrap_or_else(|| std::env::var("COMSPEC").unwrap_or("".to_string()));
+      if let Some(label) = Path::new(&shell).file_name().and_then(|exe| windows_shell_label(&exe.to_string_lossy(), None))
+      {
+         shell = label.to_string();
+      }
       user_id = -1;
       user = std::env::var("USERNAME").unwrap_or("".to_string());
       cwd = match std::env::current_dir()
@@ -540,9 +1078,64 @@ async fn get_process_info() -> (String, i32, String, PathBuf)
    (shell, user_id, user, cwd)
 }
 
+/// A command given with --command (and --timestamp and --history-no) instead of a history line. Leading whitespace is
+/// counted in the separator as it would be in a history line.
+fn command_line(command: &str, timestamp: Option<&str>, history_no: Option<i64>) -> Result<HistoryLine, String>
+//---------------------------------------------------------------------------------------------------------------
+{
+   let time = match timestamp
+   {
+      | None => chrono::Local::now().naive_local(),
+      | Some(t) => match chrono::DateTime::parse_from_rfc3339(t)
+      {
+         | Ok(t) => t.with_timezone(&chrono::Local).naive_local(),
+         | Err(_) => ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter()
+            .find_map(|format| chrono::NaiveDateTime::parse_and_remainder(t, format).ok().map(|(t, _)| t))
+            .ok_or_else(|| format!("Invalid timestamp '{}' (expected yyyy-MM-dd HH:mm:ss)", t))?,
+      },
+   };
+   let trimmed = command.trim_start();
+   Ok(HistoryLine
+   {
+      history_no, command_date: time.format("%Y-%m-%d %H:%M:%S").to_string(), separator: 1 + command.len() - trimmed.len(),
+      command: trimmed.to_string(), shell: None, exit_status: None, duration_ms: None, cwd: None,
+   })
+}
+
+/// Whether the program of a parent process is one of shells (see Settings::get_shells). The file name is matched, less
+/// the - of a login shell and any version suffix (bash-5.2, python3.12), so ssh isn't taken for sh or nuget for nu.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn is_shell_program(program: &str, shells: &[String]) -> bool
+//-----------------------------------------------------------
+{
+   let name = Path::new(program).file_name().map(|n| n.to_string_lossy().trim_start_matches('-').to_string()).unwrap_or_default();
+   shells.iter().any(|shell| name.strip_prefix(shell.as_str())
+      .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '-')))
+}
+
+/// The shell a parent process with cmdline runs, the program or, for shells such as xonsh that run in an interpreter
+/// (python xonsh), the script
 #[cfg(target_os = "linux")]
-fn find_linux_shell(proc: &procfs::process::Process) -> (String, PathBuf)
-//--------------------------------------------------------------
+fn shell_program(cmdline: &[String], shells: &[String]) -> Option<String>
+//-----------------------------------------------------------------------
+{
+   let program = cmdline.first()?;
+   if is_shell_program(program, shells)
+   {
+      return Some(program.clone());
+   }
+   let interpreters = ["python".to_string(), "pypy".to_string()];
+   if is_shell_program(program, &interpreters)
+   {
+      // Interpreter options come before the script e.g python3 -u /usr/bin/xonsh
+      return cmdline.iter().skip(1).find(|arg| !arg.starts_with('-')).filter(|script| is_shell_program(script, shells)).cloned();
+   }
+   None
+}
+
+#[cfg(target_os = "linux")]
+fn find_linux_shell(proc: &procfs::process::Process, shells: &[String]) -> (String, PathBuf)
+//------------------------------------------------------------------------------------------
 {
    let mut shell = "".to_string();
    let mut cwd = PathBuf::new();
@@ -564,15 +1157,11 @@ fn find_linux_shell(proc: &procfs::process::Process) -> (String, PathBuf)
          }
       };
       let cmdline = process.cmdline().unwrap_or(vec![]);
-      if ! cmdline.is_empty()
+      if let Some(program) = shell_program(&cmdline, shells)
       {
-         let cmd = &cmdline[0];
-         if cmd.contains("bash") || cmd.contains("zsh") || cmd.contains("pwsh") || cmd.contains("fish") //|| cmd.contains("ksh") || cmd.contains("tcsh") || cmd.contains("csh") || cmd.contains("sh")
-         {
-            shell = cmd.to_string();
-            cwd = process.cwd().unwrap_or(std::path::PathBuf::new());
-            break;
-         }
+         shell = program;
+         cwd = process.cwd().unwrap_or(std::path::PathBuf::new());
+         break;
       }
       ppid = match process.stat()
       {
@@ -583,21 +1172,233 @@ fn find_linux_shell(proc: &procfs::process::Process) -> (String, PathBuf)
    (shell, cwd)
 }
 
+/// The shell dejacmd-log was started from and its working directory, found by walking up the parent processes with
+/// libproc (macOS) or sysctl (FreeBSD) as find_linux_shell does with procfs
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn find_bsd_shell(shells: &[String]) -> (String, PathBuf)
+//-------------------------------------------------------
+{
+   let mut pid = std::os::unix::process::parent_id() as i32;
+   // Process ids can be reused while walking up so the walk is limited in case it loops
+   for _ in 0..64
+   {
+      if pid <= 0
+      {
+         break;
+      }
+      let Some((ppid, program)) = bsd_process(pid) else { break };
+      if is_shell_program(&program, shells)
+      {
+         return (program, bsd_process_cwd(pid).unwrap_or_default());
+      }
+      pid = ppid;
+   }
+   ("".to_string(), PathBuf::new())
+}
+
+/// The parent process id and executable path (or command name if the path isn't available) of process pid
+#[cfg(target_os = "macos")]
+fn bsd_process(pid: i32) -> Option<(i32, String)>
+//-----------------------------------------------
+{
+   use nix::libc::{PROC_PIDPATHINFO_MAXSIZE, PROC_PIDTBSDINFO, c_void, proc_bsdinfo, proc_pidinfo, proc_pidpath};
+
+   // SAFETY: Each call is given a buffer of the size passed to it and the sizes written are checked
+   unsafe
+   {
+      let mut info: proc_bsdinfo = std::mem::zeroed();
+      let size = std::mem::size_of::<proc_bsdinfo>() as i32;
+      if proc_pidinfo(pid, PROC_PIDTBSDINFO, 0, &mut info as *mut proc_bsdinfo as *mut c_void, size) != size
+      {
+         return None;
+      }
+      let mut path = vec![0u8; PROC_PIDPATHINFO_MAXSIZE as usize];
+      let length = proc_pidpath(pid, path.as_mut_ptr() as *mut c_void, path.len() as u32);
+      let program = if length > 0
+      {
+         String::from_utf8_lossy(&path[..length as usize]).to_string()
+      }
+      else
+      {
+         std::ffi::CStr::from_ptr(info.pbi_comm.as_ptr()).to_string_lossy().to_string()
+      };
+      Some((info.pbi_ppid as i32, program))
+   }
+}
+
+/// The working directory of process pid, None if it can't be read (e.g. it belongs to another user)
+#[cfg(target_os = "macos")]
+fn bsd_process_cwd(pid: i32) -> Option<PathBuf>
+//---------------------------------------------
+{
+   use nix::libc::{PROC_PIDVNODEPATHINFO, c_char, c_void, proc_pidinfo, proc_vnodepathinfo};
+
+   // SAFETY: The buffer is the size passed, and vip_path is a NUL terminated MAXPATHLEN string (split into rows by libc)
+   unsafe
+   {
+      let mut info: proc_vnodepathinfo = std::mem::zeroed();
+      let size = std::mem::size_of::<proc_vnodepathinfo>() as i32;
+      if proc_pidinfo(pid, PROC_PIDVNODEPATHINFO, 0, &mut info as *mut proc_vnodepathinfo as *mut c_void, size) != size
+      {
+         return None;
+      }
+      let path = std::ffi::CStr::from_ptr(info.pvi_cdir.vip_path.as_ptr() as *const c_char).to_string_lossy().to_string();
+      if path.is_empty() { None } else { Some(PathBuf::from(path)) }
+   }
+}
+
+/// Read a fixed size sysctl value (kern.proc.pid.<pid> or kern.proc.cwd.<pid>)
+#[cfg(target_os = "freebsd")]
+fn bsd_sysctl<T>(mib: &[i32]) -> Option<T>
+//-----------------------------------------
+{
+   use nix::libc::{c_void, sysctl};
+
+   // SAFETY: The kinfo structs read are plain C structs for which zeroed is valid, and size is the size of value
+   unsafe
+   {
+      let mut value: T = std::mem::zeroed();
+      let mut size = std::mem::size_of::<T>();
+      let result = sysctl(mib.as_ptr(), mib.len() as u32, &mut value as *mut T as *mut c_void, &mut size,
+                          std::ptr::null(), 0);
+      if result == 0 && size > 0 { Some(value) } else { None }
+   }
+}
+
+/// The parent process id and command name of process pid
+#[cfg(target_os = "freebsd")]
+fn bsd_process(pid: i32) -> Option<(i32, String)>
+//-----------------------------------------------
+{
+   use nix::libc::{CTL_KERN, KERN_PROC, KERN_PROC_PID, kinfo_proc};
+
+   let info: kinfo_proc = bsd_sysctl(&[CTL_KERN, KERN_PROC, KERN_PROC_PID, pid])?;
+   // SAFETY: ki_comm is NUL terminated
+   let program = unsafe { std::ffi::CStr::from_ptr(info.ki_comm.as_ptr()) }.to_string_lossy().to_string();
+   Some((info.ki_ppid, program))
+}
+
+/// The working directory of process pid, None if it can't be read (e.g. it belongs to another user)
+#[cfg(target_os = "freebsd")]
+fn bsd_process_cwd(pid: i32) -> Option<PathBuf>
+//---------------------------------------------
+{
+   use nix::libc::{CTL_KERN, KERN_PROC, KERN_PROC_CWD, kinfo_file};
+
+   let info: kinfo_file = bsd_sysctl(&[CTL_KERN, KERN_PROC, KERN_PROC_CWD, pid])?;
+   // SAFETY: kf_path is NUL terminated
+   let path = unsafe { std::ffi::CStr::from_ptr(info.kf_path.as_ptr()) }.to_string_lossy().to_string();
+   if path.is_empty() { None } else { Some(PathBuf::from(path)) }
+}
+
+/// The shell dejacmd-log was started from, found by walking up the parent processes. The full path of a bash.exe
+/// tells the WSL launcher apart from Git Bash or MSYS2.
+#[cfg(target_os = "windows")]
+fn find_windows_shell() -> Option<String>
+//---------------------------------------
+{
+   use std::collections::HashMap;
+   use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+   use windows_sys::Win32::System::Diagnostics::ToolHelp::{CreateToolhelp32Snapshot, PROCESSENTRY32W, Process32FirstW,
+                                                          Process32NextW, TH32CS_SNAPPROCESS};
+   use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+                                               QueryFullProcessImageNameW};
+
+   // Process id to parent id and executable name
+   let mut processes: HashMap<u32, (u32, String)> = HashMap::new();
+   // SAFETY: The snapshot handle is checked before use and closed, and entry has its size set as Process32FirstW requires
+   unsafe
+   {
+      let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+      if snapshot == INVALID_HANDLE_VALUE
+      {
+         return None;
+      }
+      let mut entry = PROCESSENTRY32W { dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32, ..Default::default() };
+      let mut is_entry = Process32FirstW(snapshot, &mut entry) != 0;
+      while is_entry
+      {
+         let len = entry.szExeFile.iter().position(|c| *c == 0).unwrap_or(entry.szExeFile.len());
+         processes.insert(entry.th32ProcessID, (entry.th32ParentProcessID, String::from_utf16_lossy(&entry.szExeFile[..len])));
+         is_entry = Process32NextW(snapshot, &mut entry) != 0;
+      }
+      CloseHandle(snapshot);
+   }
+   let image_path = |pid: u32| -> Option<String>
+   {
+      let mut buffer = [0u16; 1024];
+      let mut size = buffer.len() as u32;
+      // SAFETY: The handle is checked before use and closed, and size is the length of buffer which receives the path
+      unsafe
+      {
+         let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+         if process.is_null()
+         {
+            return None;
+         }
+         let is_path = QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, buffer.as_mut_ptr(), &mut size) != 0;
+         CloseHandle(process);
+         if is_path { Some(String::from_utf16_lossy(&buffer[..size as usize])) } else { None }
+      }
+   };
+   let mut pid = std::process::id();
+   // Windows reuses the ids of exited parents so the walk is limited in case it loops
+   for _ in 0..64
+   {
+      let (ppid, _) = processes.get(&pid)?;
+      let (_, exe) = processes.get(ppid)?;
+      if let Some(shell) = windows_shell_label(exe, image_path(*ppid).as_deref())
+      {
+         return Some(shell.to_string());
+      }
+      pid = *ppid;
+   }
+   None
+}
+
+/// The shell label for a Windows executable with its full path if known, powershell for both Windows PowerShell
+/// (powershell.exe) and PowerShell 7 (pwsh.exe) and wsl for wsl.exe or the bash.exe in System32 that starts the
+/// default WSL distribution. None if it isn't a shell.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn windows_shell_label(exe: &str, path: Option<&str>) -> Option<&'static str>
+//---------------------------------------------------------------------------
+{
+   let is_system32 = path.is_some_and(|p| p.to_lowercase().replace('/', "\\").contains("\\windows\\system32\\"));
+   match exe.to_lowercase().trim_end_matches(".exe")
+   {
+      | "pwsh" | "powershell" => Some("powershell"),
+      | "cmd" => Some("cmd"),
+      | "wsl" | "wslhost" => Some("wsl"),
+      | "bash" if is_system32 => Some("wsl"),
+      | "bash" => Some("bash"),
+      | "zsh" => Some("zsh"),
+      | "fish" => Some("fish"),
+      | "nu" => Some("nu"),
+      | _ => None,
+   }
+}
+
 #[cfg(test)]
 // cargo test --bin dejacmd-log
 mod tests
 {
-    use regex::Regex;
+    use super::*;
 
     #[test]
     fn test_history_regex()
     {
-        let re = Regex::new(crate::REGEX).unwrap();
-        let text = "66774  2026-01-13 17:45:51 ls -ltrh ";
-        let capture = re.captures(text).unwrap();
-        assert_eq!(&capture[1], "66774");
-        assert_eq!(&capture[2], "2026-01-13 17:45:51");
-        assert_eq!(&capture[3], "ls -ltrh ");
+        let line = parse_history("66774  2026-01-13 17:45:51 ls -ltrh ").unwrap();
+        assert_eq!(line.history_no, Some(66774));
+        assert_eq!(line.command_date, "2026-01-13 17:45:51");
+        assert_eq!(line.separator, 1);
+        assert_eq!(line.command, "ls -ltrh ");
+
+        // A leading space (HISTCONTROL=ignorespace) shows up as a longer separator
+        let line = parse_history("  8  2026-01-13 17:45:51  export TOKEN=x").unwrap();
+        assert!(crate::is_leading_space("/usr/bin/bash", line.separator));
+        assert_eq!(line.command, "export TOKEN=x");
+        assert!(!crate::is_leading_space("zsh", line.separator), "zsh's fc separates the time with two spaces");
+        assert!(crate::is_leading_space("zsh", 3));
 
       //  An * shouldn't occur for fc ... -1 - could extend REGEX to handle it or just remove the * if '\d.*\*.*' matches.
       //   (According to LLM means this entry is the most recently executed command (i.e., the fc command itself))
@@ -607,4 +1408,128 @@ mod tests
       //   assert_eq!(&capture[2], "2026-01-22 17:04:24");
       //   assert_eq!(&capture[3], "cd /src/Rust/dejacmd");
     }
+
+    #[test]
+    fn test_command_line()
+    {
+        let line = crate::command_line("Get-ChildItem -Force", Some("2026-01-13 17:45:51"), Some(12)).unwrap();
+        assert_eq!(line, HistoryLine
+        {
+            history_no: Some(12), command_date: "2026-01-13 17:45:51".to_string(), separator: 1,
+            command: "Get-ChildItem -Force".to_string(), shell: None, exit_status: None, duration_ms: None, cwd: None,
+        });
+        assert_eq!(crate::command_line("ls", Some("2026-01-13T17:45:51.1234567"), None).unwrap().command_date, "2026-01-13 17:45:51");
+        let start = chrono::DateTime::parse_from_rfc3339("2026-01-13T17:45:51+02:00").unwrap().with_timezone(&chrono::Local);
+        assert_eq!(crate::command_line("ls", Some("2026-01-13T17:45:51+02:00"), None).unwrap().command_date,
+                   start.format("%Y-%m-%d %H:%M:%S").to_string());
+        let line = crate::command_line("  $env:TOKEN = 'x'", None, None).unwrap();
+        assert_eq!((line.separator, line.command.as_str()), (3, "$env:TOKEN = 'x'"));
+        assert!(crate::command_line("ls", Some("13/01/2026"), None).is_err());
+
+        assert_eq!(crate::windows_shell_label("pwsh.exe", None), Some("powershell"));
+        assert_eq!(crate::windows_shell_label("PowerShell.EXE", None), Some("powershell"));
+        assert_eq!(crate::windows_shell_label("cmd.exe", Some(r"C:\Windows\System32\cmd.exe")), Some("cmd"));
+        assert_eq!(crate::windows_shell_label("explorer.exe", None), None);
+        assert_eq!(crate::windows_shell_label("bash.exe", Some(r"C:\WINDOWS\system32\bash.exe")), Some("wsl"));
+        assert_eq!(crate::windows_shell_label("bash.exe", Some(r"C:\Program Files\Git\usr\bin\bash.exe")), Some("bash"));
+        assert_eq!(crate::windows_shell_label("wsl.exe", None), Some("wsl"));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    #[test]
+    fn test_is_shell_program()
+    {
+        let shells = dejacmd::settings::Settings::default().get_shells();
+        assert!(crate::is_shell_program("-bash", &shells));
+        assert!(crate::is_shell_program("/usr/bin/fish", &shells));
+        assert!(crate::is_shell_program("/home/me/.cargo/bin/nu", &shells));
+        assert!(crate::is_shell_program("-nu", &shells));
+        assert!(crate::is_shell_program("/bin/dash", &shells));
+        assert!(crate::is_shell_program("/usr/bin/ksh93", &shells));
+        assert!(crate::is_shell_program("-tcsh", &shells));
+        assert!(crate::is_shell_program("/opt/bash-5.2", &shells));
+        assert!(!crate::is_shell_program("/usr/bin/nuget", &shells));
+        assert!(!crate::is_shell_program("/usr/bin/gnu-tool", &shells));
+        assert!(!crate::is_shell_program("/usr/bin/ssh", &shells));
+        assert!(!crate::is_shell_program("/usr/bin/bashbug", &shells));
+        let custom = vec!["elvish".to_string()];
+        assert!(crate::is_shell_program("/usr/local/bin/elvish", &custom));
+        assert!(!crate::is_shell_program("/bin/bash", &custom));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shell_program()
+    {
+        let shells = dejacmd::settings::Settings::default().get_shells();
+        let cmdline = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(crate::shell_program(&cmdline(&["-zsh"]), &shells).as_deref(), Some("-zsh"));
+        assert_eq!(crate::shell_program(&cmdline(&["/usr/bin/python3", "-u", "/usr/bin/xonsh"]), &shells).as_deref(),
+                   Some("/usr/bin/xonsh"));
+        assert_eq!(crate::shell_program(&cmdline(&["/usr/bin/python3", "manage.py"]), &shells), None);
+        assert_eq!(crate::shell_program(&cmdline(&["/usr/bin/vim"]), &shells), None);
+        assert_eq!(crate::shell_program(&[], &shells), None);
+    }
+
+    #[test]
+    fn test_spool_local_on_timeout()
+    {
+        let entry = HistoryEntry::new_for_test("abc", "2026-01-11 04:33:25", "make");
+        assert!(crate::spool_local_on_timeout(&entry, &Settings::new_for_test("", ""), false, "stderr"), "No local database");
+        let settings = Settings::new_for_test("sqlite:///tmp/project.db", "");
+        assert!(!crate::spool_local_on_timeout(&entry, &settings, true, "stderr"), "A project database isn't spooled for");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_direct_after_daemon_error()
+    {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        let dir = std::env::temp_dir().join(format!("dejacmd_log_test_{}", nanos));
+        std::fs::create_dir_all(&dir).unwrap();
+        let local_url = format!("sqlite://{}", dir.join("local.db").display());
+        let (mut writer, errors) = HistoryWriter::connect(&Settings::new_for_test(&local_url, "")).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // A dejacmd-logd that writes the entry to the local database but then fails writing it to the central one
+        let path = dir.join(logd::SOCKET_FILE);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel::<logd::Queued>();
+        tokio::spawn(async move
+        {
+            while let Ok((stream, _)) = listener.accept().await
+            {
+                tokio::spawn(logd::handle_client(stream, queue.clone()));
+            }
+        });
+        let daemon = async
+        {
+            let (entry, reply) = queued.recv().await.unwrap();
+            assert!(writer.write(&entry).await.is_empty());
+            let _ = reply.send(Err("Error inserting command into central database".to_string()));
+        };
+        let entry = HistoryEntry::new_for_test("a", "2026-01-11 04:33:25", "make");
+        let sent = (path.clone(), entry.clone());
+        let (_, result) = tokio::join!(daemon, tokio::task::spawn_blocking(move || logd::send(&sent.0, &sent.1, logd::SEND_TIMEOUT)));
+        assert!(result.unwrap().unwrap_err().contains("central database"));
+
+        let (pool, scheme) = get_database_with_options(&local_url, "", &SecretString::default(), &PoolSettings::default())
+            .await.unwrap();
+        let pool = pool.unwrap();
+        assert!(!write_direct(&entry, &pool, &scheme).await.unwrap(), "Already written by dejacmd-logd, so no hooks run");
+        assert!(write_direct(&HistoryEntry::new_for_test("b", "2026-01-11 04:34:00", "ls"), &pool, &scheme).await.unwrap());
+        let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM history").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 2);
+        pool.close().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_before_deadline()
+    {
+        let soon = tokio::time::Instant::now() + std::time::Duration::from_millis(50);
+        assert_eq!(crate::before_deadline(Some(soon), async { 1 }).await, Some(1));
+        assert_eq!(crate::before_deadline(Some(soon), std::future::pending::<i32>()).await, None, "A hung write is abandoned");
+        assert_eq!(crate::before_deadline(None, async { 2 }).await, Some(2));
+    }
 }
@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use tracing::{error, info, warn};
+
+use dejacmd::digest::Digest;
+use dejacmd::hooks::Hooks;
+use dejacmd::logd::{HistoryWriter, Queued, socket_path};
+use dejacmd::logging::Rotation;
+use dejacmd::settings::Settings;
+
+/// How often dejacmd-logd checks whether a digest is due
+const DIGEST_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long dejacmd-logd waits before retrying a digest it couldn't deliver
+const DIGEST_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(Parser, Debug)]
+#[command(author, version, long_about = None,
+          about = "Keeps the history database connections open and writes the commands dejacmd-log sends it over a Unix socket",
+          after_help = r#"Examples:
+   dejacmd-logd &
+   dejacmd-logd --log ~/.cache/dejacmd-logd.log -v
+
+While it is running dejacmd-log hands each command to it and returns once it has been written instead of connecting
+to the databases itself. If it isn't running (or doesn't write the command within 500ms) dejacmd-log writes the
+command directly. Commands are written in the order they arrive and changes to the settings apply from the next
+command. Stop it with Ctrl-C or SIGTERM once it has written those queued.
+
+When digest_schedule is set (daily or weekly) it also delivers an activity digest, the dejacmd report of the last day
+or week of the central database (or the local database without one), to digest_file, digest_webhook and/or
+digest_sendmail_to, e.g
+   dejacmd config --set digest_schedule weekly
+   dejacmd config --set digest_webhook https://chat.example.com/hooks/dejacmd"#)]
+struct Args
+{
+   #[arg(long = "socket", value_name = "PATH", help = "Unix socket to listen on (defaults to dejacmd-logd.sock in the config directory)")]
+   pub socket: Option<PathBuf>,
+
+   #[arg(short = 'l' ,long = "log", default_value = "stderr",
+         help = r#"Log errors (path to file or "stderr", "stdout", "syslog", "journald" or "eventlog")"#)]
+   pub log_destination: String,
+
+   #[arg(long = "log-max-size", value_name = "MB", default_value_t = 10,
+         help = "Size in megabytes at which a log file is rotated (0 never rotates)")]
+   pub log_max_size: u64,
+
+   #[arg(long = "log-files", value_name = "N", default_value_t = 3, help = "Number of rotated log files to keep")]
+   pub log_files: u32,
+
+   #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count,
+         help = "Increase diagnostic output written to the log destination (-v info, -vv debug, -vvv trace)")]
+   pub verbose: u8,
+
+   #[arg(long = "debug", help = "Write all diagnostics including source locations to the log destination (overrides -v)")]
+   pub is_debug: bool,
+}
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() -> std::process::ExitCode
+//----------------------------------------
+{
+   let args = Args::parse();
+   let rotation = Rotation::new(args.log_max_size, args.log_files);
+   dejacmd::logging::init_logging(args.verbose, args.is_debug, &args.log_destination, &rotation);
+   dejacmd::install_drivers();
+
+   let path = match args.socket.map(Ok).unwrap_or_else(socket_path)
+   {
+      Ok(p) => p,
+      Err(e) =>
+      {
+         error!("dejacmd-logd: {}", e);
+         return std::process::ExitCode::from(1);
+      }
+   };
+   if path.exists()
+   {
+      if std::os::unix::net::UnixStream::connect(&path).is_ok()
+      {
+         error!("dejacmd-logd: Already running, listening on {}", path.display());
+         return std::process::ExitCode::from(1);
+      }
+      // Left behind by one that didn't exit cleanly
+      let _ = std::fs::remove_file(&path);
+   }
+   let listener = match tokio::net::UnixListener::bind(&path)
+   {
+      Ok(l) => l,
+      Err(e) =>
+      {
+         error!("dejacmd-logd: Error listening on {}: {}", path.display(), e);
+         return std::process::ExitCode::from(1);
+      }
+   };
+   // Only the user may send commands to be logged as them
+   {
+      use std::os::unix::fs::PermissionsExt;
+      if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+      {
+         warn!("dejacmd-logd: Error restricting permissions of {}: {}", path.display(), e);
+      }
+   }
+
+   let settings_path = Settings::get_settings_path().ok();
+   let mut settings_modified = settings_path.as_deref().and_then(modified);
+   let mut settings = load_settings();
+   let mut hooks = Hooks::from_settings(&settings);
+   let mut writer = connect(&settings).await;
+
+   let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+   {
+      Ok(s) => s,
+      Err(e) =>
+      {
+         error!("dejacmd-logd: Error handling SIGTERM: {}", e);
+         return std::process::ExitCode::from(1);
+      }
+   };
+   tokio::spawn(deliver_digests());
+   let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel::<Queued>();
+   info!("dejacmd-logd: Listening on {}", path.display());
+   let socket = path.clone();
+   tokio::spawn(async move
+   {
+      loop
+      {
+         tokio::select!
+         {
+            accepted = listener.accept() => match accepted
+            {
+               Ok((stream, _)) => { tokio::spawn(dejacmd::logd::handle_client(stream, queue.clone())); },
+               Err(e) => warn!("dejacmd-logd: Error accepting connection: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+         }
+      }
+      // Stop accepting commands, the queue closes once those already sent have been queued
+      let _ = std::fs::remove_file(&socket);
+   });
+
+   while let Some((entry, reply)) = queued.recv().await
+   {
+      // Settings changed by dejacmd (e.g. a new database URL, password or exclusions) apply from the next command
+      let modified = settings_path.as_deref().and_then(modified);
+      if modified != settings_modified
+      {
+         settings_modified = modified;
+         let reloaded = load_settings();
+         // Only a digest having been delivered doesn't need a reconnect
+         settings.last_digest = reloaded.last_digest.clone();
+         if serde_json::to_string(&reloaded).ok() != serde_json::to_string(&settings).ok()
+         {
+            info!("dejacmd-logd: Settings changed, reconnecting");
+            settings = reloaded;
+            hooks = Hooks::from_settings(&settings);
+            writer.close().await;
+            writer = connect(&settings).await;
+         }
+      }
+      let errors = writer.write(&entry).await;
+      for e in &errors
+      {
+         error!("dejacmd-logd: {}", e);
+      }
+      // dejacmd-log writes the entry itself if it isn't acknowledged
+      let is_written = errors.is_empty();
+      let _ = reply.send(if is_written { Ok(()) } else { Err(errors.join("; ")) });
+      if is_written
+      {
+         for e in hooks.post_insert(std::slice::from_ref(&entry))
+         {
+            warn!("dejacmd-logd: {}", e);
+         }
+      }
+   }
+   writer.close().await;
+   info!("dejacmd-logd: Stopped");
+   std::process::ExitCode::SUCCESS
+}
+
+/// Deliver the digest to each target it is due for, checking every minute with the current settings. Targets it
+/// couldn't be delivered to are retried an hour later.
+async fn deliver_digests()
+//------------------------
+{
+   let mut retry_at: Option<tokio::time::Instant> = None;
+   let mut interval = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+   loop
+   {
+      interval.tick().await;
+      if retry_at.is_some_and(|at| tokio::time::Instant::now() < at)
+      {
+         continue;
+      }
+      retry_at = None;
+      let settings = load_settings();
+      let digest = match Digest::from_settings(&settings)
+      {
+         | Ok(Some(digest)) => digest,
+         | Ok(None) => continue,
+         | Err(e) =>
+         {
+            warn!("dejacmd-logd: {}", e);
+            retry_at = Some(tokio::time::Instant::now() + DIGEST_RETRY_INTERVAL);
+            continue;
+         },
+      };
+      let now = chrono::Utc::now();
+      let targets = digest.due_targets(settings.last_digest.as_ref(), now);
+      if targets.is_empty()
+      {
+         continue;
+      }
+      let report = match digest.generate().await
+      {
+         | Ok(report) => report,
+         | Err(e) =>
+         {
+            error!("dejacmd-logd: Error generating digest: {}", e);
+            retry_at = Some(tokio::time::Instant::now() + DIGEST_RETRY_INTERVAL);
+            continue;
+         },
+      };
+      let mut delivered = Vec::new();
+      for target in targets
+      {
+         match digest.deliver(target, &report).await
+         {
+            | Ok(_) =>
+            {
+               info!("dejacmd-logd: Digest delivered to {}", target);
+               delivered.push(target.key());
+            },
+            | Err(e) =>
+            {
+               error!("dejacmd-logd: {}", e);
+               retry_at = Some(tokio::time::Instant::now() + DIGEST_RETRY_INTERVAL);
+            },
+         }
+      }
+      if delivered.is_empty()
+      {
+         continue;
+      }
+      // Reloaded so settings changed while the digest was generated aren't overwritten
+      let mut settings = load_settings();
+      let last = settings.last_digest.get_or_insert_with(Default::default);
+      for key in delivered
+      {
+         last.insert(key.to_string(), dejacmd::digest::timestamp(now));
+      }
+      if let Err(e) = settings.write_settings()
+      {
+         error!("dejacmd-logd: Error saving last_digest: {}", e);
+      }
+   }
+}
+
+fn load_settings() -> Settings
+//----------------------------
+{
+   match Settings::new().get_settings_or_default().get_settings()
+   {
+      Ok(s) => s,
+      Err(e) =>
+      {
+         warn!("dejacmd-logd: Error loading settings ({}), using the default settings", e);
+         Settings::default()
+      }
+   }
+}
+
+async fn connect(settings: &Settings) -> HistoryWriter
+//----------------------------------------------------
+{
+   let (writer, errors) = HistoryWriter::connect(settings).await;
+   for e in errors
+   {
+      warn!("dejacmd-logd: {} (retrying with the next command)", e);
+   }
+   writer
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime>
+//-------------------------------------------------------
+{
+   std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(not(unix))]
+fn main() -> std::process::ExitCode
+//----------------------------------
+{
+   let args = Args::parse();
+   let rotation = Rotation::new(args.log_max_size, args.log_files);
+   dejacmd::logging::init_logging(args.verbose, args.is_debug, &args.log_destination, &rotation);
+   error!("dejacmd-logd: Unix domain sockets aren't available on this platform, dejacmd-log writes commands directly");
+   std::process::ExitCode::from(1)
+}
@@ -0,0 +1,196 @@
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use secrecy::ExposeSecret;
+use secrecy::zeroize::Zeroizing;
+
+use dejacmd::crypt;
+use dejacmd::error::DejacmdError;
+use dejacmd::settings::Settings;
+
+const KEY_HELP: &str = "Hex encryption key or a file containing it (defaults to the key dejacmd uses)";
+
+#[derive(Parser, Debug)]
+#[command(author, version, long_about = None,
+          about = "Encrypt, decrypt and verify database passwords in the form stored in the dejacmd settings file",
+          after_help = r#"Examples:
+   dejacmd-passwd encrypt
+   dejacmd-passwd decrypt 5f0c...e1 -k ~/.config/dejacmd/encryption-key
+   dejacmd-passwd verify 5f0c...e1 -k 3b7a...9d
+   dejacmd-passwd verify
+
+The blobs are the hex local_encrypted_password and central_encrypted_password values from settings.json. verify with a
+blob asks for the password it should hold and checks it decrypts to it, and without one checks the passwords in the
+settings file decrypt with the configured key. The exit status is 1 if a check fails, or for other errors 65 for bad
+input such as a blob that isn't hex, 77 for a wrong key or password that doesn't decrypt and 78 for a missing key."#)]
+struct Args
+{
+   #[command(subcommand)]
+   command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command
+{
+   #[command(about = "Encrypt a password (asked for if not given) and print the hex blob")]
+   Encrypt
+   {
+      #[arg(help = "Password to encrypt, asked for if omitted so it isn't left in the shell history")]
+      password: Option<String>,
+
+      #[arg(short = 'k', long = "key", help = KEY_HELP)]
+      key: Option<String>,
+
+      #[arg(short = 'c', long = "cipher", value_parser = clap::builder::PossibleValuesParser::new(crypt::Cipher::NAMES),
+            help = "Cipher to encrypt with (defaults to the cipher setting, the key file's cipher or aes-256-gcm)")]
+      cipher: Option<String>,
+   },
+
+   #[command(about = "Decrypt a hex blob and print the password")]
+   Decrypt
+   {
+      #[arg(help = "Hex encrypted password")]
+      blob: String,
+
+      #[arg(short = 'k', long = "key", help = KEY_HELP)]
+      key: Option<String>,
+   },
+
+   #[command(about = "Check a hex blob decrypts to the expected password, or that the passwords in the settings file decrypt")]
+   Verify
+   {
+      #[arg(help = "Hex encrypted password (defaults to checking the passwords in the settings file)")]
+      blob: Option<String>,
+
+      #[arg(short = 'k', long = "key", requires = "blob", help = KEY_HELP)]
+      key: Option<String>,
+   },
+}
+
+fn main() -> std::process::ExitCode
+//---------------------------------
+{
+   let args = Args::parse();
+   if std::io::IsTerminal::is_terminal(&std::io::stdin())
+   {
+      dejacmd::settings::allow_passphrase_prompt();
+   }
+   let result = match args.command
+   {
+      Command::Encrypt { password, key, cipher } => encrypt(password, key.as_deref(), cipher.as_deref()).map(|_| true),
+      Command::Decrypt { blob, key } => decrypt(&blob, key.as_deref()).map(|password| println!("{}", password.as_str())).map(|_| true),
+      Command::Verify { blob: Some(blob), key } => verify(&blob, key.as_deref()),
+      Command::Verify { blob: None, .. } => verify_settings(),
+   };
+   match result
+   {
+      Ok(true) => std::process::ExitCode::SUCCESS,
+      Ok(false) => std::process::ExitCode::from(1),
+      Err(e) =>
+      {
+         eprintln!("{}: {}", "dejacmd-passwd".bright_red(), e);
+         std::process::ExitCode::from(e.exit_code())
+      }
+   }
+}
+
+/// The key given with -k (a hex key or a file holding one) or else the key dejacmd uses
+fn get_key(key: Option<&str>) -> Result<Zeroizing<String>, DejacmdError>
+//-----------------------------------------------------------------------
+{
+   let key = match key
+   {
+      Some(key) if std::path::Path::new(key).is_file() =>
+         Zeroizing::new(std::fs::read_to_string(key).map_err(|e| DejacmdError::Config(format!("Error reading key file {}: {}", key, e)))?),
+      Some(key) => Zeroizing::new(key.to_string()),
+      None => Zeroizing::new(Settings::new().get_settings_or_default().get_password_key()?),
+   };
+   let key = Zeroizing::new(key.trim().to_string());
+   crypt::check_key(&key)?;
+   Ok(key)
+}
+
+fn encrypt(password: Option<String>, key: Option<&str>, cipher: Option<&str>) -> Result<(), DejacmdError>
+//-----------------------------------------------------------------------------------------------------
+{
+   let cipher = match cipher
+   {
+      Some(name) => Some(crypt::Cipher::from_name(name)?),
+      None => Settings::new().get_settings_or_default().get_cipher()?,
+   };
+   let key = get_key(key)?;
+   let password = match password
+   {
+      Some(password) => Zeroizing::new(password),
+      None =>
+      {
+         let password = Zeroizing::new(read_password("Password: ")?);
+         let repeated = Zeroizing::new(read_password("Repeat password: ")?);
+         if password != repeated
+         {
+            return Err(DejacmdError::Parse("The passwords don't match".to_string()));
+         }
+         password
+      }
+   };
+   let encrypted = crypt::encrypt_with(cipher, &password, &key)
+      .map_err(|e| DejacmdError::Crypto(format!("Error encrypting password: {}", e)))?;
+   println!("{}", hex::encode(encrypted));
+   Ok(())
+}
+
+fn read_password(prompt: &str) -> Result<String, DejacmdError>
+//-------------------------------------------------------------
+{
+   rpassword::prompt_password(prompt).map_err(|e| DejacmdError::Parse(format!("Error reading password: {}", e)))
+}
+
+fn decrypt(blob: &str, key: Option<&str>) -> Result<Zeroizing<String>, DejacmdError>
+//-----------------------------------------------------------------------------------
+{
+   let key = get_key(key)?;
+   let data = hex::decode(blob.trim()).map_err(|e| DejacmdError::Parse(format!("The encrypted password isn't hex: {}", e)))?;
+   crypt::decrypt(&data, &key).map(Zeroizing::new)
+      .map_err(|e| DejacmdError::Crypto(format!("Error decrypting password (wrong key or damaged blob): {}", e)))
+}
+
+/// A blob that decrypts to another password is a failed check (exit status 1) rather than an error
+fn verify(blob: &str, key: Option<&str>) -> Result<bool, DejacmdError>
+//--------------------------------------------------------------------
+{
+   let password = decrypt(blob, key)?;
+   let expected = Zeroizing::new(read_password("Expected password: ")?);
+   if password != expected
+   {
+      eprintln!("{}: The blob decrypts but not to the expected password", "dejacmd-passwd".bright_red());
+      return Ok(false);
+   }
+   let data = hex::decode(blob.trim()).unwrap_or_default();
+   let cipher = crypt::encrypted_cipher(&data).map_or("unknown cipher", |cipher| cipher.name());
+   println!("{} ({})", "The blob decrypts to the expected password".green(), cipher);
+   Ok(true)
+}
+
+fn verify_settings() -> Result<bool, DejacmdError>
+//------------------------------------------------
+{
+   let settings = Settings::new().get_settings_or_default();
+   let mut is_failed = false;
+   for (name, is_local) in [("Local", true), ("Central", false)]
+   {
+      match settings.get_credentials(is_local)
+      {
+         Ok((_, password)) if password.expose_secret().is_empty() => println!("{} database: {}", name, "no password".dimmed()),
+         Ok(_) => println!("{} database: {}", name, "password decrypts".green()),
+         Err(e) =>
+         {
+            println!("{} database: {}", name, e.message().bright_red());
+            is_failed = true;
+         }
+      }
+   }
+   if is_failed
+   {
+      eprintln!("{}: A password in the settings file doesn't decrypt", "dejacmd-passwd".bright_red());
+   }
+   Ok(!is_failed)
+}
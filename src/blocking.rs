@@ -0,0 +1,165 @@
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use secrecy::SecretString;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::error::DejacmdError;
+use crate::settings::Settings;
+use crate::store::{HistoryStore, SearchRequest, SqlHistoryStore};
+use crate::{HistoryEntry, PoolSettings};
+
+fn new_runtime() -> Result<Runtime, DejacmdError>
+//-----------------------------------------------
+{
+   Builder::new_current_thread().enable_all().build()
+      .map_err(|e| DejacmdError::Connection(format!("Error starting async runtime: {}", e)))
+}
+
+/// A SqlHistoryStore with blocking methods, for programs without a tokio runtime of their own (a prompt module, a
+/// small script). The database calls run on the store's own current-thread runtime so it mustn't be used from inside
+/// an async task.
+#[derive(Debug)]
+pub struct BlockingHistoryStore
+{
+   store: SqlHistoryStore,
+   runtime: Runtime,
+}
+
+impl BlockingHistoryStore
+{
+   /// See SqlHistoryStore::connect
+   pub fn connect(url: &str, user: &str, password: &SecretString, options: &PoolSettings) -> Result<Self, DejacmdError>
+   //-----------------------------------------------------------------------------------------------------------
+   {
+      let runtime = new_runtime()?;
+      let store = runtime.block_on(SqlHistoryStore::connect(url, user, password, options))?;
+      Ok(BlockingHistoryStore { store, runtime })
+   }
+
+   /// See SqlHistoryStore::from_settings
+   pub fn from_settings(settings: &Settings, is_local: bool) -> Result<Option<Self>, DejacmdError>
+   //-------------------------------------------------------------------------------------------
+   {
+      let runtime = new_runtime()?;
+      let store = runtime.block_on(SqlHistoryStore::from_settings(settings, is_local))?;
+      Ok(store.map(|store| BlockingHistoryStore { store, runtime }))
+   }
+
+   /// The async store, e.g. to hand to code that has a runtime
+   pub fn store(&self) -> &SqlHistoryStore { &self.store }
+
+   pub fn insert(&self, entry: &HistoryEntry) -> Result<(), DejacmdError>
+   //--------------------------------------------------------------------
+   {
+      self.runtime.block_on(self.store.insert(entry))
+   }
+
+   pub fn insert_batch(&self, entries: &[HistoryEntry]) -> Result<u64, DejacmdError>
+   //--------------------------------------------------------------------------------
+   {
+      self.runtime.block_on(self.store.insert_batch(entries))
+   }
+
+   pub fn search(&self, request: &SearchRequest) -> Result<Vec<HistoryEntry>, DejacmdError>
+   //--------------------------------------------------------------------------------------
+   {
+      self.runtime.block_on(self.store.search(request))
+   }
+
+   /// The search's entries as an iterator, read SEARCH_PAGE_SIZE at a time as for HistoryStore::search_stream
+   pub fn search_iter(&self, request: &SearchRequest) -> BlockingIter<'_>
+   //--------------------------------------------------------------------
+   {
+      BlockingIter { runtime: &self.runtime, stream: Some(self.store.search_stream(request)) }
+   }
+
+   /// Every stored entry, oldest first, as for HistoryStore::stream_all
+   pub fn iter_all(&self) -> BlockingIter<'_>
+   //----------------------------------------
+   {
+      BlockingIter { runtime: &self.runtime, stream: Some(self.store.stream_all()) }
+   }
+}
+
+/// sqlx returns connections to the pool from a task so they have to be dropped inside the runtime
+impl Drop for BlockingHistoryStore
+{
+   fn drop(&mut self)
+   //----------------
+   {
+      self.runtime.block_on(self.store.pool().close());
+   }
+}
+
+/// Entries read from a BlockingHistoryStore
+pub struct BlockingIter<'a>
+{
+   runtime: &'a Runtime,
+   stream: Option<BoxStream<'a, Result<HistoryEntry, DejacmdError>>>,
+}
+
+impl Iterator for BlockingIter<'_>
+{
+   type Item = Result<HistoryEntry, DejacmdError>;
+
+   fn next(&mut self) -> Option<Self::Item>
+   //--------------------------------------
+   {
+      let stream = self.stream.as_mut()?;
+      self.runtime.block_on(stream.next())
+   }
+}
+
+impl Drop for BlockingIter<'_>
+{
+   fn drop(&mut self)
+   //----------------
+   {
+      let _guard = self.runtime.enter();
+      self.stream.take();
+   }
+}
+
+/// store::search_history without a runtime. The runtime is dropped afterwards, so the pool it connected is closed.
+pub fn search_history(settings: &Settings, is_central: bool, request: &SearchRequest) -> Result<Vec<HistoryEntry>, DejacmdError>
+//--------------------------------------------------------------------------------------------------------------------------
+{
+   new_runtime()?.block_on(async
+   {
+      let result = crate::store::search_history(settings, is_central, request).await;
+      crate::close_shared_pools().await;
+      result
+   })
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use crate::store::imported_entry;
+
+   #[test]
+   fn test_blocking_history_store()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_blocking_test_{}.sqlite", nanos));
+      let url = format!("sqlite://{}?mode=rwc", path.display());
+      let store = BlockingHistoryStore::connect(&url, "", &SecretString::default(), &PoolSettings::default()).unwrap();
+
+      let first = imported_entry("make", "/src", 1767000000, "bash", 0, None).unwrap();
+      store.insert(&first).unwrap();
+      let more: Vec<_> = (1..4).map(|i| imported_entry(&format!("make {}", i), "/src", 1767000000 + i, "bash", 0, None).unwrap())
+         .collect();
+      assert_eq!(store.insert_batch(&more).unwrap(), 3);
+
+      let request = SearchRequest { spec: "make".to_string(), limit: 2, ..SearchRequest::default() };
+      let found: Vec<_> = store.search(&request).unwrap().into_iter().filter_map(|e| e.command).collect();
+      assert_eq!(found, vec!["make 3", "make 2"]);
+      let request = SearchRequest { limit: 0, is_sort_reversed: true, ..request };
+      let found: Vec<_> = store.search_iter(&request).map(|e| e.unwrap().command.unwrap()).collect();
+      assert_eq!(found, vec!["make", "make 1", "make 2", "make 3"]);
+      assert_eq!(store.iter_all().next().unwrap().unwrap(), first);
+      drop(store);
+      let _ = std::fs::remove_file(&path);
+   }
+}
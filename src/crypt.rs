@@ -1,9 +1,15 @@
-use std::error::Error;
-
 use aes_gcm::{ // cargo add aes-gcm
     aead::{Aead, AeadCore, KeyInit, OsRng},
     Aes256Gcm, Nonce
 };
+use chacha20poly1305::ChaCha20Poly1305;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use secrecy::zeroize::Zeroizing;
+
+use crate::error::DejacmdError;
 
 type EncryptedData = Vec<u8>;
 
@@ -16,40 +22,246 @@ pub fn generate_key() -> String
    hex::encode(key)
 }
 
-pub fn encrypt(password: &str, key: &str) -> Result<EncryptedData, aes_gcm::Error>
-//-----------------------------------------------------------------------------------------------
+/// Argon2id memory (KiB), iterations and parallelism for new passphrase keys, the OWASP minimum recommendation
+const ARGON2_PARAMS: (u32, u32, u32) = (19456, 2, 1);
+
+/// A new key derivation for a passphrase key: argon2id$m=<KiB>,t=<iterations>,p=<parallelism>$<hex salt>, which is
+/// stored in the settings so the same key is derived from the passphrase each time
+pub fn new_passphrase_kdf() -> String
+//-----------------------------------
 {
-   // let key = Aes256Gcm::generate_key(&mut OsRng);
-   let key_bytes = hex::decode(key).expect("Invalid hex key");
-   let cipher = Aes256Gcm::new_from_slice(&key_bytes).expect("Invalid key length");
-   let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+   let (m, t, p) = ARGON2_PARAMS;
+   // 16 random bytes from a new key
+   format!("argon2id$m={},t={},p={}${}", m, t, p, &generate_key()[..32])
+}
+
+/// Derive a hex AES-256 key (as generate_key returns) from passphrase with Argon2id using the parameters and salt in
+/// kdf (from new_passphrase_kdf)
+pub fn derive_key(passphrase: &str, kdf: &str) -> Result<String, DejacmdError>
+//----------------------------------------------------------------------
+{
+   let parts: Vec<&str> = kdf.split('$').collect();
+   let [algorithm, parameters, salt] = parts.as_slice()
+   else
+   {
+      return Err(DejacmdError::Crypto(format!("Invalid key derivation {}", kdf)));
+   };
+   if *algorithm != "argon2id"
+   {
+      return Err(DejacmdError::Crypto(format!("Unsupported key derivation {}", algorithm)));
+   }
+   let (mut m, mut t, mut p) = ARGON2_PARAMS;
+   for parameter in parameters.split(',')
+   {
+      let (name, value) = parameter.split_once('=')
+         .ok_or_else(|| DejacmdError::Crypto(format!("Invalid key derivation parameter {}", parameter)))?;
+      let value: u32 = value.parse().map_err(|_| DejacmdError::Crypto(format!("Invalid key derivation parameter {}", parameter)))?;
+      match name
+      {
+         | "m" => m = value,
+         | "t" => t = value,
+         | "p" => p = value,
+         | _ => return Err(DejacmdError::Crypto(format!("Unknown key derivation parameter {}", name))),
+      }
+   }
+   let salt = hex::decode(salt).map_err(|e| DejacmdError::Crypto(format!("Invalid key derivation salt: {}", e)))?;
+   let params = Params::new(m, t, p, Some(32)).map_err(|e| DejacmdError::Crypto(format!("Invalid Argon2 parameters: {}", e)))?;
+   let mut key = Zeroizing::new([0u8; 32]);
+   Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+      .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+      .map_err(|e| DejacmdError::Crypto(format!("Error deriving key from passphrase: {}", e)))?;
+   Ok(hex::encode(key.as_ref()))
+}
+
+/// The authenticated ciphers passwords can be encrypted with. AES-256-GCM is the default, ChaCha20-Poly1305 is for
+/// machines without AES hardware acceleration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher
+{
+   Aes256Gcm,
+   ChaCha20Poly1305,
+}
+
+impl Cipher
+{
+   pub const NAMES: &[&str] = &["aes-256-gcm", "chacha20-poly1305"];
+
+   pub fn from_name(name: &str) -> Result<Cipher, DejacmdError>
+   //----------------------------------------------------
+   {
+      match name.trim().to_lowercase().as_str()
+      {
+         | "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+         | "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+         | _ => Err(DejacmdError::Parse(format!("Unknown cipher {} (one of {})", name, Cipher::NAMES.join(", ")))),
+      }
+   }
+
+   pub fn name(&self) -> &'static str
+   //--------------------------------
+   {
+      match self
+      {
+         | Cipher::Aes256Gcm => "aes-256-gcm",
+         | Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+      }
+   }
+
+   /// The id in the header of encrypted data
+   fn id(&self) -> u8
+   //----------------
+   {
+      match self
+      {
+         | Cipher::Aes256Gcm => 1,
+         | Cipher::ChaCha20Poly1305 => 2,
+      }
+   }
+
+   fn from_id(id: u8) -> Option<Cipher>
+   //----------------------------------
+   {
+      match id
+      {
+         | 1 => Some(Cipher::Aes256Gcm),
+         | 2 => Some(Cipher::ChaCha20Poly1305),
+         | _ => None,
+      }
+   }
+}
+
+/// Encrypted data starts with MAGIC, FORMAT_VERSION and the cipher id followed by the nonce and ciphertext. Data
+/// encrypted before the header was added is just an AES-256-GCM nonce and ciphertext.
+const MAGIC: &[u8] = b"DJ";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 4;
+const NONCE_LEN: usize = 12; // The same for both ciphers
+
+/// Split a key into the cipher named in an optional "<cipher>:" header (as a key file can start with to choose the
+/// cipher) and the bytes of the hex AES-256 or ChaCha20 key after it
+fn parse_key(key: &str) -> Result<(Option<Cipher>, Zeroizing<Vec<u8>>), DejacmdError>
+//-----------------------------------------------------------------------------
+{
+   let (cipher, key) = match key.trim().split_once(':')
+   {
+      | Some((name, key)) => (Some(Cipher::from_name(name)?), key.trim()),
+      | None => (None, key.trim()),
+   };
+   match hex::decode(key)
+   {
+      | Ok(bytes) if bytes.len() == 32 => Ok((cipher, Zeroizing::new(bytes))),
+      | Ok(bytes) => Err(DejacmdError::Crypto(format!("The key is {} bytes instead of 32", bytes.len()))),
+      | Err(e) => Err(DejacmdError::Crypto(format!("The key isn't hex: {}", e))),
+   }
+}
+
+/// Derive a separate hex 256 bit key for purpose from key (a key as generate_key returns) with HKDF-SHA256, so the
+/// key encrypting the passwords isn't used for anything else as is
+pub fn derive_subkey(key: &str, purpose: &str) -> Result<String, DejacmdError>
+//--------------------------------------------------------------------------
+{
+   let (_, key_bytes) = parse_key(key)?;
+   let mut subkey = Zeroizing::new([0u8; 32]);
+   Hkdf::<Sha256>::new(None, &key_bytes)
+      .expand(format!("dejacmd {}", purpose).as_bytes(), subkey.as_mut())
+      .map_err(|e| DejacmdError::Crypto(format!("Error deriving {} key: {}", purpose, e)))?;
+   Ok(hex::encode(subkey.as_ref()))
+}
 
-   let mut ciphertext = cipher.encrypt(&nonce, password.as_bytes())?;
-   let mut result = Vec::with_capacity(nonce.len() + ciphertext.len());
+/// Check key is a hex 256 bit key as generate_key returns, optionally with a cipher header
+pub fn check_key(key: &str) -> Result<(), DejacmdError>
+//-----------------------------------------------
+{
+   parse_key(key).map(|_| ())
+}
+
+/// The cipher data was encrypted with
+pub fn encrypted_cipher(data: &[u8]) -> Option<Cipher>
+//----------------------------------------------------
+{
+   match data
+   {
+      | [m0, m1, version, id, rest @ ..] if [*m0, *m1] == MAGIC && *version == FORMAT_VERSION && rest.len() > NONCE_LEN =>
+         Cipher::from_id(*id),
+      | _ if data.len() > NONCE_LEN => Some(Cipher::Aes256Gcm),
+      | _ => None,
+   }
+}
+
+/// Encrypt password with the cipher in the key's header or else AES-256-GCM
+pub fn encrypt(password: &str, key: &str) -> Result<EncryptedData, DejacmdError>
+//------------------------------------------------------------------------
+{
+   encrypt_with(None, password, key)
+}
+
+/// Encrypt password with cipher, or if None the cipher in the key's header or else AES-256-GCM
+pub fn encrypt_with(cipher: Option<Cipher>, password: &str, key: &str) -> Result<EncryptedData, DejacmdError>
+//----------------------------------------------------------------------------------------------------
+{
+   let (key_cipher, key_bytes) = parse_key(key)?;
+   let cipher = cipher.or(key_cipher).unwrap_or(Cipher::Aes256Gcm);
+   let (nonce, mut ciphertext) = match cipher
+   {
+      | Cipher::Aes256Gcm =>
+      {
+         let aes = Aes256Gcm::new_from_slice(&key_bytes).map_err(|e| DejacmdError::Crypto(format!("Invalid key: {}", e)))?;
+         let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+         (nonce.to_vec(), aes.encrypt(&nonce, password.as_bytes())
+            .map_err(|e| DejacmdError::Crypto(format!("Encryption failed: {}", e)))?)
+      },
+      | Cipher::ChaCha20Poly1305 =>
+      {
+         let chacha = ChaCha20Poly1305::new_from_slice(&key_bytes).map_err(|e| DejacmdError::Crypto(format!("Invalid key: {}", e)))?;
+         let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+         (nonce.to_vec(), chacha.encrypt(&nonce, password.as_bytes())
+            .map_err(|e| DejacmdError::Crypto(format!("Encryption failed: {}", e)))?)
+      },
+   };
+   let mut result = Vec::with_capacity(HEADER_LEN + nonce.len() + ciphertext.len());
+   result.extend_from_slice(MAGIC);
+   result.push(FORMAT_VERSION);
+   result.push(cipher.id());
    result.extend_from_slice(&nonce);
    result.append(&mut ciphertext);
 
    Ok(result)
 }
 
-pub fn decrypt(data: &[u8], key: &str) -> Result<String, Box<dyn Error>>
+fn decrypt_with(cipher: Cipher, key_bytes: &[u8], data: &[u8]) -> Result<Vec<u8>, DejacmdError>
 //---------------------------------------------------------------------------------------
 {
-   // let key = Aes256Gcm::generate_key(&mut OsRng);
-   let key_bytes = hex::decode(key).expect("Invalid hex key");
-   const NONCE_LEN: usize = 12; // GCM nonce size
+   let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+   let result = match cipher
+   {
+      | Cipher::Aes256Gcm => Aes256Gcm::new_from_slice(key_bytes).map_err(|e| DejacmdError::Crypto(format!("Invalid key: {}", e)))?
+         .decrypt(Nonce::from_slice(nonce_bytes), ciphertext),
+      | Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key_bytes)
+         .map_err(|e| DejacmdError::Crypto(format!("Invalid key: {}", e)))?
+         .decrypt(chacha20poly1305::Nonce::from_slice(nonce_bytes), ciphertext),
+   };
+   result.map_err(|e| DejacmdError::Crypto(format!("Decryption failed: {:?}", e)))
+}
 
+/// Decrypt data from encrypt or encrypt_with, or from before the cipher header was added
+pub fn decrypt(data: &[u8], key: &str) -> Result<String, DejacmdError>
+//---------------------------------------------------------------------------------------
+{
+   let (_, key_bytes) = parse_key(key)?;
    if data.len() < NONCE_LEN
    {
-      return Err("Encrypted data too short".into());
+      return Err(DejacmdError::Crypto("Encrypted data too short".to_string()));
    }
 
-   let cipher = Aes256Gcm::new_from_slice(&key_bytes).expect("Invalid key length");
-   let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
-   let nonce = Nonce::clone_from_slice(nonce_bytes);
-
-   let plaintext = cipher.decrypt(&nonce, ciphertext).map_err(|e| format!("Decryption failed: {:?}", e))?;
-   Ok(String::from_utf8(plaintext)?)
+   if data.len() >= HEADER_LEN + NONCE_LEN && &data[..2] == MAGIC && data[2] == FORMAT_VERSION
+      && let Some(cipher) = Cipher::from_id(data[3])
+      && let Ok(plaintext) = decrypt_with(cipher, &key_bytes, &data[HEADER_LEN..])
+   {
+      return String::from_utf8(plaintext).map_err(|e| DejacmdError::Crypto(format!("Decrypted password isn't UTF-8: {}", e)));
+   }
+   // Untagged data, or a (1 in 2^32) untagged nonce that starts like a header
+   let plaintext = decrypt_with(Cipher::Aes256Gcm, &key_bytes, data)?;
+   String::from_utf8(plaintext).map_err(|e| DejacmdError::Crypto(format!("Decrypted password isn't UTF-8: {}", e)))
 }
 
 #[cfg(test)]
@@ -66,14 +278,26 @@ mod tests {
         assert_eq!(password, decrypted);
     }
 
+    #[test]
+    fn test_derive_subkey()
+    {
+       let key = generate_key();
+       let subkey = derive_subkey(&key, "sqlcipher database").unwrap();
+       check_key(&subkey).unwrap();
+       assert_ne!(subkey, key);
+       assert_eq!(subkey, derive_subkey(&format!("chacha20-poly1305:{}", key), "sqlcipher database").unwrap());
+       assert_ne!(subkey, derive_subkey(&key, "anonymize").unwrap());
+       assert!(derive_subkey("not hex", "sqlcipher database").is_err());
+    }
+
     #[test]
     fn test_decrypt_invalid_data() {
         // Create data that is long enough (nonce + ciphertext) but invalid
         // 12 bytes nonce + some ciphertext
         let mut data = vec![0u8; 20];
         // Fill with some random values to ensure it's not a valid tag/ciphertext
-        for i in 0..data.len() {
-            data[i] = (i % 255) as u8;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 255) as u8;
         }
         let key = generate_key();
         let result = decrypt(&data, &key);
@@ -98,4 +322,57 @@ mod tests {
         // Because of the random nonce, outputs should be different even for same input
         assert_ne!(enc1, enc2);
     }
+
+    #[test]
+    fn test_ciphers()
+    {
+        let key = generate_key();
+        let aes = encrypt("password", &key).unwrap();
+        assert_eq!(&aes[..4], b"DJ\x01\x01");
+        assert_eq!(encrypted_cipher(&aes), Some(Cipher::Aes256Gcm));
+        let chacha = encrypt_with(Some(Cipher::ChaCha20Poly1305), "password", &key).unwrap();
+        assert_eq!(encrypted_cipher(&chacha), Some(Cipher::ChaCha20Poly1305));
+        assert_eq!(decrypt(&chacha, &key).unwrap(), "password");
+
+        // A key file header chooses the cipher unless it's overridden, and the header doesn't matter for decrypting
+        let chacha_key = format!("chacha20-poly1305:{}", key);
+        assert!(check_key(&chacha_key).is_ok());
+        let chacha = encrypt("password", &chacha_key).unwrap();
+        assert_eq!(encrypted_cipher(&chacha), Some(Cipher::ChaCha20Poly1305));
+        assert_eq!(decrypt(&chacha, &key).unwrap(), "password");
+        let aes = encrypt_with(Some(Cipher::Aes256Gcm), "password", &chacha_key).unwrap();
+        assert_eq!(encrypted_cipher(&aes), Some(Cipher::Aes256Gcm));
+        assert_eq!(decrypt(&aes, &chacha_key).unwrap(), "password");
+        assert!(check_key(&format!("rot13:{}", key)).is_err());
+
+        // Data from before the header was added
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut legacy = nonce.to_vec();
+        legacy.extend(Aes256Gcm::new_from_slice(&hex::decode(&key).unwrap()).unwrap().encrypt(&nonce, b"old".as_ref()).unwrap());
+        assert_eq!(decrypt(&legacy, &key).unwrap(), "old");
+
+        assert_eq!(Cipher::from_name("ChaCha20-Poly1305").unwrap(), Cipher::ChaCha20Poly1305);
+        assert!(Cipher::NAMES.iter().all(|name| Cipher::from_name(name).unwrap().name() == *name));
+    }
+
+    #[test]
+    fn test_derive_key()
+    {
+        // Small parameters so the test is quick
+        let kdf = "argon2id$m=64,t=1,p=1$000102030405060708090a0b0c0d0e0f";
+        let key = derive_key("correct horse", kdf).unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(derive_key("correct horse", kdf).unwrap(), key, "The same key from the same passphrase and salt");
+        assert_ne!(derive_key("battery staple", kdf).unwrap(), key);
+        assert_ne!(derive_key("correct horse", "argon2id$m=64,t=1,p=1$0f0e0d0c0b0a09080706050403020100").unwrap(), key);
+        let encrypted = encrypt("db password", &key).unwrap();
+        assert_eq!(decrypt(&encrypted, &derive_key("correct horse", kdf).unwrap()).unwrap(), "db password");
+        assert!(decrypt(&encrypted, &derive_key("wrong", kdf).unwrap()).is_err());
+
+        let kdf = new_passphrase_kdf();
+        assert!(kdf.starts_with("argon2id$m=19456,t=2,p=1$"), "{}", kdf);
+        assert_ne!(kdf, new_passphrase_kdf(), "A new salt each time");
+        assert!(derive_key("x", "scrypt$n=1$00").is_err());
+        assert!(derive_key("x", "argon2id$m=64,t=1$zz").is_err());
+    }
 }
@@ -0,0 +1,278 @@
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use sqlx::{Any, Pool, Row};
+
+use crate::{CREATE_INDEX_SQL, CREATE_TABLE_SQL};
+
+/// MySQL can't index a TEXT column without a prefix length or use IF NOT EXISTS with CREATE INDEX, so the timestamp
+/// is a VARCHAR and the index is created with the table
+const MYSQL_CREATE_TABLE_SQL: &str =
+r#" CREATE TABLE IF NOT EXISTS history
+(
+   id VARCHAR(255) PRIMARY KEY,
+   command_timestamp VARCHAR(32) NOT NULL,
+   cwd TEXT,
+   shell VARCHAR(255),
+   user_id BIGINT,
+   user_name VARCHAR(255),
+   ip VARCHAR(64),
+   os VARCHAR(64),
+   exit_status BIGINT,
+   command TEXT,
+   INDEX idx_history_timestamp (command_timestamp)
+) CHARACTER SET utf8mb4"#;
+
+/// SQL Server has no CREATE TABLE/INDEX IF NOT EXISTS and TEXT is deprecated in favour of NVARCHAR(MAX)
+const MSSQL_CREATE_TABLE_SQL: &str =
+r#" IF OBJECT_ID(N'history', N'U') IS NULL
+CREATE TABLE history
+(
+   id NVARCHAR(255) PRIMARY KEY,
+   command_timestamp NVARCHAR(32) NOT NULL,
+   cwd NVARCHAR(MAX),
+   shell NVARCHAR(255),
+   user_id BIGINT,
+   user_name NVARCHAR(255),
+   ip NVARCHAR(64),
+   os NVARCHAR(64),
+   exit_status BIGINT,
+   command NVARCHAR(MAX)
+)"#;
+
+const MSSQL_CREATE_INDEX_SQL: &str =
+r#" IF NOT EXISTS (SELECT * FROM sys.indexes WHERE name = N'idx_history_timestamp' AND object_id = OBJECT_ID(N'history'))
+CREATE INDEX idx_history_timestamp ON history (command_timestamp)"#;
+
+/// The VARCHAR(255) primary keys and 255 character index prefixes of the MySQL table DDL and update files
+static MYSQL_INDEXED_255: LazyLock<Regex> =
+   LazyLock::new(|| Regex::new(r"\b(VARCHAR\(255\) PRIMARY KEY|(?:command|cwd)\(255\))").unwrap());
+
+/// A MySQL or MariaDB server, from its SELECT VERSION() e.g 8.0.36 or 10.11.6-MariaDB-0+deb12u1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MysqlServer
+{
+   pub is_mariadb: bool,
+   pub version: (u32, u32, u32),
+}
+
+impl MysqlServer
+{
+   pub fn parse(version: &str) -> Option<Self>
+   //-----------------------------------------
+   {
+      let is_mariadb = version.to_lowercase().contains("mariadb");
+      // Older MariaDB servers and proxies report 5.5.5- before the real version for MySQL 5 clients
+      let version = if is_mariadb { version.trim().strip_prefix("5.5.5-").unwrap_or(version.trim()) } else { version.trim() };
+      let mut numbers = version.split(|c: char| !c.is_ascii_digit()).map(|n| n.parse::<u32>());
+      let major = numbers.next()?.ok()?;
+      let minor = numbers.next().and_then(|n| n.ok()).unwrap_or(0);
+      let patch = numbers.next().and_then(|n| n.ok()).unwrap_or(0);
+      Some(MysqlServer { is_mariadb, version: (major, minor, patch) })
+   }
+
+   /// Characters of a utf8mb4 column that can be in an index. Index keys are limited to 767 bytes (191 four byte
+   /// characters) before MySQL 5.7.7 and MariaDB 10.2.2 unless innodb_large_prefix was turned on, and 3072 since.
+   pub fn index_prefix(&self) -> u32
+   //-------------------------------
+   {
+      let large_prefix = if self.is_mariadb { (10, 2, 2) } else { (5, 7, 7) };
+      if self.version < large_prefix { 191 } else { 255 }
+   }
+
+   /// Whether INSERT ... RETURNING is supported (MariaDB since 10.5, never MySQL)
+   pub fn has_returning(&self) -> bool
+   //---------------------------------
+   {
+      self.is_mariadb && self.version >= (10, 5, 0)
+   }
+}
+
+/// The server a MySQL or MariaDB pool is connected to
+pub async fn mysql_server(pool: &Pool<Any>) -> Result<MysqlServer, String>
+//-------------------------------------------------------------------------
+{
+   let version: String = sqlx::query("SELECT VERSION() AS version").fetch_one(pool).await
+      .and_then(|row| row.try_get("version"))
+      .map_err(|e| format!("Error reading the server version: {}", e))?;
+   MysqlServer::parse(&version).ok_or_else(|| format!("Unrecognized server version {}", version))
+}
+
+/// MySQL DDL (MYSQL_CREATE_TABLE_SQL or an update file) with its indexed VARCHAR and TEXT columns cut to what the
+/// server can index
+pub fn fit_mysql_indexes<'a>(sql: &'a str, server: &MysqlServer) -> Cow<'a, str>
+//------------------------------------------------------------------------------
+{
+   match server.index_prefix()
+   {
+      | 255 => Cow::Borrowed(sql),
+      | prefix => MYSQL_INDEXED_255.replace_all(sql, |captures: &regex::Captures| captures[1].replace("255", &prefix.to_string())),
+   }
+}
+
+/// SQL dialect of a database URL scheme: postgres, mysql (including MariaDB), mssql or sqlite (the default). Also the
+/// <dialect> of NNNNNNN.<dialect>.sql update file variants (see migrations::update_sql).
+pub fn dialect(scheme: &str) -> &'static str
+//------------------------------------------
+{
+   if scheme.starts_with("postgres")
+   {
+      "postgres"
+   }
+   else if scheme.starts_with("mysql") || scheme.starts_with("mariadb")
+   {
+      "mysql"
+   }
+   else if scheme.starts_with("mssql") || scheme.starts_with("sqlserver")
+   {
+      "mssql"
+   }
+   else
+   {
+      "sqlite"
+   }
+}
+
+/// CREATE TABLE for the history table in the dialect of a database URL scheme. SQLite, Postgres (and unknown schemes)
+/// use CREATE_TABLE_SQL. Columns added later come from the assets/NNNNNNN.sql update files.
+/// command_timestamp is a string column on every backend as timestamps are bound and compared as "YYYY-MM-DD HH:MM:SS"
+/// text and the sqlx Any driver can't decode native TIMESTAMP values. The id is a ShortUuid generated by dejacmd-log
+/// so no backend needs a SERIAL/identity column.
+pub fn create_table_sql(scheme: &str) -> &'static str
+//----------------------------------------------------
+{
+   match dialect(scheme)
+   {
+      | "mysql" => MYSQL_CREATE_TABLE_SQL,
+      | "mssql" => MSSQL_CREATE_TABLE_SQL,
+      | _ => CREATE_TABLE_SQL,
+   }
+}
+
+/// CREATE INDEX for the history table, None where create_table_sql already creates the index
+pub fn create_index_sql(scheme: &str) -> Option<&'static str>
+//------------------------------------------------------------
+{
+   match dialect(scheme)
+   {
+      | "mysql" => None,
+      | "mssql" => Some(MSSQL_CREATE_INDEX_SQL),
+      | _ => Some(CREATE_INDEX_SQL),
+   }
+}
+
+/// The table and index DDL for a scheme, e.g for query --ddl
+pub fn schema_sql(scheme: &str) -> String
+//----------------------------------------
+{
+   match create_index_sql(scheme)
+   {
+      | Some(index) => format!("{};\n{}", create_table_sql(scheme).trim(), index.trim()),
+      | None => format!("{};", create_table_sql(scheme).trim()),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[tokio::test]
+   async fn test_create_schema_sql()
+   {
+      assert_eq!(create_table_sql("sqlite"), CREATE_TABLE_SQL);
+      assert_eq!(create_table_sql("postgresql"), CREATE_TABLE_SQL);
+      assert!(create_table_sql("mysql").contains("INDEX idx_history_timestamp"));
+      assert!(create_index_sql("mariadb").is_none());
+      assert!(create_table_sql("mssql").contains("IF OBJECT_ID"));
+      assert!(schema_sql("mssql").contains("sys.indexes"));
+
+      // Creating the schema twice must succeed
+      let url = format!("sqlite:file:dejacmd-ddl-test-{}?mode=memory&cache=shared",
+                        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos());
+      let (pool, scheme) = crate::get_database(&url, "", &secrecy::SecretString::default()).await.unwrap();
+      let pool = pool.unwrap();
+      for _ in 0..2
+      {
+         sqlx::query(create_table_sql(&scheme)).execute(&pool).await.unwrap();
+         sqlx::query(create_index_sql(&scheme).unwrap()).execute(&pool).await.unwrap();
+      }
+      pool.close().await;
+   }
+
+   #[test]
+   fn test_mysql_server()
+   {
+      let mysql = MysqlServer::parse("8.0.36").unwrap();
+      assert_eq!(mysql, MysqlServer { is_mariadb: false, version: (8, 0, 36) });
+      assert_eq!(mysql.index_prefix(), 255);
+      let mariadb = MysqlServer::parse("5.5.5-10.11.6-MariaDB-0+deb12u1").unwrap();
+      assert_eq!(mariadb, MysqlServer { is_mariadb: true, version: (10, 11, 6) });
+      assert_eq!(mariadb.index_prefix(), 255);
+      assert_eq!(MysqlServer::parse("10.1.48-MariaDB").unwrap().index_prefix(), 191);
+      assert_eq!(MysqlServer::parse("5.6.51-log").unwrap().index_prefix(), 191);
+      assert!(MysqlServer::parse("unknown").is_none());
+      assert!(mariadb.has_returning() && !mysql.has_returning());
+      assert!(!MysqlServer::parse("10.4.34-MariaDB").unwrap().has_returning());
+
+      let old = MysqlServer::parse("5.6.51").unwrap();
+      let sql = fit_mysql_indexes(create_table_sql("mysql"), &old);
+      assert!(sql.contains("id VARCHAR(191) PRIMARY KEY") && sql.contains("shell VARCHAR(255)"));
+      let update = "CREATE INDEX idx_history_command ON history (command(255))";
+      assert_eq!(fit_mysql_indexes(update, &old), "CREATE INDEX idx_history_command ON history (command(191))");
+      assert_eq!(fit_mysql_indexes(update, &mysql), update);
+   }
+
+   /// Starts MySQL (without INSERT ... RETURNING) and MariaDB (with it) in Docker containers, skipped where Docker can't
+   /// be reached
+   #[tokio::test]
+   async fn test_mysql_databases()
+   {
+      use testcontainers_modules::{mariadb::Mariadb, mysql::Mysql};
+      use testcontainers_modules::testcontainers::{ContainerAsync, Image, TestcontainersError, runners::AsyncRunner};
+
+      async fn url<I: Image>(container: &ContainerAsync<I>) -> String
+      {
+         format!("mysql://root@{}:{}/test", container.get_host().await.unwrap(), container.get_host_port_ipv4(3306).await.unwrap())
+      }
+
+      let mysql = match Mysql::default().start().await
+      {
+         Ok(container) => container,
+         Err(TestcontainersError::Client(e)) =>
+         {
+            eprintln!("Skipping test_mysql_databases, Docker isn't available: {}", e);
+            return;
+         },
+         Err(e) => panic!("Error starting MySQL: {}", e),
+      };
+      let mariadb = Mariadb::default().start().await.unwrap();
+      for (url, is_mariadb) in [(url(&mysql).await, false), (url(&mariadb).await, true)]
+      {
+         let (pool, scheme) = crate::get_database(&url, "", &secrecy::SecretString::default()).await.unwrap();
+         let pool = pool.unwrap();
+         let server = mysql_server(&pool).await.unwrap();
+         assert_eq!(server.is_mariadb, is_mariadb, "{} is {:?}", url, server);
+         assert_eq!(server.has_returning(), is_mariadb);
+         sqlx::query("DROP TABLE IF EXISTS history, bookmarks, history_output, dejacmd_migrations").execute(&pool).await.unwrap();
+         crate::create_schema(&pool, &scheme).await.unwrap();
+         crate::create_schema(&pool, &scheme).await.unwrap();
+
+         let command = "echo \u{1F600} ".repeat(100);
+         let entry = crate::store::imported_entry(&command, "/", 1767000000, "bash", 0, None).unwrap();
+         assert!(entry.upsert(&pool, &scheme).await.unwrap());
+         assert!(!entry.upsert(&pool, &scheme).await.unwrap(), "An existing id is skipped");
+         let other = crate::store::imported_entry("ls", "/", 1767000001, "bash", 0, None).unwrap();
+         assert_eq!(crate::insert_new_history(&pool, &scheme, &[entry.clone(), other.clone(), other.clone()]).await.unwrap(),
+                    vec![other.id.clone()]);
+         let stored: String = sqlx::query("SELECT command FROM history WHERE id = ?").bind(&entry.id)
+            .fetch_one(&pool).await.unwrap().get("command");
+         assert_eq!(stored, command, "utf8mb4 is stored");
+         let mut too_long = crate::store::imported_entry("pwd", "/", 1767000002, "bash", 0, None).unwrap();
+         too_long.shell = Some("x".repeat(300));
+         assert!(too_long.upsert(&pool, &scheme).await.is_err(), "Errors other than duplicate ids aren't ignored");
+         pool.close().await;
+      }
+   }
+}
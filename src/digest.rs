@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Stdio;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio::io::AsyncWriteExt;
+
+use crate::remote::is_remote_url;
+use crate::settings::Settings;
+use crate::shell;
+
+/// Format of the times in the last_digest setting (UTC)
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// How often dejacmd-logd delivers a digest, from the digest_schedule setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule
+{
+   Daily,
+   Weekly,
+}
+
+impl Schedule
+{
+   pub fn parse(schedule: &str) -> Result<Self, String>
+   //--------------------------------------------------
+   {
+      match schedule.trim().to_lowercase().as_str()
+      {
+         | "daily" | "day" | "1d" => Ok(Schedule::Daily),
+         | "weekly" | "week" | "7d" | "1w" => Ok(Schedule::Weekly),
+         | _ => Err(format!("Invalid digest_schedule '{}', expected daily or weekly", schedule)),
+      }
+   }
+
+   /// The period each digest reports on, as for dejacmd report --since
+   pub fn since(&self) -> &'static str
+   //---------------------------------
+   {
+      match self
+      {
+         | Schedule::Daily => "1d",
+         | Schedule::Weekly => "7d",
+      }
+   }
+
+   pub fn period(&self) -> chrono::Duration
+   //--------------------------------------
+   {
+      match self
+      {
+         | Schedule::Daily => chrono::Duration::days(1),
+         | Schedule::Weekly => chrono::Duration::days(7),
+      }
+   }
+
+   /// Whether a digest is due at now when the last one was delivered at last (from the last_digest setting). One is
+   /// due straight away if none has been delivered or last can't be read.
+   pub fn is_due(&self, last: Option<&str>, now: DateTime<Utc>) -> bool
+   //-------------------------------------------------------------------
+   {
+      match last.and_then(|last| NaiveDateTime::parse_from_str(last.trim(), TIMESTAMP_FORMAT).ok())
+      {
+         | Some(last) => now.naive_utc() - last >= self.period(),
+         | None => true,
+      }
+   }
+}
+
+/// A last_digest time for a digest delivered at now
+pub fn timestamp(now: DateTime<Utc>) -> String
+//---------------------------------------------
+{
+   now.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Somewhere a digest is delivered to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target
+{
+   /// digest_file: written to the file, replacing the previous digest
+   File(String),
+   /// digest_webhook: POSTed to the URL with a text/html or text/markdown content type
+   Webhook(String),
+   /// digest_sendmail_to: mailed to the addresses by handing it to sendmail -t
+   Sendmail(Vec<String>),
+}
+
+impl Target
+{
+   /// The key of the target in the last_digest setting
+   pub fn key(&self) -> &'static str
+   //-------------------------------
+   {
+      match self
+      {
+         | Target::File(_) => "file",
+         | Target::Webhook(_) => "webhook",
+         | Target::Sendmail(_) => "sendmail",
+      }
+   }
+}
+
+impl fmt::Display for Target
+//--------------------------
+{
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+   {
+      match self
+      {
+         | Target::File(file) => write!(f, "{}", file),
+         | Target::Webhook(url) => write!(f, "{}", url),
+         | Target::Sendmail(to) => write!(f, "{}", to.join(", ")),
+      }
+   }
+}
+
+/// A scheduled activity digest (a dejacmd report of the schedule's period) and the targets it is delivered to, from
+/// the digest_* settings. Each target is delivered to on its own schedule, recorded in last_digest, so one that fails
+/// is retried without sending the digest to the others again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest
+{
+   pub schedule: Schedule,
+   /// html or md
+   pub format: String,
+   pub targets: Vec<Target>,
+   /// Report on the central database rather than the local one
+   pub is_central: bool,
+}
+
+impl Digest
+{
+   /// The digest configured in settings, None if digest_schedule isn't set or there is nowhere to deliver it
+   pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String>
+   //-----------------------------------------------------------------------
+   {
+      let schedule = match settings.digest_schedule.as_deref().map(str::trim).filter(|s| !s.is_empty())
+      {
+         | Some(schedule) => Schedule::parse(schedule)?,
+         | None => return Ok(None),
+      };
+      let format = settings.digest_format.as_deref().map(|f| f.trim().to_lowercase()).unwrap_or_else(|| "html".to_string());
+      if format != "html" && format != "md"
+      {
+         return Err(format!("Invalid digest_format '{}', expected html or md", format));
+      }
+      let non_empty = |value: &Option<String>| value.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+      let mut targets = Vec::new();
+      if let Some(file) = non_empty(&settings.digest_file)
+      {
+         targets.push(Target::File(crate::exclusions::expand_home(&file).display().to_string()));
+      }
+      if let Some(url) = non_empty(&settings.digest_webhook)
+      {
+         targets.push(Target::Webhook(url));
+      }
+      let sendmail_to: Vec<String> = settings.digest_sendmail_to.iter().flatten()
+         .map(|to| to.trim().to_string()).filter(|to| !to.is_empty()).collect();
+      if !sendmail_to.is_empty()
+      {
+         targets.push(Target::Sendmail(sendmail_to));
+      }
+      if targets.is_empty()
+      {
+         return Ok(None);
+      }
+      let central_url = settings.get_central_database_url();
+      if is_remote_url(&central_url)
+      {
+         return Err(format!("Digests need dejacmd report, which isn't supported when the central history is a dejacmd \
+                             server or libSQL database ({})", central_url));
+      }
+      let is_central = !central_url.trim().is_empty();
+      Ok(Some(Digest { schedule, format, targets, is_central }))
+   }
+
+   /// The targets a digest is due for at now, given the last_digest setting
+   pub fn due_targets(&self, last: Option<&BTreeMap<String, String>>, now: DateTime<Utc>) -> Vec<&Target>
+   //-----------------------------------------------------------------------------------------------------
+   {
+      self.targets.iter()
+         .filter(|target| self.schedule.is_due(last.and_then(|last| last.get(target.key())).map(String::as_str), now))
+         .collect()
+   }
+
+   /// Run dejacmd report for the schedule's period and return the report
+   pub async fn generate(&self) -> Result<String, String>
+   //-----------------------------------------------------
+   {
+      let program = shell::program("dejacmd");
+      let mut command = tokio::process::Command::new(&program);
+      command.args(["report", "--since", self.schedule.since(), "--format", &self.format]);
+      if self.is_central
+      {
+         command.arg("--central");
+      }
+      let output = command.env("NO_COLOR", "1").stdin(Stdio::null()).output().await
+         .map_err(|e| format!("Error running {}: {}", program, e))?;
+      if !output.status.success()
+      {
+         return Err(format!("{} report failed ({}): {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim()));
+      }
+      String::from_utf8(output.stdout).map_err(|e| format!("{} report printed invalid UTF-8: {}", program, e))
+   }
+
+   /// Deliver report to target
+   pub async fn deliver(&self, target: &Target, report: &str) -> Result<(), String>
+   //------------------------------------------------------------------------------
+   {
+      match target
+      {
+         | Target::File(file) => tokio::fs::write(file, report).await
+            .map_err(|e| format!("Error writing digest to {}: {}", file, e)),
+         | Target::Webhook(url) => self.post(url, report).await.map_err(|e| format!("Error posting digest to {}: {}", url, e)),
+         | Target::Sendmail(to) =>
+         {
+            let to = to.join(", ");
+            self.sendmail(&to, report).await.map_err(|e| format!("Error mailing digest to {}: {}", to, e))
+         },
+      }
+   }
+
+   fn content_type(&self) -> &'static str
+   //------------------------------------
+   {
+      if self.format == "html" { "text/html; charset=utf-8" } else { "text/markdown; charset=utf-8" }
+   }
+
+   async fn post(&self, url: &str, report: &str) -> Result<(), String>
+   //-----------------------------------------------------------------
+   {
+      let client = reqwest::Client::builder()
+         .connect_timeout(Duration::from_secs(10))
+         .timeout(Duration::from_secs(30))
+         .build()
+         .map_err(|e| format!("Error creating HTTP client: {}", e))?;
+      let response = client.post(url).header(reqwest::header::CONTENT_TYPE, self.content_type()).body(report.to_string())
+         .send().await.map_err(|e| e.to_string())?;
+      if !response.status().is_success()
+      {
+         return Err(format!("HTTP {}", response.status()));
+      }
+      Ok(())
+   }
+
+   async fn sendmail(&self, to: &str, report: &str) -> Result<(), String>
+   //--------------------------------------------------------------------
+   {
+      let mut child = tokio::process::Command::new("sendmail").arg("-t")
+         .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped())
+         .spawn().map_err(|e| format!("Error running sendmail: {}", e))?;
+      let message = format!("To: {}\nSubject: dejacmd {} activity digest\nMIME-Version: 1.0\nContent-Type: {}\n\n{}",
+                            to, if self.schedule == Schedule::Daily { "daily" } else { "weekly" }, self.content_type(), report);
+      if let Some(mut stdin) = child.stdin.take()
+      {
+         stdin.write_all(message.as_bytes()).await.map_err(|e| format!("Error writing to sendmail: {}", e))?;
+      }
+      let output = child.wait_with_output().await.map_err(|e| format!("Error waiting for sendmail: {}", e))?;
+      if !output.status.success()
+      {
+         return Err(format!("sendmail failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim()));
+      }
+      Ok(())
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_schedule()
+   {
+      assert_eq!(Schedule::parse("Weekly").unwrap(), Schedule::Weekly);
+      assert_eq!(Schedule::parse("daily").unwrap().since(), "1d");
+      assert!(Schedule::parse("hourly").is_err());
+
+      let now = DateTime::parse_from_rfc3339("2026-10-17T09:00:00Z").unwrap().with_timezone(&Utc);
+      assert!(Schedule::Daily.is_due(None, now));
+      assert!(Schedule::Daily.is_due(Some("not a time"), now));
+      assert!(Schedule::Daily.is_due(Some("2026-10-16 09:00:00"), now));
+      assert!(!Schedule::Daily.is_due(Some("2026-10-16 09:00:01"), now));
+      assert!(!Schedule::Weekly.is_due(Some("2026-10-16 09:00:00"), now));
+      assert!(Schedule::Weekly.is_due(Some("2026-10-10 08:59:59"), now));
+      assert!(!Schedule::Weekly.is_due(Some(&timestamp(now)), now));
+   }
+
+   #[tokio::test]
+   async fn test_digest_settings_and_file()
+   {
+      let mut settings = Settings::default();
+      assert_eq!(Digest::from_settings(&settings).unwrap(), None);
+      settings.digest_schedule = Some("weekly".to_string());
+      assert_eq!(Digest::from_settings(&settings).unwrap(), None, "Nowhere to deliver it");
+
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_digest_test_{}.md", nanos));
+      settings.digest_file = Some(path.display().to_string());
+      settings.digest_sendmail_to = Some(vec![" ".to_string()]);
+      settings.digest_format = Some("MD".to_string());
+      let digest = Digest::from_settings(&settings).unwrap().unwrap();
+      assert_eq!(digest.format, "md");
+      assert_eq!(digest.targets, vec![Target::File(path.display().to_string())]);
+      assert!(!digest.is_central);
+
+      digest.deliver(&digest.targets[0], "# Report\n").await.unwrap();
+      assert_eq!(std::fs::read_to_string(&path).unwrap(), "# Report\n");
+      let _ = std::fs::remove_file(&path);
+
+      settings.digest_format = Some("pdf".to_string());
+      assert!(Digest::from_settings(&settings).is_err());
+      settings.digest_format = None;
+      settings.set_central_database_url("https://dejacmd.example.com");
+      assert!(Digest::from_settings(&settings).is_err(), "A dejacmd server can't make the report");
+   }
+
+   #[test]
+   fn test_due_targets()
+   {
+      let digest = Digest
+      {
+         schedule: Schedule::Daily,
+         format: "html".to_string(),
+         targets: vec![Target::File("/tmp/digest.html".to_string()), Target::Webhook("https://example.com/hook".to_string()),
+                       Target::Sendmail(vec!["team@example.com".to_string()])],
+         is_central: false,
+      };
+      let now = DateTime::parse_from_rfc3339("2026-10-17T09:00:00Z").unwrap().with_timezone(&Utc);
+      assert_eq!(digest.due_targets(None, now).len(), 3);
+
+      // The webhook failed last time so only it is retried
+      let last = BTreeMap::from([("file".to_string(), timestamp(now)), ("sendmail".to_string(), timestamp(now))]);
+      assert_eq!(digest.due_targets(Some(&last), now), vec![&digest.targets[1]]);
+      let tomorrow = now + chrono::Duration::days(1);
+      assert_eq!(digest.due_targets(Some(&last), tomorrow).len(), 3);
+   }
+}
@@ -0,0 +1,206 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, Local, NaiveDateTime, TimeZone};
+
+use crate::settings::Settings;
+
+/// Format of timestamps in the history database, the local time on the machine the command was logged on
+pub const RECORDED_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Number of commands dejacmd search shows without -n or the search_lines setting
+pub const DEFAULT_SEARCH_LINES: u64 = 25;
+
+/// Timezone timestamps are shown in (the timezone setting)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayZone
+{
+   /// As recorded
+   Local,
+   Utc,
+   Offset(FixedOffset),
+}
+
+impl DisplayZone
+{
+   /// local, utc or an offset from UTC such as +05:30, -0800 or +2
+   pub fn parse(zone: &str) -> Result<DisplayZone, String>
+   //-----------------------------------------------------
+   {
+      let zone = zone.trim();
+      match zone.to_lowercase().as_str()
+      {
+         | "" | "local" => return Ok(DisplayZone::Local),
+         | "utc" | "gmt" | "z" => return Ok(DisplayZone::Utc),
+         | _ => (),
+      }
+      let invalid = || format!("Invalid timezone {} (use local, utc or an offset such as +05:30)", zone);
+      let (sign, offset) = match zone.split_at_checked(1)
+      {
+         | Some(("+", offset)) => (1, offset),
+         | Some(("-", offset)) => (-1, offset),
+         | _ => return Err(invalid()),
+      };
+      let (hours, minutes) = match offset.split_once(':')
+      {
+         | Some((hours, minutes)) => (hours, minutes),
+         | None if offset.len() == 4 => offset.split_at(2),
+         | None => (offset, "0"),
+      };
+      let hours: i32 = hours.parse().map_err(|_| invalid())?;
+      let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+      if hours > 14 || minutes > 59
+      {
+         return Err(invalid());
+      }
+      FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(DisplayZone::Offset).ok_or_else(invalid)
+   }
+}
+
+/// How timestamps are shown, from the timezone and date_format settings
+#[derive(Debug, Clone)]
+pub struct TimestampFormat
+{
+   zone: DisplayZone,
+   format: String,
+}
+
+impl Default for TimestampFormat
+{
+   fn default() -> Self
+   //------------------
+   {
+      TimestampFormat { zone: DisplayZone::Local, format: RECORDED_FORMAT.to_string() }
+   }
+}
+
+impl TimestampFormat
+{
+   pub fn new(zone: Option<&str>, format: Option<&str>) -> Result<TimestampFormat, String>
+   //------------------------------------------------------------------------------------
+   {
+      let zone = zone.map(DisplayZone::parse).transpose()?.unwrap_or(DisplayZone::Local);
+      let format = match format.map(str::trim)
+      {
+         | Some(format) if !format.is_empty() =>
+         {
+            if StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+            {
+               return Err(format!("Invalid date format {}", format));
+            }
+            format.to_string()
+         },
+         | _ => RECORDED_FORMAT.to_string(),
+      };
+      Ok(TimestampFormat { zone, format })
+   }
+
+   pub fn from_settings(settings: &Settings) -> Result<TimestampFormat, String>
+   //-------------------------------------------------------------------------
+   {
+      TimestampFormat::new(settings.timezone.as_deref(), settings.date_format.as_deref())
+   }
+
+   /// A recorded timestamp in the display timezone and format. Timestamps are taken to have been recorded in this
+   /// machine's timezone. Anything that isn't a recorded timestamp is returned unchanged.
+   pub fn display(&self, timestamp: &str) -> String
+   //-----------------------------------------------
+   {
+      self.display_recorded_in(timestamp, &Local)
+   }
+
+   fn display_recorded_in<Tz: TimeZone>(&self, timestamp: &str, recorded: &Tz) -> String
+   //-------------------------------------------------------------------------------------
+   {
+      if self.zone == DisplayZone::Local && self.format == RECORDED_FORMAT
+      {
+         return timestamp.to_string();
+      }
+      let Ok(naive) = NaiveDateTime::parse_from_str(timestamp, RECORDED_FORMAT)
+      else
+      {
+         return timestamp.to_string();
+      };
+      let offset = match self.zone
+      {
+         | DisplayZone::Local => return naive.format(&self.format).to_string(),
+         | DisplayZone::Utc => FixedOffset::east_opt(0).expect("UTC offset"),
+         | DisplayZone::Offset(offset) => offset,
+      };
+      match recorded.from_local_datetime(&naive).earliest()
+      {
+         | Some(time) => time.with_timezone(&offset).format(&self.format).to_string(),
+         | None => timestamp.to_string(), // In a daylight saving gap
+      }
+   }
+}
+
+/// The color setting: Some(true) for always, Some(false) for never and None for auto
+pub fn parse_color(color: Option<&str>) -> Result<Option<bool>, String>
+//---------------------------------------------------------------------
+{
+   match color.map(|c| c.trim().to_lowercase()).as_deref()
+   {
+      | None | Some("") | Some("auto") => Ok(None),
+      | Some("always") => Ok(Some(true)),
+      | Some("never") => Ok(Some(false)),
+      | Some(c) => Err(format!("Invalid color {} (use auto, always or never)", c)),
+   }
+}
+
+/// Whether to color output for color (from parse_color). auto colors a terminal unless the NO_COLOR environment
+/// variable is set to anything, see https://no-color.org
+pub fn is_color(color: Option<bool>, is_terminal: bool) -> bool
+//-------------------------------------------------------------
+{
+   is_color_with(color, is_terminal, std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()))
+}
+
+fn is_color_with(color: Option<bool>, is_terminal: bool, is_no_color: bool) -> bool
+//---------------------------------------------------------------------------------
+{
+   color.unwrap_or(is_terminal && !is_no_color)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_timestamp_format()
+   {
+      assert_eq!(DisplayZone::parse("UTC").unwrap(), DisplayZone::Utc);
+      assert_eq!(DisplayZone::parse("local").unwrap(), DisplayZone::Local);
+      assert_eq!(DisplayZone::parse("+05:30").unwrap(), DisplayZone::Offset(FixedOffset::east_opt(19800).unwrap()));
+      assert_eq!(DisplayZone::parse("-0800").unwrap(), DisplayZone::Offset(FixedOffset::west_opt(28800).unwrap()));
+      assert_eq!(DisplayZone::parse("+2").unwrap(), DisplayZone::Offset(FixedOffset::east_opt(7200).unwrap()));
+      assert!(DisplayZone::parse("Europe/Paris").is_err());
+      assert!(DisplayZone::parse("+25:00").is_err());
+      assert!(TimestampFormat::new(None, Some("%Y-%Q")).is_err());
+
+      let recorded = FixedOffset::east_opt(3600).unwrap();
+      let timestamp = "2026-03-01 10:15:30";
+      let format = TimestampFormat::default();
+      assert_eq!(format.display_recorded_in(timestamp, &recorded), timestamp);
+      let format = TimestampFormat::new(Some("utc"), None).unwrap();
+      assert_eq!(format.display_recorded_in(timestamp, &recorded), "2026-03-01 09:15:30");
+      let format = TimestampFormat::new(Some("-05:00"), Some("%d/%m/%Y %H:%M")).unwrap();
+      assert_eq!(format.display_recorded_in(timestamp, &recorded), "01/03/2026 04:15");
+      let format = TimestampFormat::new(None, Some("%a %e %b %H:%M")).unwrap();
+      assert_eq!(format.display_recorded_in(timestamp, &recorded), "Sun  1 Mar 10:15");
+      assert_eq!(format.display_recorded_in("not a time", &recorded), "not a time");
+   }
+
+   #[test]
+   fn test_color()
+   {
+      assert_eq!(parse_color(None).unwrap(), None);
+      assert_eq!(parse_color(Some("Always")).unwrap(), Some(true));
+      assert_eq!(parse_color(Some("never")).unwrap(), Some(false));
+      assert!(parse_color(Some("sometimes")).is_err());
+      assert!(is_color_with(None, true, false));
+      assert!(!is_color_with(None, true, true), "NO_COLOR");
+      assert!(!is_color_with(None, false, false));
+      assert!(is_color_with(Some(true), false, true), "always overrides NO_COLOR");
+      assert!(!is_color_with(Some(false), true, false));
+   }
+}
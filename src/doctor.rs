@@ -0,0 +1,431 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime};
+use sqlx::{Any, Pool};
+
+use crate::remote::{HEALTH_PATH, RemoteCentral, is_remote_url};
+use crate::settings::Settings;
+use crate::shell::{BEGIN_MARKER, SHELLS};
+use crate::{PoolSettings, fix_placeholders, get_database_with_options, pause, shell, spool};
+
+/// How long since this machine last logged a command before dejacmd doctor warns that commands may not be landing
+pub const RECENT_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status
+{
+   Ok,
+   Warning,
+   Failed,
+}
+
+/// The result of one dejacmd doctor check with a suggested fix for a warning or failure
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check
+{
+   pub name: String,
+   pub status: Status,
+   pub message: String,
+   pub fix: Option<String>,
+}
+
+impl Check
+{
+   pub fn ok(name: &str, message: &str) -> Self
+   //------------------------------------------
+   {
+      Check { name: name.to_string(), status: Status::Ok, message: message.to_string(), fix: None }
+   }
+
+   pub fn warning(name: &str, message: &str, fix: &str) -> Self
+   //-----------------------------------------------------------
+   {
+      Check { name: name.to_string(), status: Status::Warning, message: message.to_string(), fix: Some(fix.to_string()) }
+   }
+
+   pub fn failed(name: &str, message: &str, fix: &str) -> Self
+   //----------------------------------------------------------
+   {
+      Check { name: name.to_string(), status: Status::Failed, message: message.to_string(), fix: Some(fix.to_string()) }
+   }
+}
+
+/// Run every check: dejacmd-log, the shell hooks, file permissions, pausing, the spool, the databases and whether
+/// commands from this machine have been logged recently
+pub async fn run(settings: &Settings) -> Vec<Check>
+//-------------------------------------------------
+{
+   let mut checks = vec![check_log_program(), check_hooks(std::env::var("SHELL").ok().as_deref())];
+   if let Ok(config) = Settings::get_config_path()
+   {
+      checks.push(check_permissions("Settings file", &config.join("settings.json"), false));
+      checks.push(if settings.is_passphrase_key()
+      {
+         Check::ok("Encryption key", "derived from a passphrase")
+      }
+      else
+      {
+         match settings.get_encryption_key_path()
+         {
+            | Ok(path) => check_permissions("Encryption key", &path, true),
+            | Err(e) => Check::failed("Encryption key", e.message(), "Mount or create the directory, or change encryption_key_file"),
+         }
+      });
+   }
+   checks.push(check_pause());
+   checks.push(check_spool());
+   checks.push(check_local_spool());
+   let hostname = crate::get_hostname();
+   for is_local in [true, false]
+   {
+      let (check, pool) = check_database(settings, is_local).await;
+      checks.push(check);
+      if let Some((pool, scheme)) = pool
+      {
+         let name = if is_local { "Recent local commands" } else { "Recent central commands" };
+         checks.push(match last_logged(&pool, &scheme, &hostname).await
+         {
+            | Ok(last) => check_recent(name, last.as_deref(), Local::now().naive_local()),
+            | Err(e) => Check::failed(name, &e, "Run dejacmd db check to look for a damaged database"),
+         });
+         pool.close().await;
+      }
+   }
+   checks
+}
+
+/// Search the directories in path (a PATH value) for program, adding the executable suffix on Windows
+pub fn find_on_path(program: &str, path: &OsStr) -> Option<PathBuf>
+//-----------------------------------------------------------------
+{
+   let name = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+   std::env::split_paths(path).map(|dir| dir.join(&name)).find(|file| file.is_file())
+}
+
+pub fn check_log_program() -> Check
+//---------------------------------
+{
+   const NAME: &str = "dejacmd-log";
+   let on_path = std::env::var_os("PATH").and_then(|path| find_on_path("dejacmd-log", &path));
+   let beside = PathBuf::from(shell::log_program());
+   match on_path
+   {
+      | Some(path) => Check::ok(NAME, &format!("found at {}", path.display())),
+      | None if beside.is_absolute() =>
+      {
+         let dir = beside.parent().map(|d| d.display().to_string()).unwrap_or_default();
+         Check::warning(NAME, &format!("not on the PATH, hooks from dejacmd init use {}", beside.display()),
+                        &format!("Add {} to the PATH if the shell hooks were added by hand", dir))
+      },
+      | None => Check::failed(NAME, "not found on the PATH or beside dejacmd",
+                              "Install dejacmd-log (cargo install --path . installs both programs) or add its directory to the PATH"),
+   }
+}
+
+/// Whether the startup file at path has hooks from dejacmd init or a hand written call to dejacmd-log
+pub fn is_hook_installed(path: &Path) -> bool
+//-------------------------------------------
+{
+   std::fs::read_to_string(path).is_ok_and(|content| content.contains(BEGIN_MARKER) || content.contains("dejacmd-log"))
+}
+
+/// Check the startup file of the login shell (SHELL) has the hooks, or failing that any supported shell's
+pub fn check_hooks(login_shell: Option<&str>) -> Check
+//----------------------------------------------------
+{
+   const NAME: &str = "Shell hooks";
+   let installed: Vec<(&str, PathBuf)> = SHELLS.iter()
+      .filter_map(|shell| shell::rc_file(shell).ok().map(|path| (*shell, path)))
+      .filter(|(_, path)| is_hook_installed(path))
+      .collect();
+   let login_shell = login_shell.and_then(|s| Path::new(s).file_name()).map(|s| s.to_string_lossy().to_string())
+      .map(|s| if s == "pwsh" { "powershell".to_string() } else { s })
+      .filter(|s| SHELLS.contains(&s.as_str()));
+   let list = |shells: &[(&str, PathBuf)]| shells.iter().map(|(_, path)| path.display().to_string()).collect::<Vec<_>>().join(", ");
+   match login_shell
+   {
+      | Some(login) if installed.iter().any(|(shell, _)| *shell == login) => Check::ok(NAME, &format!("installed in {}", list(&installed))),
+      | Some(login) if !installed.is_empty() =>
+         Check::warning(NAME, &format!("installed in {} but not for {}", list(&installed), login),
+                        &format!("dejacmd init {} --install", login)),
+      | Some(login) => Check::failed(NAME, &format!("not installed for {}", login), &format!("dejacmd init {} --install", login)),
+      | None if !installed.is_empty() => Check::ok(NAME, &format!("installed in {}", list(&installed))),
+      | None => Check::failed(NAME, "not installed for any shell",
+                              &format!("dejacmd init <{}> --install", SHELLS.join("|"))),
+   }
+}
+
+/// A file holding credentials or the key for them should only be readable by its owner. A key readable by others is
+/// a failure as it decrypts the passwords in the settings file.
+pub fn check_permissions(name: &str, path: &Path, is_key: bool) -> Check
+//----------------------------------------------------------------------
+{
+   if !path.exists()
+   {
+      return Check::ok(name, &format!("{} not created yet", path.display()));
+   }
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = match std::fs::metadata(path)
+      {
+         | Ok(metadata) => metadata.permissions().mode() & 0o777,
+         | Err(e) => return Check::failed(name, &format!("can't read {}: {}", path.display(), e), "Check the ownership of the file"),
+      };
+      if mode & 0o077 != 0
+      {
+         let message = format!("{} is accessible by other users (mode {:o})", path.display(), mode);
+         let fix = format!("chmod 600 {}", path.display());
+         return if is_key { Check::failed(name, &message, &fix) } else { Check::warning(name, &message, &fix) };
+      }
+      Check::ok(name, &format!("{} (mode {:o})", path.display(), mode))
+   }
+   #[cfg(not(unix))]
+   {
+      let _ = is_key;
+      Check::ok(name, &format!("{} (permissions not checked on this platform)", path.display()))
+   }
+}
+
+pub fn check_pause() -> Check
+//---------------------------
+{
+   match pause::paused()
+   {
+      | Some(pause) => Check::warning("Logging", &format!("paused since {}{}", pause.since,
+                                      pause.until.map(|u| format!(" until {}", u)).unwrap_or_default()), "dejacmd resume"),
+      | None => Check::ok("Logging", "not paused"),
+   }
+}
+
+/// Commands waiting in the spool for a central database or server that couldn't be reached
+pub fn check_spool() -> Check
+//---------------------------
+{
+   const NAME: &str = "Central spool";
+   let count = spool::spool_path().map(|path| spool::backlog_at(&path)).unwrap_or(0);
+   if count == 0
+   {
+      return Check::ok(NAME, "empty");
+   }
+   Check::warning(NAME, &format!("{} commands waiting for the central database", count),
+                  "They're written with the next command logged once the central database can be reached, check it is up")
+}
+
+/// Commands dejacmd-log ran out of time writing to the local database
+pub fn check_local_spool() -> Check
+//---------------------------------
+{
+   const NAME: &str = "Local spool";
+   let count = spool::local_spool_path().map(|path| spool::backlog_at(&path)).unwrap_or(0);
+   if count == 0
+   {
+      return Check::ok(NAME, "empty");
+   }
+   Check::warning(NAME, &format!("{} commands waiting for the local database", count),
+                  "They're written with the next command logged, raise log_timeout_ms if the local database is often slow")
+}
+
+/// Connect to the local or central database (or check a central dejacmd server is up) and report a missing history
+/// table or schema updates that dejacmd-log hasn't applied yet, without changing the database. Returns the pool so the
+/// recent commands can be checked.
+pub async fn check_database(settings: &Settings, is_local: bool) -> (Check, Option<(Pool<Any>, String)>)
+//-----------------------------------------------------------------------------------------------------
+{
+   const FIX_SCHEMA: &str = "dejacmd-log creates or updates it with the next command logged, check the database user can \
+                             create and alter the history table";
+   let name = if is_local { "Local database" } else { "Central database" };
+   let option = if is_local { "-L" } else { "-C" };
+   let url = if is_local { settings.get_local_database_url() } else { settings.get_central_database_url() };
+   if url.trim().is_empty()
+   {
+      return (Check::ok(name, "not configured"), None);
+   }
+   if is_remote_url(&url)
+   {
+      let check = match RemoteCentral::from_settings(settings)
+      {
+         | Ok(Some(remote)) => match remote.get::<serde_json::Value>(HEALTH_PATH).await
+         {
+            | Ok(_) if remote.is_libsql() => Check::ok(name, &format!("libSQL database {} is up", remote.url())),
+            | Ok(_) => Check::ok(name, &format!("dejacmd server {} is up", remote.url())),
+            | Err(e) => Check::failed(name, &e.to_string(), "Check dejacmd serve is running and the token is right (dejacmd config -C URL -p)"),
+         },
+         | Ok(None) => Check::ok(name, "not configured"),
+         | Err(e) => Check::failed(name, &e, "dejacmd config -C URL -p"),
+      };
+      return (check, None);
+   }
+   if let Some(path) = sqlite_file(&url) && !path.exists()
+   {
+      return (Check::warning(name, &format!("{} doesn't exist yet", path.display()), FIX_SCHEMA), None);
+   }
+   let (user, password) = match settings.get_credentials(is_local)
+   {
+      | Ok(credentials) => credentials,
+      | Err(e) => return (Check::failed(name, e.message(), &format!("Set the credentials again with dejacmd config {} URL -u USER -p", option)), None),
+   };
+   match get_database_with_options(&read_only_url(&url), &user, &password, &PoolSettings::from_settings(settings)).await
+   {
+      | Ok((Some(pool), scheme)) =>
+      {
+         let connected = format!("connected to {} database", crate::ddl::dialect(&scheme));
+         if sqlx::query("SELECT COUNT(*) FROM history WHERE 1 = 0").fetch_one(&pool).await.is_err()
+         {
+            pool.close().await;
+            return (Check::warning(name, &format!("{} without a history table", connected), FIX_SCHEMA), None);
+         }
+         let pending = crate::migrations::pending_updates(&pool, &scheme).await;
+         let check = if pending.is_empty()
+         {
+            Check::ok(name, &connected)
+         }
+         else
+         {
+            Check::warning(name, &format!("{}, schema updates not applied: {}", connected, pending.join(", ")), FIX_SCHEMA)
+         };
+         (check, Some((pool, scheme)))
+      },
+      | Ok((None, _)) => (Check::ok(name, "not configured"), None),
+      | Err(e) => (Check::failed(name, &e.to_string(), &format!("Check the database is up and the URL and credentials (dejacmd config {})", option)), None),
+   }
+}
+
+/// The file of a SQLite or SQLCipher database URL
+fn sqlite_file(url: &str) -> Option<PathBuf>
+//------------------------------------------
+{
+   let path = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix(&format!("{}://", crate::SQLCIPHER_SCHEME)))?;
+   let path = path.split('?').next().unwrap_or(path);
+   match path
+   {
+      | "" | ":memory:" => None,
+      | _ => Some(PathBuf::from(path)),
+   }
+}
+
+/// A SQLite or SQLCipher URL opened read only, other URLs as they are
+fn read_only_url(url: &str) -> String
+//-----------------------------------
+{
+   if sqlite_file(url).is_none() || url.contains("mode=")
+   {
+      return url.to_string();
+   }
+   format!("{}{}mode=ro", url, if url.contains('?') { "&" } else { "?" })
+}
+
+/// Timestamp of the last command logged on hostname, None if there aren't any
+pub async fn last_logged(pool: &Pool<Any>, scheme: &str, hostname: &str) -> Result<Option<String>, String>
+//-------------------------------------------------------------------------------------------------------
+{
+   let sql = fix_placeholders("SELECT MAX(command_timestamp) FROM history WHERE hostname = $1", scheme);
+   sqlx::query_scalar::<_, Option<String>>(&sql).bind(hostname).fetch_one(pool).await
+      .map_err(|e| format!("Error reading the last command logged: {}", e))
+}
+
+/// Warn if the last command from this machine (a local %Y-%m-%d %H:%M:%S time) was logged more than RECENT_HOURS
+/// before now
+pub fn check_recent(name: &str, last: Option<&str>, now: NaiveDateTime) -> Check
+//-----------------------------------------------------------------------------
+{
+   const FIX: &str = "Run a command in a new shell and check again. If it isn't logged add -l /tmp/dejacmd-log.txt to the \
+                      dejacmd-log call in the shell hooks to see its errors";
+   let Some(last) = last
+   else
+   {
+      return Check::warning(name, "no commands logged from this machine", FIX);
+   };
+   match NaiveDateTime::parse_from_str(last, "%Y-%m-%d %H:%M:%S")
+   {
+      | Ok(time) if now - time > chrono::Duration::hours(RECENT_HOURS) =>
+         Check::warning(name, &format!("the last command from this machine was logged at {}", last), FIX),
+      | _ => Check::ok(name, &format!("last command logged at {}", last)),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn temp_dir() -> PathBuf
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_doctor_test_{}", nanos));
+      std::fs::create_dir_all(&dir).unwrap();
+      dir
+   }
+
+   #[test]
+   fn test_checks()
+   {
+      let dir = temp_dir();
+      let program = dir.join(format!("dejacmd-log{}", std::env::consts::EXE_SUFFIX));
+      let path = std::env::join_paths([dir.join("missing"), dir.clone()]).unwrap();
+      assert_eq!(find_on_path("dejacmd-log", &path), None);
+      std::fs::write(&program, "").unwrap();
+      assert_eq!(find_on_path("dejacmd-log", &path), Some(program.clone()));
+
+      let rc = dir.join(".bashrc");
+      assert!(!is_hook_installed(&rc));
+      std::fs::write(&rc, "alias ll='ls -l'\n").unwrap();
+      assert!(!is_hook_installed(&rc));
+      std::fs::write(&rc, format!("{}\ntrap ... DEBUG\n", BEGIN_MARKER)).unwrap();
+      assert!(is_hook_installed(&rc));
+
+      let key = dir.join("encryption-key");
+      assert_eq!(check_permissions("Encryption key", &key, true).status, Status::Ok, "Not created yet");
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::PermissionsExt;
+         std::fs::write(&key, "00").unwrap();
+         std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+         let check = check_permissions("Encryption key", &key, true);
+         assert_eq!(check.status, Status::Failed);
+         assert_eq!(check.fix, Some(format!("chmod 600 {}", key.display())));
+         assert_eq!(check_permissions("Settings file", &key, false).status, Status::Warning);
+         std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600)).unwrap();
+         assert_eq!(check_permissions("Encryption key", &key, true).status, Status::Ok);
+      }
+
+      let now = NaiveDateTime::parse_from_str("2026-03-10 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+      assert_eq!(check_recent("Recent", Some("2026-03-10 09:30:00"), now).status, Status::Ok);
+      assert_eq!(check_recent("Recent", Some("2026-03-08 09:30:00"), now).status, Status::Warning);
+      assert_eq!(check_recent("Recent", None, now).status, Status::Warning);
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+
+   #[tokio::test]
+   async fn test_check_database()
+   {
+      let dir = temp_dir();
+      let url = format!("sqlite://{}", dir.join("local.db").display());
+      let settings = Settings::new_for_test(&url, "");
+      let (check, pool) = check_database(&settings, true).await;
+      assert_eq!((check.status, pool.is_none()), (Status::Warning, true), "{:?}", check);
+      assert!(!dir.join("local.db").exists(), "Checking doesn't create the database");
+
+      // Only the history table, without the schema updates
+      let (created, scheme) = get_database_with_options(&url, "", &Default::default(), &PoolSettings::default()).await.unwrap();
+      let created = created.unwrap();
+      sqlx::query(crate::ddl::create_table_sql(&scheme)).execute(&created).await.unwrap();
+      let (check, pool) = check_database(&settings, true).await;
+      assert_eq!(check.status, Status::Warning, "{:?}", check);
+      assert!(check.message.contains("schema updates not applied"), "{}", check.message);
+      pool.unwrap().0.close().await;
+      assert!(!crate::migrations::pending_updates(&created, &scheme).await.is_empty(), "Checking doesn't apply them");
+
+      crate::create_schema(&created, &scheme).await.unwrap();
+      created.close().await;
+      let (check, pool) = check_database(&settings, true).await;
+      assert_eq!(check.status, Status::Ok, "{:?}", check);
+      let (pool, scheme) = pool.unwrap();
+      assert_eq!(last_logged(&pool, &scheme, "host").await.unwrap(), None);
+      pool.close().await;
+      let (check, pool) = check_database(&settings, false).await;
+      assert_eq!((check.status, pool.is_none()), (Status::Ok, true), "No central database");
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
@@ -0,0 +1,66 @@
+/// Errors from the dejacmd library, by the kind of failure so callers can react to (or choose an exit code for) each
+/// kind.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DejacmdError
+{
+   /// The settings file, a setting's value or a file kept beside the settings such as the encryption-key file
+   #[error("{0}")]
+   Config(String),
+   /// Connecting to a database or dejacmd server
+   #[error("{0}")]
+   Connection(String),
+   /// A statement against a connected database
+   #[error("{0}")]
+   Query(String),
+   /// Encryption keys, passphrases and encrypting or decrypting passwords
+   #[error("{0}")]
+   Crypto(String),
+   /// Input that isn't in the expected form, e.g. a hex blob, cipher name or setting value
+   #[error("{0}")]
+   Parse(String),
+}
+
+impl DejacmdError
+{
+   /// The error's message without its kind
+   pub fn message(&self) -> &str
+   //---------------------------
+   {
+      match self
+      {
+         | DejacmdError::Config(m) | DejacmdError::Connection(m) | DejacmdError::Query(m) | DejacmdError::Crypto(m)
+         | DejacmdError::Parse(m) => m,
+      }
+   }
+
+   /// A sysexits(3) exit status for the kind of error: EX_CONFIG, EX_UNAVAILABLE, EX_SOFTWARE, EX_NOPERM or
+   /// EX_DATAERR
+   pub fn exit_code(&self) -> u8
+   //---------------------------
+   {
+      match self
+      {
+         | DejacmdError::Config(_) => 78,
+         | DejacmdError::Connection(_) => 69,
+         | DejacmdError::Query(_) => 70,
+         | DejacmdError::Crypto(_) => 77,
+         | DejacmdError::Parse(_) => 65,
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_error()
+   {
+      let e = DejacmdError::Crypto("Wrong passphrase".to_string());
+      assert_eq!(e.to_string(), "Wrong passphrase");
+      assert_eq!(e.message(), "Wrong passphrase");
+      assert_eq!(e.exit_code(), 77);
+      assert_eq!(DejacmdError::Parse("Invalid cipher".to_string()).exit_code(), 65);
+   }
+}
@@ -0,0 +1,337 @@
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::HistoryEntry;
+use crate::settings::Settings;
+
+/// Commands that are kept in the local database but never written to the central database, built from the
+/// central_exclude_commands (glob patterns matched against the whole command e.g `vault *`), central_exclude_dirs
+/// (the directory and everything below it, ~ is the home directory) and central_exclude_shells settings.
+#[derive(Debug, Default)]
+pub struct CentralExclusions
+{
+   commands: Vec<Regex>,
+   dirs: Vec<PathBuf>,
+   shells: Vec<String>,
+}
+
+impl CentralExclusions
+{
+   pub fn from_settings(settings: &Settings) -> Result<Self, String>
+   //----------------------------------------------------------------
+   {
+      let commands = settings.central_exclude_commands.iter().flatten()
+         .map(|pattern| glob_regex(pattern).map_err(|e| format!("Invalid central_exclude_commands pattern '{}': {}", pattern, e)))
+         .collect::<Result<Vec<Regex>, String>>()?;
+      let dirs = settings.central_exclude_dirs.iter().flatten()
+         .filter(|dir| !dir.trim().is_empty())
+         .map(|dir| expand_home(dir.trim()))
+         .collect();
+      let shells = settings.central_exclude_shells.iter().flatten()
+         .map(|shell| shell.trim().to_string())
+         .filter(|shell| !shell.is_empty())
+         .collect();
+      Ok(CentralExclusions { commands, dirs, shells })
+   }
+
+   pub fn is_empty(&self) -> bool
+   //----------------------------
+   {
+      self.commands.is_empty() && self.dirs.is_empty() && self.shells.is_empty()
+   }
+
+   pub fn is_excluded(&self, command: &str, cwd: &str, shell: &str) -> bool
+   //----------------------------------------------------------------------
+   {
+      if self.commands.iter().any(|re| re.is_match(command.trim()))
+      {
+         return true;
+      }
+      if !cwd.is_empty() && self.dirs.iter().any(|dir| Path::new(cwd).starts_with(dir))
+      {
+         return true;
+      }
+      // Shells are matched on their name so bash also excludes /usr/bin/bash
+      let shell_name = Path::new(shell).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+      !shell.is_empty() && self.shells.iter().any(|s| s == shell || *s == shell_name)
+   }
+
+   pub fn is_excluded_entry(&self, entry: &HistoryEntry) -> bool
+   //------------------------------------------------------------
+   {
+      self.is_excluded(entry.command.as_deref().unwrap_or(""), entry.cwd.as_deref().unwrap_or(""),
+                       entry.shell.as_deref().unwrap_or(""))
+   }
+}
+
+/// Commands that dejacmd-log doesn't log at all, built from the ignore_commands (glob patterns as for
+/// central_exclude_commands) and ignore_space settings
+#[derive(Debug)]
+pub struct IgnoreRules
+{
+   commands: Vec<Regex>,
+   is_ignore_space: bool,
+}
+
+impl IgnoreRules
+{
+   pub fn from_settings(settings: &Settings) -> Result<Self, String>
+   //----------------------------------------------------------------
+   {
+      let commands = settings.ignore_commands.iter().flatten()
+         .map(|pattern| glob_regex(pattern).map_err(|e| format!("Invalid ignore_commands pattern '{}': {}", pattern, e)))
+         .collect::<Result<Vec<Regex>, String>>()?;
+      Ok(IgnoreRules { commands, is_ignore_space: settings.ignore_space.unwrap_or(true) })
+   }
+
+   /// True if command (which was typed with a leading space if is_leading_space) shouldn't be logged
+   pub fn is_ignored(&self, command: &str, is_leading_space: bool) -> bool
+   //---------------------------------------------------------------------
+   {
+      (is_leading_space && self.is_ignore_space) || self.commands.iter().any(|re| re.is_match(command.trim()))
+   }
+}
+
+/// Commands --exec won't run unless exec_deny_commands is set
+pub const DEFAULT_EXEC_DENY: &[&str] = &["*rm -rf *", "*rm -fr *", "*mkfs*", "*dd *of=/dev/*"];
+
+/// Commands --exec asks about before running unless exec_confirm_commands is set
+pub const DEFAULT_EXEC_CONFIRM: &[&str] = &["sudo *", "* sudo *", "su *", "doas *"];
+
+/// What ExecPolicy allows for a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecDecision
+{
+   Run,
+   Confirm,
+   /// Why it isn't run
+   Deny(String),
+}
+
+/// Whether a command chosen from the history can be run again with --exec, built from the exec_allow_commands,
+/// exec_deny_commands and exec_confirm_commands settings (glob patterns as for central_exclude_commands). Denied
+/// commands are never run, and when allow patterns are set only the commands they match are. Commands matching a
+/// confirm pattern are only run once the user agrees.
+///
+/// A command line is checked statement by statement (split at ;, &, |, && and || and newlines), and as a whole too for
+/// the deny and confirm patterns, so `make; sudo make install` needs confirming and every statement has to be allowed.
+/// For the deny and confirm patterns the short options of both the pattern and the statement are merged, lowercased and
+/// sorted, so `rm -Rf`, `rm -r -f` and `rm -fr` all match `rm -rf *`.
+#[derive(Debug)]
+pub struct ExecPolicy
+{
+   allow: Vec<Regex>,
+   deny: Vec<(String, Regex)>,
+   confirm: Vec<Regex>,
+}
+
+impl ExecPolicy
+{
+   pub fn from_settings(settings: &Settings) -> Result<Self, String>
+   //----------------------------------------------------------------
+   {
+      let patterns = |patterns: &Option<Vec<String>>, defaults: &[&str], name: &str, is_normalized: bool|
+         patterns.clone().unwrap_or_else(|| defaults.iter().map(|p| p.to_string()).collect()).into_iter()
+            .filter(|pattern| !pattern.trim().is_empty())
+            .map(|pattern|
+            {
+               let glob = if is_normalized { normalize_options(&pattern) } else { pattern.clone() };
+               glob_regex(&glob).map(|re| (pattern.clone(), re))
+                                .map_err(|e| format!("Invalid {} pattern '{}': {}", name, pattern, e))
+            })
+            .collect::<Result<Vec<(String, Regex)>, String>>();
+      Ok(ExecPolicy
+      {
+         allow: patterns(&settings.exec_allow_commands, &[], "exec_allow_commands", false)?.into_iter().map(|(_, re)| re).collect(),
+         deny: patterns(&settings.exec_deny_commands, DEFAULT_EXEC_DENY, "exec_deny_commands", true)?,
+         confirm: patterns(&settings.exec_confirm_commands, DEFAULT_EXEC_CONFIRM, "exec_confirm_commands", true)?.into_iter()
+            .map(|(_, re)| re).collect(),
+      })
+   }
+
+   pub fn check(&self, command: &str) -> ExecDecision
+   //------------------------------------------------
+   {
+      let command = command.trim();
+      let statements = statements(command);
+      let normalized: Vec<String> = std::iter::once(command).chain(statements.iter().copied()).map(normalize_options).collect();
+      let is_match = |re: &Regex| normalized.iter().any(|statement| re.is_match(statement));
+      if let Some((pattern, _)) = self.deny.iter().find(|(_, re)| is_match(re))
+      {
+         return ExecDecision::Deny(format!("it matches the exec_deny_commands pattern '{}'", pattern));
+      }
+      let is_allowed = |statement: &str| self.allow.iter().any(|re| re.is_match(statement));
+      if !self.allow.is_empty() && !statements.iter().all(|statement| is_allowed(statement))
+      {
+         return ExecDecision::Deny("it doesn't match any exec_allow_commands pattern".to_string());
+      }
+      if self.confirm.iter().any(is_match) { ExecDecision::Confirm } else { ExecDecision::Run }
+   }
+}
+
+/// The statements of a command line, split at ;, &, |, && and || and newlines (but not the & of redirections like
+/// 2>&1). Quotes aren't taken into account so a separator in a quoted string splits it too.
+fn statements(command: &str) -> Vec<&str>
+//---------------------------------------
+{
+   let bytes = command.as_bytes();
+   let mut statements = Vec::new();
+   let mut start = 0;
+   for (i, c) in command.char_indices()
+   {
+      let is_redirection = c == '&' && (i > 0 && matches!(bytes[i - 1], b'>' | b'<') || bytes.get(i + 1) == Some(&b'>'));
+      if matches!(c, ';' | '|' | '&' | '\n' | '\r') && !is_redirection
+      {
+         statements.push(command[start..i].trim());
+         start = i + 1;
+      }
+   }
+   statements.push(command[start..].trim());
+   statements.retain(|statement| !statement.is_empty());
+   statements
+}
+
+/// statement with its words separated by single spaces and each run of short options (-R -f) merged into one,
+/// lowercased and sorted (-fr)
+fn normalize_options(statement: &str) -> String
+//----------------------------------------------
+{
+   let is_short_options = |word: &str| word.len() > 1 && word.starts_with('-') && word[1..].chars().all(|c| c.is_ascii_alphabetic());
+   let mut words: Vec<String> = Vec::new();
+   let mut options: Vec<char> = Vec::new();
+   let flush = |options: &mut Vec<char>, words: &mut Vec<String>|
+   {
+      if !options.is_empty()
+      {
+         options.sort_unstable();
+         options.dedup();
+         words.push(format!("-{}", options.drain(..).collect::<String>()));
+      }
+   };
+   for word in statement.split_whitespace()
+   {
+      if is_short_options(word)
+      {
+         options.extend(word[1..].chars().map(|c| c.to_ascii_lowercase()));
+      }
+      else
+      {
+         flush(&mut options, &mut words);
+         words.push(word.to_string());
+      }
+   }
+   flush(&mut options, &mut words);
+   words.join(" ")
+}
+
+/// Regex matching the whole of a string against a glob where * matches anything (including spaces, / and newlines) and
+/// ? a single character
+pub fn glob_regex(pattern: &str) -> Result<Regex, regex::Error>
+//---------------------------------------------------------
+{
+   let mut re = String::from("(?s)^");
+   for c in pattern.trim().chars()
+   {
+      match c
+      {
+         | '*' => re.push_str(".*"),
+         | '?' => re.push('.'),
+         | _ => re.push_str(&regex::escape(&c.to_string())),
+      }
+   }
+   re.push('$');
+   Regex::new(&re)
+}
+
+pub(crate) fn expand_home(dir: &str) -> PathBuf
+//-----------------------------------
+{
+   match dir.strip_prefix('~')
+   {
+      | Some(rest) => Settings::get_home_dir().join(rest.trim_start_matches(['/', '\\'])),
+      | None => PathBuf::from(dir),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_central_exclusions()
+   {
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      assert!(CentralExclusions::from_settings(&settings).unwrap().is_empty());
+
+      settings.central_exclude_commands = Some(vec!["vault *".to_string(), "export ?ASSWORD=*".to_string()]);
+      settings.central_exclude_dirs = Some(vec!["~/private".to_string(), "/srv/secret/".to_string()]);
+      settings.central_exclude_shells = Some(vec!["fish".to_string()]);
+      let exclusions = CentralExclusions::from_settings(&settings).unwrap();
+      let home = Settings::get_home_dir();
+      let private = home.join("private").join("notes").display().to_string();
+
+      assert!(exclusions.is_excluded("vault kv get secret/db", "/tmp", "bash"));
+      assert!(exclusions.is_excluded("  vault login", "/tmp", "bash"));
+      assert!(!exclusions.is_excluded("echo vault status", "/tmp", "bash"), "Globs match the whole command");
+      assert!(exclusions.is_excluded("export PASSWORD=x", "/tmp", "bash"));
+      assert!(exclusions.is_excluded("ls", &private, "bash"));
+      assert!(exclusions.is_excluded("ls", "/srv/secret", "bash"));
+      assert!(!exclusions.is_excluded("ls", &home.join("private-not").display().to_string(), "bash"));
+      assert!(exclusions.is_excluded("ls", "/tmp", "/usr/bin/fish"));
+      assert!(!exclusions.is_excluded("ls", "/tmp", "bash"));
+   }
+
+   #[test]
+   fn test_exec_policy()
+   {
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      let policy = ExecPolicy::from_settings(&settings).unwrap();
+      assert_eq!(policy.check("make test"), ExecDecision::Run);
+      assert!(matches!(policy.check("rm -rf build"), ExecDecision::Deny(_)));
+      assert!(matches!(policy.check("cd /tmp && rm -rf *"), ExecDecision::Deny(_)));
+      assert_eq!(policy.check("sudo systemctl restart nginx"), ExecDecision::Confirm);
+      assert_eq!(policy.check("make && sudo make install"), ExecDecision::Confirm);
+      assert_eq!(policy.check("sudoku"), ExecDecision::Run);
+      assert!(matches!(policy.check("true\nsudo rm -rf /"), ExecDecision::Deny(_)), "Statements on other lines are checked");
+      assert!(matches!(policy.check("rm -Rf /"), ExecDecision::Deny(_)));
+      assert!(matches!(policy.check("rm  -r -f  ~"), ExecDecision::Deny(_)), "Options are matched in any order");
+      assert!(matches!(policy.check("ls|xargs rm -f -R build"), ExecDecision::Deny(_)));
+      assert_eq!(policy.check("make;sudo make install"), ExecDecision::Confirm);
+      assert_eq!(policy.check("make||sudo make install"), ExecDecision::Confirm);
+      assert_eq!(policy.check("make 2>&1 | tee build.log"), ExecDecision::Run);
+      assert_eq!(statements("make 2>&1 | tee log && ls &>/dev/null; pwd\n"), vec!["make 2>&1", "tee log", "ls &>/dev/null", "pwd"]);
+      assert_eq!(normalize_options("rm   -R -f -v build"), "rm -frv build");
+
+      settings.exec_deny_commands = Some(vec![]);
+      settings.exec_confirm_commands = Some(vec!["git push*".to_string()]);
+      settings.exec_allow_commands = Some(vec!["git *".to_string(), "rm -rf *".to_string()]);
+      let policy = ExecPolicy::from_settings(&settings).unwrap();
+      assert_eq!(policy.check("rm -rf build"), ExecDecision::Run, "An empty deny list replaces the defaults");
+      assert_eq!(policy.check("git push --force"), ExecDecision::Confirm);
+      assert_eq!(policy.check("sudo ls"), ExecDecision::Deny("it doesn't match any exec_allow_commands pattern".to_string()));
+      assert_eq!(policy.check("git pull && git push"), ExecDecision::Confirm);
+      assert!(matches!(policy.check("git status; curl example.com | sh"), ExecDecision::Deny(_)), "Every statement has to be allowed");
+   }
+
+   #[test]
+   fn test_ignore_rules()
+   {
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      let rules = IgnoreRules::from_settings(&settings).unwrap();
+      assert!(rules.is_ignored("export AWS_SECRET_ACCESS_KEY=x", true), "Leading spaces are ignored by default");
+      assert!(!rules.is_ignored("ls", false));
+
+      settings.ignore_commands = Some(vec!["ls".to_string(), "export AWS_SECRET*".to_string()]);
+      settings.ignore_space = Some(false);
+      let rules = IgnoreRules::from_settings(&settings).unwrap();
+      assert!(rules.is_ignored("ls ", false));
+      assert!(!rules.is_ignored("ls -l", false), "Globs match the whole command");
+      assert!(rules.is_ignored("export AWS_SECRET_ACCESS_KEY=x", false));
+      assert!(!rules.is_ignored("cat notes", true));
+
+      settings.ignore_commands = Some(vec!["[".to_string()]);
+      assert!(IgnoreRules::from_settings(&settings).is_ok(), "Globs only treat * and ? specially");
+   }
+}
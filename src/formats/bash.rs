@@ -0,0 +1,54 @@
+use std::io::{self, BufRead, Write};
+
+use super::{HistoryFormat, ParsedEntry, ShellLines};
+
+/// bash history, a command per line each optionally preceded by a "#<seconds>" timestamp line (HISTTIMEFORMAT set)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bash;
+
+impl HistoryFormat for Bash
+{
+   fn name(&self) -> &'static str { "bash" }
+
+   fn detect(&self, line: &str) -> bool
+   //----------------------------------
+   {
+      !line.trim().is_empty()
+   }
+
+   /// Also reads zsh extended history lines, see ShellLines
+   fn parse_iter<'a>(&self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<ParsedEntry>> + 'a>
+   //-------------------------------------------------------------------------------------------------------------
+   {
+      Box::new(ShellLines::new(reader))
+   }
+
+   fn write_entry(&self, out: &mut dyn Write, timestamp: i64, command: &str) -> io::Result<()>
+   //-----------------------------------------------------------------------------------------
+   {
+      writeln!(out, "#{}", timestamp)?;
+      writeln!(out, "{}", command)
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_bash_format()
+   {
+      let mut out = Vec::new();
+      Bash.write_entry(&mut out, 1768106544, "ls -l").unwrap();
+      Bash.write_entry(&mut out, 1768106600, "df -h").unwrap();
+      assert_eq!(String::from_utf8(out.clone()).unwrap(), "#1768106544\nls -l\n#1768106600\ndf -h\n");
+      let commands: Vec<(i64, String)> = Bash.parse_iter(Box::new(out.as_slice()))
+         .map(|e| e.map(|e| (e.timestamp, e.command)).unwrap())
+         .collect();
+      assert_eq!(commands, vec![(1768106544, "ls -l".to_string()), (1768106600, "df -h".to_string())]);
+
+      // A timestamp without a command after it is a comment
+      assert_eq!(Bash.parse_iter(Box::new("#1768106544\n#1768106600\n".as_bytes())).count(), 0);
+   }
+}
@@ -0,0 +1,137 @@
+use std::io::{self, BufRead, Lines, Write};
+use std::iter::Peekable;
+
+use super::{HistoryFormat, ParsedEntry, SkippedLines};
+
+/// fish history (~/.local/share/fish/fish_history), YAML-like "- cmd: <command>" lines each followed by
+/// "  when: <seconds>" and optionally a "  paths:" list
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fish;
+
+impl HistoryFormat for Fish
+{
+   fn name(&self) -> &'static str { "fish" }
+
+   fn detect(&self, line: &str) -> bool
+   //----------------------------------
+   {
+      line.starts_with("- cmd: ")
+   }
+
+   fn parse_iter<'a>(&self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<ParsedEntry>> + 'a>
+   //-------------------------------------------------------------------------------------------------------------
+   {
+      Box::new(FishLines { lines: reader.lines().peekable() })
+   }
+
+   fn write_entry(&self, out: &mut dyn Write, timestamp: i64, command: &str) -> io::Result<()>
+   //-----------------------------------------------------------------------------------------
+   {
+      writeln!(out, "- cmd: {}", escape_command(command))?;
+      writeln!(out, "  when: {}", timestamp)
+   }
+}
+
+struct FishLines<'a>
+{
+   lines: Peekable<Lines<Box<dyn BufRead + 'a>>>,
+}
+
+impl Iterator for FishLines<'_>
+{
+   type Item = io::Result<ParsedEntry>;
+
+   fn next(&mut self) -> Option<Self::Item>
+   //--------------------------------------
+   {
+      let mut skipped = SkippedLines::default();
+      loop
+      {
+         let line = match self.lines.next()?
+         {
+            | Ok(line) => line,
+            | Err(e) => return Some(Err(e)),
+         };
+         let Some(command) = line.strip_prefix("- cmd: ")
+         else
+         {
+            skipped.add(&line);
+            continue;
+         };
+         let mut entry = ParsedEntry { command: unescape_command(command), timestamp: 0, shell: "fish", lines: skipped.total() + 1,
+                                       skipped };
+         // The entry's other fields are indented, up to the next "- cmd:"
+         while let Some(Ok(field)) = self.lines.peek()
+            && field.starts_with(' ')
+         {
+            if let Some(when) = field.trim().strip_prefix("when: ")
+            {
+               entry.timestamp = when.trim().parse().unwrap_or(0);
+            }
+            self.lines.next();
+            entry.lines += 1;
+         }
+         if entry.command.trim().is_empty()
+         {
+            skipped.other += entry.lines - skipped.total();
+            continue;
+         }
+         return Some(Ok(entry));
+      }
+   }
+}
+
+/// Fish stores each history entry on a single line, so backslashes and newlines in the command have to be escaped
+pub fn escape_command(command: &str) -> String
+//--------------------------------------------
+{
+   command.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// The command from its escaped form in the history file
+pub fn unescape_command(command: &str) -> String
+//----------------------------------------------
+{
+   let mut unescaped = String::with_capacity(command.len());
+   let mut chars = command.chars();
+   while let Some(c) = chars.next()
+   {
+      if c != '\\'
+      {
+         unescaped.push(c);
+         continue;
+      }
+      match chars.next()
+      {
+         | Some('n') => unescaped.push('\n'),
+         | Some(c) => unescaped.push(c),
+         | None => unescaped.push('\\'),
+      }
+   }
+   unescaped
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_fish_format()
+   {
+      assert_eq!(escape_command("ls -l"), "ls -l");
+      assert_eq!(escape_command("echo a\\b"), "echo a\\\\b");
+      assert_eq!(escape_command("for i in 1 2\ndo echo $i\ndone"), "for i in 1 2\\ndo echo $i\\ndone");
+      assert_eq!(unescape_command("echo a\\\\b\\nls"), "echo a\\b\nls");
+
+      let mut out = Vec::new();
+      Fish.write_entry(&mut out, 1768106544, "echo a\\b\nls").unwrap();
+      out.extend_from_slice(b"- cmd: cd src\n  when: 1768106600\n  paths:\n    - src\n");
+      let entries: Vec<ParsedEntry> = Fish.parse_iter(Box::new(out.as_slice())).map(|e| e.unwrap()).collect();
+      assert_eq!(entries, vec![
+         ParsedEntry { command: "echo a\\b\nls".to_string(), timestamp: 1768106544, shell: "fish", lines: 2,
+                       skipped: SkippedLines::default() },
+         ParsedEntry { command: "cd src".to_string(), timestamp: 1768106600, shell: "fish", lines: 4, skipped: SkippedLines::default() },
+      ]);
+   }
+}
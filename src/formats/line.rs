@@ -0,0 +1,134 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+/// bash and zsh `history 1` with HISTTIMEFORMAT="%F %T " or `fc -t '%Y-%m-%d %T' -il -1`
+static HISTORY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*(\d+)\s+(\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2})(\s+)(.+)$").unwrap());
+/// fish's history --show-time='%F %T ' --max 1, which has no history number
+static FISH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)^(\d{4}-\d{2}-\d{2}\s+\d{2}:\d{2}:\d{2})(\s+)(.+)$").unwrap());
+/// fish's history --show-time --max 1, the time (%c) in a comment above the command
+static FISH_COMMENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)^#\s*([^\n]+?)\s*\n(.+)$").unwrap());
+const FISH_TIME_FORMATS: [&str; 3] = ["%a %b %e %H:%M:%S %Y", "%a %d %b %Y %I:%M:%S %p", "%a %d %b %Y %H:%M:%S"];
+
+/// A command parsed from the history text the shell hooks pass to dejacmd-log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryLine
+{
+   pub history_no: Option<i64>,
+   pub command_date: String,
+   /// Length of the whitespace between the time and the command, longer if the command was typed with a leading space
+   pub separator: usize,
+   pub command: String,
+   /// The shell when the text is in a format only it writes
+   pub shell: Option<&'static str>,
+   pub exit_status: Option<i64>,
+   pub duration_ms: Option<i64>,
+   pub cwd: Option<String>,
+}
+
+/// Parse the history text from bash or zsh (`history 1` with HISTTIMEFORMAT="%F %T " or `fc -t '%Y-%m-%d %T' -il -1`),
+/// fish (`history --show-time='%F %T ' --max 1` or `history --show-time --max 1`) or nushell (`history | last | to json`)
+pub fn parse_history(text: &str) -> Option<HistoryLine>
+//------------------------------------------------------
+{
+   // 66774  2026-01-13 17:45:51 ls -ltrh
+   if let Some(capture) = HISTORY.captures(text)
+   {
+      return Some(HistoryLine
+      {
+         history_no: capture[1].parse::<i64>().ok(), command_date: capture[2].to_string(), separator: capture[3].len(),
+         command: capture[4].to_string(), shell: None, exit_status: None, duration_ms: None, cwd: None,
+      });
+   }
+   if let Some(capture) = FISH.captures(text)
+   {
+      return Some(HistoryLine
+      {
+         history_no: None, command_date: capture[1].to_string(), separator: capture[2].len(),
+         command: capture[3].to_string(), shell: Some("fish"), exit_status: None, duration_ms: None, cwd: None,
+      });
+   }
+   if let Some(capture) = FISH_COMMENT.captures(text)
+   {
+      // %c depends on the locale, the time the command was logged is close enough if it isn't one of these
+      let time = FISH_TIME_FORMATS.iter()
+         .find_map(|format| chrono::NaiveDateTime::parse_and_remainder(&capture[1], format).ok().map(|(t, _)| t))
+         .unwrap_or_else(|| chrono::Local::now().naive_local());
+      return Some(HistoryLine
+      {
+         history_no: None, command_date: time.format("%Y-%m-%d %H:%M:%S").to_string(), separator: 1,
+         command: capture[2].trim_end_matches('\n').to_string(), shell: Some("fish"), exit_status: None,
+         duration_ms: None, cwd: None,
+      });
+   }
+   if text.trim_start().starts_with('{')
+   {
+      return parse_nushell_history(text);
+   }
+   None
+}
+
+/// A nushell history item as JSON. Only command is required, the SQLite history format adds the rest.
+pub fn parse_nushell_history(text: &str) -> Option<HistoryLine>
+//--------------------------------------------------------------
+{
+   let item: serde_json::Value = serde_json::from_str(text).ok()?;
+   let command = item.get("command")?.as_str()?.to_string();
+   let start = item.get("start_timestamp").and_then(|v| v.as_str());
+   let time = match start.map(chrono::DateTime::parse_from_rfc3339)
+   {
+      Some(Ok(t)) => t.with_timezone(&chrono::Local).naive_local(),
+      _ => start.and_then(|s| chrono::NaiveDateTime::parse_and_remainder(s, "%Y-%m-%d %H:%M:%S").ok().map(|(t, _)| t))
+                .unwrap_or_else(|| chrono::Local::now().naive_local()),
+   };
+   Some(HistoryLine
+   {
+      history_no: ["item_id", "index"].iter().find_map(|key| item.get(*key).and_then(|v| v.as_i64())),
+      command_date: time.format("%Y-%m-%d %H:%M:%S").to_string(),
+      separator: 1,
+      command,
+      shell: Some("nu"),
+      exit_status: item.get("exit_status").and_then(|v| v.as_i64()),
+      // nanoseconds
+      duration_ms: item.get("duration").and_then(|v| v.as_i64()).map(|ns| ns / 1_000_000),
+      cwd: item.get("cwd").and_then(|v| v.as_str()).filter(|cwd| !cwd.is_empty()).map(|cwd| cwd.to_string()),
+   })
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_parse_history()
+   {
+      let line = parse_history("66774  2026-01-13 17:45:51 ls -ltrh").unwrap();
+      assert_eq!((line.history_no, line.command_date.as_str(), line.command.as_str(), line.shell), (Some(66774), "2026-01-13 17:45:51", "ls -ltrh", None));
+
+      // fish
+      let line = parse_history("2026-01-13 17:45:51 for f in *.rs\n    wc -l $f\nend").unwrap();
+      assert_eq!((line.history_no, line.command_date.as_str(), line.separator), (None, "2026-01-13 17:45:51", 1));
+      assert_eq!((line.command.as_str(), line.shell), ("for f in *.rs\n    wc -l $f\nend", Some("fish")));
+      let line = parse_history("# Tue Jan 13 17:45:51 2026\ngit status\n").unwrap();
+      assert_eq!((line.command_date.as_str(), line.command.as_str(), line.shell), ("2026-01-13 17:45:51", "git status", Some("fish")));
+      let line = parse_history("# Tue 13 Jan 2026 05:45:51 PM SAST\ngit status").unwrap();
+      assert_eq!(line.command_date, "2026-01-13 17:45:51");
+
+      // nushell
+      let json = r#"{"item_id":42,"start_timestamp":"2026-01-13T17:45:51.123+00:00","command":"ls | where size > 1kb",
+                     "session_id":7,"hostname":"host","cwd":"/tmp","duration":1500000000,"exit_status":2,"more_info":null}"#;
+      let line = parse_history(json).unwrap();
+      let start = chrono::DateTime::parse_from_rfc3339("2026-01-13T17:45:51+00:00").unwrap().with_timezone(&chrono::Local);
+      assert_eq!(line, HistoryLine
+      {
+         history_no: Some(42), command_date: start.format("%Y-%m-%d %H:%M:%S").to_string(), separator: 1,
+         command: "ls | where size > 1kb".to_string(), shell: Some("nu"), exit_status: Some(2), duration_ms: Some(1500),
+         cwd: Some("/tmp".to_string()),
+      });
+      let line = parse_history(r#"{"command":"cd ~","index":3}"#).unwrap();
+      assert_eq!((line.history_no, line.command.as_str(), line.exit_status, line.cwd), (Some(3), "cd ~", None, None));
+      assert!(parse_history(r#"{"cmd":"ls"}"#).is_none());
+      assert!(parse_history("ls -ltrh").is_none());
+   }
+}
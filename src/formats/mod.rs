@@ -0,0 +1,204 @@
+use std::io::{self, BufRead, Lines, Write};
+use std::iter::Peekable;
+
+pub mod bash;
+pub mod fish;
+pub mod line;
+pub mod zsh;
+
+pub use bash::Bash;
+pub use fish::Fish;
+pub use zsh::Zsh;
+
+/// A command read from a shell history file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedEntry
+{
+   pub command: String,
+   /// Unix seconds, 0 if the file doesn't record when the command was run
+   pub timestamp: i64,
+   /// Shell whose format the entry was in, bash, zsh or fish
+   pub shell: &'static str,
+   /// Number of lines of the file read for the entry, including any blank or comment lines skipped before it
+   pub lines: u64,
+   /// The lines skipped before the entry
+   pub skipped: SkippedLines,
+}
+
+/// Lines of a history file that aren't part of an entry, by why they were skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkippedLines
+{
+   pub blank: u64,
+   /// # comments, including bash timestamps without a command after them
+   pub comments: u64,
+   /// Lines that aren't an entry or a comment e.g a zsh line without a command
+   pub other: u64,
+}
+
+impl SkippedLines
+{
+   /// Count a skipped line as blank, a comment or other from its text
+   pub fn add(&mut self, line: &str)
+   //--------------------------------
+   {
+      if line.trim().is_empty()
+      {
+         self.blank += 1;
+      }
+      else if line.trim_start().starts_with('#')
+      {
+         self.comments += 1;
+      }
+      else
+      {
+         self.other += 1;
+      }
+   }
+
+   pub fn merge(&mut self, skipped: &SkippedLines)
+   //---------------------------------------------
+   {
+      self.blank += skipped.blank;
+      self.comments += skipped.comments;
+      self.other += skipped.other;
+   }
+
+   pub fn total(&self) -> u64
+   //------------------------
+   {
+      self.blank + self.comments + self.other
+   }
+}
+
+/// A shell history file format, used to import history files and to export the history as one
+pub trait HistoryFormat: Sync
+{
+   /// The shell, also the name of the format for dejacmd export --format
+   fn name(&self) -> &'static str;
+
+   /// Whether a line from the start of a history file is in this format
+   fn detect(&self, line: &str) -> bool;
+
+   /// The entries in a history file. Lines that can't be read are returned as errors and reading carries on after them.
+   fn parse_iter<'a>(&self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<ParsedEntry>> + 'a>;
+
+   /// Write a command run at timestamp (Unix seconds) in the format
+   fn write_entry(&self, out: &mut dyn Write, timestamp: i64, command: &str) -> io::Result<()>;
+}
+
+/// The formats in the order they're detected, bash last as any line is a bash command
+pub static FORMATS: [&dyn HistoryFormat; 3] = [&Fish, &Zsh, &Bash];
+
+/// The format of a history file from its first non-blank line
+pub fn detect(line: &str) -> &'static dyn HistoryFormat
+//-----------------------------------------------------
+{
+   FORMATS.iter().copied().find(|format| format.detect(line)).unwrap_or(&Bash)
+}
+
+/// The format called name (case insensitive), None if there isn't one
+pub fn named(name: &str) -> Option<&'static dyn HistoryFormat>
+//------------------------------------------------------------
+{
+   FORMATS.iter().copied().find(|format| format.name().eq_ignore_ascii_case(name.trim()))
+}
+
+/// Reads bash and zsh history a line at a time with each line in either format, as a history file shared by both
+/// shells (or kept when switching from one to the other) mixes them. bash timestamps are "#<seconds>" comment lines
+/// before the command and zsh extended history lines are ": <seconds>:<duration>;<command>".
+pub(crate) struct ShellLines<'a>
+{
+   lines: Peekable<Lines<Box<dyn BufRead + 'a>>>,
+}
+
+impl<'a> ShellLines<'a>
+{
+   pub(crate) fn new(reader: Box<dyn BufRead + 'a>) -> Self
+   //-------------------------------------------------------
+   {
+      ShellLines { lines: reader.lines().peekable() }
+   }
+}
+
+impl Iterator for ShellLines<'_>
+{
+   type Item = io::Result<ParsedEntry>;
+
+   fn next(&mut self) -> Option<Self::Item>
+   //--------------------------------------
+   {
+      let mut skipped = SkippedLines::default();
+      loop
+      {
+         let line = match self.lines.next()?
+         {
+            | Ok(line) => line,
+            | Err(e) => return Some(Err(e)),
+         };
+         let lines = skipped.total() + 1;
+         if line.trim().is_empty()
+         {
+            skipped.add(&line);
+            continue;
+         }
+
+         if let Some((timestamp, command)) = zsh::parse_line(&line)
+         {
+            // A bash timestamp recorded as a zsh command e.g ": 1768106083:0;#1768105585" isn't a command
+            if command.is_empty() || (command.starts_with('#') && command.len() == 11)
+            {
+               skipped.other += 1;
+               continue;
+            }
+            let (command, continued) = zsh::join_continuations(command, &mut self.lines);
+            return Some(Ok(ParsedEntry { command, timestamp, shell: "zsh", lines: lines + continued, skipped }));
+         }
+
+         // bash timestamp comment "#<timestamp>" followed by the command
+         if line.trim().starts_with('#')
+            && let Ok(timestamp) = line.trim()[1..].trim().parse::<i64>()
+            && let Some(Ok(command)) = self.lines.peek()
+            && !command.is_empty() && !command.starts_with('#')
+         {
+            let command = command.clone();
+            self.lines.next();
+            return Some(Ok(ParsedEntry { command, timestamp, shell: "bash", lines: lines + 1, skipped }));
+         }
+
+         // Single line bash format (no timestamp)
+         if !line.starts_with('#')
+         {
+            return Some(Ok(ParsedEntry { command: line, timestamp: 0, shell: "bash", lines, skipped }));
+         }
+         skipped.add(&line);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_formats()
+   {
+      assert_eq!(detect("- cmd: ls -l").name(), "fish");
+      assert_eq!(detect(": 1768106544:0;ls -altrh").name(), "zsh");
+      assert_eq!(detect("#1768106544").name(), "bash");
+      assert_eq!(detect("ls -l").name(), "bash");
+      assert_eq!(named("ZSH").map(|f| f.name()), Some("zsh"));
+      assert!(named("csh").is_none());
+
+      let history = "ls -l\n\n#1768106000\ngit status\n: 1768106544:0;cat .zshrc\n# a comment\n: 1768106600:0;\ndf -h\n";
+      let entries: Vec<ParsedEntry> = Bash.parse_iter(Box::new(history.as_bytes())).map(|e| e.unwrap()).collect();
+      let skipped = |blank, comments, other| SkippedLines { blank, comments, other };
+      assert_eq!(entries, vec![
+         ParsedEntry { command: "ls -l".to_string(), timestamp: 0, shell: "bash", lines: 1, skipped: skipped(0, 0, 0) },
+         ParsedEntry { command: "git status".to_string(), timestamp: 1768106000, shell: "bash", lines: 3, skipped: skipped(1, 0, 0) },
+         ParsedEntry { command: "cat .zshrc".to_string(), timestamp: 1768106544, shell: "zsh", lines: 1, skipped: skipped(0, 0, 0) },
+         ParsedEntry { command: "df -h".to_string(), timestamp: 0, shell: "bash", lines: 3, skipped: skipped(0, 1, 1) },
+      ]);
+   }
+}
@@ -0,0 +1,95 @@
+use std::io::{self, BufRead, Lines, Write};
+use std::iter::Peekable;
+
+use super::{HistoryFormat, ParsedEntry, ShellLines};
+
+/// zsh extended history (setopt EXTENDED_HISTORY), ": <seconds>:<duration>;<command>" with the lines of a multi-line
+/// command ended by a backslash
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zsh;
+
+impl HistoryFormat for Zsh
+{
+   fn name(&self) -> &'static str { "zsh" }
+
+   fn detect(&self, line: &str) -> bool
+   //----------------------------------
+   {
+      parse_line(line).is_some()
+   }
+
+   /// Also reads bash lines, see ShellLines
+   fn parse_iter<'a>(&self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<ParsedEntry>> + 'a>
+   //-------------------------------------------------------------------------------------------------------------
+   {
+      Box::new(ShellLines::new(reader))
+   }
+
+   fn write_entry(&self, out: &mut dyn Write, timestamp: i64, command: &str) -> io::Result<()>
+   //-----------------------------------------------------------------------------------------
+   {
+      writeln!(out, ": {}:0;{}", timestamp, command.replace('\n', "\\\n"))
+   }
+}
+
+/// The timestamp and command of a zsh extended history line
+pub fn parse_line(line: &str) -> Option<(i64, &str)>
+//--------------------------------------------------
+{
+   let rest = line.strip_prefix(": ")?;
+   let (time, command) = rest.split_once(';')?;
+   let (timestamp, duration) = time.split_once(':')?;
+   if duration.contains(':')
+   {
+      return None;
+   }
+   Some((timestamp.parse::<i64>().ok()?, command))
+}
+
+/// The command with the lines that continue it (where it ends with a backslash) appended, and the number of lines
+/// appended
+pub(crate) fn join_continuations(command: &str, lines: &mut Peekable<Lines<Box<dyn BufRead + '_>>>) -> (String, u64)
+//--------------------------------------------------------------------------------------------------------------
+{
+   let mut command = command.to_string();
+   let mut count = 0;
+   while command.ends_with('\\') && let Some(Ok(next)) = lines.peek()
+   {
+      command.pop();
+      command.push('\n');
+      command.push_str(next);
+      lines.next();
+      count += 1;
+   }
+   (command, count)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_parse_line()
+   {
+      assert_eq!(parse_line(": 1768106544:0;ls -altrh"), Some((1768106544, "ls -altrh")));
+      assert_eq!(parse_line(": 1768106544:0;echo \"test; with semicolon\""), Some((1768106544, "echo \"test; with semicolon\"")));
+      assert_eq!(parse_line(": 1768106544:0;"), Some((1768106544, "")));
+      assert!(parse_line("1768106544:0;ls").is_none(), "Missing leading ': '");
+      assert!(parse_line(": 1768106544:0 ls").is_none(), "Missing semicolon");
+      assert!(parse_line(": abc:0;ls").is_none(), "Invalid timestamp");
+   }
+
+   #[test]
+   fn test_zsh_format()
+   {
+      let mut out = Vec::new();
+      Zsh.write_entry(&mut out, 1768106544, "ls -l").unwrap();
+      Zsh.write_entry(&mut out, 1768106600, "for i in 1 2\ndo echo $i\ndone").unwrap();
+      assert_eq!(String::from_utf8(out.clone()).unwrap(), ": 1768106544:0;ls -l\n: 1768106600:0;for i in 1 2\\\ndo echo $i\\\ndone\n");
+      let entries: Vec<ParsedEntry> = Zsh.parse_iter(Box::new(out.as_slice())).map(|e| e.unwrap()).collect();
+      assert_eq!(entries.len(), 2);
+      assert_eq!(entries[1].command, "for i in 1 2\ndo echo $i\ndone");
+      assert_eq!((entries[1].timestamp, entries[1].shell, entries[1].lines), (1768106600, "zsh", 3));
+   }
+}
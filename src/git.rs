@@ -0,0 +1,100 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The git repository a directory is in, found by walking up from the directory to one containing .git. Reading
+/// the files directly rather than running git keeps dejacmd-log fast and works where git isn't installed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitContext
+{
+   /// Top level directory of the working tree
+   pub repo: String,
+   /// Current branch, or the abbreviated commit for a detached HEAD. None if HEAD can't be read.
+   pub branch: Option<String>,
+}
+
+impl GitContext
+{
+   pub fn from_dir(dir: &Path) -> Option<Self>
+   //------------------------------------------
+   {
+      let mut current = Some(dir);
+      while let Some(d) = current
+      {
+         let dot_git = d.join(".git");
+         if dot_git.exists()
+         {
+            let branch = git_dir(&dot_git).and_then(|g| head_branch(&g));
+            return Some(GitContext { repo: d.display().to_string(), branch });
+         }
+         current = d.parent();
+      }
+      None
+   }
+}
+
+/// The git directory for .git, which is a file holding "gitdir: <path>" for worktrees and submodules
+fn git_dir(dot_git: &Path) -> Option<PathBuf>
+//-------------------------------------------
+{
+   if dot_git.is_dir()
+   {
+      return Some(dot_git.to_path_buf());
+   }
+   let content = fs::read_to_string(dot_git).ok()?;
+   let path = PathBuf::from(content.trim().strip_prefix("gitdir:")?.trim());
+   if path.is_absolute() { Some(path) } else { dot_git.parent().map(|p| p.join(path)) }
+}
+
+fn head_branch(git_dir: &Path) -> Option<String>
+//----------------------------------------------
+{
+   let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+   let head = head.trim();
+   match head.strip_prefix("ref:")
+   {
+      | Some(reference) =>
+      {
+         let reference = reference.trim();
+         Some(reference.strip_prefix("refs/heads/").unwrap_or(reference).to_string())
+      },
+      | None if head.is_empty() => None,
+      | None => Some(head.chars().take(12).collect()),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_git_context()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let root = std::env::temp_dir().join(format!("dejacmd_git_test_{}", nanos));
+      let sub = root.join("src").join("bin");
+      fs::create_dir_all(&sub).unwrap();
+      // The temporary directory could itself be in a repository
+      assert!(GitContext::from_dir(&sub).is_none_or(|g| !g.repo.starts_with(&root.display().to_string())));
+
+      fs::create_dir_all(root.join(".git")).unwrap();
+      fs::write(root.join(".git").join("HEAD"), "ref: refs/heads/feature/migrations\n").unwrap();
+      let context = GitContext::from_dir(&sub).unwrap();
+      assert_eq!(context, GitContext { repo: root.display().to_string(), branch: Some("feature/migrations".to_string()) });
+
+      fs::write(root.join(".git").join("HEAD"), "4b825dc642cb6eb9a060e54bf8d69288fbee4904\n").unwrap();
+      assert_eq!(GitContext::from_dir(&root).unwrap().branch.as_deref(), Some("4b825dc642cb"), "Detached HEAD");
+
+      // A worktree's .git is a file pointing at the git directory
+      let worktree = root.join("worktree");
+      let worktree_git = root.join(".git").join("worktrees").join("wt");
+      fs::create_dir_all(&worktree).unwrap();
+      fs::create_dir_all(&worktree_git).unwrap();
+      fs::write(worktree.join(".git"), format!("gitdir: {}\n", worktree_git.display())).unwrap();
+      fs::write(worktree_git.join("HEAD"), "ref: refs/heads/hotfix\n").unwrap();
+      let context = GitContext::from_dir(&worktree).unwrap();
+      assert_eq!(context, GitContext { repo: worktree.display().to_string(), branch: Some("hotfix".to_string()) });
+
+      let _ = fs::remove_dir_all(&root);
+   }
+}
@@ -0,0 +1,330 @@
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::HistoryEntry;
+use crate::settings::Settings;
+
+/// How long a pre_insert_hooks command may take when hook_timeout_ms isn't set
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Environment variable telling a hook command which hook it is run as (pre_insert or post_insert)
+pub const HOOK_ENV: &str = "DEJACMD_HOOK";
+
+/// What a pre-insert hook decided about an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction
+{
+   Keep,
+   Skip,
+}
+
+/// A callback registered with register_hook by a program using dejacmd as a library, run for every entry dejacmd-log
+/// or an import writes in that program before the pre_insert_hooks commands
+pub trait InsertHook: Send + Sync
+{
+   /// Called before the entry is written, can change it (e.g. redact a secret) or drop it
+   fn pre_insert(&self, _entry: &mut HistoryEntry) -> HookAction { HookAction::Keep }
+
+   /// Called with entries once they have been written
+   fn post_insert(&self, _entries: &[HistoryEntry]) {}
+}
+
+static REGISTERED: RwLock<Vec<Arc<dyn InsertHook>>> = RwLock::new(Vec::new());
+
+/// Add a callback run by every Hooks created afterwards
+pub fn register_hook(hook: Arc<dyn InsertHook>)
+//---------------------------------------------
+{
+   REGISTERED.write().unwrap_or_else(|e| e.into_inner()).push(hook);
+}
+
+/// The registered callbacks and the pre_insert_hooks and post_insert_hooks commands from the settings. The commands are
+/// run by the shell (sh -c, or cmd /C on Windows) with DEJACMD_HOOK set to the hook:
+///
+/// * A pre_insert_hooks command is given the entry as JSON on stdin. Exiting non-zero drops the entry, and a JSON entry
+///   printed on stdout replaces it (printing nothing keeps it as it is). A command that can't be run, prints something
+///   that isn't an entry or takes longer than hook_timeout_ms is an error and the entry is dropped rather than written
+///   unredacted.
+/// * A post_insert_hooks command is given the entries written as JSON lines on stdin. Only writing them is waited for,
+///   up to hook_timeout_ms, not the command.
+pub struct Hooks
+{
+   callbacks: Vec<Arc<dyn InsertHook>>,
+   pre_commands: Vec<String>,
+   post_commands: Vec<String>,
+   timeout: Duration,
+}
+
+impl Hooks
+{
+   pub fn from_settings(settings: &Settings) -> Self
+   //-----------------------------------------------
+   {
+      let commands = |commands: &Option<Vec<String>>| commands.iter().flatten()
+         .map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+      Hooks
+      {
+         callbacks: REGISTERED.read().unwrap_or_else(|e| e.into_inner()).clone(),
+         pre_commands: commands(&settings.pre_insert_hooks),
+         post_commands: commands(&settings.post_insert_hooks),
+         timeout: settings.hook_timeout_ms.filter(|ms| *ms > 0).map(Duration::from_millis).unwrap_or(DEFAULT_HOOK_TIMEOUT),
+      }
+   }
+
+   pub fn is_empty(&self) -> bool
+   //----------------------------
+   {
+      self.callbacks.is_empty() && self.pre_commands.is_empty() && self.post_commands.is_empty()
+   }
+
+   /// Run the pre-insert hooks on entry, returning the entry to write or None if a hook dropped it
+   pub fn pre_insert(&self, mut entry: HistoryEntry) -> Result<Option<HistoryEntry>, String>
+   //--------------------------------------------------------------------------------------
+   {
+      for callback in &self.callbacks
+      {
+         if callback.pre_insert(&mut entry) == HookAction::Skip
+         {
+            return Ok(None);
+         }
+      }
+      for command in &self.pre_commands
+      {
+         match self.run_pre_command(command, &entry)?
+         {
+            | Some(changed) => entry = changed,
+            | None => return Ok(None),
+         }
+      }
+      Ok(Some(entry))
+   }
+
+   /// The captured output summary of entry after the pre-insert hooks, which are run on a copy of the entry with the
+   /// summary as its command so a hook redacting secrets from commands redacts them from output too. None if a hook
+   /// dropped it or failed, then the output isn't kept.
+   pub fn pre_insert_output(&self, entry: &HistoryEntry, output: String) -> Option<String>
+   //-------------------------------------------------------------------------------------
+   {
+      if self.callbacks.is_empty() && self.pre_commands.is_empty()
+      {
+         return Some(output);
+      }
+      let entry = HistoryEntry { command: Some(output), ..entry.clone() };
+      self.pre_insert(entry).ok().flatten().and_then(|entry| entry.command)
+   }
+
+   /// Run the pre-insert hooks on each entry, returning those to write and an error for each entry a hook failed on
+   pub fn pre_insert_all(&self, entries: Vec<HistoryEntry>) -> (Vec<HistoryEntry>, Vec<String>)
+   //-------------------------------------------------------------------------------------------
+   {
+      if self.callbacks.is_empty() && self.pre_commands.is_empty()
+      {
+         return (entries, Vec::new());
+      }
+      let mut errors = Vec::new();
+      let kept = entries.into_iter().filter_map(|entry|
+      {
+         let id = entry.id.clone();
+         self.pre_insert(entry).unwrap_or_else(|e|
+         {
+            errors.push(format!("Pre-insert hook failed for {}: {}", id, e));
+            None
+         })
+      }).collect();
+      (kept, errors)
+   }
+
+   /// Run the post-insert hooks on entries that have been written, returning an error for each command that couldn't
+   /// be started
+   pub fn post_insert(&self, entries: &[HistoryEntry]) -> Vec<String>
+   //-----------------------------------------------------------------
+   {
+      if entries.is_empty()
+      {
+         return Vec::new();
+      }
+      for callback in &self.callbacks
+      {
+         callback.post_insert(entries);
+      }
+      let mut errors = Vec::new();
+      if self.post_commands.is_empty()
+      {
+         return errors;
+      }
+      let input: String = entries.iter().filter_map(|e| serde_json::to_string(e).ok()).map(|json| json + "\n").collect();
+      for command in &self.post_commands
+      {
+         match spawn(command, "post_insert")
+         {
+            | Ok(mut child) =>
+            {
+               // The input is written before returning so it isn't lost if the process exits straight afterwards,
+               // from a thread so a command that doesn't read it only holds up logging for the hook timeout
+               let input = input.clone();
+               let mut stdin = child.stdin.take();
+               let (done, written) = std::sync::mpsc::channel();
+               std::thread::spawn(move ||
+               {
+                  let result = stdin.as_mut().map_or(Ok(()), |stdin| stdin.write_all(input.as_bytes()));
+                  drop(stdin);
+                  let _ = done.send(result);
+               });
+               match written.recv_timeout(self.timeout)
+               {
+                  // A command that exits without reading its input doesn't need it
+                  | Ok(Ok(_)) => {},
+                  | Ok(Err(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {},
+                  | Ok(Err(e)) => errors.push(format!("Error writing to post-insert hook {}: {}", command, e)),
+                  | Err(_) =>
+                  {
+                     let _ = child.kill();
+                     errors.push(format!("Post-insert hook {} didn't read its input within {}ms", command, self.timeout.as_millis()));
+                  },
+               }
+               // The command isn't waited for, only reaped when it exits
+               std::thread::spawn(move || child.wait());
+            },
+            | Err(e) => errors.push(format!("Error running post-insert hook {}: {}", command, e)),
+         }
+      }
+      errors
+   }
+
+   fn run_pre_command(&self, command: &str, entry: &HistoryEntry) -> Result<Option<HistoryEntry>, String>
+   //-----------------------------------------------------------------------------------------------------
+   {
+      let input = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+      let mut child = spawn(command, "pre_insert").map_err(|e| format!("Error running {}: {}", command, e))?;
+      let mut stdin = child.stdin.take();
+      std::thread::spawn(move || stdin.as_mut().map(|stdin| stdin.write_all(input.as_bytes())));
+      let mut stdout = child.stdout.take();
+      let reader = std::thread::spawn(move ||
+      {
+         let mut output = String::new();
+         stdout.as_mut().map(|stdout| stdout.read_to_string(&mut output));
+         output
+      });
+      let started = Instant::now();
+      let status = loop
+      {
+         match child.try_wait().map_err(|e| format!("Error waiting for {}: {}", command, e))?
+         {
+            | Some(status) => break status,
+            | None if started.elapsed() >= self.timeout =>
+            {
+               let _ = child.kill();
+               let _ = child.wait();
+               return Err(format!("{} took longer than {:?}", command, self.timeout));
+            },
+            | None => std::thread::sleep(Duration::from_millis(5)),
+         }
+      };
+      if !status.success()
+      {
+         debug!("Pre-insert hook {} dropped {} ({})", command, entry.id, status);
+         return Ok(None);
+      }
+      let output = reader.join().unwrap_or_default();
+      if output.trim().is_empty()
+      {
+         return Ok(Some(entry.clone()));
+      }
+      serde_json::from_str::<HistoryEntry>(output.trim()).map(Some)
+         .map_err(|e| format!("{} printed something other than an entry: {}", command, e))
+   }
+}
+
+/// Start command with the shell, with its output piped for the pre-insert hook
+fn spawn(command: &str, hook: &str) -> std::io::Result<Child>
+//-----------------------------------------------------------
+{
+   let mut shell = if cfg!(windows)
+   {
+      let mut shell = Command::new("cmd");
+      shell.arg("/C");
+      shell
+   }
+   else
+   {
+      let mut shell = Command::new("sh");
+      shell.arg("-c");
+      shell
+   };
+   let stdout = if hook == "pre_insert" { Stdio::piped() } else { Stdio::null() };
+   shell.arg(command).env(HOOK_ENV, hook).stdin(Stdio::piped()).stdout(stdout).stderr(Stdio::null()).spawn()
+}
+
+#[cfg(all(test, unix))]
+mod tests
+{
+   use super::*;
+
+   fn entry(command: &str) -> HistoryEntry
+   {
+      crate::store::imported_entry(command, "/", 1767000000, "bash", 0, None).unwrap()
+   }
+
+   struct Redact;
+
+   impl InsertHook for Redact
+   {
+      fn pre_insert(&self, entry: &mut HistoryEntry) -> HookAction
+      {
+         entry.command = entry.command.as_ref().map(|c| c.replace("hunter2", "****"));
+         if entry.command.as_deref() == Some("exit") { HookAction::Skip } else { HookAction::Keep }
+      }
+   }
+
+   #[test]
+   fn test_hooks()
+   {
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      assert!(Hooks::from_settings(&settings).pre_commands.is_empty());
+      settings.pre_insert_hooks = Some(vec![
+         "sed 's/secret/[redacted]/'".to_string(),
+         r#"if grep -q '"command":"rm '; then exit 1; fi"#.to_string(),
+      ]);
+      let mut hooks = Hooks::from_settings(&settings);
+      hooks.callbacks = vec![Arc::new(Redact)];
+
+      let kept = hooks.pre_insert(entry("login --password hunter2 --token secret")).unwrap().unwrap();
+      assert_eq!(kept.command.as_deref(), Some("login --password **** --token [redacted]"));
+      assert_eq!(hooks.pre_insert(entry("rm -rf build")).unwrap(), None, "A hook exiting non-zero drops the entry");
+      assert_eq!(hooks.pre_insert(entry("exit")).unwrap(), None);
+      assert_eq!(hooks.pre_insert_output(&entry("login"), "token: secret\npassword: hunter2".to_string()).as_deref(),
+                 Some("token: [redacted]\npassword: ****"), "Output is redacted like commands");
+      assert_eq!(hooks.pre_insert_output(&entry("ls"), "rm 1.txt".to_string()), None);
+
+      hooks.pre_commands = vec!["echo not json".to_string()];
+      let (kept, errors) = hooks.pre_insert_all(vec![entry("ls"), entry("pwd")]);
+      assert!(kept.is_empty(), "Entries aren't written when a hook fails");
+      assert_eq!(errors.len(), 2);
+      hooks.pre_commands = vec!["sleep 5".to_string()];
+      hooks.timeout = Duration::from_millis(50);
+      let started = Instant::now();
+      assert!(hooks.pre_insert(entry("ls")).is_err());
+      assert!(started.elapsed() < Duration::from_secs(2));
+
+      let path = std::env::temp_dir().join(format!("dejacmd_hook_test_{}", std::process::id()));
+      hooks.post_commands = vec![format!("cat > {}", path.display())];
+      assert!(hooks.post_insert(&[entry("ls"), entry("pwd")]).is_empty());
+      let started = Instant::now();
+      while std::fs::read_to_string(&path).map(|s| s.lines().count()).unwrap_or(0) < 2 && started.elapsed() < Duration::from_secs(5)
+      {
+         std::thread::sleep(Duration::from_millis(10));
+      }
+      assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+      let _ = std::fs::remove_file(&path);
+      hooks.post_commands = vec!["true".to_string()];
+      assert!(hooks.post_insert(&[entry("ls")]).is_empty(), "The command needn't read its input");
+      hooks.post_commands = vec!["sleep 5".to_string()];
+      let started = Instant::now();
+      assert_eq!(hooks.post_insert(&[entry(&"x".repeat(256 * 1024))]).len(), 1, "Input that is never read is an error");
+      assert!(started.elapsed() < Duration::from_secs(2));
+   }
+}
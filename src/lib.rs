@@ -1,14 +1,47 @@
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex, Once};
 
-use colored::Colorize;
-use sqlx::{ Any, AnyPool, Pool };
+use secrecy::{ExposeSecret, SecretString};
+use secrecy::zeroize::Zeroizing;
+use sqlx::{ Any, Pool, Row };
+use sqlx::any::AnyPoolOptions;
 use tokio::time::{timeout, Duration};
+use tracing::debug;
 
+use crate::error::DejacmdError;
 use crate::settings::Settings;
 
+pub mod anonymize;
+pub mod blocking;
 pub mod crypt;
+pub mod ddl;
+pub mod digest;
+pub mod display;
+pub mod doctor;
+pub mod error;
+pub mod exclusions;
+pub mod formats;
+pub mod git;
+pub mod hooks;
+pub mod libsql;
+pub mod logd;
+pub mod logging;
+pub mod metrics;
+pub mod normalize;
+pub mod output;
+pub mod pause;
+pub mod project;
+pub mod migrations;
+pub mod picker;
+pub mod remote;
 pub mod settings;
+pub mod shell;
+pub mod snapshot;
+pub mod spool;
+pub mod store;
 
+/// Base history table for SQLite and Postgres, see ddl::create_table_sql for other databases. Later columns are added by
+/// the assets/NNNNNNN.sql update files (see migrations::apply_updates).
 pub const CREATE_TABLE_SQL: &str = 
 r#" CREATE TABLE IF NOT EXISTS history 
 (
@@ -28,29 +61,671 @@ pub const CREATE_INDEX_SQL: &str =
 r#" CREATE INDEX IF NOT EXISTS idx_history_timestamp ON history (command_timestamp);
 "#;
 
+/// Named shared-cache in-memory SQLite database used by --ephemeral so every pool in the process sees the same data.
+/// It only exists while at least one connection to it is open.
+pub const EPHEMERAL_DATABASE_URL: &str = "sqlite:file:dejacmd-ephemeral?mode=memory&cache=shared";
+
+/// Scheme of SQLite databases encrypted with SQLCipher (the sqlcipher feature), e.g sqlite+sqlcipher:///path/history.db.
+/// The key is the database password if one is set, otherwise a key derived from the dejacmd encryption key (see
+/// Settings::get_credentials).
+pub const SQLCIPHER_SCHEME: &str = "sqlite+sqlcipher";
+
+/// Setting this environment variable to 1 (or true/yes) is equivalent to --ephemeral
+pub const EPHEMERAL_ENV: &str = "DEJACMD_EPHEMERAL";
+
+/// Session id exported by a shell hook, overriding the one derived from the shell's process
+pub const SESSION_ENV: &str = "DEJACMD_SESSION";
+
+/// The session:window.pane of the tmux pane a shell is in, exported by the shell hooks when the shell starts so
+/// dejacmd-log doesn't ask the tmux server for it on every command
+pub const TMUX_LOCATION_ENV: &str = "DEJACMD_TMUX_LOCATION";
+
+/// Every history column in INSERT_HISTORY_SQL order, for copying complete rows
+pub const HISTORY_COLUMNS: &str =
+   "id, command_timestamp, cwd, shell, user_id, user_name, ip, os, exit_status, command, history_no, hostname, session_id, git_repo, git_branch,
+   duration_ms, ssh_origin, tty, project, multiplexer";
+
 pub const INSERT_HISTORY_SQL: &str = 
-r#"INSERT INTO history (id, command_timestamp, cwd, shell, user_id, user_name, ip, os, exit_status, command) 
-VALUES ( ?, ?, ?, ?, ?, ?, ?, ?, ?, ? )"#;
+r#"INSERT INTO history (id, command_timestamp, cwd, shell, user_id, user_name, ip, os, exit_status, command, history_no, hostname, session_id,
+   git_repo, git_branch, duration_ms, ssh_origin, tty, project, multiplexer) 
+VALUES ( ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ? )"#;
+
+/// INSERT_HISTORY_SQL (with placeholders fixed for the scheme) that skips a row whose id is already present instead
+/// of failing with a primary key violation, for re-running syncs, imports and spool flushes.
+pub fn upsert_history_sql(scheme: &str) -> String
+//-----------------------------------------------
+{
+   let sql = match ddl::dialect(scheme)
+   {
+      // INSERT IGNORE would turn every error (truncation, NULLs, bad values) into a warning rather than only duplicate
+      // ids, and ON DUPLICATE KEY UPDATE id = id counts skipped rows as affected as sqlx connects with CLIENT_FOUND_ROWS
+      | "mysql" =>
+      {
+         let values = HISTORY_COLUMNS.split(',').map(|column| format!("? AS {}", column.trim())).collect::<Vec<_>>().join(", ");
+         format!("INSERT INTO history ({}) SELECT {} FROM (SELECT {}) AS new_row \
+                  WHERE NOT EXISTS (SELECT 1 FROM history WHERE history.id = new_row.id)", HISTORY_COLUMNS, HISTORY_COLUMNS, values)
+      },
+      | "mssql" => INSERT_HISTORY_SQL.to_string(),
+      | _ => format!("{} ON CONFLICT (id) DO NOTHING", INSERT_HISTORY_SQL),
+   };
+   fix_placeholders(&sql, scheme)
+}
+
+/// Most rows insert_history_batch puts in one INSERT
+pub const MAX_BATCH_ROWS: usize = 1000;
+
+/// Rows per INSERT for insert_history_batch, within the database's limit on the number of bound parameters in a
+/// statement (32766 for SQLite, 65535 for PostgreSQL and MySQL)
+fn batch_rows(scheme: &str) -> usize
+//----------------------------------
+{
+   let max_parameters = if scheme.starts_with("sqlite") { 32766 } else { 65535 };
+   (max_parameters / HISTORY_COLUMNS.split(',').count()).min(MAX_BATCH_ROWS)
+}
+
+/// Insert entries with multi-row INSERTs in one transaction, skipping those whose ids are already present as for
+/// upsert_history_sql. Much faster than a row at a time for a database across a network as there is a round trip
+/// per statement rather than per row. Returns the number of rows inserted.
+pub async fn insert_history_batch(pool: &Pool<Any>, scheme: &str, entries: &[HistoryEntry]) -> Result<u64, DejacmdError>
+//-------------------------------------------------------------------------------------------------------------------
+{
+   insert_new_history(pool, scheme, entries).await.map(|ids| ids.len() as u64)
+}
+
+/// insert_history_batch returning the ids of the entries that were inserted rather than already present, e.g for the
+/// post-insert hooks. SQLite, PostgreSQL and MariaDB 10.5+ return them with INSERT ... RETURNING id, skipping ids
+/// already present with ON CONFLICT DO NOTHING or (MariaDB) NOT EXISTS. MySQL has no RETURNING so the ids already
+/// present are selected first in the transaction, and a chunk an id was inserted into since by another connection is
+/// retried a row at a time with upsert_history_sql.
+pub async fn insert_new_history(pool: &Pool<Any>, scheme: &str, entries: &[HistoryEntry]) -> Result<Vec<String>, DejacmdError>
+//-------------------------------------------------------------------------------------------------------------------------
+{
+   let mut inserted = Vec::new();
+   if entries.is_empty()
+   {
+      return Ok(inserted);
+   }
+   let dialect = ddl::dialect(scheme);
+   let is_returning = dialect != "mysql" || ddl::mysql_server(pool).await.is_ok_and(|server| server.has_returning());
+   let row = format!("({})", vec!["?"; HISTORY_COLUMNS.split(',').count()].join(", "));
+   let mut tx = pool.begin().await.map_err(|e| DejacmdError::Query(format!("Error starting transaction: {}", e)))?;
+   for chunk in entries.chunks(batch_rows(scheme))
+   {
+      let error = |e: sqlx::Error| DejacmdError::Query(format!("Error inserting {} history entries: {}", chunk.len(), e));
+      // An id repeated in the batch would fail the INSERT
+      let mut ids = HashSet::new();
+      let mut chunk: Vec<&HistoryEntry> = chunk.iter().filter(|entry| ids.insert(entry.id.as_str())).collect();
+      if dialect == "mysql" && !is_returning
+      {
+         let sql = format!("SELECT id FROM history WHERE id IN ({})", vec!["?"; chunk.len()].join(", "));
+         let present: HashSet<String> = chunk.iter().fold(sqlx::query(&sql), |query, entry| query.bind(&entry.id))
+            .fetch_all(&mut *tx).await.map_err(error)?
+            .iter().map(|row| row.get("id")).collect();
+         chunk.retain(|entry| !present.contains(&entry.id));
+         if chunk.is_empty()
+         {
+            continue;
+         }
+      }
+      let sql = format!("INSERT INTO history ({}) VALUES {}", HISTORY_COLUMNS, vec![row.as_str(); chunk.len()].join(", "));
+      let query = |sql| chunk.iter().fold(sqlx::query(sql), |query, entry| entry.bind(query));
+      let result: Result<Vec<String>, sqlx::Error> = match dialect
+      {
+         | "mysql" if is_returning =>
+         {
+            // As upsert_history_sql, with the rows as a derived table of SELECTs
+            let values = HISTORY_COLUMNS.split(',').map(|column| format!("? AS {}", column.trim())).collect::<Vec<_>>().join(", ");
+            let rows = vec![format!("SELECT {}", values); chunk.len()].join(" UNION ALL ");
+            let sql = fix_placeholders(&format!("INSERT INTO history ({}) SELECT {} FROM ({}) AS new_rows \
+                                                 WHERE NOT EXISTS (SELECT 1 FROM history WHERE history.id = new_rows.id) RETURNING id",
+                                                HISTORY_COLUMNS, HISTORY_COLUMNS, rows), scheme);
+            query(&sql).fetch_all(&mut *tx).await.map(|rows| rows.iter().map(|row| row.get::<String, _>("id")).collect())
+         },
+         | "mysql" | "mssql" =>
+         {
+            let sql = fix_placeholders(&sql, scheme);
+            query(&sql).execute(&mut *tx).await.map(|_| chunk.iter().map(|entry| entry.id.clone()).collect())
+         },
+         | _ =>
+         {
+            let sql = fix_placeholders(&format!("{} ON CONFLICT (id) DO NOTHING RETURNING id", sql), scheme);
+            query(&sql).fetch_all(&mut *tx).await.map(|rows| rows.iter().map(|row| row.get::<String, _>("id")).collect())
+         },
+      };
+      match result
+      {
+         | Ok(ids) => inserted.extend(ids),
+         // Only the failed statement is rolled back, so the rest of the transaction stands
+         | Err(e) if dialect == "mysql" && e.as_database_error().is_some_and(|e| e.is_unique_violation()) =>
+         {
+            for entry in chunk
+            {
+               if entry.upsert(&mut *tx, scheme).await.map_err(error)?
+               {
+                  inserted.push(entry.id.clone());
+               }
+            }
+         },
+         | Err(e) => return Err(error(e)),
+      }
+   }
+   tx.commit().await.map_err(|e| DejacmdError::Query(format!("Error committing history entries: {}", e)))?;
+   Ok(inserted)
+}
+
+
+/// A complete history row, as written by the json/jsonl export formats and the central database spool
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HistoryEntry
+{
+   pub id: String,
+   pub command_timestamp: String,
+   pub cwd: Option<String>,
+   pub shell: Option<String>,
+   pub user_id: Option<i64>,
+   pub user_name: Option<String>,
+   pub ip: Option<String>,
+   pub os: Option<String>,
+   pub exit_status: Option<i64>,
+   pub command: Option<String>,
+   pub history_no: Option<i64>,
+   #[serde(default)]
+   pub hostname: Option<String>,
+   #[serde(default)]
+   pub session_id: Option<String>,
+   #[serde(default)]
+   pub git_repo: Option<String>,
+   #[serde(default)]
+   pub git_branch: Option<String>,
+   #[serde(default)]
+   pub duration_ms: Option<i64>,
+   #[serde(default)]
+   pub ssh_origin: Option<String>,
+   #[serde(default)]
+   pub tty: Option<String>,
+   /// Tag from the .dejacmd.toml of the project the command was run in (see project::ProjectConfig)
+   #[serde(default)]
+   pub project: Option<String>,
+   /// The tmux pane or screen window the command was run in (see get_multiplexer)
+   #[serde(default)]
+   pub multiplexer: Option<String>,
+   /// Number of times the command was run, only set by frequency ordered searches (see store::SearchSort)
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   pub runs: Option<i64>,
+}
+
+impl HistoryEntry
+{
+   /// An entry with only the id, timestamp and command set and an exit status of 0, for tests and programs embedding
+   /// dejacmd. The other columns can be set with struct update syntax.
+   pub fn new_for_test(id: &str, command_timestamp: &str, command: &str) -> Self
+   //---------------------------------------------------------------------------
+   {
+      HistoryEntry
+      {
+         id: id.to_string(), command_timestamp: command_timestamp.to_string(), exit_status: Some(0),
+         command: Some(command.to_string()), ..Default::default()
+      }
+   }
+
+   /// From a row selected with HISTORY_COLUMNS
+   pub fn from_row(row: &sqlx::any::AnyRow) -> Self
+   //-----------------------------------------------
+   {
+      HistoryEntry
+      {
+         id: row.try_get("id").unwrap_or_default(),
+         command_timestamp: row.try_get("command_timestamp").unwrap_or_default(),
+         cwd: row.try_get("cwd").unwrap_or_default(),
+         shell: row.try_get("shell").unwrap_or_default(),
+         user_id: row.try_get("user_id").unwrap_or_default(),
+         user_name: row.try_get("user_name").unwrap_or_default(),
+         ip: row.try_get("ip").unwrap_or_default(),
+         os: row.try_get("os").unwrap_or_default(),
+         exit_status: row.try_get("exit_status").unwrap_or_default(),
+         command: row.try_get("command").unwrap_or_default(),
+         history_no: row.try_get("history_no").unwrap_or_default(),
+         hostname: row.try_get("hostname").unwrap_or_default(),
+         session_id: row.try_get("session_id").unwrap_or_default(),
+         git_repo: row.try_get("git_repo").unwrap_or_default(),
+         git_branch: row.try_get("git_branch").unwrap_or_default(),
+         duration_ms: row.try_get("duration_ms").unwrap_or_default(),
+         ssh_origin: row.try_get("ssh_origin").unwrap_or_default(),
+         tty: row.try_get("tty").unwrap_or_default(),
+         project: row.try_get("project").unwrap_or_default(),
+         multiplexer: row.try_get("multiplexer").unwrap_or_default(),
+         runs: row.try_get("runs").unwrap_or_default(),
+      }
+   }
+
+   /// Insert the entry with INSERT_HISTORY_SQL using a pool, connection or transaction
+   pub async fn insert<'e, E>(&self, executor: E, scheme: &str) -> Result<(), sqlx::Error>
+      where E: sqlx::Executor<'e, Database = Any>
+   //------------------------------------------------------------------------------------
+   {
+      self.execute(executor, &fix_placeholders(INSERT_HISTORY_SQL, scheme)).await.map(|_| ())
+   }
+
+   /// Insert the entry with upsert_history_sql, returning false if a row with its id already exists
+   pub async fn upsert<'e, E>(&self, executor: E, scheme: &str) -> Result<bool, sqlx::Error>
+      where E: sqlx::Executor<'e, Database = Any>
+   //------------------------------------------------------------------------------------
+   {
+      self.execute(executor, &upsert_history_sql(scheme)).await.map(|rows| rows > 0)
+   }
+
+   async fn execute<'e, E>(&self, executor: E, sql: &str) -> Result<u64, sqlx::Error>
+      where E: sqlx::Executor<'e, Database = Any>
+   //---------------------------------------------------------------------------------
+   {
+      let result = self.bind(sqlx::query(sql)).execute(executor).await?;
+      Ok(result.rows_affected())
+   }
+
+   /// Bind the entry's columns in HISTORY_COLUMNS order
+   fn bind<'q>(&'q self, query: sqlx::query::Query<'q, Any, sqlx::any::AnyArguments<'q>>)
+      -> sqlx::query::Query<'q, Any, sqlx::any::AnyArguments<'q>>
+   //------------------------------------------------------------------------------------
+   {
+      query
+         .bind(&self.id)
+         .bind(&self.command_timestamp)
+         .bind(&self.cwd)
+         .bind(&self.shell)
+         .bind(self.user_id)
+         .bind(&self.user_name)
+         .bind(&self.ip)
+         .bind(&self.os)
+         .bind(self.exit_status)
+         .bind(&self.command)
+         .bind(self.history_no)
+         .bind(&self.hostname)
+         .bind(&self.session_id)
+         .bind(&self.git_repo)
+         .bind(&self.git_branch)
+         .bind(self.duration_ms)
+         .bind(&self.ssh_origin)
+         .bind(&self.tty)
+         .bind(&self.project)
+         .bind(&self.multiplexer)
+   }
+}
+
+/// True if ephemeral mode has been requested through the DEJACMD_EPHEMERAL environment variable
+pub fn is_ephemeral_env() -> bool
+//-------------------------------
+{
+   matches!(std::env::var(EPHEMERAL_ENV).unwrap_or_default().trim().to_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+/// Local IP address of the machine (empty if it can't be determined)
+pub fn get_local_ip() -> String
+//-----------------------------
+{
+   localip::get_local_ip().map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// Operating system dejacmd is running on, std::env::consts::OS except under WSL where it is linux-wsl1 or linux-wsl2
+/// so that commands from Windows and WSL sessions on the same machine can be told apart
+pub fn get_os() -> String
+//-----------------------
+{
+   match wsl_version()
+   {
+      | Some(version) => format!("linux-wsl{}", version),
+      | None => std::env::consts::OS.to_string(),
+   }
+}
+
+/// The WSL version (1 or 2) when running in a WSL distribution, None otherwise
+pub fn wsl_version() -> Option<u8>
+//--------------------------------
+{
+   #[cfg(target_os = "linux")]
+   {
+      let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+      wsl_version_from_release(&release, std::env::var_os("WSL_DISTRO_NAME").is_some())
+   }
+   #[cfg(not(target_os = "linux"))]
+   {
+      None
+   }
+}
+
+/// WSL 2 kernels are named like 5.15.153.1-microsoft-standard-WSL2 and WSL 1 reports a release like
+/// 4.4.0-19041-Microsoft. WSL_DISTRO_NAME covers custom WSL 2 kernels without microsoft in the name.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn wsl_version_from_release(release: &str, is_wsl_distro: bool) -> Option<u8>
+//----------------------------------------------------------------------------
+{
+   let release = release.trim().to_lowercase();
+   if release.contains("microsoft")
+   {
+      if release.contains("wsl2") || release.contains("microsoft-standard") { Some(2) } else { Some(1) }
+   }
+   else if is_wsl_distro
+   {
+      Some(2)
+   }
+   else
+   {
+      None
+   }
+}
+
+/// Login name of the user dejacmd is running as (empty if it can't be determined)
+pub fn get_user_name() -> String
+//------------------------------
+{
+   #[cfg(windows)]
+   {
+      std::env::var("USERNAME").unwrap_or_default()
+   }
+   #[cfg(not(windows))]
+   {
+      match nix::unistd::User::from_uid(nix::unistd::getuid())
+      {
+         | Ok(Some(user)) => user.name,
+         | _ => String::new(),
+      }
+   }
+}
+
+/// Name of the machine dejacmd is running on (empty if it can't be determined). Under WSL this is the Windows
+/// hostname (COMPUTERNAME when it is shared through WSLENV), so WSL and Windows sessions on a machine share a hostname
+/// and are told apart by their os.
+pub fn get_hostname() -> String
+//-----------------------------
+{
+   if wsl_version().is_some()
+      && let Ok(hostname) = std::env::var("COMPUTERNAME")
+      && !hostname.trim().is_empty()
+   {
+      return hostname.trim().to_string();
+   }
+   #[cfg(unix)]
+   {
+      nix::unistd::gethostname().ok().and_then(|h| h.into_string().ok()).unwrap_or_default()
+   }
+   #[cfg(not(unix))]
+   {
+      std::env::var("COMPUTERNAME").unwrap_or_default()
+   }
+}
+
+/// Address of the machine the SSH session dejacmd is running in was opened from, from SSH_CONNECTION
+/// ("client_ip client_port server_ip server_port") or the older SSH_CLIENT ("client_ip client_port server_port").
+/// None outside an SSH session.
+pub fn get_ssh_origin() -> Option<String>
+//---------------------------------------
+{
+   ["SSH_CONNECTION", "SSH_CLIENT"].iter()
+      .filter_map(|name| std::env::var(name).ok())
+      .find_map(|value| value.split_whitespace().next().map(|ip| ip.to_string()))
+}
+
+/// Terminal device of the shell with process id shell_pid e.g /dev/pts/3, from standard input which the shell hooks
+/// inherit or else the shell's own standard input. None if it isn't a terminal.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub fn get_tty(shell_pid: i64) -> Option<String>
+//-----------------------------------------------
+{
+   #[cfg(unix)]
+   {
+      if let Ok(tty) = nix::unistd::ttyname(std::io::stdin())
+      {
+         return Some(tty.display().to_string());
+      }
+   }
+   #[cfg(target_os = "linux")]
+   {
+      if shell_pid > 0
+         && let Ok(path) = std::fs::read_link(format!("/proc/{}/fd/0", shell_pid))
+         && (path.starts_with("/dev/pts") || path.starts_with("/dev/tty"))
+      {
+         return Some(path.display().to_string());
+      }
+   }
+   None
+}
+
+/// The terminal multiplexer pane dejacmd is running in, tmux:SESSION:WINDOW.PANE inside tmux ($TMUX, with the location
+/// the shell hooks export in DEJACMD_TMUX_LOCATION) or screen:SESSION:WINDOW inside GNU screen ($STY). None outside a
+/// multiplexer.
+pub fn get_multiplexer() -> Option<String>
+//----------------------------------------
+{
+   let var = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+   multiplexer_context(var("TMUX").as_deref(), var("TMUX_PANE").as_deref(), var(TMUX_LOCATION_ENV).as_deref(),
+                       var("STY").as_deref(), var("WINDOW").as_deref())
+}
+
+/// The multiplexer value for the tmux ($TMUX and $TMUX_PANE, with location the session:window.pane tmux reports for
+/// the pane) or screen ($STY and $WINDOW) environment. Without a location the tmux session number and pane id are used.
+pub fn multiplexer_context(tmux: Option<&str>, tmux_pane: Option<&str>, location: Option<&str>, sty: Option<&str>,
+                           window: Option<&str>) -> Option<String>
+//------------------------------------------------------------------------------------------------------------------
+{
+   if let Some(tmux) = tmux
+   {
+      return match location.filter(|l| !l.is_empty())
+      {
+         | Some(location) => Some(format!("tmux:{}", location)),
+         // $TMUX is socket,server pid,session number
+         | None => Some(format!("tmux:${}:{}", tmux.rsplit(',').next().unwrap_or_default(), tmux_pane.unwrap_or_default())),
+      };
+   }
+   sty.map(|sty| format!("screen:{}:{}", sty, window.unwrap_or_default()))
+}
+
+/// Id of the shell session with process id shell_pid, the DEJACMD_SESSION environment variable if a shell hook
+/// exports it or else the shell's process id and start time (just the process id where the start time isn't available).
+/// Empty if neither is known.
+pub fn get_session_id(shell_pid: i64) -> String
+//---------------------------------------------
+{
+   if let Ok(session) = std::env::var(SESSION_ENV) && !session.trim().is_empty()
+   {
+      return session.trim().to_string();
+   }
+   if shell_pid <= 0
+   {
+      return String::new();
+   }
+   #[cfg(target_os = "linux")]
+   {
+      let started = procfs::process::Process::new(shell_pid as i32).and_then(|p| p.stat())
+         .and_then(|stat| procfs::boot_time_secs().map(|boot| boot + stat.starttime / procfs::ticks_per_second()));
+      if let Ok(started) = started
+      {
+         return format!("{}-{}", shell_pid, started);
+      }
+   }
+   shell_pid.to_string()
+}
+
+/// Connection pool options used by get_database_with_options
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolSettings
+{
+   pub max_connections: u32,
+   /// How long to wait for a connection from the pool
+   pub acquire_timeout: Duration,
+   /// How long to wait for a connection to a database server (SQLite isn't affected)
+   pub connect_timeout: Duration,
+}
+
+impl Default for PoolSettings
+{
+   fn default() -> Self
+   //------------------
+   {
+      PoolSettings { max_connections: 5, acquire_timeout: Duration::from_secs(10), connect_timeout: Duration::from_secs(3) }
+   }
+}
+
+impl PoolSettings
+{
+   /// The pool_max_connections, pool_acquire_timeout_secs and connect_timeout_secs settings
+   pub fn from_settings(settings: &Settings) -> Self
+   //-----------------------------------------------
+   {
+      let defaults = PoolSettings::default();
+      PoolSettings
+      {
+         max_connections: settings.pool_max_connections.filter(|n| *n > 0).unwrap_or(defaults.max_connections),
+         acquire_timeout: settings.pool_acquire_timeout_secs.filter(|s| *s > 0).map(Duration::from_secs)
+            .unwrap_or(defaults.acquire_timeout),
+         connect_timeout: settings.connect_timeout_secs.filter(|s| *s > 0).map(Duration::from_secs)
+            .unwrap_or(defaults.connect_timeout),
+      }
+   }
+
+   /// Options for dejacmd-log, which only needs a connection or two and must give up quickly on a database that is
+   /// down (log_connect_timeout_ms, default 750ms) rather than hang the user's prompt
+   pub fn for_logging(settings: &Settings) -> Self
+   //---------------------------------------------
+   {
+      let connect_timeout = Duration::from_millis(settings.log_connect_timeout_ms.filter(|ms| *ms > 0).unwrap_or(750));
+      PoolSettings { max_connections: 2, acquire_timeout: connect_timeout, connect_timeout }
+   }
+}
+
+/// Install the sqlx Any drivers, once per process. Every connection goes through get_database_with_options which
+/// calls this, so callers don't need to.
+pub fn install_drivers()
+//----------------------
+{
+   static INSTALLED: Once = Once::new();
+   INSTALLED.call_once(sqlx::any::install_default_drivers);
+}
+
+/// Runtime, URL, user and options of a pool connected by shared_database. A pool's connections belong to the runtime
+/// they were made on, so each runtime has its own pools.
+type SharedPoolKey = (tokio::runtime::Id, String, String, PoolSettings);
+
+static SHARED_POOLS: LazyLock<Mutex<HashMap<SharedPoolKey, SharedPool>>> =
+   LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone)]
+struct SharedPool
+{
+   pool: Pool<Any>,
+   scheme: String,
+   /// The pool is only reused with the password it was connected with
+   password: SecretString,
+   is_schema_created: bool,
+}
+
+/// get_database_with_options, but reusing the pool connected earlier on this runtime for the same URL, user, password
+/// and options (unless it has since been closed) so an invocation that searches, exports and syncs connects once. With
+/// is_create the schema is created or updated the first time the pool is used.
+pub async fn shared_database(url: &str, user: &str, password: &SecretString, options: &PoolSettings, is_create: bool)
+   -> Result<(Option< Pool<Any> >, String), DejacmdError>
+//------------------------------------------------------------------------------------------------------------------
+{
+   if url.trim().is_empty()
+   {
+      return Ok((None, String::new()));
+   }
+   let key = (tokio::runtime::Handle::current().id(), url.to_string(), user.to_string(), options.clone());
+   let cached = SHARED_POOLS.lock().unwrap_or_else(|e| e.into_inner()).get(&key)
+      .filter(|c| !c.pool.is_closed() && c.password.expose_secret() == password.expose_secret()).cloned();
+   let mut shared = match cached
+   {
+      | Some(shared) =>
+      {
+         debug!("Reusing the connection pool for {}", shared.scheme);
+         shared
+      },
+      | None => match get_database_with_options(url, user, password, options).await?
+      {
+         | (Some(pool), scheme) => SharedPool { pool, scheme, password: password.clone(), is_schema_created: false },
+         | (None, scheme) => return Ok((None, scheme)),
+      },
+   };
+   if is_create && !shared.is_schema_created
+   {
+      create_schema(&shared.pool, &shared.scheme).await?;
+      shared.is_schema_created = true;
+   }
+   SHARED_POOLS.lock().unwrap_or_else(|e| e.into_inner()).insert(key, shared.clone());
+   Ok((Some(shared.pool), shared.scheme))
+}
 
+/// Close the pools shared_database connected on the current runtime and forget them, for a runtime that is about to be
+/// dropped (such as those of the blocking module)
+pub async fn close_shared_pools()
+//-------------------------------
+{
+   let runtime = tokio::runtime::Handle::current().id();
+   let mut pools = Vec::new();
+   SHARED_POOLS.lock().unwrap_or_else(|e| e.into_inner()).retain(|key, shared|
+   {
+      if key.0 == runtime
+      {
+         pools.push(shared.pool.clone());
+      }
+      key.0 != runtime
+   });
+   for pool in pools
+   {
+      pool.close().await;
+   }
+}
 
-pub async fn get_database(url: &str, user: &str, password: &str) -> Result<(Option< Pool<Any> >, String), Box<dyn Error>>
+/// Connect with the default PoolSettings
+pub async fn get_database(url: &str, user: &str, password: &SecretString) -> Result<(Option< Pool<Any> >, String), DejacmdError>
 //---------------------------------------------------------------------------------
+{
+   get_database_with_options(url, user, password, &PoolSettings::default()).await
+}
+
+pub async fn get_database_with_options(url: &str, user: &str, password: &SecretString, options: &PoolSettings)
+   -> Result<(Option< Pool<Any> >, String), DejacmdError>
+//------------------------------------------------------------------------------------------------------------
 {
    // Handle empty URL - return None pool
    if url.trim().is_empty()
    {
       return Ok((None, String::new()));
    }
+   if remote::is_http_url(url)
+   {
+      return Err(DejacmdError::Config(format!("{} is a dejacmd server, not a database", url)));
+   }
+   if libsql::is_libsql_url(url)
+   {
+      return Err(DejacmdError::Config(format!("{} is a libSQL database, reached with remote::RemoteCentral", url)));
+   }
+   install_drivers();
 
    let mut database_url = url.to_string();
    let mut error_url = database_url.clone();
-   let scheme = database_url.split("://").next().unwrap_or("").to_string();
+   let mut scheme = database_url.split("://").next().unwrap_or("").to_string();
+   // Statement setting the key of each new connection to a SQLCipher database, which is otherwise an SQLite database
+   let mut key_statement: Option<Zeroizing<String>> = None;
+   if scheme == SQLCIPHER_SCHEME
+   {
+      if !cfg!(feature = "sqlcipher")
+      {
+         return Err(DejacmdError::Config(format!("{} databases need dejacmd built with the sqlcipher feature (cargo install --features sqlcipher)", scheme)));
+      }
+      if password.expose_secret().is_empty()
+      {
+         return Err(DejacmdError::Config(format!("No key for the SQLCipher database {}", url)));
+      }
+      key_statement = Some(sqlcipher_key_statement(password.expose_secret()));
+      database_url = database_url.replacen(SQLCIPHER_SCHEME, "sqlite", 1);
+      error_url = url.to_string();
+      scheme = "sqlite".to_string();
+   }
    let is_postgres = scheme.starts_with("postgres");
-   let is_mysql = scheme.starts_with("mysql");
+   let is_mysql = scheme.starts_with("mysql") || scheme.starts_with("mariadb");
    let is_mssql = scheme.starts_with("mssql");
    if is_postgres || is_mysql || is_mssql
    {
-      if user.is_empty() && password.is_empty()
+      if user.is_empty() && password.expose_secret().is_empty()
       {
          let p = database_url.find("@");
          if let Some(pos) = p
@@ -68,13 +743,11 @@ pub async fn get_database(url: &str, user: &str, password: &str) -> Result<(Opti
             let errmsg = format!("Database URL for {} must contain {{{{user}}}} and {{{{password}}}} placeholders when username and password are provided.\n{}", 
                scheme, settings);
             // eprintln!("{}", errmsg.red());
-            return Err(Box::new(std::io::Error::other(errmsg)));
+            return Err(DejacmdError::Config(errmsg));
          }
-         let dburl = database_url.replace("{{user}}", user);
-         let err_url = error_url.replace("{{user}}", user);
-         let n = password.len();
-         database_url = dburl.replace("{{password}}", password);
-         error_url = err_url.replace("{{password}}", "*".repeat(n).as_str());
+         // The {{password}} placeholder is only substituted in the zeroized connect URL below
+         database_url = database_url.replace("{{user}}", user);
+         error_url = error_url.replace("{{user}}", user).replace("{{password}}", "****");
       }
       // Add connection timeout if not already specified
       let timeout_parameter: String =  if is_postgres { "connect_timeout" } 
@@ -84,9 +757,11 @@ pub async fn get_database(url: &str, user: &str, password: &str) -> Result<(Opti
 
       if !database_url.contains(timeout_parameter.as_str())
       {
+         // Whole seconds, rounded up so a sub-second timeout doesn't become 0 (no timeout)
+         let seconds = options.connect_timeout.as_millis().div_ceil(1000).max(1);
          let separator = if database_url.contains("?") { "&" } else { "?" };
-         database_url = format!("{}{}{}=3", database_url, separator, timeout_parameter);
-         error_url = format!("{}{}{}=3", error_url, separator, timeout_parameter);
+         database_url = format!("{}{}{}={}", database_url, separator, timeout_parameter, seconds);
+         error_url = format!("{}{}{}={}", error_url, separator, timeout_parameter, seconds);
       }
    }
    else if scheme.starts_with("sqlite")
@@ -108,8 +783,9 @@ pub async fn get_database(url: &str, user: &str, password: &str) -> Result<(Opti
          }
       }
 
-      // Also don't add mode=rwc to in-memory databases in case used in for tests.
-      if ! database_url.contains("mode=rwc") && ! database_url.contains(":memory:")
+      // Also don't add mode=rwc to in-memory databases in case used in for tests, or to a URL that sets the mode (e.g
+      // mode=ro).
+      if ! database_url.contains("mode=") && ! database_url.contains(":memory:")
       {
          if database_url.contains("?")
          {
@@ -138,45 +814,120 @@ pub async fn get_database(url: &str, user: &str, password: &str) -> Result<(Opti
    }
    else
    {
-      return Err( Box::new( std::io::Error::other(
-         format!("{} {} [{}]", "Unsupported database scheme: ".red(), scheme.red(), "Supported schemes are: sqlite, postgres, mysql, mssql".bright_red()) ) ) );
+      return Err(DejacmdError::Config(
+         format!("Unsupported database scheme: {} [Supported schemes are: sqlite, sqlite+sqlcipher, postgres, mysql, mssql]", scheme)));
    }
 
    let is_sqlite = scheme.starts_with("sqlite");
+   debug!("Connecting to {}", error_url);
+   let connect_url = Zeroizing::new(database_url.replace("{{password}}", password.expose_secret()));
+   let mut pool_options = AnyPoolOptions::new()
+      .max_connections(options.max_connections)
+      .acquire_timeout(options.acquire_timeout);
+   let is_encrypted = key_statement.is_some();
+   if let Some(key_statement) = key_statement
+   {
+      pool_options = pool_options.after_connect(move |connection, _|
+      {
+         let key_statement = key_statement.clone();
+         Box::pin(async move
+         {
+            sqlx::query(&key_statement).execute(&mut *connection).await?;
+            Ok(())
+         })
+      });
+   }
    let pool = if is_sqlite
    {
       // SQLite connections are local and fast, no timeout needed
-      match AnyPool::connect(&database_url).await
+      match pool_options.connect(&connect_url).await
       {
          Ok(p) => p,
          Err(e) =>
          {
-            return Err( Box::new( std::io::Error::other(
-               format!("{} {} [{}]", "Error connecting to database: ".red(), error_url.red(), e.to_string().bright_red()) ) ) );
+            return Err(DejacmdError::Connection(
+               format!("Error connecting to database: {} [{}]", error_url, e)));
          }
       }
    }
    else
    {
       // Use tokio timeout for remote database connections
-      match timeout(Duration::from_secs(3), AnyPool::connect(&database_url)).await
+      match timeout(options.connect_timeout, pool_options.connect(&connect_url)).await
       {
          Ok(Ok(p)) => p,
          Ok(Err(e)) =>
          {
-            return Err( Box::new( std::io::Error::other(
-               format!("{} {} [{}]", "Error connecting to database: ".red(), error_url.red(), e.to_string().bright_red()) ) ) );
+            return Err(DejacmdError::Connection(
+               format!("Error connecting to database: {} [{}]", error_url, e)));
          }
          Err(_) =>
          {
-            return Err( Box::new( std::io::Error::other(
-               format!("{} {} [{}]", "Database connection timed out: ".red(), error_url.red(), "Connection took longer than 3 seconds".bright_red()) ) ) );
+            return Err(DejacmdError::Connection(
+               format!("Database connection timed out: {} [Connection took longer than {:?}]", error_url, options.connect_timeout)));
          }
       }
    };
+   // The key isn't checked until the database is read, a wrong key fails with "file is not a database"
+   if is_encrypted && let Err(e) = sqlx::query("SELECT COUNT(*) FROM sqlite_master").execute(&pool).await
+   {
+      pool.close().await;
+      return Err(DejacmdError::Connection(
+         format!("Error opening SQLCipher database (wrong key?): {} [{}]", error_url, e)));
+   }
    Ok((Some(pool), scheme))
 }
 
+/// The PRAGMA key statement for a SQLCipher database key, used as a raw key if it is 64 hex digits (such as the dejacmd
+/// encryption key) or else as a passphrase
+fn sqlcipher_key_statement(key: &str) -> Zeroizing<String>
+//--------------------------------------------------------
+{
+   sqlcipher_pragma("key", key)
+}
+
+fn sqlcipher_pragma(pragma: &str, key: &str) -> Zeroizing<String>
+//---------------------------------------------------------------
+{
+   if key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit())
+   {
+      Zeroizing::new(format!("PRAGMA {} = \"x'{}'\"", pragma, key))
+   }
+   else
+   {
+      Zeroizing::new(format!("PRAGMA {} = '{}'", pragma, key.replace('\'', "''")))
+   }
+}
+
+/// Change the key of the SQLCipher database at url from key to new_key with PRAGMA rekey, so a changed password
+/// still opens it. A database that doesn't exist yet is keyed when it is created and is left alone.
+pub async fn rekey_sqlcipher(url: &str, key: &SecretString, new_key: &SecretString) -> Result<(), DejacmdError>
+//--------------------------------------------------------------------------------------------------------------
+{
+   let path = url.strip_prefix(&format!("{}://", SQLCIPHER_SCHEME))
+      .ok_or_else(|| DejacmdError::Config(format!("{} is not a SQLCipher database", url)))?;
+   let path = path.split('?').next().unwrap_or(path);
+   if !std::path::Path::new(path).exists() || key.expose_secret() == new_key.expose_secret()
+   {
+      return Ok(());
+   }
+   if new_key.expose_secret().is_empty()
+   {
+      return Err(DejacmdError::Config(format!("No new key for the SQLCipher database {}", url)));
+   }
+   let (pool, _) = get_database(url, "", key).await?;
+   let pool = pool.ok_or_else(|| DejacmdError::Connection(format!("No database at {}", url)))?;
+   let result = async
+   {
+      let mut connection = pool.acquire().await?;
+      sqlx::query("SELECT COUNT(*) FROM sqlite_master").fetch_one(&mut *connection).await?;
+      sqlx::query(&sqlcipher_pragma("rekey", new_key.expose_secret())).execute(&mut *connection).await?;
+      Ok::<(), sqlx::Error>(())
+   }.await;
+   pool.close().await;
+   result.map_err(|e| DejacmdError::Query(format!("Error changing the key of the SQLCipher database {} [{}]", url, e)))
+}
+
 pub fn fix_placeholders(sql: &str, scheme: &str) -> String
 //--------------------------------------------------------------
 {
@@ -200,8 +951,39 @@ pub fn fix_placeholders(sql: &str, scheme: &str) -> String
     }
 }
 
+/// Create the history table and index if needed and bring it up to date with the schema update files
+pub async fn create_schema(pool: &Pool<Any>, scheme: &str) -> Result<(), DejacmdError>
+//------------------------------------------------------------------------------
+{
+   let create_table_sql = match ddl::dialect(scheme)
+   {
+      | "mysql" =>
+      {
+         let server = ddl::mysql_server(pool).await.map_err(DejacmdError::Query)?;
+         ddl::fit_mysql_indexes(ddl::create_table_sql(scheme), &server)
+      },
+      | _ => std::borrow::Cow::Borrowed(ddl::create_table_sql(scheme)),
+   };
+   sqlx::query(&create_table_sql).execute(pool).await
+      .map_err(|e| DejacmdError::Query(format!("Error creating table: {}", e)))?;
+   if let Some(index_sql) = ddl::create_index_sql(scheme)
+   {
+      sqlx::query(index_sql).execute(pool).await
+         .map_err(|e| DejacmdError::Query(format!("Error creating index: {}", e)))?;
+   }
+   migrations::apply_updates(pool, scheme).await.map_err(DejacmdError::Query)?;
+   Ok(())
+}
+
 pub async fn connections(settings: &Settings, is_create: bool, is_truncate: bool) ->
-   Result<(Option<sqlx::Pool<sqlx::Any>>, String, Option<sqlx::Pool<sqlx::Any>>, String), String>
+   Result<(Option<sqlx::Pool<sqlx::Any>>, String, Option<sqlx::Pool<sqlx::Any>>, String), DejacmdError>
+//----------------------------------------------------------------------------------------------------------------------------------------
+{
+   connections_with_options(settings, &PoolSettings::from_settings(settings), is_create, is_truncate).await
+}
+
+pub async fn connections_with_options(settings: &Settings, pool_settings: &PoolSettings, is_create: bool, is_truncate: bool) ->
+   Result<(Option<sqlx::Pool<sqlx::Any>>, String, Option<sqlx::Pool<sqlx::Any>>, String), DejacmdError>
 //----------------------------------------------------------------------------------------------------------------------------------------
 {
    // Connect to database
@@ -211,53 +993,204 @@ pub async fn connections(settings: &Settings, is_create: bool, is_truncate: bool
    let (local_user, local_password) = match settings.get_credentials(true)
    {
       Ok((u, p)) => (u, p),
-      Err(_) => ("".to_string(), "".to_string())
+      Err(_) => ("".to_string(), SecretString::default())
    };
 
-   let (local_pool_opt, local_scheme) = match get_database(&local_url, &local_user, &local_password).await
-   {
-      Ok((p, s)) => (p, s),
-      Err(e) => return Err(format!("Error connecting to database: {}", e)),
-   };
+   let (local_pool_opt, local_scheme) = shared_database(&local_url, &local_user, &local_password, pool_settings, is_create).await?;
 
    let (central_user, central_password) = match settings.get_credentials(false)
    {
       Ok((u, p)) => (u, p),
-      Err(_) => ("".to_string(), "".to_string())
+      Err(_) => ("".to_string(), SecretString::default())
    };
 
-   let (central_pool_opt, central_scheme) = match get_database(&central_url, &central_user, &central_password).await
+   let (central_pool_opt, central_scheme) = if remote::is_remote_url(&central_url)
    {
-      Ok((p, s)) => (p, s),
-      Err(e) => return Err(format!("Error connecting to database: {}", e)),
+      debug!("Central history {} is a dejacmd server or libSQL database so only the local database is connected", central_url);
+      (None, String::new())
+   }
+   else
+   {
+      shared_database(&central_url, &central_user, &central_password, pool_settings, is_create).await?
    };
-   if is_create
+   if is_truncate
    {
       if let Some(ref local_pool) = local_pool_opt
       {
-         sqlx::query(CREATE_TABLE_SQL).execute(local_pool).await
-         .map_err(|e| format!("Error creating table: {}", e))?;
+         sqlx::query("DELETE FROM history").execute(local_pool).await
+         .map_err(|e| DejacmdError::Query(format!("Error truncating local history table: {}", e)))?;
+         // Output summaries are only kept locally, and only once the table has been created by an update
+         let _ = sqlx::query("DELETE FROM history_output").execute(local_pool).await;
       };
 
       if let Some(ref central_pool) = central_pool_opt
       {
-         sqlx::query(CREATE_TABLE_SQL).execute(central_pool).await
-         .map_err(|e| format!("Error creating table: {}", e))?;
+         sqlx::query("DELETE FROM history").execute(central_pool).await
+         .map_err(|e| DejacmdError::Query(format!("Error truncating central history table: {}", e)))?;
       };
    }
-   if is_truncate
+   Ok((local_pool_opt, local_scheme, central_pool_opt, central_scheme))
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[tokio::test]
+   async fn test_pool_settings()
    {
-      if let Some(ref local_pool) = local_pool_opt
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      assert_eq!(PoolSettings::from_settings(&settings), PoolSettings::default());
+      settings.pool_max_connections = Some(2);
+      settings.connect_timeout_secs = Some(0); // 0 is treated as unset rather than no timeout
+      settings.log_connect_timeout_ms = Some(200);
+      let options = PoolSettings::from_settings(&settings);
+      assert_eq!(options.max_connections, 2);
+      assert_eq!(options.connect_timeout, PoolSettings::default().connect_timeout);
+      let options = PoolSettings::for_logging(&settings);
+      assert_eq!(options.connect_timeout, Duration::from_millis(200));
+
+      // A server that doesn't answer gives up after the connect timeout
+      let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+      let url = format!("postgres://{{{{user}}}}:{{{{password}}}}@{}/dejacmd", listener.local_addr().unwrap());
+      let started = std::time::Instant::now();
+      let result = get_database_with_options(&url, "me", &SecretString::from("pw"), &options).await;
+      assert!(result.is_err());
+      assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+   }
+
+   #[tokio::test]
+   async fn test_insert_history_batch()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_batch_test_{}.sqlite", nanos));
+      let (pool, scheme) = get_database(&format!("sqlite://{}?mode=rwc", path.display()), "", &SecretString::default()).await.unwrap();
+      let pool = pool.unwrap();
+      create_schema(&pool, &scheme).await.unwrap();
+
+      let entries: Vec<HistoryEntry> = (0..MAX_BATCH_ROWS as i64 + 20)
+         .map(|i| crate::store::imported_entry(&format!("echo {}", i), "/", 1767000000 + i, "bash", 0, Some(i)).unwrap())
+         .collect();
+      assert_eq!(insert_history_batch(&pool, &scheme, &entries[..10]).await.unwrap(), 10);
+      assert_eq!(insert_history_batch(&pool, &scheme, &entries).await.unwrap(), entries.len() as u64 - 10, "Stored ids are skipped");
+      assert_eq!(insert_history_batch(&pool, &scheme, &[]).await.unwrap(), 0);
+      let more = crate::store::imported_entry("ls", "/", 1767100000, "bash", 0, None).unwrap();
+      assert_eq!(insert_new_history(&pool, &scheme, &[entries[0].clone(), more.clone(), more.clone()]).await.unwrap(), vec![more.id],
+                 "Only the ids of new entries are returned, once");
+      let last = entries.last().unwrap();
+      let row = sqlx::query(&fix_placeholders(&format!("SELECT {} FROM history WHERE id = ?", HISTORY_COLUMNS), &scheme))
+         .bind(&last.id).fetch_one(&pool).await.unwrap();
+      assert_eq!(&HistoryEntry::from_row(&row), last);
+      pool.close().await;
+      let _ = std::fs::remove_file(&path);
+   }
+
+   #[tokio::test]
+   async fn test_sqlcipher()
+   {
+      let key = crate::crypt::generate_key();
+      assert_eq!(*sqlcipher_key_statement(&key), format!("PRAGMA key = \"x'{}'\"", key));
+      assert_eq!(*sqlcipher_key_statement("it's secret"), "PRAGMA key = 'it''s secret'");
+
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_sqlcipher_test_{}.sqlite", nanos));
+      let url = format!("{}://{}", SQLCIPHER_SCHEME, path.display());
+      assert!(get_database(&url, "", &SecretString::default()).await.is_err(), "A key is required");
+      let result = get_database(&url, "", &SecretString::from(key.clone())).await;
+      if !cfg!(feature = "sqlcipher")
       {
-         sqlx::query("DELETE FROM history").execute(local_pool).await
-         .map_err(|e| format!("Error truncating local history table: {}", e))?;
-      };
+         assert!(result.is_err());
+         return;
+      }
+      let (pool, scheme) = result.unwrap();
+      assert_eq!(scheme, "sqlite");
+      let pool = pool.unwrap();
+      create_schema(&pool, &scheme).await.unwrap();
+      crate::store::imported_entry("ls", "/", 1767000000, "bash", 0, None).unwrap().insert(&pool, &scheme).await.unwrap();
+      pool.close().await;
 
-      if let Some(ref central_pool) = central_pool_opt
+      let contents = std::fs::read(&path).unwrap();
+      assert!(!contents.starts_with(b"SQLite format 3"), "The database is encrypted");
+      let plain = format!("sqlite://{}", path.display());
+      let (pool, _) = get_database(&plain, "", &SecretString::default()).await.unwrap();
+      assert!(sqlx::query("SELECT COUNT(*) FROM history").fetch_one(&pool.unwrap()).await.is_err(), "Unreadable without the key");
+      assert!(get_database(&url, "", &SecretString::from(crate::crypt::generate_key())).await.is_err());
+      let (pool, _) = get_database(&url, "", &SecretString::from(key.clone())).await.unwrap();
+      let count: i64 = sqlx::query("SELECT COUNT(*) AS count FROM history").fetch_one(&pool.unwrap()).await.unwrap().get("count");
+      assert_eq!(count, 1);
+
+      let password = SecretString::from("new password");
+      assert!(rekey_sqlcipher(&url, &SecretString::from(crate::crypt::generate_key()), &password).await.is_err(), "The old key is checked");
+      rekey_sqlcipher(&url, &SecretString::from(key.clone()), &password).await.unwrap();
+      let (pool, _) = get_database(&url, "", &SecretString::from(key)).await.unwrap();
+      assert!(sqlx::query("SELECT COUNT(*) FROM history").fetch_one(&pool.unwrap()).await.is_err(), "The old key no longer opens it");
+      let (pool, _) = get_database(&url, "", &password).await.unwrap();
+      let count: i64 = sqlx::query("SELECT COUNT(*) AS count FROM history").fetch_one(&pool.unwrap()).await.unwrap().get("count");
+      assert_eq!(count, 1);
+      let _ = std::fs::remove_file(&path);
+   }
+
+   #[tokio::test]
+   async fn test_shared_database()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_shared_test_{}.sqlite", nanos));
+      let url = format!("sqlite://{}?mode=rwc", path.display());
+      let options = PoolSettings::default();
+      let (pool, scheme) = shared_database(&url, "", &SecretString::default(), &options, true).await.unwrap();
+      let pool = pool.unwrap();
+      let (again, _) = shared_database(&url, "", &SecretString::default(), &options, true).await.unwrap();
+      let entry = crate::store::imported_entry("ls", "/", 1767000000, "bash", 0, None).unwrap();
+      entry.insert(&again.unwrap(), &scheme).await.unwrap();
+      pool.close().await;
+      let (reopened, _) = shared_database(&url, "", &SecretString::default(), &options, false).await.unwrap();
+      let reopened = reopened.unwrap();
+      assert!(!reopened.is_closed(), "A closed pool is replaced");
+      let count: i64 = sqlx::query("SELECT COUNT(*) AS count FROM history").fetch_one(&reopened).await.unwrap().get("count");
+      assert_eq!(count, 1);
+      let (other, _) = shared_database(&url, "", &SecretString::from("changed"), &options, false).await.unwrap();
+      let other = other.unwrap();
+      other.close().await;
+      assert!(!reopened.is_closed(), "A pool isn't shared with another password");
+      let url_copy = url.clone();
+      let in_other_runtime = std::thread::spawn(move || tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+         .block_on(async
+         {
+            let (pool, _) = shared_database(&url_copy, "", &SecretString::default(), &PoolSettings::default(), false).await.unwrap();
+            close_shared_pools().await;
+            pool.unwrap().is_closed()
+         }));
+      assert!(in_other_runtime.join().unwrap(), "Another runtime has its own pools");
+      let (cached, _) = shared_database(&url, "", &SecretString::default(), &options, false).await.unwrap();
+      close_shared_pools().await;
+      assert!(cached.unwrap().is_closed());
+      reopened.close().await;
+      let _ = std::fs::remove_file(&path);
+   }
+
+   #[test]
+   fn test_multiplexer_context()
+   {
+      assert_eq!(multiplexer_context(Some("/tmp/tmux-1000/default,4321,2"), Some("%7"), Some("debug:1.0"), None, None).as_deref(),
+                 Some("tmux:debug:1.0"));
+      assert_eq!(multiplexer_context(Some("/tmp/tmux-1000/default,4321,2"), Some("%7"), None, Some("99.outer"), None).as_deref(),
+                 Some("tmux:$2:%7"), "tmux is inside screen");
+      assert_eq!(multiplexer_context(None, None, None, Some("12345.debug"), Some("2")).as_deref(), Some("screen:12345.debug:2"));
+      assert_eq!(multiplexer_context(None, None, None, None, None), None);
+   }
+
+   #[test]
+   fn test_wsl_version()
+   {
+      assert_eq!(wsl_version_from_release("5.15.153.1-microsoft-standard-WSL2\n", false), Some(2));
+      assert_eq!(wsl_version_from_release("4.19.128-microsoft-standard", false), Some(2));
+      assert_eq!(wsl_version_from_release("4.4.0-19041-Microsoft", false), Some(1));
+      assert_eq!(wsl_version_from_release("6.8.0-45-generic", false), None);
+      assert_eq!(wsl_version_from_release("6.6.36-custom", true), Some(2));
+      if wsl_version().is_none()
       {
-         sqlx::query("DELETE FROM history").execute(central_pool).await
-         .map_err(|e| format!("Error truncating central history table: {}", e))?;
-      };
+         assert_eq!(get_os(), std::env::consts::OS);
+      }
    }
-   Ok((local_pool_opt, local_scheme, central_pool_opt, central_scheme))
 }
@@ -0,0 +1,428 @@
+use chrono::Utc;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value, json};
+
+use crate::remote::{HEALTH_PATH, HISTORY_PATH, InsertResponse, QUERY_PATH, RemoteCentral, RemoteError, SEARCH_PATH};
+use crate::store::{SearchRequest, search_sql};
+use crate::{HISTORY_COLUMNS, HistoryEntry, MAX_BATCH_ROWS, ddl, migrations, upsert_history_sql};
+
+/// A libSQL database such as Turso (libsql://NAME-ORG.turso.io) used as the central history. It is reached over HTTPS
+/// (HTTP with ?tls=0, e.g for a local sqld) with the Hrana pipeline API, the central password being the bearer token.
+/// RemoteCentral answers its dejacmd server endpoints with SQL run here.
+pub const LIBSQL_SCHEME: &str = "libsql";
+
+/// Hrana endpoint running a sequence of requests on a new stream
+pub const PIPELINE_PATH: &str = "/v2/pipeline";
+
+/// libSQL is SQLite as far as ddl, migrations and search_sql are concerned
+const DIALECT: &str = "sqlite";
+
+pub fn is_libsql_url(url: &str) -> bool
+//--------------------------------------
+{
+   url.trim().to_lowercase().starts_with(&format!("{}://", LIBSQL_SCHEME))
+}
+
+/// The HTTP URL of a libsql:// URL's database. A token given as an authToken parameter is refused as the URL is
+/// stored and shown in plain text, the token is given as the (encrypted) central password instead.
+pub fn http_url(url: &str) -> Result<String, String>
+//--------------------------------------------------
+{
+   let url = url.trim();
+   let rest = &url[(LIBSQL_SCHEME.len() + 3).min(url.len())..];
+   let (location, parameters) = rest.split_once('?').unwrap_or((rest, ""));
+   let mut protocol = "https";
+   for (name, value) in parameters.split('&').map(|parameter| parameter.split_once('=').unwrap_or((parameter, "")))
+   {
+      match name
+      {
+         | "tls" if value == "0" => protocol = "http",
+         | "authToken" => return Err("Give the libSQL token as the central password (dejacmd config -C URL -p) rather \
+                                      than as an authToken in the URL, which is stored unencrypted".to_string()),
+         | _ => {},
+      }
+   }
+   Ok(format!("{}://{}", protocol, location.trim_end_matches('/')))
+}
+
+/// Answer a dejacmd server POST endpoint from the libSQL database
+pub(crate) async fn post<T, R>(remote: &RemoteCentral, path: &str, body: &T) -> Result<R, RemoteError>
+   where T: Serialize + ?Sized, R: DeserializeOwned
+//----------------------------------------------------------------------------------------------------
+{
+   let body = serde_json::to_value(body).map_err(|e| RemoteError::Failed(format!("Error encoding {} request: {}", path, e)))?;
+   let invalid = |e: serde_json::Error| RemoteError::Failed(format!("Invalid {} request: {}", path, e));
+   let response = match path
+   {
+      | HISTORY_PATH =>
+      {
+         let entries: Vec<HistoryEntry> = serde_json::from_value(body).map_err(invalid)?;
+         json!(InsertResponse { inserted: insert(remote, &entries).await? })
+      },
+      | SEARCH_PATH =>
+      {
+         let request: SearchRequest = serde_json::from_value(body).map_err(invalid)?;
+         json!(search(remote, &request).await?)
+      },
+      | QUERY_PATH =>
+      {
+         let sql = body.get("sql").and_then(Value::as_str).unwrap_or_default();
+         let (columns, rows) = query(remote, sql).await?;
+         json!({ "columns": columns, "rows": rows })
+      },
+      | _ => return Err(RemoteError::Failed(format!("{} isn't available for a libSQL database", path))),
+   };
+   serde_json::from_value(response).map_err(|e| RemoteError::Failed(format!("Error decoding {} response: {}", path, e)))
+}
+
+/// Answer a dejacmd server GET endpoint from the libSQL database
+pub(crate) async fn get<R>(remote: &RemoteCentral, path: &str) -> Result<R, RemoteError>
+   where R: DeserializeOwned
+//--------------------------------------------------------------------------------------
+{
+   let response = match path
+   {
+      | HEALTH_PATH =>
+      {
+         execute(remote, vec![statement("SELECT 1", vec![])]).await?;
+         json!({ "status": "ok" })
+      },
+      | _ => return Err(RemoteError::Failed(format!("{} isn't available for a libSQL database", path))),
+   };
+   serde_json::from_value(response).map_err(|e| RemoteError::Failed(format!("Error decoding {} response: {}", path, e)))
+}
+
+/// Insert entries, skipping those whose ids are already stored, in a transaction per MAX_BATCH_ROWS entries so a
+/// large import isn't sent in one request. The history table is created or updated and the insert retried if the
+/// database doesn't have it or its newest columns. Returns the number inserted.
+pub async fn insert(remote: &RemoteCentral, entries: &[HistoryEntry]) -> Result<u64, RemoteError>
+//------------------------------------------------------------------------------------------------
+{
+   let sql = upsert_history_sql(DIALECT);
+   let mut inserted = 0;
+   for chunk in entries.chunks(MAX_BATCH_ROWS)
+   {
+      let statements: Vec<Value> = chunk.iter().map(|entry| statement(&sql, entry_arguments(entry))).collect();
+      let results = match transaction(remote, statements.clone()).await
+      {
+         | Err(RemoteError::Failed(e)) if e.contains("no such table") || e.contains("no column named") =>
+         {
+            create_schema(remote).await?;
+            transaction(remote, statements).await?
+         },
+         | result => result?,
+      };
+      inserted += results.iter().filter_map(|result| result.get("affected_row_count").and_then(Value::as_u64)).sum::<u64>();
+   }
+   Ok(inserted)
+}
+
+/// Entries matching the search as for store::search_rows
+pub async fn search(remote: &RemoteCentral, request: &SearchRequest) -> Result<Vec<HistoryEntry>, RemoteError>
+//-------------------------------------------------------------------------------------------------------------
+{
+   let (sql, values) = search_sql(DIALECT, request);
+   let arguments = values.into_iter().map(|value| json!({ "type": "text", "value": value })).collect();
+   let result = execute(remote, vec![statement(&sql, arguments)]).await?.into_iter().next().unwrap_or_default();
+   rows(&result).into_iter().map(|mut row|
+   {
+      // Searches for unique commands don't select the id
+      for column in ["id", "command_timestamp"]
+      {
+         if !matches!(row.get(column), Some(Value::String(_)))
+         {
+            row.insert(column.to_string(), json!(""));
+         }
+      }
+      serde_json::from_value(Value::Object(row)).map_err(|e| RemoteError::Failed(format!("Invalid history row: {}", e)))
+   })
+   .collect()
+}
+
+/// The column names and rows of a query's result with the values as strings (NULL for null), as from a dejacmd server.
+/// As for a dejacmd server the query can't change the database: libSQL has to describe it as read only, and it is run
+/// in a transaction that is always rolled back.
+pub async fn query(remote: &RemoteCentral, sql: &str) -> Result<(Vec<String>, Vec<Vec<String>>), RemoteError>
+//-----------------------------------------------------------------------------------------------------------
+{
+   let description = pipeline(remote, vec![json!({ "type": "describe", "sql": sql })]).await?.into_iter().next().unwrap_or_default();
+   if description.pointer("/result/is_readonly").and_then(Value::as_bool) != Some(true)
+   {
+      return Err(RemoteError::Failed("Only queries that don't change the database can be run".to_string()));
+   }
+   let steps = json!([{ "stmt": statement("BEGIN", vec![]) },
+                      { "stmt": statement(sql, vec![]), "condition": { "type": "ok", "step": 0 } },
+                      { "stmt": statement("ROLLBACK", vec![]) }]);
+   let batch = pipeline(remote, vec![json!({ "type": "batch", "batch": { "steps": steps } })]).await?
+      .into_iter().next().and_then(|response| response.get("result").cloned()).unwrap_or_default();
+   if let Some(error) = batch.get("step_errors").and_then(Value::as_array).and_then(|errors| errors.iter().take(2).find(|e| !e.is_null()))
+   {
+      return Err(RemoteError::Failed(error_message(Some(error))));
+   }
+   let result = batch.pointer("/step_results/1").cloned().unwrap_or_default();
+   let columns = column_names(&result);
+   let rows = result.get("rows").and_then(Value::as_array).map(|rows| rows.iter().map(|row|
+   {
+      row.as_array().map(|cells| cells.iter().map(|cell| match value(cell)
+      {
+         | Value::Null => "NULL".to_string(),
+         | Value::String(text) => text,
+         | other => other.to_string(),
+      })
+      .collect()).unwrap_or_default()
+   })
+   .collect()).unwrap_or_default();
+   Ok((columns, rows))
+}
+
+/// Create the history table if it doesn't exist and apply the schema updates it hasn't had, recorded in
+/// dejacmd_migrations as migrations::apply_updates does. Each statement is applied with its record in a transaction.
+pub async fn create_schema(remote: &RemoteCentral) -> Result<(), RemoteError>
+//--------------------------------------------------------------------------
+{
+   let mut statements = vec![statement(ddl::create_table_sql(DIALECT), vec![])];
+   statements.extend(ddl::create_index_sql(DIALECT).map(|sql| statement(sql, vec![])));
+   statements.push(statement(migrations::CREATE_MIGRATIONS_TABLE_SQL, vec![]));
+   execute(remote, statements).await?;
+   for file in migrations::update_files()
+   {
+      let filename = file.path().file_name().and_then(|n| n.to_str()).unwrap_or("");
+      let Some(sql_content) = migrations::update_sql(file, DIALECT)
+      else
+      {
+         continue;
+      };
+      let count = statement("SELECT COUNT(*) AS applied FROM dejacmd_migrations WHERE filename = ?", vec![text(filename)]);
+      let applied = execute(remote, vec![count]).await?.first()
+         .and_then(|result| result.pointer("/rows/0/0")).map(value)
+         .and_then(|applied| applied.as_i64()).unwrap_or(0);
+      for (no, sql) in migrations::split_sql_statements(sql_content).iter().enumerate().skip(applied as usize)
+      {
+         let record = statement(migrations::INSERT_MIGRATION_SQL,
+                                vec![text(filename), json!({ "type": "integer", "value": (no + 1).to_string() }),
+                                     text(&Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())]);
+         transaction(remote, vec![statement(sql, vec![]), record]).await
+            .map_err(|e| RemoteError::Failed(format!("Statement {} of {} failed: {} [{}]", no + 1, filename, e, sql)))?;
+      }
+   }
+   Ok(())
+}
+
+fn statement(sql: &str, arguments: Vec<Value>) -> Value
+//-------------------------------------------------------
+{
+   json!({ "sql": sql, "args": arguments })
+}
+
+fn text(value: &str) -> Value
+//---------------------------
+{
+   json!({ "type": "text", "value": value })
+}
+
+/// An entry's values as Hrana arguments for INSERT_HISTORY_SQL
+fn entry_arguments(entry: &HistoryEntry) -> Vec<Value>
+//-----------------------------------------------------
+{
+   let fields = serde_json::to_value(entry).unwrap_or_default();
+   HISTORY_COLUMNS.split(',').map(|column| match fields.get(column.trim())
+   {
+      | Some(Value::String(value)) => text(value),
+      | Some(Value::Number(value)) => json!({ "type": "integer", "value": value.to_string() }),
+      | _ => json!({ "type": "null" }),
+   })
+   .collect()
+}
+
+/// A Hrana value as JSON, integers (which Hrana sends as strings) as numbers and blobs as their base64
+fn value(cell: &Value) -> Value
+//-----------------------------
+{
+   match cell.get("type").and_then(Value::as_str)
+   {
+      | Some("integer") => cell.get("value").and_then(Value::as_str).and_then(|v| v.parse::<i64>().ok()).map_or(Value::Null, |v| json!(v)),
+      | Some("float") | Some("text") => cell.get("value").cloned().unwrap_or(Value::Null),
+      | Some("blob") => cell.get("base64").cloned().unwrap_or(Value::Null),
+      | _ => Value::Null,
+   }
+}
+
+fn column_names(result: &Value) -> Vec<String>
+//--------------------------------------------
+{
+   result.get("cols").and_then(Value::as_array)
+      .map(|columns| columns.iter().map(|c| c.get("name").and_then(Value::as_str).unwrap_or_default().to_string()).collect())
+      .unwrap_or_default()
+}
+
+/// The rows of a statement result as JSON objects keyed by column name
+fn rows(result: &Value) -> Vec<Map<String, Value>>
+//------------------------------------------------
+{
+   let columns = column_names(result);
+   result.get("rows").and_then(Value::as_array).map(|rows| rows.iter().map(|row|
+   {
+      columns.iter().cloned()
+         .zip(row.as_array().map(|cells| cells.iter().map(value).collect::<Vec<_>>()).unwrap_or_default())
+         .collect()
+   })
+   .collect()).unwrap_or_default()
+}
+
+/// Run statements one after another, returning their results or the first statement's error
+async fn execute(remote: &RemoteCentral, statements: Vec<Value>) -> Result<Vec<Value>, RemoteError>
+//-----------------------------------------------------------------------------------------------
+{
+   let requests = statements.into_iter().map(|stmt| json!({ "type": "execute", "stmt": stmt })).collect();
+   Ok(pipeline(remote, requests).await?.into_iter()
+      .map(|response| response.get("result").cloned().unwrap_or_default()).collect())
+}
+
+/// Run Hrana requests one after another on a new stream, which is closed after them, returning their responses or
+/// the first request's error
+async fn pipeline(remote: &RemoteCentral, mut requests: Vec<Value>) -> Result<Vec<Value>, RemoteError>
+//-------------------------------------------------------------------------------------------------
+{
+   requests.push(json!({ "type": "close" }));
+   let response: Value = remote.post_json(PIPELINE_PATH, &json!({ "baton": null, "requests": requests })).await?;
+   let results = response.get("results").and_then(Value::as_array).cloned().unwrap_or_default();
+   let mut responses = Vec::new();
+   for result in &results[..results.len().saturating_sub(1)]
+   {
+      match result.get("type").and_then(Value::as_str)
+      {
+         | Some("ok") => responses.push(result.get("response").cloned().unwrap_or_default()),
+         | _ => return Err(RemoteError::Failed(error_message(result.get("error")))),
+      }
+   }
+   Ok(responses)
+}
+
+/// Run statements in a transaction that is rolled back if any of them fails, returning their results
+async fn transaction(remote: &RemoteCentral, statements: Vec<Value>) -> Result<Vec<Value>, RemoteError>
+//----------------------------------------------------------------------------------------------------
+{
+   let count = statements.len();
+   let mut steps = vec![json!({ "stmt": statement("BEGIN", vec![]) })];
+   for (no, stmt) in statements.into_iter().enumerate()
+   {
+      steps.push(json!({ "stmt": stmt, "condition": { "type": "ok", "step": no } }));
+   }
+   steps.push(json!({ "stmt": statement("COMMIT", vec![]), "condition": { "type": "ok", "step": count } }));
+   steps.push(json!({ "stmt": statement("ROLLBACK", vec![]),
+                      "condition": { "type": "not", "cond": { "type": "ok", "step": count + 1 } } }));
+   let requests = json!([{ "type": "batch", "batch": { "steps": steps } }, { "type": "close" }]);
+   let response: Value = remote.post_json(PIPELINE_PATH, &json!({ "baton": null, "requests": requests })).await?;
+   let result = response.pointer("/results/0").cloned().unwrap_or_default();
+   if result.get("type").and_then(Value::as_str) != Some("ok")
+   {
+      return Err(RemoteError::Failed(error_message(result.get("error"))));
+   }
+   let batch = result.pointer("/response/result").cloned().unwrap_or_default();
+   let errors = batch.get("step_errors").and_then(Value::as_array).cloned().unwrap_or_default();
+   if let Some(error) = errors.iter().take(count + 2).find(|error| !error.is_null())
+   {
+      return Err(RemoteError::Failed(error_message(Some(error))));
+   }
+   let results = batch.get("step_results").and_then(Value::as_array).cloned().unwrap_or_default();
+   if results.get(count + 1).is_none_or(Value::is_null)
+   {
+      return Err(RemoteError::Failed("The libSQL transaction wasn't committed".to_string()));
+   }
+   Ok(results[1..=count].to_vec())
+}
+
+fn error_message(error: Option<&Value>) -> String
+//-----------------------------------------------
+{
+   error.and_then(|e| e.get("message")).and_then(Value::as_str).unwrap_or("Unknown libSQL error").to_string()
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use secrecy::SecretString;
+   use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+   #[test]
+   fn test_http_url()
+   {
+      assert!(is_libsql_url("libsql://history-me.turso.io"));
+      assert!(!is_libsql_url("https://history-me.turso.io"));
+      assert_eq!(http_url("libsql://history-me.turso.io/"), Ok("https://history-me.turso.io".to_string()));
+      assert_eq!(http_url("libsql://localhost:8080?tls=0"), Ok("http://localhost:8080".to_string()));
+      assert!(http_url("libsql://localhost:8080?tls=0&authToken=abc").is_err(), "The token isn't kept in the URL");
+   }
+
+   #[test]
+   fn test_values()
+   {
+      let entry = crate::store::imported_entry("ls", "/", 1767000000, "bash", 2, None).unwrap();
+      let arguments = entry_arguments(&entry);
+      assert_eq!(arguments.len(), HISTORY_COLUMNS.split(',').count());
+      assert_eq!(arguments[0], text(&entry.id));
+      assert_eq!(arguments[8], json!({ "type": "integer", "value": "2" }), "exit_status");
+      assert_eq!(arguments[10], json!({ "type": "null" }), "history_no");
+
+      let result = json!({ "cols": [{ "name": "command" }, { "name": "exit_status" }, { "name": "tty" }],
+                           "rows": [[text("ls"), { "type": "integer", "value": "2" }, { "type": "null" }]] });
+      assert_eq!(rows(&result)[0], json!({ "command": "ls", "exit_status": 2, "tty": null }).as_object().unwrap().clone());
+   }
+
+   /// Answer one HTTP request on listener with body, returning the request
+   fn respond_once(listener: tokio::net::TcpListener, body: Value) -> tokio::task::JoinHandle<String>
+   {
+      tokio::spawn(async move
+      {
+         let (mut socket, _) = listener.accept().await.unwrap();
+         let mut request = Vec::new();
+         let mut buf = [0u8; 4096];
+         while !String::from_utf8_lossy(&request).ends_with("}]}")
+         {
+            let n = socket.read(&mut buf).await.unwrap();
+            if n == 0 { break; }
+            request.extend_from_slice(&buf[..n]);
+         }
+         let body = body.to_string();
+         socket.write_all(format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                  body.len(), body).as_bytes()).await.unwrap();
+         String::from_utf8_lossy(&request).to_string()
+      })
+   }
+
+   #[tokio::test]
+   async fn test_libsql_insert()
+   {
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let url = format!("libsql://{}?tls=0", listener.local_addr().unwrap());
+      let server = respond_once(listener, json!({ "baton": null, "results": [{ "type": "ok", "response": { "type": "batch", "result":
+      {
+         "step_results": [{}, { "affected_row_count": 1 }, {}, null],
+         "step_errors": [null, null, null, null],
+      }}}, { "type": "ok", "response": { "type": "close" } }] }));
+
+      let remote = RemoteCentral::new(&url, SecretString::from("t0ken")).unwrap();
+      let entry = crate::store::imported_entry("cargo build", "/src", 1767000000, "bash", 0, None).unwrap();
+      assert_eq!(remote.insert(std::slice::from_ref(&entry)).await.unwrap(), 1);
+      let request = server.await.unwrap();
+      assert!(request.starts_with("POST /v2/pipeline "), "{}", request);
+      assert!(request.to_lowercase().contains("authorization: bearer t0ken"));
+      assert!(request.contains("ON CONFLICT (id) DO NOTHING") && request.contains("cargo build"));
+   }
+
+   #[tokio::test]
+   async fn test_libsql_query_read_only()
+   {
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let url = format!("libsql://{}?tls=0", listener.local_addr().unwrap());
+      let server = respond_once(listener, json!({ "baton": null, "results": [{ "type": "ok", "response": { "type": "describe",
+         "result": { "params": [], "cols": [], "is_explain": false, "is_readonly": false }}},
+         { "type": "ok", "response": { "type": "close" } }] }));
+
+      let remote = RemoteCentral::new(&url, SecretString::from("t0ken")).unwrap();
+      assert!(query(&remote, "DELETE FROM history").await.is_err(), "Only read only queries are run");
+      let request = server.await.unwrap();
+      assert!(request.contains(r#""type":"describe""#) && !request.contains(r#""type":"execute""#), "{}", request);
+   }
+}
@@ -0,0 +1,437 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use secrecy::SecretString;
+use sqlx::{Any, Pool};
+use tracing::{debug, info};
+
+use crate::exclusions::CentralExclusions;
+use crate::remote::{RemoteCentral, RemoteError, is_remote_url};
+use crate::settings::Settings;
+use crate::{HistoryEntry, PoolSettings, create_schema, get_database_with_options, spool};
+
+/// Unix domain socket in the config directory that dejacmd-logd listens on
+pub const SOCKET_FILE: &str = "dejacmd-logd.sock";
+
+/// dejacmd-logd's reply to an entry (a line of JSON) once it has been written (or spooled for the central database)
+pub const ACK: &str = "ok";
+
+/// How long dejacmd-log waits for dejacmd-logd to write an entry before writing it itself
+pub const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// An entry queued for dejacmd-logd's writer with the channel the writer replies on once it has written it, with
+/// the errors if it couldn't be
+pub type Queued = (HistoryEntry, tokio::sync::oneshot::Sender<Result<(), String>>);
+
+pub fn socket_path() -> Result<PathBuf, String>
+//---------------------------------------------
+{
+   let mut path = Settings::get_config_path().map_err(|e| format!("Error getting socket path: {}", e))?;
+   path.push(SOCKET_FILE);
+   Ok(path)
+}
+
+/// Hand an entry to the dejacmd-logd listening on path. An error (usually because it isn't running) means the entry
+/// hasn't been written and should be written directly.
+#[cfg(unix)]
+pub fn send(path: &Path, entry: &HistoryEntry, timeout: Duration) -> Result<(), String>
+//-------------------------------------------------------------------------------------
+{
+   use std::io::{BufRead, BufReader, Write};
+   use std::os::unix::net::UnixStream;
+
+   let mut stream = UnixStream::connect(path).map_err(|e| format!("Error connecting to {}: {}", path.display(), e))?;
+   stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout)))
+      .map_err(|e| format!("Error setting socket timeout: {}", e))?;
+   let mut json = serde_json::to_string(entry).map_err(|e| format!("Error serializing entry {}: {}", entry.id, e))?;
+   json.push('\n');
+   stream.write_all(json.as_bytes()).map_err(|e| format!("Error sending entry to {}: {}", path.display(), e))?;
+   let mut reply = String::new();
+   BufReader::new(stream).read_line(&mut reply).map_err(|e| format!("No reply from {}: {}", path.display(), e))?;
+   match reply.trim()
+   {
+      | ACK => Ok(()),
+      | r => Err(format!("dejacmd-logd rejected entry {}: {}", entry.id, r)),
+   }
+}
+
+#[cfg(not(unix))]
+pub fn send(_path: &Path, _entry: &HistoryEntry, _timeout: Duration) -> Result<(), String>
+//-------------------------------------------------------------------------------------
+{
+   Err("dejacmd-logd needs Unix domain sockets".to_string())
+}
+
+/// Read entries (one JSON object per line) from a dejacmd-log connection, queueing each for the writer and replying
+/// ACK once the writer has written it, or the error for an entry that couldn't be written or a line that isn't one
+#[cfg(unix)]
+pub async fn handle_client(stream: tokio::net::UnixStream, queue: tokio::sync::mpsc::UnboundedSender<Queued>)
+//----------------------------------------------------------------------------------------------------------
+{
+   use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+   let (reader, mut writer) = stream.into_split();
+   let mut lines = BufReader::new(reader).lines();
+   while let Ok(Some(line)) = lines.next_line().await
+   {
+      if line.trim().is_empty()
+      {
+         continue;
+      }
+      let reply = match serde_json::from_str::<HistoryEntry>(&line)
+      {
+         | Ok(entry) =>
+         {
+            let (reply, written) = tokio::sync::oneshot::channel();
+            match queue.send((entry, reply))
+            {
+               | Ok(_) => match written.await
+               {
+                  | Ok(Ok(_)) => ACK.to_string(),
+                  | Ok(Err(e)) => format!("error: {}", e),
+                  | Err(_) => "error: shutting down".to_string(),
+               },
+               | Err(_) => "error: shutting down".to_string(),
+            }
+         },
+         | Err(e) => format!("error: {}", e),
+      };
+      if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err()
+      {
+         break;
+      }
+   }
+}
+
+/// Errors where a database couldn't be reached (as opposed to rejecting the insert)
+pub fn is_connection_error(e: &sqlx::Error) -> bool
+//-------------------------------------------------
+{
+   matches!(e, sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed)
+}
+
+enum Central
+{
+   None,
+   Remote(RemoteCentral),
+   /// Connected on the first entry after it couldn't be
+   Database(Option<(Pool<Any>, String)>),
+}
+
+/// Writes entries to the local and central history with connections that are kept open between entries, applying
+/// the central exclusions and spooling entries for a central database or server that can't be reached as
+/// dejacmd-log does
+pub struct HistoryWriter
+{
+   settings: Settings,
+   pool_settings: PoolSettings,
+   local: Option<(Pool<Any>, String)>,
+   central: Central,
+   exclusions: Result<CentralExclusions, String>,
+}
+
+impl HistoryWriter
+{
+   /// Connect to the configured databases. A database that can't be connected to is retried with the next entry.
+   /// Returns the writer and the connection errors.
+   pub async fn connect(settings: &Settings) -> (Self, Vec<String>)
+   //--------------------------------------------------------------
+   {
+      let pool_settings = PoolSettings::from_settings(settings);
+      let mut errors = Vec::new();
+      let local = connect(settings, true, &pool_settings).await.unwrap_or_else(|e|
+      {
+         errors.push(format!("Error connecting to local database: {}", e));
+         None
+      });
+      let central_url = settings.get_central_database_url();
+      let central = if central_url.trim().is_empty()
+      {
+         Central::None
+      }
+      else if is_remote_url(&central_url)
+      {
+         match RemoteCentral::from_settings(settings)
+         {
+            | Ok(Some(remote)) => Central::Remote(remote),
+            | Ok(None) => Central::None,
+            | Err(e) =>
+            {
+               errors.push(format!("Error creating central server client: {}", e));
+               Central::None
+            },
+         }
+      }
+      else
+      {
+         Central::Database(connect(settings, false, &pool_settings).await.unwrap_or_else(|e|
+         {
+            errors.push(format!("Error connecting to central database: {}", e));
+            None
+         }))
+      };
+      let exclusions = CentralExclusions::from_settings(settings);
+      (HistoryWriter { settings: settings.clone(), pool_settings, local, central, exclusions }, errors)
+   }
+
+   /// Write an entry, returning the errors. An entry whose id is already present is skipped.
+   pub async fn write(&mut self, entry: &HistoryEntry) -> Vec<String>
+   //-----------------------------------------------------------------
+   {
+      self.write_batch(std::slice::from_ref(entry)).await
+   }
+
+   /// Write entries in a single transaction for each database, returning the errors. Entries whose id is already
+   /// present are skipped.
+   pub async fn write_batch(&mut self, entries: &[HistoryEntry]) -> Vec<String>
+   //--------------------------------------------------------------------------
+   {
+      let mut errors = Vec::new();
+      if entries.is_empty()
+      {
+         return errors;
+      }
+      let described = match entries
+      {
+         | [entry] => entry.id.clone(),
+         | _ => format!("{} commands", entries.len()),
+      };
+      if self.local.is_none() && !self.settings.get_local_database_url().trim().is_empty()
+      {
+         match connect(&self.settings, true, &self.pool_settings).await
+         {
+            | Ok(local) => self.local = local,
+            | Err(e) => errors.push(format!("Error connecting to local database: {}", e)),
+         }
+      }
+      if let Some((pool, scheme)) = &self.local
+      {
+         match upsert_all(pool, scheme, entries).await
+         {
+            // Commands dejacmd-log ran out of time writing to the local database
+            | Ok(_) => match spool::flush_local(pool, scheme).await
+            {
+               | Ok(0) => {},
+               | Ok(n) => info!("Flushed {} commands spooled for the local database", n),
+               | Err(e) => errors.push(format!("Error flushing commands spooled for the local database: {}", e)),
+            },
+            | Err(e) => errors.push(format!("Error inserting {} into local database: {}", described, e)),
+         }
+      }
+
+      if matches!(self.central, Central::None)
+      {
+         return errors;
+      }
+      let entries: Vec<HistoryEntry> = match &self.exclusions
+      {
+         | Ok(exclusions) => entries.iter().filter(|entry| !exclusions.is_excluded_entry(entry)).cloned().collect(),
+         | Err(e) =>
+         {
+            // Rather not log to central than risk leaking a command that should have been excluded
+            errors.push(format!("Not logging to central database: {}", e));
+            return errors;
+         },
+      };
+      if entries.is_empty()
+      {
+         return errors;
+      }
+      match &mut self.central
+      {
+         | Central::None => {},
+         | Central::Remote(remote) =>
+         {
+            match remote.insert(&entries).await
+            {
+               | Ok(_) => match spool::flush_remote(remote).await
+               {
+                  | Ok(0) => {},
+                  | Ok(n) => info!("Flushed {} spooled commands to the central server", n),
+                  | Err(e) => errors.push(format!("Error flushing spooled commands: {}", e)),
+               },
+               | Err(RemoteError::Unreachable(e)) => spool_entries(&entries, &format!("Central server unavailable ({})", e), &mut errors),
+               | Err(e) => errors.push(format!("Error inserting {} into central server: {}", described, e)),
+            }
+         },
+         | Central::Database(database) =>
+         {
+            if database.is_none()
+            {
+               match connect(&self.settings, false, &self.pool_settings).await
+               {
+                  | Ok(connected) => *database = connected,
+                  | Err(e) =>
+                  {
+                     spool_entries(&entries, &format!("Central database unavailable ({})", e), &mut errors);
+                     return errors;
+                  },
+               }
+            }
+            if let Some((pool, scheme)) = database
+            {
+               match upsert_all(pool, scheme, &entries).await
+               {
+                  | Ok(_) => match spool::flush(pool, scheme).await
+                  {
+                     | Ok(0) => {},
+                     | Ok(n) => info!("Flushed {} spooled commands to the central database", n),
+                     | Err(e) => errors.push(format!("Error flushing spooled commands: {}", e)),
+                  },
+                  | Err(e) if is_connection_error(&e) => spool_entries(&entries, &format!("Central database unavailable ({})", e), &mut errors),
+                  | Err(e) => errors.push(format!("Error inserting {} into central database: {}", described, e)),
+               }
+            }
+         },
+      }
+      errors
+   }
+
+   pub async fn close(self)
+   //----------------------
+   {
+      if let Some((pool, _)) = self.local
+      {
+         pool.close().await;
+      }
+      if let Central::Database(Some((pool, _))) = self.central
+      {
+         pool.close().await;
+      }
+   }
+}
+
+/// Connect to the local or central database and create or update its schema. None if it isn't configured.
+async fn connect(settings: &Settings, is_local: bool, pool_settings: &PoolSettings) -> Result<Option<(Pool<Any>, String)>, String>
+//------------------------------------------------------------------------------------------------------------------------------
+{
+   let url = if is_local { settings.get_local_database_url() } else { settings.get_central_database_url() };
+   let (user, password) = settings.get_credentials(is_local).unwrap_or_else(|_| (String::new(), SecretString::default()));
+   let (pool, scheme) = get_database_with_options(&url, &user, &password, pool_settings).await.map_err(|e| e.to_string())?;
+   match pool
+   {
+      | Some(pool) =>
+      {
+         create_schema(&pool, &scheme).await.map_err(|e| e.to_string())?;
+         debug!("Connected to {} {} database", if is_local { "local" } else { "central" }, crate::ddl::dialect(&scheme));
+         Ok(Some((pool, scheme)))
+      },
+      | None => Ok(None),
+   }
+}
+
+/// Insert the entries that aren't already present in a single transaction, returning the number inserted
+async fn upsert_all(pool: &Pool<Any>, scheme: &str, entries: &[HistoryEntry]) -> Result<usize, sqlx::Error>
+//----------------------------------------------------------------------------------------------------------
+{
+   let mut transaction = pool.begin().await?;
+   let mut inserted = 0;
+   for entry in entries
+   {
+      if entry.upsert(&mut *transaction, scheme).await?
+      {
+         inserted += 1;
+      }
+   }
+   transaction.commit().await?;
+   Ok(inserted)
+}
+
+fn spool_entries(entries: &[HistoryEntry], reason: &str, errors: &mut Vec<String>)
+//---------------------------------------------------------------------------------
+{
+   if let Err(e) = entries.iter().try_for_each(spool::append)
+   {
+      errors.push(format!("{} and the commands couldn't be spooled: {}", reason, e));
+      return;
+   }
+   info!("{}, {} spooled for later", reason, if entries.len() == 1 { "command".to_string() } else { format!("{} commands", entries.len()) });
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn entry(id: &str, command: &str) -> HistoryEntry
+   {
+      HistoryEntry
+      {
+         cwd: Some("/tmp".to_string()), shell: Some("bash".to_string()), history_no: Some(1), duration_ms: Some(12),
+         ..HistoryEntry::new_for_test(id, "2026-01-11 04:33:25", command)
+      }
+   }
+
+   fn temp_dir() -> PathBuf
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_logd_test_{}", nanos));
+      std::fs::create_dir_all(&dir).unwrap();
+      dir
+   }
+
+   #[tokio::test]
+   async fn test_history_writer()
+   {
+      let dir = temp_dir();
+      let mut settings = Settings::new_for_test(&format!("sqlite://{}", dir.join("local.db").display()),
+                                                &format!("sqlite://{}", dir.join("central.db").display()));
+      settings.central_exclude_commands = Some(vec!["vault *".to_string()]);
+      let (mut writer, errors) = HistoryWriter::connect(&settings).await;
+      assert!(errors.is_empty(), "{:?}", errors);
+
+      assert!(writer.write(&entry("a", "make")).await.is_empty());
+      assert!(writer.write(&entry("b", "vault login")).await.is_empty());
+      assert!(writer.write(&entry("a", "make")).await.is_empty(), "An entry already written is skipped");
+      let count = async |pool: &Pool<Any>| sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM history").fetch_one(pool).await;
+      let (local, _) = writer.local.clone().unwrap();
+      let Central::Database(Some((central, _))) = &writer.central else { panic!("Central database not connected") };
+      let central = central.clone();
+      assert_eq!(count(&local).await.unwrap(), 2);
+      assert_eq!(count(&central).await.unwrap(), 1, "Excluded commands aren't written to the central database");
+      let row = sqlx::query(&format!("SELECT {} FROM history WHERE id = 'a'", crate::HISTORY_COLUMNS))
+         .fetch_one(&central).await.unwrap();
+      assert_eq!(HistoryEntry::from_row(&row), entry("a", "make"));
+
+      let batch = [entry("c", "ls"), entry("a", "make"), entry("d", "vault read x"), entry("e", "pwd")];
+      assert!(writer.write_batch(&batch).await.is_empty());
+      assert_eq!(count(&local).await.unwrap(), 5);
+      assert_eq!(count(&central).await.unwrap(), 3, "Excluded and existing commands are skipped in a batch");
+      assert!(writer.write_batch(&[]).await.is_empty());
+
+      writer.close().await;
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+
+   #[cfg(unix)]
+   #[tokio::test]
+   async fn test_send()
+   {
+      let dir = temp_dir();
+      let path = dir.join(SOCKET_FILE);
+      assert!(send(&path, &entry("a", "ls"), SEND_TIMEOUT).is_err(), "Nothing listening");
+
+      let listener = tokio::net::UnixListener::bind(&path).unwrap();
+      let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel();
+      tokio::spawn(async move
+      {
+         while let Ok((stream, _)) = listener.accept().await
+         {
+            tokio::spawn(handle_client(stream, queue.clone()));
+         }
+      });
+      tokio::spawn(async move
+      {
+         while let Some((entry, reply)) = queued.recv().await
+         {
+            let _ = reply.send(if entry.id == "a" { Ok(()) } else { Err("Error inserting b".to_string()) });
+         }
+      });
+      let sent = path.clone();
+      let results = tokio::task::spawn_blocking(move || (send(&sent, &entry("a", "ls"), SEND_TIMEOUT),
+                                                         send(&sent, &entry("b", "ls"), SEND_TIMEOUT))).await.unwrap();
+      assert!(results.0.is_ok(), "{:?}", results.0);
+      assert!(results.1.unwrap_err().contains("Error inserting b"), "Only acknowledged once written");
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
@@ -0,0 +1,523 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::{EnvFilter, fmt::writer::{BoxMakeWriter, MakeWriter}};
+
+/// Environment variable that overrides the -v/--debug derived level with a tracing filter directive
+/// e.g DEJACMD_LOG=dejacmd=trace,sqlx=warn
+pub const LOG_ENV: &str = "DEJACMD_LOG";
+
+/// Map -v/-vv/-vvv counts and --debug onto a tracing level (warn when neither is given).
+pub fn verbosity_level(verbosity: u8, is_debug: bool) -> Level
+//-------------------------------------------------------------
+{
+   if is_debug
+   {
+      return Level::TRACE;
+   }
+   match verbosity
+   {
+      | 0 => Level::WARN,
+      | 1 => Level::INFO,
+      | 2 => Level::DEBUG,
+      | _ => Level::TRACE,
+   }
+}
+
+/// Syslog socket on Linux and most BSDs, then on macOS
+#[cfg(unix)]
+const SYSLOG_SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// A system log that can be given as a log destination instead of stderr, stdout or a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemLog
+{
+   Syslog,
+   Journald,
+   EventLog,
+}
+
+impl SystemLog
+{
+   /// The system log for a destination of "syslog", "journald" or "eventlog". The Windows Event Log is used for any of
+   /// them on Windows and syslog for journald where there is no journald. None for stderr, stdout or a file.
+   pub fn from_destination(destination: &str) -> Option<SystemLog>
+   //--------------------------------------------------------------
+   {
+      let destination = destination.trim().to_lowercase();
+      if !["syslog", "journald", "eventlog"].contains(&destination.as_str())
+      {
+         return None;
+      }
+      if cfg!(target_os = "windows")
+      {
+         Some(SystemLog::EventLog)
+      }
+      else if cfg!(target_os = "linux") && destination == "journald"
+      {
+         Some(SystemLog::Journald)
+      }
+      else
+      {
+         Some(SystemLog::Syslog)
+      }
+   }
+
+   /// Write a message from program (e.g dejacmd-log) with the severity of level. Colours are removed.
+   pub fn write(&self, program: &str, level: Level, message: &str) -> Result<(), String>
+   //-----------------------------------------------------------------------------------
+   {
+      let message = strip_ansi(message);
+      match self
+      {
+         | SystemLog::Syslog => syslog(&syslog_message(program, level, &message)),
+         | SystemLog::Journald => journald(&journald_message(program, level, &message)),
+         | SystemLog::EventLog => event_log(program, level, &message),
+      }
+   }
+}
+
+/// Syslog severity (RFC 5424) for a tracing level
+fn severity(level: Level) -> u8
+//-----------------------------
+{
+   match level
+   {
+      | Level::ERROR => 3,
+      | Level::WARN => 4,
+      | Level::INFO => 6,
+      | _ => 7,
+   }
+}
+
+fn strip_ansi(message: &str) -> String
+//------------------------------------
+{
+   let mut stripped = String::with_capacity(message.len());
+   let mut chars = message.chars();
+   while let Some(c) = chars.next()
+   {
+      if c == '\x1b'
+      {
+         // CSI sequences such as colours end with a letter
+         let _ = chars.by_ref().find(|c| c.is_ascii_alphabetic());
+      }
+      else
+      {
+         stripped.push(c);
+      }
+   }
+   stripped
+}
+
+/// A message for the local syslog socket in the BSD (RFC 3164) format with the user facility, without the time which
+/// the syslog daemon adds
+fn syslog_message(program: &str, level: Level, message: &str) -> String
+//---------------------------------------------------------------------
+{
+   format!("<{}>{}[{}]: {}", 8 + severity(level), program, std::process::id(), message)
+}
+
+#[cfg(unix)]
+fn syslog(message: &str) -> Result<(), String>
+//---------------------------------------------
+{
+   let socket = std::os::unix::net::UnixDatagram::unbound().map_err(|e| format!("Error creating syslog socket: {}", e))?;
+   let mut error = String::new();
+   for path in SYSLOG_SOCKETS
+   {
+      match socket.send_to(message.as_bytes(), path)
+      {
+         | Ok(_) => return Ok(()),
+         | Err(e) => error = format!("Error writing to syslog ({}): {}", path, e),
+      }
+   }
+   Err(error)
+}
+
+#[cfg(not(unix))]
+fn syslog(_message: &str) -> Result<(), String>
+//----------------------------------------------
+{
+   Err("syslog isn't available on this platform".to_string())
+}
+
+/// A message in the journald native protocol. Values containing a newline are written with their length instead of
+/// after an =.
+fn journald_message(program: &str, level: Level, message: &str) -> Vec<u8>
+//------------------------------------------------------------------------
+{
+   let mut datagram = Vec::new();
+   let fields = [("MESSAGE", message.to_string()), ("PRIORITY", severity(level).to_string()),
+                 ("SYSLOG_IDENTIFIER", program.to_string()), ("SYSLOG_PID", std::process::id().to_string())];
+   for (name, value) in fields
+   {
+      datagram.extend_from_slice(name.as_bytes());
+      if value.contains('\n')
+      {
+         datagram.push(b'\n');
+         datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+      }
+      else
+      {
+         datagram.push(b'=');
+      }
+      datagram.extend_from_slice(value.as_bytes());
+      datagram.push(b'\n');
+   }
+   datagram
+}
+
+#[cfg(target_os = "linux")]
+fn journald(datagram: &[u8]) -> Result<(), String>
+//-------------------------------------------------
+{
+   let socket = std::os::unix::net::UnixDatagram::unbound().map_err(|e| format!("Error creating journald socket: {}", e))?;
+   socket.send_to(datagram, JOURNALD_SOCKET).map_err(|e| format!("Error writing to journald ({}): {}", JOURNALD_SOCKET, e))?;
+   Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn journald(_datagram: &[u8]) -> Result<(), String>
+//--------------------------------------------------
+{
+   Err("journald isn't available on this platform".to_string())
+}
+
+#[cfg(target_os = "windows")]
+fn event_log(program: &str, level: Level, message: &str) -> Result<(), String>
+//----------------------------------------------------------------------------
+{
+   use windows_sys::Win32::System::EventLog::{DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+                                              EVENTLOG_WARNING_TYPE, RegisterEventSourceW, ReportEventW};
+
+   let wide = |s: &str| s.encode_utf16().chain(std::iter::once(0)).collect::<Vec<u16>>();
+   let source = wide(program);
+   let text = wide(message);
+   let event_type = match level
+   {
+      | Level::ERROR => EVENTLOG_ERROR_TYPE,
+      | Level::WARN => EVENTLOG_WARNING_TYPE,
+      | _ => EVENTLOG_INFORMATION_TYPE,
+   };
+   // SAFETY: The strings are NUL terminated and outlive the calls, and the handle is checked before use and deregistered
+   unsafe
+   {
+      let handle = RegisterEventSourceW(std::ptr::null(), source.as_ptr());
+      if handle.is_null()
+      {
+         return Err(format!("Error registering Event Log source {}: {}", program, std::io::Error::last_os_error()));
+      }
+      let strings = [text.as_ptr()];
+      let is_reported = ReportEventW(handle, event_type, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null()) != 0;
+      let error = std::io::Error::last_os_error();
+      DeregisterEventSource(handle);
+      if is_reported { Ok(()) } else { Err(format!("Error writing to the Event Log: {}", error)) }
+   }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn event_log(_program: &str, _level: Level, _message: &str) -> Result<(), String>
+//-------------------------------------------------------------------------------
+{
+   Err("The Event Log is only available on Windows".to_string())
+}
+
+/// Makes a writer per tracing event that sends the formatted event to a system log when it is dropped
+struct SystemLogWriter
+{
+   log: SystemLog,
+   program: String,
+}
+
+struct SystemLogEvent
+{
+   log: SystemLog,
+   program: String,
+   level: Level,
+   buffer: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for SystemLogWriter
+{
+   type Writer = SystemLogEvent;
+
+   fn make_writer(&'a self) -> Self::Writer
+   //---------------------------------------
+   {
+      SystemLogEvent { log: self.log, program: self.program.clone(), level: Level::INFO, buffer: Vec::new() }
+   }
+
+   fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer
+   //----------------------------------------------------------------
+   {
+      SystemLogEvent { log: self.log, program: self.program.clone(), level: *meta.level(), buffer: Vec::new() }
+   }
+}
+
+impl std::io::Write for SystemLogEvent
+{
+   fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+   //--------------------------------------------------------
+   {
+      self.buffer.extend_from_slice(buf);
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+impl Drop for SystemLogEvent
+{
+   fn drop(&mut self)
+   //----------------
+   {
+      let message = String::from_utf8_lossy(&self.buffer);
+      let message = message.trim_end();
+      if !message.is_empty() && let Err(e) = self.log.write(&self.program, self.level, message)
+      {
+         eprintln!("{} ({})", message, e);
+      }
+   }
+}
+
+/// Size based rotation of a log file, keeping files old logs as <file>.1 (the newest) to <file>.N
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation
+{
+   /// 0 never rotates
+   pub max_bytes: u64,
+   pub files: u32,
+}
+
+impl Default for Rotation
+{
+   /// 10 MB, keeping 3 old logs
+   fn default() -> Self { Rotation { max_bytes: 10 * 1024 * 1024, files: 3 } }
+}
+
+impl Rotation
+{
+   pub fn new(max_megabytes: u64, files: u32) -> Self
+   //-------------------------------------------------
+   {
+      Rotation { max_bytes: max_megabytes.saturating_mul(1024 * 1024), files }
+   }
+}
+
+/// Rotate the log file at path if adding bytes would take it past the maximum size: the oldest is removed and the rest
+/// renumbered with path becoming path.1. Without any files kept path is just removed. True if it was rotated.
+pub fn rotate(path: &Path, rotation: &Rotation, bytes: u64) -> std::io::Result<bool>
+//----------------------------------------------------------------------------------
+{
+   if rotation.max_bytes == 0
+   {
+      return Ok(false);
+   }
+   let size = match std::fs::metadata(path)
+   {
+      | Ok(m) => m.len(),
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+      | Err(e) => return Err(e),
+   };
+   if size == 0 || size + bytes <= rotation.max_bytes
+   {
+      return Ok(false);
+   }
+   if rotation.files == 0
+   {
+      std::fs::remove_file(path)?;
+      return Ok(true);
+   }
+   let numbered = |n: u32|
+   {
+      let mut numbered = path.as_os_str().to_owned();
+      numbered.push(format!(".{}", n));
+      PathBuf::from(numbered)
+   };
+   let _ = std::fs::remove_file(numbered(rotation.files));
+   for n in (1..rotation.files).rev()
+   {
+      if numbered(n).exists()
+      {
+         std::fs::rename(numbered(n), numbered(n + 1))?;
+      }
+   }
+   std::fs::rename(path, numbered(1))?;
+   Ok(true)
+}
+
+/// Appends to a log file, rotating it first when a write would take it past the maximum size. The file is opened for
+/// each write so it follows a rotation by another process logging to the same file (e.g dejacmd-log from every shell).
+#[derive(Debug, Clone)]
+pub struct RotatingFile
+{
+   path: PathBuf,
+   rotation: Rotation,
+}
+
+impl RotatingFile
+{
+   pub fn new(path: &Path, rotation: &Rotation) -> Self
+   //---------------------------------------------------
+   {
+      RotatingFile { path: path.to_path_buf(), rotation: *rotation }
+   }
+
+   pub fn append(&self, bytes: &[u8]) -> std::io::Result<()>
+   //--------------------------------------------------------
+   {
+      // A failed rotation (e.g another process rotated it first) shouldn't lose the message
+      let _ = rotate(&self.path, &self.rotation, bytes.len() as u64);
+      OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(bytes)
+   }
+}
+
+impl Write for RotatingFile
+{
+   fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+   //--------------------------------------------------------
+   {
+      self.append(buf)?;
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+/// Install the global tracing subscriber used by the library and binaries.
+/// destination is "stderr", "stdout", "syslog", "journald", "eventlog" (see SystemLog::from_destination) or a path to a
+/// file that is appended to and rotated as given. If the file can't be opened diagnostics fall back to stderr. Calling
+/// this more than once (e.g from tests) is harmless.
+pub fn init_logging(verbosity: u8, is_debug: bool, destination: &str, rotation: &Rotation)
+//---------------------------------------------------------------------------------------
+{
+   let level = verbosity_level(verbosity, is_debug);
+   let filter = match EnvFilter::try_from_env(LOG_ENV)
+   {
+      | Ok(f) => f,
+      | Err(_) => EnvFilter::new(level.as_str().to_lowercase())
+   };
+
+   let mut is_file = false;
+   let system_log = SystemLog::from_destination(destination);
+   let writer = if destination.eq_ignore_ascii_case("stderr") || destination.trim().is_empty()
+   {
+      BoxMakeWriter::new(std::io::stderr)
+   }
+   else if let Some(log) = system_log
+   {
+      // The identifier the messages are logged with, e.g dejacmd-log
+      let program = std::env::args().next()
+         .and_then(|arg0| std::path::Path::new(&arg0).file_stem().map(|s| s.to_string_lossy().to_string()))
+         .unwrap_or_else(|| "dejacmd".to_string());
+      BoxMakeWriter::new(SystemLogWriter { log, program })
+   }
+   else if destination.eq_ignore_ascii_case("stdout")
+   {
+      BoxMakeWriter::new(std::io::stdout)
+   }
+   else
+   {
+      match OpenOptions::new().create(true).append(true).open(destination)
+      {
+         | Ok(f) =>
+         {
+            drop(f);
+            is_file = true;
+            let file = RotatingFile::new(Path::new(destination), rotation);
+            BoxMakeWriter::new(move || file.clone())
+         }
+         | Err(e) =>
+         {
+            eprintln!("Error opening log file {}: {} (logging to stderr)", destination, e);
+            BoxMakeWriter::new(std::io::stderr)
+         }
+      }
+   };
+
+   let builder = tracing_subscriber::fmt()
+      .with_env_filter(filter)
+      .with_writer(writer)
+      .with_ansi(!is_file && system_log.is_none())
+      // A system log records the level and time itself
+      .with_level(system_log.is_none())
+      .with_target(is_debug)
+      .with_file(is_debug)
+      .with_line_number(is_debug);
+   // Timestamps only add noise on a terminal but are needed to make sense of a log file
+   let _ = if is_file { builder.try_init() } else { builder.without_time().try_init() };
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_verbosity_level()
+   {
+      assert_eq!(verbosity_level(0, false), Level::WARN);
+      assert_eq!(verbosity_level(1, false), Level::INFO);
+      assert_eq!(verbosity_level(2, false), Level::DEBUG);
+      assert_eq!(verbosity_level(5, false), Level::TRACE);
+      assert_eq!(verbosity_level(0, true), Level::TRACE);
+   }
+
+   #[test]
+   fn test_system_log()
+   {
+      assert_eq!(SystemLog::from_destination("stderr"), None);
+      assert_eq!(SystemLog::from_destination("/tmp/syslog"), None);
+      if cfg!(target_os = "windows")
+      {
+         assert_eq!(SystemLog::from_destination("syslog"), Some(SystemLog::EventLog));
+      }
+      else
+      {
+         assert_eq!(SystemLog::from_destination("Syslog"), Some(SystemLog::Syslog));
+         let journald = if cfg!(target_os = "linux") { SystemLog::Journald } else { SystemLog::Syslog };
+         assert_eq!(SystemLog::from_destination("journald"), Some(journald));
+      }
+
+      assert_eq!(strip_ansi("\x1b[31mError\x1b[0m: x"), "Error: x");
+      let pid = std::process::id();
+      assert_eq!(syslog_message("dejacmd-log", Level::ERROR, "failed"), format!("<11>dejacmd-log[{}]: failed", pid));
+      assert_eq!(syslog_message("dejacmd-log", Level::DEBUG, "x"), format!("<15>dejacmd-log[{}]: x", pid));
+      let mut expected = b"MESSAGE\n".to_vec();
+      expected.extend_from_slice(&4u64.to_le_bytes());
+      expected.extend_from_slice(format!("a\nbc\nPRIORITY=4\nSYSLOG_IDENTIFIER=dejacmd-logd\nSYSLOG_PID={}\n", pid).as_bytes());
+      assert_eq!(journald_message("dejacmd-logd", Level::WARN, "a\nbc"), expected);
+      assert!(journald_message("x", Level::INFO, "ok").starts_with(b"MESSAGE=ok\nPRIORITY=6\n"));
+   }
+
+   #[test]
+   fn test_rotate()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_rotate_test_{}", nanos));
+      std::fs::create_dir_all(&dir).unwrap();
+      let path = dir.join("dejacmd.log");
+      let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+      let log = RotatingFile::new(&path, &Rotation { max_bytes: 10, files: 2 });
+      for line in ["aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeee\n", "ffff\n", "gggg\n"]
+      {
+         log.append(line.as_bytes()).unwrap();
+      }
+      assert_eq!(read("dejacmd.log"), "gggg\n");
+      assert_eq!(read("dejacmd.log.1"), "eeee\nffff\n");
+      assert_eq!(read("dejacmd.log.2"), "cccc\ndddd\n");
+      assert!(!dir.join("dejacmd.log.3").exists(), "Only 2 old logs are kept");
+
+      assert!(!rotate(&path, &Rotation { max_bytes: 0, files: 2 }, 100).unwrap(), "0 never rotates");
+      assert!(!rotate(&dir.join("missing.log"), &Rotation::new(1, 2), 100).unwrap());
+      assert!(rotate(&path, &Rotation { max_bytes: 5, files: 0 }, 1).unwrap());
+      assert!(!path.exists() && read("dejacmd.log.1") == "eeee\nffff\n", "Without old logs the log is just removed");
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
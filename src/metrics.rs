@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::HistoryEntry;
+
+/// Path of the Prometheus metrics served by dejacmd serve --metrics
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bounds in seconds of the database latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Debug, Default)]
+struct Histogram
+{
+   /// Observations in each bucket (not cumulative, rendering adds them up)
+   buckets: [u64; LATENCY_BUCKETS.len()],
+   count: u64,
+   sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Values
+{
+   /// Commands inserted by (shell, host)
+   logged: BTreeMap<(String, String), u64>,
+   insert_failures: u64,
+   /// Database latency by operation
+   latencies: BTreeMap<String, Histogram>,
+}
+
+/// Counters for a dejacmd server, rendered in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics
+{
+   values: Mutex<Values>,
+}
+
+impl Metrics
+{
+   pub fn new() -> Self { Metrics::default() }
+
+   /// Count a command written to the database
+   pub fn logged(&self, entry: &HistoryEntry)
+   //----------------------------------------
+   {
+      let key = (entry.shell.clone().unwrap_or_default(), entry.hostname.clone().unwrap_or_default());
+      *self.lock().logged.entry(key).or_default() += 1;
+   }
+
+   /// Count an insert request that failed
+   pub fn insert_failed(&self)
+   //-------------------------
+   {
+      self.lock().insert_failures += 1;
+   }
+
+   /// Record how long a database operation (insert, search etc.) took
+   pub fn observe(&self, operation: &str, elapsed: Duration)
+   //--------------------------------------------------------
+   {
+      let seconds = elapsed.as_secs_f64();
+      let mut values = self.lock();
+      let histogram = values.latencies.entry(operation.to_string()).or_default();
+      if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound)
+      {
+         histogram.buckets[i] += 1;
+      }
+      histogram.count += 1;
+      histogram.sum += seconds;
+   }
+
+   /// The metrics in the Prometheus text exposition format
+   pub fn render(&self) -> String
+   //---------------------------------------------
+   {
+      let values = self.lock();
+      let mut text = String::new();
+      text.push_str("# HELP dejacmd_commands_logged_total Commands written to the database by this server\n");
+      text.push_str("# TYPE dejacmd_commands_logged_total counter\n");
+      for ((shell, host), count) in &values.logged
+      {
+         let _ = writeln!(text, "dejacmd_commands_logged_total{{shell=\"{}\",host=\"{}\"}} {}", label(shell), label(host), count);
+      }
+      text.push_str("# HELP dejacmd_insert_failures_total Insert requests that failed\n");
+      text.push_str("# TYPE dejacmd_insert_failures_total counter\n");
+      let _ = writeln!(text, "dejacmd_insert_failures_total {}", values.insert_failures);
+      text.push_str("# HELP dejacmd_db_duration_seconds Time taken by database operations\n");
+      text.push_str("# TYPE dejacmd_db_duration_seconds histogram\n");
+      for (operation, histogram) in &values.latencies
+      {
+         let operation = label(operation);
+         let mut cumulative = 0;
+         for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets)
+         {
+            cumulative += count;
+            let _ = writeln!(text, "dejacmd_db_duration_seconds_bucket{{operation=\"{}\",le=\"{}\"}} {}", operation, bound, cumulative);
+         }
+         let _ = writeln!(text, "dejacmd_db_duration_seconds_bucket{{operation=\"{}\",le=\"+Inf\"}} {}", operation, histogram.count);
+         let _ = writeln!(text, "dejacmd_db_duration_seconds_sum{{operation=\"{}\"}} {}", operation, histogram.sum);
+         let _ = writeln!(text, "dejacmd_db_duration_seconds_count{{operation=\"{}\"}} {}", operation, histogram.count);
+      }
+      text
+   }
+
+   fn lock(&self) -> std::sync::MutexGuard<'_, Values>
+   //-------------------------------------------------
+   {
+      self.values.lock().unwrap_or_else(|e| e.into_inner())
+   }
+}
+
+/// Escape a label value for the text format
+fn label(value: &str) -> String
+//-----------------------------
+{
+   value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_render()
+   {
+      let metrics = Metrics::new();
+      let mut entry = crate::store::imported_entry("ls", "/", 1767000000, "bash", 0, None).unwrap();
+      entry.hostname = Some("build\"01".to_string());
+      metrics.logged(&entry);
+      metrics.logged(&entry);
+      metrics.insert_failed();
+      metrics.observe("insert", Duration::from_millis(3));
+      metrics.observe("insert", Duration::from_secs(5));
+
+      let text = metrics.render();
+      assert!(text.contains("dejacmd_commands_logged_total{shell=\"bash\",host=\"build\\\"01\"} 2\n"), "{}", text);
+      assert!(text.contains("dejacmd_insert_failures_total 1\n"));
+      assert!(text.contains("dejacmd_db_duration_seconds_bucket{operation=\"insert\",le=\"0.0025\"} 0\n"));
+      assert!(text.contains("dejacmd_db_duration_seconds_bucket{operation=\"insert\",le=\"0.005\"} 1\n"));
+      assert!(text.contains("dejacmd_db_duration_seconds_bucket{operation=\"insert\",le=\"2.5\"} 1\n"));
+      assert!(text.contains("dejacmd_db_duration_seconds_bucket{operation=\"insert\",le=\"+Inf\"} 2\n"));
+      assert!(text.contains("dejacmd_db_duration_seconds_count{operation=\"insert\"} 2\n"));
+   }
+}
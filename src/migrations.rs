@@ -0,0 +1,347 @@
+use chrono::Utc;
+use include_dir::{include_dir, Dir, File};
+use sqlx::{Any, Pool, Row};
+use tracing::debug;
+
+use crate::fix_placeholders;
+
+/// Schema update files named NNNNNNN.sql, applied in filename order on top of CREATE_TABLE_SQL
+pub static ASSETS_DIR: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/assets");
+
+/// Records each statement of an assets/NNNNNNN.sql update file once it has been applied, so a file that fails
+/// part way through (or that partly commits because the backend auto-commits DDL e.g MySQL) is resumed rather
+/// than re-run from the start.
+pub const CREATE_MIGRATIONS_TABLE_SQL: &str =
+r#" CREATE TABLE IF NOT EXISTS dejacmd_migrations
+(
+   filename VARCHAR(255) NOT NULL,
+   statement_no BIGINT NOT NULL,
+   applied_at TEXT NOT NULL,
+   PRIMARY KEY (filename, statement_no)
+)"#;
+
+pub const INSERT_MIGRATION_SQL: &str =
+r#"INSERT INTO dejacmd_migrations (filename, statement_no, applied_at) VALUES ( ?, ?, ? )"#;
+
+/// Split the contents of a SQL file into individual statements on `;` boundaries. Semicolons inside
+/// single or double quoted literals, `--` and `/* */` comments and Postgres `$tag$` quoted bodies are not
+/// treated as boundaries. Comment-only and empty statements are dropped.
+pub fn split_sql_statements(sql: &str) -> Vec<String>
+//----------------------------------------------------
+{
+   let chars: Vec<char> = sql.chars().collect();
+   let mut statements = Vec::new();
+   let mut current = String::new();
+   let mut has_code = false; // current contains something other than whitespace and comments
+   let mut i = 0;
+   while i < chars.len()
+   {
+      let c = chars[i];
+      let next = chars.get(i + 1).copied();
+      match c
+      {
+         | '\'' | '"' | '`' =>
+         {
+            // Quoted literal or identifier, a doubled quote is an escaped quote
+            current.push(c);
+            i += 1;
+            while i < chars.len()
+            {
+               current.push(chars[i]);
+               if chars[i] == c
+               {
+                  if chars.get(i + 1) == Some(&c)
+                  {
+                     current.push(c);
+                     i += 2;
+                     continue;
+                  }
+                  break;
+               }
+               i += 1;
+            }
+            has_code = true;
+         }
+         | '-' if next == Some('-') =>
+         {
+            while i < chars.len() && chars[i] != '\n'
+            {
+               current.push(chars[i]);
+               i += 1;
+            }
+            continue;
+         }
+         | '/' if next == Some('*') =>
+         {
+            current.push_str("/*");
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/'))
+            {
+               current.push(chars[i]);
+               i += 1;
+            }
+            if i < chars.len()
+            {
+               current.push_str("*/");
+               i += 1;
+            }
+         }
+         | '$' =>
+         {
+            // Postgres dollar quoting: $$ ... $$ or $tag$ ... $tag$
+            let tag_end = chars[i + 1..].iter().position(|ch| !(ch.is_alphanumeric() || *ch == '_'));
+            match tag_end
+            {
+               | Some(n) if chars[i + 1 + n] == '$' =>
+               {
+                  let tag: String = chars[i..i + n + 2].iter().collect();
+                  let rest: String = chars[i + n + 2..].iter().collect();
+                  let body_len = match rest.find(&tag)
+                  {
+                     | Some(p) => rest[..p].chars().count() + tag.chars().count(),
+                     | None => rest.chars().count(),
+                  };
+                  let end = (i + n + 2 + body_len).min(chars.len());
+                  current.extend(&chars[i..end]);
+                  i = end;
+                  has_code = true;
+                  continue;
+               }
+               | _ =>
+               {
+                  current.push(c);
+                  has_code = true;
+               }
+            }
+         }
+         | ';' =>
+         {
+            if has_code
+            {
+               statements.push(current.trim().to_string());
+            }
+            current.clear();
+            has_code = false;
+         }
+         | _ =>
+         {
+            current.push(c);
+            if !c.is_whitespace()
+            {
+               has_code = true;
+            }
+         }
+      }
+      i += 1;
+   }
+   if has_code
+   {
+      statements.push(current.trim().to_string());
+   }
+   statements
+}
+
+/// The schema update files (NNNNNNN.sql) sorted by name. Database specific variants are found by update_sql.
+pub fn update_files() -> Vec<&'static File<'static>>
+//--------------------------------------------------
+{
+   let mut sql_files: Vec<_> = ASSETS_DIR.files()
+      .filter(|file| {
+         let name = file.path().file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+         name.len() == 11 && name.ends_with(".sql") && name.chars().take(7).all(|c| c.is_ascii_digit())
+      })
+      .collect();
+
+   sql_files.sort_by_key(|file| {
+      file.path().file_name().and_then(|n| n.to_str()).unwrap_or("")
+   });
+   sql_files
+}
+
+/// The SQL of an update file for a database: the NNNNNNN.<dialect>.sql variant (e.g 0000005.postgres.sql, see
+/// ddl::dialect) if there is one, otherwise the NNNNNNN.sql file itself. Either way it's recorded under the
+/// NNNNNNN.sql name so each database applies one or the other.
+pub fn update_sql(file: &'static File<'static>, scheme: &str) -> Option<&'static str>
+//-----------------------------------------------------------------------------------
+{
+   let variant = file.path().with_extension(format!("{}.sql", crate::ddl::dialect(scheme)));
+   ASSETS_DIR.get_file(&variant).unwrap_or(file).contents_utf8()
+}
+
+/// Apply every schema update file that hasn't already been applied to the database (according to
+/// dejacmd_migrations). Returns the name of the last update file or an empty string if there are none.
+pub async fn apply_updates(pool: &Pool<Any>, scheme: &str) -> Result<String, String>
+//----------------------------------------------------------------------------------
+{
+   let mut last = String::new();
+   for file in update_files()
+   {
+      let filename = file.path().file_name().and_then(|n| n.to_str()).unwrap_or("");
+      if let Some(sql_content) = update_sql(file, scheme)
+      {
+         apply_sql_update(pool, scheme, filename, sql_content).await
+            .map_err(|e| format!("Failed to execute update {}: {}", filename, e))?;
+         last = filename.to_string();
+      }
+   }
+   Ok(last)
+}
+
+/// Names of the schema update files that haven't been completely applied to the database (according to
+/// dejacmd_migrations), without changing it
+pub async fn pending_updates(pool: &Pool<Any>, scheme: &str) -> Vec<String>
+//--------------------------------------------------------------------------
+{
+   let select_sql = fix_placeholders("SELECT COUNT(*) AS applied FROM dejacmd_migrations WHERE filename = ?", scheme);
+   let mut pending = Vec::new();
+   for file in update_files()
+   {
+      let filename = file.path().file_name().and_then(|n| n.to_str()).unwrap_or("");
+      if let Some(sql_content) = update_sql(file, scheme)
+      {
+         // A database without the migrations table hasn't had any applied
+         let applied: i64 = sqlx::query(&select_sql).bind(filename).fetch_one(pool).await.ok()
+            .and_then(|row| row.try_get("applied").ok())
+            .unwrap_or(0);
+         if (applied as usize) < split_sql_statements(sql_content).len()
+         {
+            pending.push(filename.to_string());
+         }
+      }
+   }
+   pending
+}
+
+/// Apply an update file statement by statement inside a transaction. Statements already recorded in
+/// dejacmd_migrations for this file are skipped and each newly applied statement is recorded in the same
+/// transaction. Returns the number of statements executed.
+pub async fn apply_sql_update(pool: &Pool<Any>, scheme: &str, filename: &str, sql_content: &str) -> Result<usize, String>
+//-------------------------------------------------------------------------------------------------------------------------
+{
+   sqlx::query(CREATE_MIGRATIONS_TABLE_SQL).execute(pool).await
+      .map_err(|e| format!("Error creating migrations table: {}", e))?;
+
+   let select_sql = fix_placeholders("SELECT COUNT(*) AS applied FROM dejacmd_migrations WHERE filename = ?", scheme);
+   let applied: i64 = sqlx::query(&select_sql)
+      .bind(filename)
+      .fetch_one(pool).await
+      .map_err(|e| format!("Error reading migrations table: {}", e))?
+      .try_get("applied")
+      .unwrap_or(0);
+
+   let statements = split_sql_statements(sql_content);
+   if applied as usize >= statements.len()
+   {
+      return Ok(0);
+   }
+   let server = match crate::ddl::dialect(scheme)
+   {
+      | "mysql" => Some(crate::ddl::mysql_server(pool).await?),
+      | _ => None,
+   };
+
+   let insert_sql = fix_placeholders(INSERT_MIGRATION_SQL, scheme);
+   let mut tx = pool.begin().await.map_err(|e| format!("Error starting transaction for {}: {}", filename, e))?;
+   let mut count = 0;
+   for (no, statement) in statements.iter().enumerate().skip(applied as usize)
+   {
+      let sql = match &server
+      {
+         | Some(server) => crate::ddl::fit_mysql_indexes(statement, server).into_owned(),
+         | None => fix_placeholders(statement, scheme),
+      };
+      debug!("{} statement {}: {}", filename, no + 1, sql);
+      sqlx::query(&sql).execute(&mut *tx).await
+         .map_err(|e| format!("Statement {} of {} failed: {} [{}]", no + 1, filename, e, statement))?;
+      sqlx::query(&insert_sql)
+         .bind(filename)
+         .bind((no + 1) as i64)
+         .bind(Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
+         .execute(&mut *tx).await
+         .map_err(|e| format!("Error recording statement {} of {} in migrations table: {}", no + 1, filename, e))?;
+      count += 1;
+   }
+   tx.commit().await.map_err(|e| format!("Error committing {}: {}", filename, e))?;
+   Ok(count)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use secrecy::SecretString;
+
+   #[test]
+   fn test_split_simple_statements()
+   {
+      let sql = "ALTER TABLE history ADD COLUMN a TEXT;\nALTER TABLE history ADD COLUMN b TEXT;\n";
+      let statements = split_sql_statements(sql);
+      assert_eq!(statements, vec!["ALTER TABLE history ADD COLUMN a TEXT", "ALTER TABLE history ADD COLUMN b TEXT"]);
+   }
+
+   #[test]
+   fn test_split_respects_literals_and_comments()
+   {
+      let sql = r#"-- leading comment; not a boundary
+UPDATE history SET command = 'a;b' WHERE shell = "x;y";
+/* block; comment */ UPDATE history SET command = 'it''s;';
+CREATE FUNCTION f() RETURNS void AS $body$ BEGIN PERFORM 1; END; $body$ LANGUAGE plpgsql;
+-- trailing comment only"#;
+      let statements = split_sql_statements(sql);
+      assert_eq!(statements.len(), 3, "{:?}", statements);
+      assert!(statements[0].ends_with(r#"UPDATE history SET command = 'a;b' WHERE shell = "x;y""#));
+      assert!(statements[1].ends_with("UPDATE history SET command = 'it''s;'"));
+      assert!(statements[2].contains("BEGIN PERFORM 1; END;"));
+   }
+
+   #[tokio::test]
+   async fn test_apply_sql_update_resumes()
+   {
+      let url = format!("sqlite:///tmp/dejacmd_migrations_test_{}.db",
+         std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos());
+      let (pool, scheme) = crate::get_database(&url, "", &SecretString::default()).await.unwrap();
+      let pool = pool.unwrap();
+      sqlx::query(crate::CREATE_TABLE_SQL).execute(&pool).await.unwrap();
+
+      let sql = "ALTER TABLE history ADD COLUMN a TEXT; ALTER TABLE history ADD COLUMN b TEXT;";
+      assert_eq!(apply_sql_update(&pool, &scheme, "0000001.sql", sql).await.unwrap(), 2);
+      // Already applied statements are skipped
+      assert_eq!(apply_sql_update(&pool, &scheme, "0000001.sql", sql).await.unwrap(), 0);
+
+      // A failing statement rolls back the whole file so nothing is recorded for it
+      let bad = "ALTER TABLE history ADD COLUMN c TEXT; ALTER TABLE no_such_table ADD COLUMN d TEXT;";
+      assert!(apply_sql_update(&pool, &scheme, "0000002.sql", bad).await.is_err());
+      let applied: i64 = sqlx::query("SELECT COUNT(*) AS applied FROM dejacmd_migrations WHERE filename = '0000002.sql'")
+         .fetch_one(&pool).await.unwrap().get("applied");
+      assert_eq!(applied, 0);
+
+      pool.close().await;
+      let _ = std::fs::remove_file(url.trim_start_matches("sqlite://"));
+   }
+
+   #[tokio::test]
+   async fn test_update_sql_variants()
+   {
+      let files = update_files();
+      let names: Vec<_> = files.iter().filter_map(|f| f.path().file_name().and_then(|n| n.to_str())).collect();
+      assert!(names.contains(&"0000005.sql"));
+      assert!(names.iter().all(|n| n.len() == 11), "Variants aren't separate updates: {:?}", names);
+
+      let indexes = files.iter().find(|f| f.path().ends_with("0000005.sql")).unwrap();
+      assert!(update_sql(indexes, "postgresql").unwrap().contains("gin_trgm_ops"));
+      assert!(update_sql(indexes, "mysql").unwrap().contains("command(255)"));
+      assert_eq!(update_sql(indexes, "sqlite"), indexes.contents_utf8());
+      assert_eq!(split_sql_statements(update_sql(indexes, "postgres").unwrap()).len(), 2, "The DO block is one statement");
+
+      // Every update applies to a new SQLite database and the search indexes exist afterwards
+      let url = format!("sqlite:file:dejacmd-update-variants-{}?mode=memory&cache=shared",
+         std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos());
+      let (pool, scheme) = crate::get_database(&url, "", &SecretString::default()).await.unwrap();
+      let pool = pool.unwrap();
+      crate::create_schema(&pool, &scheme).await.unwrap();
+      let indexes: i64 = sqlx::query("SELECT COUNT(*) AS n FROM sqlite_master WHERE type = 'index' AND name IN ('idx_history_command', 'idx_history_cwd')")
+         .fetch_one(&pool).await.unwrap().get("n");
+      assert_eq!(indexes, 2);
+      pool.close().await;
+   }
+}
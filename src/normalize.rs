@@ -0,0 +1,83 @@
+/// Commands that run another command, which is the program of interest, after their own options e.g sudo or env.
+/// Each is given with its options that take a value as a separate word so the value isn't taken for the program.
+pub const WRAPPERS: &[(&str, &[&str])] =
+&[
+   ("sudo", &["-u", "-g", "-h", "-p", "-C", "-D", "-R", "-r", "-t", "-T", "-U", "--user", "--group", "--host", "--prompt",
+              "--close-from", "--chdir", "--chroot", "--role", "--type", "--command-timeout", "--other-user"]),
+   ("doas", &["-u", "-C"]),
+   ("env", &["-u", "-C", "-S", "--unset", "--chdir", "--split-string"]),
+   ("nice", &["-n", "--adjustment"]),
+   ("ionice", &["-c", "-n", "-p", "--class", "--classdata"]),
+   ("time", &["-f", "-o", "--format", "--output"]),
+   ("nohup", &[]),
+   ("stdbuf", &[]),
+   ("command", &[]),
+   ("builtin", &[]),
+   ("exec", &["-a"]),
+];
+
+/// The program a command runs, its first word without the directory after any VAR=value assignments and wrappers such
+/// as sudo or env (see WRAPPERS) and their options, so git commit -m fix, /usr/bin/git status and
+/// sudo -u build GIT_DIR=x git log are all git. None for blank commands.
+pub fn program_name(command: &str) -> Option<String>
+//--------------------------------------------------
+{
+   let is_assignment = |word: &str| word.split_once('=')
+      .is_some_and(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+   let mut words = command.split_whitespace().filter(|w| !is_assignment(w)).peekable();
+   let mut last = None;
+   while let Some(word) = words.next()
+   {
+      // \ls and "ls" bypass aliases but still run ls
+      let name = base_name(word.trim_start_matches('\\').trim_matches(['"', '\'']));
+      last = Some(name);
+      let Some((_, value_options)) = WRAPPERS.iter().find(|(wrapper, _)| *wrapper == name)
+      else
+      {
+         return Some(name.to_string()).filter(|n| !n.is_empty());
+      };
+      while let Some(option) = words.next_if(|w| w.starts_with('-'))
+      {
+         if option == "--"
+         {
+            break;
+         }
+         if value_options.contains(&option)
+         {
+            words.next();
+         }
+      }
+   }
+   // A wrapper on its own e.g sudo -v or env is the program
+   last.map(|name| name.to_string())
+}
+
+fn base_name(word: &str) -> &str
+//------------------------------
+{
+   word.rsplit(['/', '\\']).next().unwrap_or(word)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_program_name()
+   {
+      assert_eq!(program_name("  /usr/bin/git status").as_deref(), Some("git"));
+      assert_eq!(program_name("RUST_LOG=debug CC=clang cargo build").as_deref(), Some("cargo"));
+      assert_eq!(program_name("   "), None);
+      assert_eq!(program_name("git commit -m 'sudo make'").as_deref(), Some("git"));
+      assert_eq!(program_name("sudo apt install ripgrep").as_deref(), Some("apt"));
+      assert_eq!(program_name("sudo -u postgres -E psql -c 'select 1'").as_deref(), Some("psql"));
+      assert_eq!(program_name("sudo --user=www-data ls").as_deref(), Some("ls"));
+      assert_eq!(program_name("env -u HOME LANG=C /bin/sort -u").as_deref(), Some("sort"));
+      assert_eq!(program_name("FOO=1 nohup nice -n 10 time make -j8").as_deref(), Some("make"));
+      assert_eq!(program_name("sudo -- rm -rf build").as_deref(), Some("rm"));
+      assert_eq!(program_name(r"\ls -la").as_deref(), Some("ls"));
+      assert_eq!(program_name("sudo -v").as_deref(), Some("sudo"));
+      assert_eq!(program_name("env").as_deref(), Some("env"));
+   }
+}
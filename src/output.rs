@@ -0,0 +1,142 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use sqlx::{Any, Pool, Row};
+
+use crate::fix_placeholders;
+
+/// Lines kept from the start and from the end of a command's output when output_summary_lines isn't set
+pub const DEFAULT_OUTPUT_SUMMARY_LINES: usize = 5;
+
+/// Most of a capture file read for one command, half from the start of its output and half from the end
+pub const MAX_CAPTURE_BYTES: u64 = 256 * 1024;
+
+/// Terminal escape sequences (CSI, OSC and two character escapes)
+static ESCAPES: LazyLock<Regex> =
+   LazyLock::new(|| Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]").unwrap());
+
+/// The output of a command, the bytes script wrote to capture_file after offset (its size when the command started).
+/// Output longer than MAX_CAPTURE_BYTES is cut in the middle.
+pub fn read_capture(capture_file: &Path, offset: u64) -> Result<String, String>
+//-----------------------------------------------------------------------------
+{
+   let error = |e: std::io::Error| format!("Error reading output from {}: {}", capture_file.display(), e);
+   let mut file = std::fs::File::open(capture_file).map_err(error)?;
+   let length = file.metadata().map_err(error)?.len();
+   let size = length.saturating_sub(offset);
+   let mut read = |start: u64, size: u64| -> Result<Vec<u8>, String>
+   {
+      let mut bytes = Vec::with_capacity(size as usize);
+      file.seek(SeekFrom::Start(start)).map_err(error)?;
+      (&mut file).take(size).read_to_end(&mut bytes).map_err(error)?;
+      Ok(bytes)
+   };
+   if size <= MAX_CAPTURE_BYTES
+   {
+      return Ok(String::from_utf8_lossy(&read(offset, size)?).into_owned());
+   }
+   let half = MAX_CAPTURE_BYTES / 2;
+   let head = read(offset, half)?;
+   let tail = read(length - half, half)?;
+   Ok(format!("{}\n{}", String::from_utf8_lossy(&head), String::from_utf8_lossy(&tail)))
+}
+
+/// output cut to its first and last `lines` lines, without terminal escapes and keeping only the last of the lines a
+/// progress bar overwrites with carriage returns. None if there is no output.
+pub fn summarize(output: &str, lines: usize) -> Option<String>
+//------------------------------------------------------------
+{
+   let text = ESCAPES.replace_all(output, "");
+   let cleaned: Vec<&str> = text.lines()
+      .map(|line| line.trim_end_matches('\r').rsplit('\r').next().unwrap_or_default().trim_end())
+      .collect();
+   let start = cleaned.iter().position(|line| !line.is_empty())?;
+   let end = cleaned.iter().rposition(|line| !line.is_empty()).map_or(start, |end| end + 1);
+   let cleaned = &cleaned[start..end];
+   let lines = lines.max(1);
+   if cleaned.len() <= 2 * lines
+   {
+      return Some(cleaned.join("\n"));
+   }
+   Some(format!("{}\n... {} lines ...\n{}", cleaned[..lines].join("\n"), cleaned.len() - 2 * lines,
+                cleaned[cleaned.len() - lines..].join("\n")))
+}
+
+/// Save the output summary of the history entry with id
+pub async fn insert_output(pool: &Pool<Any>, scheme: &str, id: &str, summary: &str) -> Result<(), sqlx::Error>
+//-------------------------------------------------------------------------------------------------------------
+{
+   sqlx::query(&fix_placeholders("INSERT INTO history_output (id, output) VALUES (?, ?)", scheme))
+      .bind(id).bind(summary).execute(pool).await?;
+   Ok(())
+}
+
+/// Replace the output summary of the history entry with id
+pub async fn update_output(pool: &Pool<Any>, scheme: &str, id: &str, summary: &str) -> Result<(), sqlx::Error>
+//-------------------------------------------------------------------------------------------------------------
+{
+   sqlx::query(&fix_placeholders("UPDATE history_output SET output = ? WHERE id = ?", scheme))
+      .bind(summary).bind(id).execute(pool).await?;
+   Ok(())
+}
+
+/// Delete the output summaries of the history entries with ids
+pub async fn delete_outputs(pool: &Pool<Any>, scheme: &str, ids: &[String]) -> Result<(), sqlx::Error>
+//-----------------------------------------------------------------------------------------------------
+{
+   let sql = fix_placeholders(&format!("DELETE FROM history_output WHERE id IN ({})", vec!["?"; ids.len()].join(", ")), scheme);
+   let mut query = sqlx::query(&sql);
+   for id in ids
+   {
+      query = query.bind(id);
+   }
+   query.execute(pool).await?;
+   Ok(())
+}
+
+/// The output summary of the history entry with id, None if its output wasn't captured
+pub async fn get_output(pool: &Pool<Any>, scheme: &str, id: &str) -> Result<Option<String>, sqlx::Error>
+//------------------------------------------------------------------------------------------------------
+{
+   let row = sqlx::query(&fix_placeholders("SELECT output FROM history_output WHERE id = ?", scheme))
+      .bind(id).fetch_optional(pool).await?;
+   Ok(row.and_then(|row| row.try_get::<Option<String>, _>("output").ok().flatten()))
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_summarize()
+   {
+      assert_eq!(summarize("\r\n  \n", 5), None);
+      assert_eq!(summarize("\x1b[1;31merror\x1b[0m: not found\r\n", 5).as_deref(), Some("error: not found"));
+      assert_eq!(summarize("\x1b]0;title\x07 10%\r 50%\r100%\r\ndone\n", 5).as_deref(), Some("100%\ndone"),
+                 "Only the last of the overwritten lines is kept");
+      let output: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+      assert_eq!(summarize(&output, 2).as_deref(), Some("line 1\nline 2\n... 16 lines ...\nline 19\nline 20"));
+      assert_eq!(summarize(&output, 10).unwrap().lines().count(), 20);
+   }
+
+   #[test]
+   fn test_read_capture()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_capture_test_{}", nanos));
+      std::fs::write(&path, "$ ls\r\nCargo.toml\r\n").unwrap();
+      assert_eq!(read_capture(&path, 6).unwrap(), "Cargo.toml\r\n");
+      assert_eq!(read_capture(&path, 100).unwrap(), "", "An offset past the end is no output");
+
+      let long = format!("first\n{}\nlast\n", "x".repeat(MAX_CAPTURE_BYTES as usize * 2));
+      std::fs::write(&path, &long).unwrap();
+      let output = read_capture(&path, 0).unwrap();
+      assert!(output.len() <= MAX_CAPTURE_BYTES as usize + 1);
+      assert!(output.starts_with("first\n") && output.ends_with("\nlast\n"));
+      let _ = std::fs::remove_file(&path);
+      assert!(read_capture(&path, 0).is_err());
+   }
+}
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+
+/// File in the config directory whose presence stops dejacmd-log logging commands until dejacmd resume (or the time
+/// it holds)
+pub const PAUSE_FILE: &str = "paused.json";
+
+/// When logging was paused and when it resumes by itself, RFC 3339 times
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pause
+{
+   pub since: String,
+   #[serde(default)]
+   pub until: Option<String>,
+}
+
+impl Pause
+{
+   /// True while the pause applies at now, which is always without an until (or with one that can't be read)
+   pub fn is_active(&self, now: DateTime<Local>) -> bool
+   //---------------------------------------------------
+   {
+      match self.until.as_deref().map(DateTime::parse_from_rfc3339)
+      {
+         | Some(Ok(until)) => now < until,
+         | _ => true,
+      }
+   }
+}
+
+pub fn pause_path() -> Result<PathBuf, String>
+//--------------------------------------------
+{
+   let mut path = Settings::get_config_path().map_err(|e| format!("Error getting pause file path: {}", e))?;
+   path.push(PAUSE_FILE);
+   Ok(path)
+}
+
+/// Pause logging until dejacmd resume or for minutes
+pub fn pause(minutes: Option<u32>) -> Result<Pause, String>
+//----------------------------------------------------------
+{
+   pause_at(&pause_path()?, Local::now(), minutes)
+}
+
+pub fn pause_at(path: &Path, now: DateTime<Local>, minutes: Option<u32>) -> Result<Pause, String>
+//-----------------------------------------------------------------------------------------------
+{
+   let pause = Pause
+   {
+      since: now.to_rfc3339(),
+      until: minutes.map(|m| (now + chrono::Duration::minutes(m as i64)).to_rfc3339()),
+   };
+   let json = serde_json::to_string(&pause).map_err(|e| format!("Error serializing pause: {}", e))?;
+   std::fs::write(path, json).map_err(|e| format!("Error writing pause file {}: {}", path.display(), e))?;
+   Ok(pause)
+}
+
+/// Resume logging, returning false if it wasn't paused
+pub fn resume() -> Result<bool, String>
+//-------------------------------------
+{
+   resume_at(&pause_path()?)
+}
+
+pub fn resume_at(path: &Path) -> Result<bool, String>
+//----------------------------------------------------
+{
+   match std::fs::remove_file(path)
+   {
+      | Ok(_) => Ok(true),
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+      | Err(e) => Err(format!("Error removing pause file {}: {}", path.display(), e)),
+   }
+}
+
+/// The pause in effect, None if logging isn't paused or the pause has ended
+pub fn paused() -> Option<Pause>
+//------------------------------
+{
+   paused_at(&pause_path().ok()?, Local::now())
+}
+
+/// A pause file that can't be read still pauses logging, as it is safer to miss commands than to log ones the user
+/// didn't want logged
+pub fn paused_at(path: &Path, now: DateTime<Local>) -> Option<Pause>
+//-------------------------------------------------------------------
+{
+   let text = match std::fs::read_to_string(path)
+   {
+      | Ok(text) => text,
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+      | Err(_) => String::new(),
+   };
+   let pause = serde_json::from_str::<Pause>(&text).unwrap_or_else(|_| Pause { since: String::new(), until: None });
+   if pause.is_active(now) { Some(pause) } else { None }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_pause()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_pause_test_{}.json", nanos));
+      let now = Local::now();
+      assert_eq!(paused_at(&path, now), None);
+      assert!(!resume_at(&path).unwrap(), "Not paused");
+
+      let pause = pause_at(&path, now, None).unwrap();
+      assert_eq!(paused_at(&path, now + chrono::Duration::days(30)), Some(pause));
+      assert!(resume_at(&path).unwrap());
+      assert_eq!(paused_at(&path, now), None);
+
+      let pause = pause_at(&path, now, Some(15)).unwrap();
+      assert_eq!(paused_at(&path, now + chrono::Duration::minutes(14)), Some(pause));
+      assert_eq!(paused_at(&path, now + chrono::Duration::minutes(15)), None, "The pause has ended");
+
+      std::fs::write(&path, "not json").unwrap();
+      assert!(paused_at(&path, now).is_some(), "An unreadable pause file still pauses");
+      let _ = std::fs::remove_file(&path);
+   }
+}
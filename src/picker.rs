@@ -0,0 +1,331 @@
+/// Keys understood by the interactive picker
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key
+{
+   Char(char),
+   Backspace,
+   ClearQuery,
+   Up,
+   Down,
+   Enter,
+   Cancel,
+}
+
+/// What the picker should do after a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action
+{
+   Continue,
+   /// Index into the items of the chosen one
+   Chosen(usize),
+   Cancelled,
+}
+
+/// Incremental filter over a list of items (most relevant first) where every whitespace separated word of the query
+/// must appear in an item, ignoring case
+pub struct Picker<'a>
+{
+   items: &'a [String],
+   lowercase: Vec<String>,
+   query: String,
+   matches: Vec<usize>,
+   selected: usize,
+   offset: usize,
+}
+
+impl<'a> Picker<'a>
+{
+   pub fn new(items: &'a [String], query: &str) -> Self
+   //---------------------------------------------------
+   {
+      let lowercase = items.iter().map(|item| item.to_lowercase()).collect();
+      let mut picker = Picker { items, lowercase, query: query.to_string(), matches: Vec::new(), selected: 0, offset: 0 };
+      picker.filter();
+      picker
+   }
+
+   pub fn query(&self) -> &str { &self.query }
+
+   /// Indexes of the items matching the query
+   pub fn matches(&self) -> &[usize] { &self.matches }
+
+   /// Position of the highlighted item in matches
+   pub fn selected(&self) -> usize { self.selected }
+
+   fn filter(&mut self)
+   //------------------
+   {
+      let words: Vec<String> = self.query.split_whitespace().map(|w| w.to_lowercase()).collect();
+      self.matches = self.lowercase.iter().enumerate()
+         .filter(|(_, item)| words.iter().all(|w| item.contains(w.as_str())))
+         .map(|(i, _)| i)
+         .collect();
+      self.selected = 0;
+      self.offset = 0;
+   }
+
+   pub fn handle(&mut self, key: &Key) -> Action
+   //-------------------------------------------
+   {
+      match key
+      {
+         | Key::Char(c) =>
+         {
+            self.query.push(*c);
+            self.filter();
+         },
+         | Key::Backspace =>
+         {
+            if self.query.pop().is_some()
+            {
+               self.filter();
+            }
+         },
+         | Key::ClearQuery =>
+         {
+            self.query.clear();
+            self.filter();
+         },
+         | Key::Up => self.selected = (self.selected + 1).min(self.matches.len().saturating_sub(1)),
+         | Key::Down => self.selected = self.selected.saturating_sub(1),
+         | Key::Enter => return match self.matches.get(self.selected)
+         {
+            | Some(i) => Action::Chosen(*i),
+            | None => Action::Cancelled,
+         },
+         | Key::Cancel => return Action::Cancelled,
+      }
+      Action::Continue
+   }
+
+   /// The items to show in height lines, keeping the selected one in view, with true for the selected one.
+   /// The first match is shown at the bottom next to the query, older ones above it as with the shell's own search.
+   pub fn visible(&mut self, height: usize) -> Vec<(&'a str, bool)>
+   //--------------------------------------------------------------
+   {
+      if self.selected < self.offset
+      {
+         self.offset = self.selected;
+      }
+      else if height > 0 && self.selected >= self.offset + height
+      {
+         self.offset = self.selected + 1 - height;
+      }
+      let items = self.items;
+      self.matches.iter().enumerate().skip(self.offset).take(height)
+         .map(|(position, i)| (items[*i].as_str(), position == self.selected))
+         .collect()
+   }
+}
+
+/// Keys in the bytes read from a terminal in raw mode
+pub fn parse_keys(bytes: &[u8]) -> Vec<Key>
+//------------------------------------------
+{
+   let mut keys = Vec::new();
+   let text = String::from_utf8_lossy(bytes);
+   let mut chars = text.chars().peekable();
+   while let Some(c) = chars.next()
+   {
+      match c
+      {
+         | '\x1b' =>
+         {
+            // A lone escape cancels, otherwise it starts a sequence for a cursor key
+            if chars.peek().is_some_and(|c| *c == '[' || *c == 'O')
+            {
+               chars.next();
+               match chars.next()
+               {
+                  | Some('A') => keys.push(Key::Up),
+                  | Some('B') => keys.push(Key::Down),
+                  | _ => (),
+               }
+            }
+            else
+            {
+               keys.push(Key::Cancel);
+            }
+         },
+         | '\r' | '\n' => keys.push(Key::Enter),
+         | '\x7f' | '\x08' => keys.push(Key::Backspace),
+         | '\x15' => keys.push(Key::ClearQuery),          // Ctrl-U
+         | '\x10' | '\x12' => keys.push(Key::Up),         // Ctrl-P, Ctrl-R
+         | '\x0e' | '\x13' => keys.push(Key::Down),       // Ctrl-N, Ctrl-S
+         | '\x03' | '\x07' | '\x04' => keys.push(Key::Cancel), // Ctrl-C, Ctrl-G, Ctrl-D
+         | c if !c.is_control() => keys.push(Key::Char(c)),
+         | _ => (),
+      }
+   }
+   keys
+}
+
+/// Let the user choose one of items (most relevant first) on the terminal, typing to filter them starting with query.
+/// The terminal is used directly so standard output can be captured by a shell widget. Returns None if cancelled.
+#[cfg(unix)]
+pub fn pick(items: &[String], query: &str) -> Result<Option<usize>, String>
+//-------------------------------------------------------------------------
+{
+   use std::io::{Read, Write};
+   use nix::sys::termios::{SetArg, cfmakeraw, tcgetattr, tcsetattr};
+
+   let mut tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+      .map_err(|e| format!("Error opening the terminal: {}", e))?;
+   let original = tcgetattr(&tty).map_err(|e| format!("Error reading terminal settings: {}", e))?;
+   let mut raw = original.clone();
+   cfmakeraw(&mut raw);
+   tcsetattr(&tty, SetArg::TCSANOW, &raw).map_err(|e| format!("Error setting terminal settings: {}", e))?;
+
+   let (width, rows) = terminal_size(&tty);
+   let height = rows.saturating_sub(2).clamp(1, 15);
+   let mut picker = Picker::new(items, query);
+   let mut buffer = [0u8; 64];
+   let mut is_first = true;
+   let result = loop
+   {
+      let screen = render(&mut picker, items.len(), width, height, is_first);
+      is_first = false;
+      if let Err(e) = tty.write_all(screen.as_bytes()).and_then(|_| tty.flush())
+      {
+         break Err(format!("Error writing to the terminal: {}", e));
+      }
+      let n = match tty.read(&mut buffer)
+      {
+         | Ok(0) => break Ok(None),
+         | Ok(n) => n,
+         | Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+         | Err(e) => break Err(format!("Error reading from the terminal: {}", e)),
+      };
+      let action = parse_keys(&buffer[..n]).iter()
+         .map(|key| picker.handle(key))
+         .find(|action| *action != Action::Continue);
+      match action
+      {
+         | Some(Action::Chosen(i)) => break Ok(Some(i)),
+         | Some(Action::Cancelled) => break Ok(None),
+         | _ => (),
+      }
+   };
+   // Erase the picker and return to the line it was started from, which the shell redraws
+   let _ = tty.write_all(format!("\x1b[{}A\r{}\x1b[1A", height, CLEAR_BELOW).as_bytes());
+   let _ = tcsetattr(&tty, SetArg::TCSANOW, &original);
+   result
+}
+
+#[cfg(not(unix))]
+pub fn pick(_items: &[String], _query: &str) -> Result<Option<usize>, String>
+//---------------------------------------------------------------------------
+{
+   Err("The interactive picker needs a Unix terminal".to_string())
+}
+
+const CLEAR_BELOW: &str = "\x1b[J";
+const REVERSE: &str = "\x1b[7m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// The matching items in height lines above a query line, leaving the cursor at the end of the query. The first time
+/// it is drawn below the line the cursor is on, afterwards over the previous one. In raw mode so lines end with \r\n.
+fn render(picker: &mut Picker, total: usize, width: usize, height: usize, is_first: bool) -> String
+//-------------------------------------------------------------------------------------------------
+{
+   let count = picker.matches().len();
+   let visible = picker.visible(height);
+   let mut screen = if is_first { "\r\n".to_string() } else { format!("\x1b[{}A", height) };
+   screen.push_str(&format!("\r{}", CLEAR_BELOW));
+   // Blank lines fill the space when there are fewer matches than lines so the query line doesn't move
+   for _ in visible.len()..height
+   {
+      screen.push_str("\r\n");
+   }
+   for (item, is_selected) in visible.iter().rev()
+   {
+      let line = truncate(&item.replace(['\n', '\r'], " ↵ "), width.saturating_sub(3));
+      if *is_selected
+      {
+         screen.push_str(&format!("{}> {}{}\r\n", REVERSE, line, RESET));
+      }
+      else
+      {
+         screen.push_str(&format!("  {}\r\n", line));
+      }
+   }
+   let prompt = format!("dejacmd> {}", picker.query());
+   screen.push_str(&format!("{}  {}{}/{}{}\r", prompt, DIM, count, total, RESET));
+   let column = prompt.chars().count();
+   if column > 0
+   {
+      screen.push_str(&format!("\x1b[{}C", column));
+   }
+   screen
+}
+
+fn truncate(s: &str, width: usize) -> String
+//-------------------------------------------
+{
+   if s.chars().count() <= width
+   {
+      return s.to_string();
+   }
+   let mut truncated: String = s.chars().take(width.saturating_sub(1)).collect();
+   truncated.push('…');
+   truncated
+}
+
+/// Columns and rows of the terminal, 80x24 if they can't be determined
+#[cfg(unix)]
+fn terminal_size(tty: &std::fs::File) -> (usize, usize)
+//------------------------------------------------------
+{
+   use std::os::fd::AsRawFd;
+   let mut size = nix::libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+   // SAFETY: TIOCGWINSZ only writes a winsize to the pointer, which is valid for the duration of the call
+   let result = unsafe { nix::libc::ioctl(tty.as_raw_fd(), nix::libc::TIOCGWINSZ, &mut size) };
+   if result == 0 && size.ws_col > 0 && size.ws_row > 0 { (size.ws_col as usize, size.ws_row as usize) } else { (80, 24) }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_picker()
+   {
+      let items: Vec<String> = ["git push origin main", "ls -l", "git status", "cargo build"].iter().map(|s| s.to_string()).collect();
+      let mut picker = Picker::new(&items, "git");
+      assert_eq!(picker.matches(), &[0, 2]);
+      assert_eq!(picker.handle(&Key::Up), Action::Continue);
+      assert_eq!(picker.selected(), 1);
+      assert_eq!(picker.handle(&Key::Up), Action::Continue);
+      assert_eq!(picker.selected(), 1, "Selection stops at the last match");
+      assert_eq!(picker.visible(1), vec![("git status", true)]);
+      assert_eq!(picker.handle(&Key::Enter), Action::Chosen(2));
+
+      // Words can appear in any order, ignoring case
+      picker.handle(&Key::ClearQuery);
+      for c in "MAIN push".chars()
+      {
+         picker.handle(&Key::Char(c));
+      }
+      assert_eq!(picker.matches(), &[0]);
+      assert_eq!(picker.selected(), 0, "Filtering resets the selection");
+      picker.handle(&Key::Char('x'));
+      assert!(picker.matches().is_empty());
+      assert_eq!(picker.handle(&Key::Enter), Action::Cancelled);
+      picker.handle(&Key::Backspace);
+      assert_eq!(picker.query(), "MAIN push");
+      assert_eq!(picker.handle(&Key::Cancel), Action::Cancelled);
+      assert_eq!(Picker::new(&items, "").matches().len(), 4);
+   }
+
+   #[test]
+   fn test_parse_keys()
+   {
+      assert_eq!(parse_keys(b"l\xc3\xa9\x7f"), vec![Key::Char('l'), Key::Char('é'), Key::Backspace]);
+      assert_eq!(parse_keys(b"\x1b[A\x1b[B\x1bOA"), vec![Key::Up, Key::Down, Key::Up]);
+      assert_eq!(parse_keys(b"\x1b"), vec![Key::Cancel]);
+      assert_eq!(parse_keys(b"\x12\x0e\r\x03\x15"), vec![Key::Up, Key::Down, Key::Enter, Key::Cancel, Key::ClearQuery]);
+      assert_eq!(truncate("cargo build --release", 8), "cargo b…");
+   }
+}
@@ -0,0 +1,154 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::settings::Settings;
+
+/// Per-project configuration file, found in the directory a command is run in or the nearest directory above it
+pub const PROJECT_FILE: &str = ".dejacmd.toml";
+
+/// Overrides from a .dejacmd.toml merged over the global settings for commands run in the project, e.g.
+/// ```toml
+/// tag = "website"
+/// ignore_commands = ["npm run dev", "make watch"]
+/// local_database_url = "sqlite://.dejacmd/history.sqlite"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig
+{
+   /// Recorded in the project column of commands run in the project
+   #[serde(default)]
+   pub tag: Option<String>,
+   /// Glob patterns of commands that aren't logged, added to the ignore_commands setting
+   #[serde(default)]
+   pub ignore_commands: Vec<String>,
+   /// Local database for commands run in the project instead of the local_database_url setting. It has to be a
+   /// sqlite:// database in the directory holding the .dejacmd.toml (or below it) and a relative path is relative to
+   /// that directory. Anyone can leave a .dejacmd.toml in a cloned repository or a shared directory, so it can't send
+   /// commands (or the user's credentials) anywhere else.
+   #[serde(default)]
+   pub local_database_url: Option<String>,
+   /// The .dejacmd.toml file
+   #[serde(skip)]
+   pub path: PathBuf,
+}
+
+impl ProjectConfig
+{
+   /// The .dejacmd.toml in dir or the nearest directory above it, if there is one
+   pub fn find(dir: &Path) -> Result<Option<ProjectConfig>, String>
+   //--------------------------------------------------------------
+   {
+      match dir.ancestors().map(|d| d.join(PROJECT_FILE)).find(|path| path.is_file())
+      {
+         | Some(path) => ProjectConfig::read(&path).map(Some),
+         | None => Ok(None),
+      }
+   }
+
+   /// The project configuration for the current directory
+   pub fn current() -> Result<Option<ProjectConfig>, String>
+   //-------------------------------------------------------
+   {
+      let dir = std::env::current_dir().map_err(|e| format!("Error getting current directory: {}", e))?;
+      ProjectConfig::find(&dir)
+   }
+
+   pub fn read(path: &Path) -> Result<ProjectConfig, String>
+   //-------------------------------------------------------
+   {
+      let text = std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+      let mut config: ProjectConfig = toml::from_str(&text).map_err(|e| format!("Error in {}: {}", path.display(), e))?;
+      config.tag = config.tag.map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty());
+      config.path = path.to_path_buf();
+      if let Some(url) = config.local_database_url.as_deref().map(str::trim).filter(|url| !url.is_empty())
+         && config.database_url().is_none()
+      {
+         return Err(format!("Error in {}: local_database_url {} isn't a sqlite:// database in the project directory",
+                            path.display(), url));
+      }
+      Ok(config)
+   }
+
+   /// The project's local database URL with a relative SQLite path made absolute, None if there isn't one or it isn't
+   /// a sqlite:// database in the project directory
+   pub fn database_url(&self) -> Option<String>
+   //------------------------------------------
+   {
+      let url = self.local_database_url.as_deref().map(str::trim).filter(|url| !url.is_empty())?;
+      let path = url.strip_prefix("sqlite://").filter(|path| !path.starts_with(['~', ':']) && !path.starts_with("file:"))?;
+      let (path, options) = path.split_once('?').map_or((path, None), |(path, options)| (path, Some(options)));
+      let project_dir = self.path.parent().unwrap_or(Path::new("."));
+      let path = project_dir.join(path);
+      let is_in_project = !path.components().any(|c| matches!(c, Component::ParentDir)) && path.starts_with(project_dir)
+                          && path != project_dir;
+      if !is_in_project
+      {
+         return None;
+      }
+      Some(match options
+      {
+         | Some(options) => format!("sqlite://{}?{}", path.display(), options),
+         | None => format!("sqlite://{}", path.display()),
+      })
+   }
+
+   /// Merge the project's overrides over settings
+   pub fn apply(&self, settings: &mut Settings)
+   //------------------------------------------
+   {
+      if !self.ignore_commands.is_empty()
+      {
+         settings.ignore_commands.get_or_insert_default().extend(self.ignore_commands.iter().cloned());
+      }
+      if let Some(url) = self.database_url()
+      {
+         settings.set_local_database_url(&url);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_project_config()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_project_test_{}", nanos));
+      let sub_dir = dir.join("src").join("bin");
+      std::fs::create_dir_all(&sub_dir).unwrap();
+      assert_eq!(ProjectConfig::find(&sub_dir).unwrap(), None);
+
+      std::fs::write(dir.join(PROJECT_FILE), "tag = \" website \"\nignore_commands = [\"npm run dev\"]\n\
+                                              local_database_url = \"sqlite://.dejacmd/history.sqlite?mode=rwc\"\n").unwrap();
+      let config = ProjectConfig::find(&sub_dir).unwrap().unwrap();
+      assert_eq!(config.tag.as_deref(), Some("website"));
+      assert_eq!(config.path, dir.join(PROJECT_FILE));
+      assert_eq!(config.database_url().unwrap(), format!("sqlite://{}?mode=rwc", dir.join(".dejacmd/history.sqlite").display()));
+
+      let mut settings = Settings::new_for_test("sqlite:///tmp/global.sqlite", "");
+      settings.ignore_commands = Some(vec!["ls".to_string()]);
+      config.apply(&mut settings);
+      assert_eq!(settings.ignore_commands, Some(vec!["ls".to_string(), "npm run dev".to_string()]));
+      assert!(settings.get_local_database_url().ends_with(".dejacmd/history.sqlite?mode=rwc"));
+
+      let inside = format!("sqlite://{}", dir.join("history.sqlite").display());
+      for (url, is_accepted) in [("postgresql://me@db.example.com/history", false), ("mysql://db.example.com/history", false),
+                                 ("libsql://history.turso.io", false), ("https://dejacmd.example.com", false),
+                                 ("sqlite+sqlcipher://history.sqlite", false), ("sqlite:///var/lib/history.sqlite", false),
+                                 ("sqlite://../history.sqlite", false), ("sqlite://~/history.sqlite", false),
+                                 ("sqlite://.dejacmd/history.sqlite", true), (inside.as_str(), true)]
+      {
+         std::fs::write(dir.join(PROJECT_FILE), format!("local_database_url = \"{}\"\n", url)).unwrap();
+         assert_eq!(ProjectConfig::find(&sub_dir).is_ok(), is_accepted, "{}", url);
+      }
+
+      std::fs::write(dir.join(PROJECT_FILE), "tags = \"typo\"\n").unwrap();
+      assert!(ProjectConfig::find(&sub_dir).is_err(), "Unknown keys are errors");
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::time::Duration;
+
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Serialize, de::DeserializeOwned};
+use tracing::debug;
+
+use crate::{HistoryEntry, PoolSettings, libsql};
+use crate::settings::Settings;
+
+/// REST endpoints of a dejacmd server, used instead of a database when the central database URL is http:// or https://.
+/// Requests and responses are JSON and a configured central password is sent as a bearer token.
+/// POST an array of HistoryEntry, entries with ids that already exist are skipped. Responds with InsertResponse.
+pub const HISTORY_PATH: &str = "/api/v1/history";
+/// POST the search parameters, responds with an array of HistoryEntry
+pub const SEARCH_PATH: &str = "/api/v1/search";
+/// POST {"sql": "..."}, responds with the column names and rows of the result as strings
+pub const QUERY_PATH: &str = "/api/v1/query";
+/// GET row counts, the time span of the history and the most common hosts and commands
+pub const STATS_PATH: &str = "/api/v1/stats";
+/// GET {"status": "ok"}, the only endpoint that doesn't require the token
+pub const HEALTH_PATH: &str = "/api/v1/health";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct InsertResponse
+{
+   pub inserted: u64,
+}
+
+/// Error body returned by a dejacmd server with a non-success status
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorResponse
+{
+   pub error: String,
+}
+
+pub fn is_http_url(url: &str) -> bool
+//------------------------------------
+{
+   let url = url.trim().to_lowercase();
+   url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Whether a central history URL is reached with RemoteCentral rather than being a database for sqlx, either a dejacmd
+/// server or a libSQL database (see libsql::LIBSQL_SCHEME)
+pub fn is_remote_url(url: &str) -> bool
+//--------------------------------------
+{
+   is_http_url(url) || libsql::is_libsql_url(url)
+}
+
+#[derive(Debug)]
+pub enum RemoteError
+{
+   /// The server couldn't be reached (connection refused, DNS, timeout), so the request can be retried later
+   Unreachable(String),
+   /// The server rejected the request or its response couldn't be read
+   Failed(String),
+}
+
+impl fmt::Display for RemoteError
+{
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+   {
+      match self
+      {
+         | RemoteError::Unreachable(e) => write!(f, "central history unreachable: {}", e),
+         | RemoteError::Failed(e) => write!(f, "{}", e),
+      }
+   }
+}
+
+/// Client for a central history stored by a dejacmd server or in a libSQL database, whose endpoints are answered by
+/// the libsql module
+pub struct RemoteCentral
+{
+   base_url: String,
+   token: SecretString,
+   client: reqwest::Client,
+   is_libsql: bool,
+}
+
+impl RemoteCentral
+{
+   pub fn new(url: &str, token: SecretString) -> Result<Self, String>
+   //-----------------------------------------------------------------
+   {
+      RemoteCentral::with_connect_timeout(url, token, PoolSettings::default().connect_timeout)
+   }
+
+   pub fn with_connect_timeout(url: &str, token: SecretString, connect_timeout: Duration) -> Result<Self, String>
+   //-----------------------------------------------------------------------------------------------------------
+   {
+      let client = reqwest::Client::builder()
+         .connect_timeout(connect_timeout)
+         .timeout(Duration::from_secs(30))
+         .build()
+         .map_err(|e| format!("Error creating HTTP client: {}", e))?;
+      if libsql::is_libsql_url(url)
+      {
+         return Ok(RemoteCentral { base_url: libsql::http_url(url)?, token, client, is_libsql: true });
+      }
+      Ok(RemoteCentral { base_url: url.trim().trim_end_matches('/').to_string(), token, client, is_libsql: false })
+   }
+
+   /// The central database if it's a dejacmd server or libSQL database, otherwise None
+   pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String>
+   //-----------------------------------------------------------------------
+   {
+      let url = settings.get_central_database_url();
+      if !is_remote_url(&url)
+      {
+         return Ok(None);
+      }
+      let (_, token) = settings.get_credentials(false).map_err(|e| e.to_string())?;
+      Ok(Some(RemoteCentral::with_connect_timeout(&url, token, PoolSettings::from_settings(settings).connect_timeout)?))
+   }
+
+   pub fn url(&self) -> &str { &self.base_url }
+
+   /// Whether the central history is a libSQL database rather than a dejacmd server
+   pub fn is_libsql(&self) -> bool { self.is_libsql }
+
+   pub async fn post<T, R>(&self, path: &str, body: &T) -> Result<R, RemoteError>
+      where T: Serialize + ?Sized, R: DeserializeOwned
+   //----------------------------------------------------------------------------
+   {
+      if self.is_libsql
+      {
+         return libsql::post(self, path, body).await;
+      }
+      self.post_json(path, body).await
+   }
+
+   /// POST body to the path without answering dejacmd server endpoints for a libSQL database
+   pub(crate) async fn post_json<T, R>(&self, path: &str, body: &T) -> Result<R, RemoteError>
+      where T: Serialize + ?Sized, R: DeserializeOwned
+   //-----------------------------------------------------------------------------------------
+   {
+      let url = format!("{}{}", self.base_url, path);
+      debug!("POST {}", url);
+      self.send(&url, self.client.post(&url).json(body)).await
+   }
+
+   pub async fn get<R>(&self, path: &str) -> Result<R, RemoteError>
+      where R: DeserializeOwned
+   //---------------------------------------------------------------
+   {
+      if self.is_libsql
+      {
+         return libsql::get(self, path).await;
+      }
+      let url = format!("{}{}", self.base_url, path);
+      debug!("GET {}", url);
+      self.send(&url, self.client.get(&url)).await
+   }
+
+   async fn send<R>(&self, url: &str, mut request: reqwest::RequestBuilder) -> Result<R, RemoteError>
+      where R: DeserializeOwned
+   //-------------------------------------------------------------------------------------------------
+   {
+      if !self.token.expose_secret().is_empty()
+      {
+         request = request.bearer_auth(self.token.expose_secret());
+      }
+      let response = request.send().await.map_err(|e|
+      {
+         if e.is_connect() || e.is_timeout() { RemoteError::Unreachable(format!("{}: {}", url, e)) }
+         else { RemoteError::Failed(format!("Error sending request to {}: {}", url, e)) }
+      })?;
+      let status = response.status();
+      if !status.is_success()
+      {
+         let text = response.text().await.unwrap_or_default();
+         let message = serde_json::from_str::<ErrorResponse>(&text).map(|e| e.error).unwrap_or(text);
+         return Err(RemoteError::Failed(format!("{} returned {}: {}", url, status, message)));
+      }
+      response.json::<R>().await.map_err(|e| RemoteError::Failed(format!("Invalid response from {}: {}", url, e)))
+   }
+
+   /// Insert entries, skipping any whose id already exists. Returns the number inserted.
+   pub async fn insert(&self, entries: &[HistoryEntry]) -> Result<u64, RemoteError>
+   //-------------------------------------------------------------------------------
+   {
+      self.post::<_, InsertResponse>(HISTORY_PATH, entries).await.map(|r| r.inserted)
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+   #[tokio::test]
+   async fn test_remote_insert()
+   {
+      assert!(is_http_url("HTTPS://history.example.com"));
+      assert!(!is_http_url("postgres://localhost/dejacmd"));
+
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let url = format!("http://{}/", listener.local_addr().unwrap());
+      let server = tokio::spawn(async move
+      {
+         let (mut socket, _) = listener.accept().await.unwrap();
+         let mut request = Vec::new();
+         let mut buf = [0u8; 4096];
+         // Headers and the small JSON body arrive well within a few reads
+         while !String::from_utf8_lossy(&request).contains("\"command\"")
+         {
+            let n = socket.read(&mut buf).await.unwrap();
+            if n == 0 { break; }
+            request.extend_from_slice(&buf[..n]);
+         }
+         let body = r#"{"inserted":1}"#;
+         socket.write_all(format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                  body.len(), body).as_bytes()).await.unwrap();
+         String::from_utf8_lossy(&request).to_string()
+      });
+
+      let remote = RemoteCentral::new(&url, SecretString::from("s3cret")).unwrap();
+      let entry = HistoryEntry::new_for_test("a", "2026-01-11 04:33:25", "ls");
+      assert_eq!(remote.insert(std::slice::from_ref(&entry)).await.unwrap(), 1);
+      let request = server.await.unwrap();
+      assert!(request.starts_with("POST /api/v1/history "), "{}", request);
+      assert!(request.to_lowercase().contains("authorization: bearer s3cret"));
+
+      // Nothing listening
+      let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let closed_url = format!("http://{}", closed.local_addr().unwrap());
+      drop(closed);
+      let remote = RemoteCentral::new(&closed_url, SecretString::default()).unwrap();
+      assert!(matches!(remote.insert(&[entry]).await, Err(RemoteError::Unreachable(_))));
+   }
+}
@@ -1,20 +1,87 @@
 //#![feature(os_str_display)]
-use std::{fmt, env, ffi::os_str::Display, fs::File, io::Write, path::PathBuf};
+use std::{fmt, env, fs::File, io::Write, path::{Path, PathBuf}};
 
-use aes_gcm::{ // cargo add aes-gcm
-    aead::{KeyInit, OsRng},
-    Aes256Gcm
-};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use secrecy::{ExposeSecret, SecretString};
+use secrecy::zeroize::Zeroizing;
+use tracing::{debug, error, warn};
 
 use crate::crypt;
+use crate::error::DejacmdError;
 use crate::crypt::generate_key;
 
 const PROGRAM: &str = "dejacmd";
 
+/// Version of the settings file format written by this dejacmd. A file without a version predates versioning and is
+/// version 0. Settings::migrate brings older files up to date.
+pub const SETTINGS_VERSION: u32 = 2;
+pub const DEFAULT_HYPERLINK_TEMPLATE: &str = "dejacmd://show/{id}";
+
+/// Keys in the settings file, in file order, for `dejacmd config --list/--set/--unset`
+pub const SETTING_KEYS: &[&str] =
+&[
+   "version", "local_database_url", "local_user", "local_encrypted_password", "central_database_url", "central_user",
+   "central_encrypted_password", "encryption_key", "passphrase_kdf", "encryption_key_file", "cipher",
+   "last_local_update_file",
+   "last_central_update_file", "last_local_sync", "last_local_sync_rows", "last_central_sync", "last_central_sync_rows",
+   "hyperlink_template", "search_lines", "timezone", "date_format", "color",
+   "central_exclude_commands", "central_exclude_dirs", "central_exclude_shells", "ignore_commands", "ignore_space",
+   "pool_max_connections", "pool_acquire_timeout_secs", "connect_timeout_secs", "log_connect_timeout_ms",
+   "log_timeout_ms", "retention_older_than", "retention_keep_unique", "retention_exclude_commands",
+   "pre_insert_hooks", "post_insert_hooks", "hook_timeout_ms", "exec_allow_commands", "exec_deny_commands",
+   "exec_confirm_commands", "shells", "output_summary_lines", "digest_schedule", "digest_format", "digest_file",
+   "digest_webhook", "digest_sendmail_to", "last_digest",
+];
+
+/// Shells dejacmd-log looks for among its parent processes when the shell setting isn't set
+pub const DEFAULT_SHELLS: &[&str] = &["bash", "zsh", "pwsh", "fish", "nu", "ksh", "mksh", "tcsh", "csh", "dash", "xonsh"];
+
+/// Settings whose values are masked when listed and which can't be changed with --set/--unset
+pub const SECRET_SETTING_KEYS: &[&str] = &["local_encrypted_password", "central_encrypted_password", "encryption_key"];
+
+/// Settings that need the database URL, user and password handling of `dejacmd config -L/-C`
+pub const DATABASE_SETTING_KEYS: &[&str] = &["local_database_url", "local_user", "central_database_url", "central_user"];
+
+/// Environment variable overriding the location of the encryption-key file (see Settings::key_file_path)
+pub const KEY_FILE_ENV: &str = "DEJACMD_KEY_FILE";
+
+/// Environment variable overriding the directory the default local database is kept in (see Settings::get_data_path)
+pub const DATA_DIR_ENV: &str = "DEJACMD_DATA_DIR";
+
+/// File name of the default local database in the data directory
+const DATABASE_FILE: &str = "history.sqlite";
+
+/// Environment variable holding the passphrase the encryption key is derived from when it isn't stored in a file
+pub const PASSPHRASE_ENV: &str = "DEJACMD_PASSPHRASE";
+
+/// File in the runtime directory holding the key derived by dejacmd unlock, so dejacmd-log can decrypt passwords
+/// without the passphrase until dejacmd lock or the user logs out
+const UNLOCKED_KEY_FILE: &str = "dejacmd-unlocked.key";
+
+/// The key derived from the passphrase with the derivation it was derived with, kept so the passphrase is only asked
+/// for once
+static PASSPHRASE_KEY: Mutex<Option<(String, Zeroizing<String>)>> = Mutex::new(None);
+
+static IS_PASSPHRASE_PROMPT: AtomicBool = AtomicBool::new(false);
+
+/// Allow asking for the passphrase on the terminal when a passphrase key is needed and it isn't unlocked or in
+/// DEJACMD_PASSPHRASE. Only for interactive programs, dejacmd-log never asks.
+pub fn allow_passphrase_prompt()
+//------------------------------
+{
+   IS_PASSPHRASE_PROMPT.store(true, Ordering::Relaxed);
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Settings
 {
    // #[serde(skip)] program: String,
+   /// Settings file format version (SETTINGS_VERSION when written), 0 for files from before it was added
+   #[serde(default)]
+   pub version:                        u32,
+
    #[serde(default = "Settings::default_local_database_url")]
    local_database_url:                 String,
    #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,11 +97,150 @@ pub struct Settings
    central_encrypted_password:         Option<String>,
    #[serde(skip_serializing_if = "Option::is_none")]
    encryption_key:                     Option<String>,
+   /// Set when the key encrypting the passwords is derived from a passphrase instead of being stored in the
+   /// encryption-key file (see crypt::new_passphrase_kdf)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   passphrase_kdf:                     Option<String>,
+   /// Where the encryption-key file is kept instead of the config directory (DEJACMD_KEY_FILE overrides it)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub encryption_key_file:            Option<String>,
+   /// Cipher new passwords are encrypted with (crypt::Cipher::NAMES), otherwise the one named in the encryption-key
+   /// file header or AES-256-GCM
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub cipher:                         Option<String>,
 
    #[serde(skip_serializing_if = "Option::is_none")]
    pub last_local_update_file:         Option<String>,
    #[serde(skip_serializing_if = "Option::is_none")]
    pub last_central_update_file:       Option<String>,
+
+   /// UTC time rows were last synced into the local database by `dejacmd sync` and its row count afterwards
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub last_local_sync:                Option<String>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub last_local_sync_rows:           Option<i64>,
+   /// UTC time rows were last synced into the central database by `dejacmd sync` and its row count afterwards
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub last_central_sync:              Option<String>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub last_central_sync_rows:         Option<i64>,
+
+   #[serde(skip_serializing_if = "Option::is_none")]
+   hyperlink_template:                 Option<String>,
+
+   /// Number of commands dejacmd search shows without -n
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub search_lines:                   Option<u64>,
+   /// Timezone timestamps are shown in: local (as recorded), utc or an offset such as +05:30 (see display::DisplayZone)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub timezone:                       Option<String>,
+   /// strftime format timestamps are shown in, defaults to %Y-%m-%d %H:%M:%S as recorded
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub date_format:                    Option<String>,
+   /// auto, always or never. auto colors output to a terminal unless NO_COLOR is set
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub color:                          Option<String>,
+
+   /// Commands kept locally but never written to the central database (see exclusions::CentralExclusions)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub central_exclude_commands:       Option<Vec<String>>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub central_exclude_dirs:           Option<Vec<String>>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub central_exclude_shells:         Option<Vec<String>>,
+
+   /// Commands dejacmd-log never logs to either database, glob patterns as for central_exclude_commands
+   /// (see exclusions::IgnoreRules)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub ignore_commands:                Option<Vec<String>>,
+   /// Don't log commands typed with a leading space, as with HISTCONTROL=ignorespace (defaults to true)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub ignore_space:                   Option<bool>,
+
+   /// Connection pool tuning, unset values use the defaults in PoolSettings
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub pool_max_connections:           Option<u32>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub pool_acquire_timeout_secs:      Option<u64>,
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub connect_timeout_secs:           Option<u64>,
+   /// Connect timeout for dejacmd-log, kept short so a database that is down doesn't hold up the shell prompt
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub log_connect_timeout_ms:         Option<u64>,
+   /// Overall time dejacmd-log may take writing a command before it spools it for the central database and exits
+   /// (unset or 0 for no limit)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub log_timeout_ms:                 Option<u64>,
+
+   /// Retention policy used by `dejacmd prune` when it isn't given on the command line. Age such as 2y, 6m, 4w or 90d.
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub retention_older_than:           Option<String>,
+   /// Keep the most recent row of each command however old it is
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub retention_keep_unique:          Option<bool>,
+   /// Glob patterns (as for central_exclude_commands) of commands that are never pruned
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub retention_exclude_commands:     Option<Vec<String>>,
+
+   /// Commands run with each new entry as JSON on stdin before it is written, which can print a changed entry or exit
+   /// non-zero to drop it (see hooks::Hooks)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub pre_insert_hooks:               Option<Vec<String>>,
+   /// Commands run with the entries written (JSON lines) on stdin, not waited for
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub post_insert_hooks:              Option<Vec<String>>,
+   /// How long a pre_insert_hooks command may take before it is killed (default 1000ms)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub hook_timeout_ms:                Option<u64>,
+
+   /// Policy for running a command chosen with --exec (see exclusions::ExecPolicy), glob patterns as for
+   /// central_exclude_commands. When exec_allow_commands is set only commands it matches are run.
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub exec_allow_commands:            Option<Vec<String>>,
+   /// Commands never run (defaults to exclusions::DEFAULT_EXEC_DENY, set to [] to allow them)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub exec_deny_commands:             Option<Vec<String>>,
+   /// Commands only run after confirming (defaults to exclusions::DEFAULT_EXEC_CONFIRM)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub exec_confirm_commands:          Option<Vec<String>>,
+
+   /// Program names of the shells dejacmd-log takes its shell and directory from when walking up its parent
+   /// processes (defaults to DEFAULT_SHELLS)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub shells:                         Option<Vec<String>>,
+   /// Lines kept from the start and from the end of the output of each command captured by the hooks from
+   /// dejacmd init --capture-output (defaults to output::DEFAULT_OUTPUT_SUMMARY_LINES)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub output_summary_lines:           Option<usize>,
+
+   /// How often dejacmd-logd delivers a digest (a dejacmd report of the central database, or the local database if
+   /// there is no central database) to the digest targets: daily or weekly (unset for never, see digest::Schedule)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub digest_schedule:                Option<String>,
+   /// Format of the digest, html or md (defaults to html)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub digest_format:                  Option<String>,
+   /// File the digest is written to, replacing the previous digest
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub digest_file:                    Option<String>,
+   /// URL the digest is POSTed to
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub digest_webhook:                 Option<String>,
+   /// Addresses the digest is mailed to with sendmail
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub digest_sendmail_to:             Option<Vec<String>>,
+   /// When dejacmd-logd last delivered a digest to each target (file, webhook and sendmail, UTC)
+   #[serde(skip_serializing_if = "Option::is_none")]
+   pub last_digest:                    Option<std::collections::BTreeMap<String, String>>,
+
+   /// In-memory settings for --ephemeral or from Settings::builder that are never read from or written to the settings
+   /// file
+   #[serde(skip)]
+   is_ephemeral:                       bool,
+   /// The key the passwords of settings from Settings::builder are encrypted with, used instead of the encryption-key
+   /// file or passphrase
+   #[serde(skip)]
+   memory_key:                         Option<SecretString>,
 }
 
 impl Default for Settings
@@ -44,6 +250,7 @@ impl Default for Settings
    {
       Self
       {
+         version: SETTINGS_VERSION,
          local_database_url: Settings::default_local_database_url(),
          local_user: None,
          local_encrypted_password: None,
@@ -51,8 +258,49 @@ impl Default for Settings
          central_user: None,
          central_encrypted_password: None,
          encryption_key: None,
+         passphrase_kdf: None,
+         encryption_key_file: None,
+         cipher: None,
          last_local_update_file: None,
          last_central_update_file: None,
+         last_local_sync: None,
+         last_local_sync_rows: None,
+         last_central_sync: None,
+         last_central_sync_rows: None,
+         hyperlink_template: None,
+         search_lines: None,
+         timezone: None,
+         date_format: None,
+         color: None,
+         central_exclude_commands: None,
+         central_exclude_dirs: None,
+         central_exclude_shells: None,
+         ignore_commands: None,
+         ignore_space: None,
+         pool_max_connections: None,
+         pool_acquire_timeout_secs: None,
+         connect_timeout_secs: None,
+         log_connect_timeout_ms: None,
+         log_timeout_ms: None,
+         retention_older_than: None,
+         retention_keep_unique: None,
+         retention_exclude_commands: None,
+         pre_insert_hooks: None,
+         post_insert_hooks: None,
+         hook_timeout_ms: None,
+         exec_allow_commands: None,
+         exec_deny_commands: None,
+         exec_confirm_commands: None,
+         shells: None,
+         output_summary_lines: None,
+         digest_schedule: None,
+         digest_format: None,
+         digest_file: None,
+         digest_webhook: None,
+         digest_sendmail_to: None,
+         last_digest: None,
+         is_ephemeral: false,
+         memory_key: None,
       }
    }
 }
@@ -69,11 +317,18 @@ impl fmt::Display for Settings
                             self.local_database_url, self.local_user, self.central_database_url, self.central_user)
       };
 
-      let settings_file = match Settings::get_settings_path()
+      let settings_file = if self.is_ephemeral
       {
-         | Ok(p) => "(".to_string() + p.display().to_string().as_str() + ")",
-         | Err(_) => "".to_string()
-       };
+         "(ephemeral, not saved)".to_string()
+      }
+      else
+      {
+         match Settings::get_settings_path()
+         {
+            | Ok(p) => "(".to_string() + p.display().to_string().as_str() + ")",
+            | Err(_) => "".to_string()
+         }
+      };
       write!(f, "Settings {}\n{}\nLocal database URL: {}, Local_user: {:?}\nCentral Database URL: {:?}, Central User: {:?}",
              settings_file, settings, self.local_database_url, self.local_user, self.central_database_url, self.central_user)
    }
@@ -82,8 +337,26 @@ impl fmt::Display for Settings
 impl Settings
 //===========
 {
+   /// The default local database, history.sqlite in the data directory or, if there is no data directory, the
+   /// database in the home directory used before
    fn default_local_database_path() -> PathBuf
    //----------------------------------------
+   {
+      match Settings::get_data_path()
+      {
+         | Ok(path) => path.join(DATABASE_FILE),
+         | Err(e) =>
+         {
+            warn!("Using a local database in the home directory: {}", e);
+            Settings::legacy_local_database_path()
+         }
+      }
+   }
+
+   /// ~/.dejacmd.sqlite (~/dejacmd.sqlite on Windows), the default local database before it moved to the data
+   /// directory
+   fn legacy_local_database_path() -> PathBuf
+   //---------------------------------------
    {
       let home_dir = Settings::get_home_dir();
       if env::consts::OS == "windows"
@@ -102,7 +375,22 @@ impl Settings
 
    pub fn new() -> Self { Settings::default() }
 
-   pub fn get_settings(&self) -> Result<Settings, String>
+   /// Settings for a throwaway in-memory local database and no central database. Nothing is read from or
+   /// written to the user's config directory.
+   pub fn new_ephemeral() -> Self
+   //----------------------------
+   {
+      Self
+      {
+         local_database_url: crate::EPHEMERAL_DATABASE_URL.to_string(),
+         is_ephemeral: true,
+         ..Settings::default()
+      }
+   }
+
+   pub fn is_ephemeral(&self) -> bool { self.is_ephemeral }
+
+   pub fn get_settings(&self) -> Result<Settings, DejacmdError>
 //-------------------------------------------
    {
       let settings_path = match Settings::get_settings_path()
@@ -114,7 +402,7 @@ impl Settings
             | Err(e) =>
             {
                let errmsg = format!("Error on get settings: {}", e);
-               return Err(errmsg);
+               return Err(DejacmdError::Config(errmsg));
             }
          },
       };
@@ -126,7 +414,7 @@ impl Settings
             | Ok(_) => (),
             | Err(e) =>
             {
-               eprintln!("Error creating default settings: {}", e);
+               warn!("Error creating default settings: {}", e);
                // PathBuf::new()
             }
          };
@@ -139,35 +427,17 @@ impl Settings
    {
       match self.get_settings()
       {
-         | Ok(mut s) => //s,
-         {  //TODO: Moving encryption key to separate file - remove later
-            match s.encryption_key.clone()
+         | Ok(mut s) =>
+         {
+            let version = s.version;
+            if let Err(e) = s.migrate()
             {
-               |  Some(k) =>
-                  {
-                     match s.set_encrypt_key(Some(k.clone()))
-                     {
-                        | Ok(_) =>
-                        {
-                           s.encryption_key = None;
-                           match s.write_settings()
-                           {
-                              | Ok(_) => (),
-                              | Err(e) =>
-                              {
-                                 s.encryption_key = Some(k);
-                                 eprintln!("Error writing settings after moving encryption key: {}", e);
-                              }
-                           }
-                        },
-                        | Err(e) =>
-                        {
-                           eprintln!("Error moving encryption key to separate file: {}", e);
-                        }
-                     }
-                  },
-                  None => { }
-            };
+               warn!("Error updating the settings file from version {} to {}: {}", s.version, SETTINGS_VERSION, e);
+            }
+            if s.version != version && let Err(e) = s.write_settings()
+            {
+               warn!("Error writing settings updated to version {}: {}", s.version, e);
+            }
             *self = s.clone();
             s
          }
@@ -180,23 +450,76 @@ impl Settings
       }
    }
 
-   fn set_encrypt_key(&mut self, hex_key: Option<String>) -> Result<(), String>
-   //--------------------------------------------------------------------------
+   /// Update settings read from an older settings file one version at a time up to SETTINGS_VERSION. If an update
+   /// fails the version is left at the last one that succeeded so the rest are tried again next time. The caller
+   /// writes the settings if the version changed.
+   fn migrate(&mut self) -> Result<(), DejacmdError>
+   //-----------------------------------------
    {
-      let encryption_file_path = match Settings::get_config_path()
+      if self.is_ephemeral
       {
-         | Ok(mut p) =>
-         {
-            p.push("encryption-key");
-            p
-         }
-         | Err(e) =>
+         return Ok(());
+      }
+      if self.version > SETTINGS_VERSION
+      {
+         warn!("The settings file is version {}, from a newer dejacmd than this one (version {})", self.version, SETTINGS_VERSION);
+         return Ok(());
+      }
+      while self.version < SETTINGS_VERSION
+      {
+         match self.version
          {
-            let errmsg = format!("Failed to get config path for encryption key: {}", e);
-            eprintln!("{errmsg}");
-            return Err(errmsg);
+            | 0 => self.migrate_encryption_key()?,
+            | 1 => self.migrate_legacy_database()?,
+            | _ => (),
          }
-      };
+         self.version += 1;
+         debug!("Updated settings to version {}", self.version);
+      }
+      Ok(())
+   }
+
+   /// Version 1: the encryption key moved from the settings file to the encryption-key file
+   fn migrate_encryption_key(&mut self) -> Result<(), DejacmdError>
+   //--------------------------------------------------------
+   {
+      if let Some(key) = self.encryption_key.clone()
+      {
+         self.set_encrypt_key(Some(key))
+            .map_err(|e| DejacmdError::Crypto(format!("Error moving encryption key to separate file: {}", e)))?;
+         self.encryption_key = None;
+      }
+      Ok(())
+   }
+
+   /// Version 2: move a local database at the old default location (~/.dejacmd.sqlite) to the data directory if the
+   /// settings still use the old default, and use the new location. The move is made holding a lock beside the new
+   /// location, as every dejacmd process reading the old settings tries it, e.g several dejacmd-log at once.
+   fn migrate_legacy_database(&mut self) -> Result<(), DejacmdError>
+   //---------------------------------------------------------
+   {
+      let legacy_path = Settings::legacy_local_database_path();
+      let path = Settings::default_local_database_path();
+      if path == legacy_path || self.local_database_url != format!("sqlite://{}", legacy_path.display())
+      {
+         return Ok(());
+      }
+      // Released when dropped, by then a process that was waiting sees the database already moved
+      let _lock = lock_beside(&path)
+         .map_err(|e| DejacmdError::Config(format!("Error locking the local database to move it: {}", e)))?;
+      let is_moved = move_database(&legacy_path, &path)
+         .map_err(|e| DejacmdError::Config(format!("Error moving the local database to the data directory: {}", e)))?;
+      if is_moved
+      {
+         self.local_database_url = Settings::default_local_database_url();
+      }
+      Ok(())
+   }
+
+   fn set_encrypt_key(&mut self, hex_key: Option<String>) -> Result<(), DejacmdError>
+   //--------------------------------------------------------------------------
+   {
+      let encryption_file_path = Settings::key_file_path(self.encryption_key_file.as_deref())?;
       let key = match hex_key
       {
          | Some(k) => k,
@@ -204,7 +527,7 @@ impl Settings
       };
       if key.trim().is_empty()
       {
-         return Err("Encryption key cannot be empty".to_string());
+         return Err(DejacmdError::Crypto("Encryption key cannot be empty".to_string()));
       }
       match std::fs::write(&encryption_file_path, &key)
       {
@@ -212,8 +535,8 @@ impl Settings
          | Err(e) =>
          {
             let errmsg = format!("Failed to write encryption key to file {}: {}", encryption_file_path.display(), e);
-            eprintln!("{errmsg}");
-            return Err(errmsg);
+            debug!("{errmsg}");
+            return Err(DejacmdError::Config(errmsg));
          }
       };
       // Restrict file permissions to owner read/write only
@@ -237,24 +560,263 @@ impl Settings
       Ok(())
    }
 
-   fn get_encryption_key(is_generate: bool) -> Result<String, String>
-   //-----------------------------------------------
+   /// The key encrypting the database passwords, derived from the passphrase if kdf (the passphrase_kdf setting) is
+   /// set or else read from the encryption-key file (see key_file_path), which is created if is_generate and it
+   /// doesn't exist
+   fn get_encryption_key(kdf: Option<&str>, key_file: Option<&str>, is_generate: bool) -> Result<String, DejacmdError>
+   //-----------------------------------------------------------------------------------------------------------
    {
-      // Read encryption key from hidden file encryption-key with read permissions only for current user
-      let encryption_file_path = match Settings::get_config_path()
+      match kdf
+      {
+         | Some(kdf) => Settings::get_passphrase_key(kdf),
+         | None => Settings::get_file_encryption_key(key_file, is_generate),
+      }
+   }
+
+   /// The encryption-key file: DEJACMD_KEY_FILE, or key_file (the encryption_key_file setting) if either is set e.g to
+   /// keep the key on removable media or a tmpfs, or else encryption-key in the config directory. A file elsewhere
+   /// whose directory doesn't exist (e.g the media isn't mounted) is an error rather than a new key being generated.
+   pub fn key_file_path(key_file: Option<&str>) -> Result<PathBuf, DejacmdError>
+   //---------------------------------------------------------------------
+   {
+      let configured = env::var(KEY_FILE_ENV).ok().filter(|f| !f.trim().is_empty())
+         .or_else(|| key_file.filter(|f| !f.trim().is_empty()).map(|f| f.to_string()));
+      match configured
+      {
+         | Some(file) =>
+         {
+            let path = match file.trim().strip_prefix("~/")
+            {
+               | Some(rest) => Settings::get_home_dir().join(rest),
+               | None => PathBuf::from(file.trim()),
+            };
+            match path.parent()
+            {
+               | Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() =>
+                  Err(DejacmdError::Config(format!("The directory of the encryption key file {} doesn't exist", path.display()))),
+               | _ => Ok(path),
+            }
+         },
+         | None => Settings::get_config_path().map(|p| p.join("encryption-key"))
+            .map_err(|e| DejacmdError::Config(format!("Failed to get config path for encryption key: {}", e))),
+      }
+   }
+
+   /// The encryption-key file these settings use (see key_file_path)
+   pub fn get_encryption_key_path(&self) -> Result<PathBuf, DejacmdError>
+   //--------------------------------------------------------------
+   {
+      Settings::key_file_path(self.encryption_key_file.as_deref())
+   }
+
+   /// The key derived from the passphrase in DEJACMD_PASSPHRASE, the key from dejacmd unlock or the passphrase entered
+   /// on the terminal if allow_passphrase_prompt was called, in that order
+   fn get_passphrase_key(kdf: &str) -> Result<String, DejacmdError>
+   //--------------------------------------------------------
+   {
+      let mut cached = PASSPHRASE_KEY.lock().map_err(|_| DejacmdError::Crypto("Error reading the passphrase key".to_string()))?;
+      if let Some((cached_kdf, key)) = cached.as_ref()
+         && cached_kdf == kdf
+      {
+         return Ok(key.to_string());
+      }
+      let passphrase = Zeroizing::new(env::var(PASSPHRASE_ENV).unwrap_or_default());
+      let key = if !passphrase.is_empty()
+      {
+         Zeroizing::new(crypt::derive_key(&passphrase, kdf)?)
+      }
+      else if let Some(key) = Settings::read_unlocked_key(kdf)
+      {
+         key
+      }
+      else if IS_PASSPHRASE_PROMPT.load(Ordering::Relaxed)
+      {
+         let passphrase = Zeroizing::new(rpassword::prompt_password("Passphrase for the dejacmd encryption key: ")
+            .map_err(|e| DejacmdError::Crypto(format!("Error reading passphrase: {}", e)))?);
+         Zeroizing::new(crypt::derive_key(&passphrase, kdf)?)
+      }
+      else
+      {
+         return Err(DejacmdError::Crypto(format!("The encryption key is derived from a passphrase, run dejacmd unlock or set {}", PASSPHRASE_ENV)));
+      };
+      *cached = Some((kdf.to_string(), key.clone()));
+      Ok(key.to_string())
+   }
+
+   /// The hex key the database passwords are encrypted with, from the passphrase or the encryption-key file
+   pub fn get_password_key(&self) -> Result<String, DejacmdError>
+   //------------------------------------------------------
+   {
+      match &self.memory_key
+      {
+         | Some(key) => Ok(key.expose_secret().to_string()),
+         | None => Settings::get_encryption_key(self.passphrase_kdf.as_deref(), self.encryption_key_file.as_deref(), false),
+      }
+   }
+
+   /// The key of a SQLCipher database without a password, derived from the key the database passwords are encrypted
+   /// with. The encryption-key file is created if it doesn't exist yet, as for a new password.
+   pub fn get_database_key(&self) -> Result<String, DejacmdError>
+   //----------------------------------------------------------
+   {
+      self.derive_key("sqlcipher database")
+   }
+
+   /// The key dejacmd anonymize hashes values into pseudonyms with, derived from the key the database passwords are
+   /// encrypted with so a value gets the same pseudonym every time it is anonymized on this machine
+   pub fn get_anonymize_key(&self) -> Result<String, DejacmdError>
+   //-----------------------------------------------------------
+   {
+      self.derive_key("anonymize")
+   }
+
+   fn derive_key(&self, purpose: &str) -> Result<String, DejacmdError>
+   //-----------------------------------------------------------------
+   {
+      let key = match &self.memory_key
+      {
+         | Some(key) => Zeroizing::new(key.expose_secret().to_string()),
+         | None => Zeroizing::new(Settings::get_encryption_key(self.passphrase_kdf.as_deref(), self.encryption_key_file.as_deref(), true)?),
+      };
+      crypt::derive_subkey(&key, purpose)
+   }
+
+   /// Whether the key encrypting the database passwords is derived from a passphrase rather than stored in a file
+   pub fn is_passphrase_key(&self) -> bool { self.passphrase_kdf.is_some() }
+
+   /// The cipher setting, None to use the key file's cipher or the default
+   pub fn get_cipher(&self) -> Result<Option<crypt::Cipher>, DejacmdError>
+   //----------------------------------------------------------------
+   {
+      self.cipher.as_deref().map(crypt::Cipher::from_name).transpose()
+   }
+
+   /// Encrypt the database passwords with a key derived from passphrase, or with None a key stored in the
+   /// encryption-key file again, re-encrypting the passwords already set. The encryption-key file is deleted when
+   /// changing to a passphrase so the key is no longer stored beside the passwords.
+   pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), DejacmdError>
+   //------------------------------------------------------------------------------
+   {
+      // A SQLCipher database encrypted with the old key keeps it as its password
+      let (_, local_password) = self.get_credentials(true)?;
+      let (_, central_password) = self.get_credentials(false)?;
+      let kdf = passphrase.map(|_| crypt::new_passphrase_kdf());
+      let key = match (&kdf, passphrase)
       {
-         | Ok(mut p) =>
+         | (Some(kdf), Some(passphrase)) =>
          {
-            p.push("encryption-key");
-            p
+            let key = Zeroizing::new(crypt::derive_key(passphrase, kdf)?);
+            if let Ok(mut cached) = PASSPHRASE_KEY.lock()
+            {
+               *cached = Some((kdf.clone(), key.clone()));
+            }
+            key
+         },
+         | _ => Zeroizing::new(Settings::get_file_encryption_key(self.encryption_key_file.as_deref(), true)?),
+      };
+      let cipher = self.get_cipher()?;
+      let encrypt = |password: &SecretString| -> Result<Option<String>, DejacmdError>
+      {
+         if password.expose_secret().is_empty()
+         {
+            return Ok(None);
          }
-         | Err(e) =>
+         crypt::encrypt_with(cipher, password.expose_secret(), &key).map(|data| Some(hex::encode(data)))
+            .map_err(|e| DejacmdError::Crypto(format!("Failed to encrypt database password: {}", e)))
+      };
+      self.local_encrypted_password = encrypt(&local_password)?;
+      self.central_encrypted_password = encrypt(&central_password)?;
+      self.passphrase_kdf = kdf;
+      self.write_settings().map_err(|e| DejacmdError::Config(format!("Failed to write settings file: {}", e)))?;
+      Settings::lock()?;
+      if self.passphrase_kdf.is_some()
+      {
+         let path = self.get_encryption_key_path()?;
+         match std::fs::remove_file(&path)
          {
-            let errmsg = format!("Failed to get config path for encryption key: {}", e);
-            eprintln!("{errmsg}");
-            return Err(errmsg);
+            | Ok(_) => {},
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            | Err(e) => return Err(DejacmdError::Crypto(format!("Failed to remove encryption key file {}: {}", path.display(), e))),
          }
+      }
+      Ok(())
+   }
+
+   /// File holding the key from dejacmd unlock, in the user's runtime directory ($XDG_RUNTIME_DIR, which is emptied on
+   /// logout) where there is one and otherwise in a dejacmd-<uid> directory in the temporary directory. Fails if the
+   /// directory is accessible by other users.
+   pub fn unlocked_key_path() -> Result<PathBuf, DejacmdError>
+   //---------------------------------------------------------
+   {
+      let dir = match dirs::runtime_dir()
+      {
+         | Some(dir) => dir,
+         | None =>
+         {
+            #[cfg(unix)]
+            let name = format!("{}-{}", PROGRAM, nix::unistd::getuid());
+            #[cfg(not(unix))]
+            let name = PROGRAM.to_string();
+            env::temp_dir().join(name)
+         },
       };
+      private_dir(&dir)?;
+      Ok(dir.join(UNLOCKED_KEY_FILE))
+   }
+
+   fn read_unlocked_key(kdf: &str) -> Option<Zeroizing<String>>
+   //-----------------------------------------------------------
+   {
+      let text = Zeroizing::new(std::fs::read_to_string(Settings::unlocked_key_path().ok()?).ok()?);
+      // A key unlocked for an earlier passphrase is ignored
+      match text.trim().split_once('\n')
+      {
+         | Some((unlocked_kdf, key)) if unlocked_kdf == kdf && key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit()) =>
+            Some(Zeroizing::new(key.to_string())),
+         | _ => None,
+      }
+   }
+
+   /// Derive the key from passphrase and keep it (readable only by the user) until dejacmd lock, so dejacmd-log can
+   /// decrypt the database passwords without the passphrase. Fails if passphrase doesn't decrypt the passwords.
+   pub fn unlock(&self, passphrase: &str) -> Result<PathBuf, DejacmdError>
+   //----------------------------------------------------------------
+   {
+      let kdf = self.passphrase_kdf.as_deref()
+         .ok_or_else(|| DejacmdError::Crypto("The encryption key isn't derived from a passphrase (see dejacmd config --passphrase)".to_string()))?;
+      let key = Zeroizing::new(crypt::derive_key(passphrase, kdf)?);
+      for encrypted in [&self.local_encrypted_password, &self.central_encrypted_password].into_iter().flatten()
+      {
+         let data = hex::decode(encrypted)
+            .map_err(|e| DejacmdError::Parse(format!("Failed to hex decode encrypted password: {}", e)))?;
+         crypt::decrypt(&data, &key).map_err(|_| DejacmdError::Crypto("Wrong passphrase".to_string()))?;
+      }
+      let path = Settings::unlocked_key_path()?;
+      Settings::lock()?;
+      let text = Zeroizing::new(format!("{}\n{}\n", kdf, key.as_str()));
+      write_atomically(&path, text.as_bytes())
+         .map_err(|e| DejacmdError::Config(format!("Failed to write {}: {}", path.display(), e)))?;
+      Ok(path)
+   }
+
+   /// Forget the key from dejacmd unlock, returning false if it wasn't unlocked
+   pub fn lock() -> Result<bool, DejacmdError>
+   //-----------------------------------
+   {
+      let path = Settings::unlocked_key_path()?;
+      match std::fs::remove_file(&path)
+      {
+         | Ok(_) => Ok(true),
+         | Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+         | Err(e) => Err(DejacmdError::Config(format!("Failed to remove {}: {}", path.display(), e))),
+      }
+   }
+
+   fn get_file_encryption_key(key_file: Option<&str>, is_generate: bool) -> Result<String, DejacmdError>
+   //---------------------------------------------------------------------------------------------
+   {
+      // Read encryption key from hidden file encryption-key with read permissions only for current user
+      let encryption_file_path = Settings::key_file_path(key_file)?;
       if !encryption_file_path.exists() && is_generate
       {
          // generate_key() already returns a hex-encoded string (64 hex chars = 32 bytes)
@@ -265,8 +827,8 @@ impl Settings
             | Err(e) =>
             {
                let errmsg = format!("Failed to write encryption key to file {}: {}", encryption_file_path.display(), e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Config(errmsg));
             }
          };
          // Restrict file permissions to owner read/write only
@@ -297,15 +859,15 @@ impl Settings
             | Err(e) =>
             {
                let errmsg = format!("Failed to read encryption key from file {}: {}", encryption_file_path.display(), e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Config(errmsg));
             }
          };
          Ok(hex_key)
       }
       else
       {
-         Err("Encryption key file does not exist".to_string())
+         Err(DejacmdError::Crypto("Encryption key file does not exist".to_string()))
       }
       
    }
@@ -313,6 +875,15 @@ impl Settings
    pub fn write_settings(&self) -> Result<PathBuf, std::io::Error>
 //-----------------------------------------------------------------------
    {
+      if self.is_ephemeral
+      {
+         return Err(std::io::Error::other("Settings can't be changed in ephemeral mode"));
+      }
+      if self.version > SETTINGS_VERSION
+      {
+         return Err(std::io::Error::other(format!("The settings file is version {} from a newer dejacmd, upgrade dejacmd \
+                                                   to change it (this is version {})", self.version, SETTINGS_VERSION)));
+      }
       let settings_path = match Settings::get_settings_path()
       {
          | Ok(p) => p,
@@ -326,26 +897,9 @@ impl Settings
             }
          },
       };
-      let mut file = File::create(&settings_path)?;
-      for retry in 0..3
-      {
-         match file.try_lock()
-         {
-            | Ok(_) => break,
-            | Err(e) =>
-            {
-               if retry == 2
-               {
-                  let errmsg = format!("Failed to lock settings file {}: {}", settings_path.display(), e);
-                  // println!("{errmsg}");
-                  return Err(std::io::Error::other(errmsg));
-               }
-               std::thread::sleep(std::time::Duration::from_millis(500));
-            }
-         }
-      }
+      let _lock = lock_settings(&settings_path)?;
       let json = serde_json::to_string_pretty(&self)?;
-      file.write_all(json.as_bytes())?;
+      write_atomically(&settings_path, json.as_bytes())?;
       // println!("Wrote settings {} to {}", json, settings_path.display());
       Ok(settings_path)
    }
@@ -381,7 +935,126 @@ impl Settings
       self.central_database_url.clone().unwrap_or_default()
    }
 
-   pub fn get_credentials(&self, is_local: bool) -> Result<(String, String), String>
+   /// The shells dejacmd-log recognizes among its parent processes, DEFAULT_SHELLS unless the shells setting is set
+   pub fn get_shells(&self) -> Vec<String>
+   //-------------------------------------
+   {
+      match &self.shells
+      {
+         | Some(shells) if !shells.is_empty() => shells.iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+         | _ => DEFAULT_SHELLS.iter().map(|s| s.to_string()).collect(),
+      }
+   }
+
+   /// URL template for search result hyperlinks, {id} is replaced by the history entry id
+   pub fn get_hyperlink_template(&self) -> String
+   //--------------------------------------------
+   {
+      match &self.hyperlink_template
+      {
+         | Some(t) if !t.trim().is_empty() => t.clone(),
+         | _ => DEFAULT_HYPERLINK_TEMPLATE.to_string()
+      }
+   }
+
+   pub fn set_hyperlink_template(&mut self, template: &str)
+   //------------------------------------------------------
+   {
+      self.hyperlink_template = if template.trim().is_empty() { None } else { Some(template.to_string()) };
+   }
+
+   /// Every setting with its value as JSON, None if it is unset. Secrets are masked.
+   pub fn list_values(&self) -> Vec<(&'static str, Option<String>)>
+   //--------------------------------------------------------------
+   {
+      let values = match serde_json::to_value(self)
+      {
+         | Ok(serde_json::Value::Object(values)) => values,
+         | _ => serde_json::Map::new(),
+      };
+      SETTING_KEYS.iter().map(|key|
+      {
+         let value = values.get(*key).filter(|v| !v.is_null()).map(|v|
+         {
+            if SECRET_SETTING_KEYS.contains(key) { "\"********\"".to_string() } else { v.to_string() }
+         });
+         (*key, value)
+      }).collect()
+   }
+
+   /// Set a setting from the command line. The value is read as JSON if it is valid for the setting (e.g 10, true or
+   /// ["ls *", "cd *"]) or else as a string, or a comma separated list for lists.
+   pub fn set_value(&mut self, key: &str, value: &str) -> Result<(), DejacmdError>
+   //-----------------------------------------------------------------------
+   {
+      let mut values = self.values_for_change(key)?;
+      let mut candidates = Vec::new();
+      if let Ok(json) = serde_json::from_str::<serde_json::Value>(value)
+      {
+         candidates.push(json);
+      }
+      candidates.push(serde_json::Value::String(value.to_string()));
+      candidates.push(serde_json::Value::Array(value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty())
+                                                  .map(|v| serde_json::Value::String(v.to_string())).collect()));
+      let mut first_error = None;
+      for candidate in candidates
+      {
+         values.insert(key.to_string(), candidate);
+         match serde_json::from_value::<Settings>(serde_json::Value::Object(values.clone()))
+         {
+            | Ok(settings) =>
+            {
+               *self = Settings { is_ephemeral: self.is_ephemeral, memory_key: self.memory_key.clone(), ..settings };
+               return Ok(());
+            },
+            | Err(e) => { first_error.get_or_insert(e); },
+         }
+      }
+      Err(DejacmdError::Parse(format!("Invalid value {} for {}: {}", value, key, first_error.map(|e| e.to_string()).unwrap_or_default())))
+   }
+
+   /// Remove a setting so its default is used
+   pub fn unset_value(&mut self, key: &str) -> Result<(), DejacmdError>
+   //-------------------------------------------------------------
+   {
+      let mut values = self.values_for_change(key)?;
+      values.remove(key);
+      let settings = serde_json::from_value::<Settings>(serde_json::Value::Object(values))
+         .map_err(|e| DejacmdError::Parse(format!("Error unsetting {}: {}", key, e)))?;
+      *self = Settings { is_ephemeral: self.is_ephemeral, memory_key: self.memory_key.clone(), ..settings };
+      Ok(())
+   }
+
+   fn values_for_change(&self, key: &str) -> Result<serde_json::Map<String, serde_json::Value>, DejacmdError>
+   //--------------------------------------------------------------------------------------------------
+   {
+      if !SETTING_KEYS.contains(&key)
+      {
+         return Err(DejacmdError::Parse(format!("Unknown setting {} (dejacmd config --list shows the settings)", key)));
+      }
+      if key == "version"
+      {
+         return Err(DejacmdError::Config("version is the settings file format, which dejacmd updates itself".to_string()));
+      }
+      if key == "passphrase_kdf"
+      {
+         return Err(DejacmdError::Config(format!("{} is set with dejacmd config --passphrase or --no-passphrase", key)));
+      }
+      if SECRET_SETTING_KEYS.contains(&key) || DATABASE_SETTING_KEYS.contains(&key)
+      {
+         return Err(DejacmdError::Config(format!("{} is set with dejacmd config -L or -C and -u/-p", key)));
+      }
+      match serde_json::to_value(self)
+      {
+         | Ok(serde_json::Value::Object(values)) => Ok(values),
+         | Ok(_) => Err(DejacmdError::Config("Settings aren't a JSON object".to_string())),
+         | Err(e) => Err(DejacmdError::Config(format!("Error serializing settings: {}", e))),
+      }
+   }
+
+   /// Returns the user and decrypted password for the local or central database. The password is a SecretString
+   /// so it is zeroized on drop and can't accidentally end up in Debug output.
+   pub fn get_credentials(&self, is_local: bool) -> Result<(String, SecretString), DejacmdError>
    //-------------------------------------------------------
    {
       let user: String;
@@ -398,7 +1071,13 @@ impl Settings
       }
       if encrypted_password.trim().is_empty()
       {
-         return Ok((user.clone(), "".to_string()))
+         let url = if is_local { self.get_local_database_url() } else { self.get_central_database_url() };
+         if url.starts_with(&format!("{}://", crate::SQLCIPHER_SCHEME))
+         {
+            // A SQLCipher database without a password of its own is encrypted with a key derived from the encryption key
+            return Ok((user.clone(), SecretString::from(self.get_database_key()?)));
+         }
+         return Ok((user.clone(), SecretString::default()))
       }
       let encrypted_bytes = match hex::decode(encrypted_password)
       {
@@ -406,20 +1085,20 @@ impl Settings
          Err(e) =>
          {
             let errmsg = format!("Failed to hex decode encrypted password: {}", e);
-            return Err(errmsg);
+            return Err(DejacmdError::Parse(errmsg));
          }
       };
       if encrypted_bytes.is_empty()
       {
-         return Ok((user.clone(), "".to_string()));
+         return Ok((user.clone(), SecretString::default()));
       }
       {
-         let key = match Settings::get_encryption_key(false)
+         let key = match self.get_password_key()
          {
             |  Ok(k) => k,
                Err(e) => 
                { 
-                  return Err(format!("Encryption key is missing [{}]", e)); 
+                  return Err(DejacmdError::Crypto(format!("Encryption key is missing [{}]", e))); 
                }
          };
          // let mut key = match self.encryption_key.clone()
@@ -436,27 +1115,31 @@ impl Settings
          // };
          if key.trim().is_empty() 
          {
-            return Err("Encryption key is empty".to_string()); 
+            return Err(DejacmdError::Crypto("Encryption key is empty".to_string())); 
          }         
          match crypt::decrypt(&encrypted_bytes, &key)
          {
             |  Ok(decrypted_password) =>
                {
-                  Ok((user, decrypted_password))
+                  Ok((user, SecretString::from(decrypted_password)))
                }
                Err(e) =>
                {
                   let errmsg = format!("Failed to decrypt database password: {}", e);
-                  eprintln!("{errmsg}");
-                  Err(errmsg)
+                  warn!("{errmsg}");
+                  Err(DejacmdError::Crypto(errmsg))
                }
          }
       }
    }
 
-   pub fn set_database_url(&mut self, url: &str, is_local: bool) -> Result<(), String>
+   pub fn set_database_url(&mut self, url: &str, is_local: bool) -> Result<(), DejacmdError>
    //----------------------------------------------------------------
    {
+      if crate::libsql::is_libsql_url(url)
+      {
+         crate::libsql::http_url(url).map_err(DejacmdError::Config)?;
+      }
       if is_local
       {
          self.local_database_url = url.to_string();
@@ -475,13 +1158,13 @@ impl Settings
          | Err(e) =>
          {
             let errmsg = format!("Failed to write settings file: {}", e);
-            eprintln!("{errmsg}");
-            Err(errmsg)
+            debug!("{errmsg}");
+            Err(DejacmdError::Config(errmsg))
          }
       }
    }
 
-   pub fn set_user(&mut self, user: &str, is_local: bool) -> Result<(), String>
+   pub fn set_user(&mut self, user: &str, is_local: bool) -> Result<(), DejacmdError>
    //----------------------------------------------------------------
    {
       if is_local
@@ -506,24 +1189,25 @@ impl Settings
          | Err(e) =>
          {
             let errmsg = format!("Failed to write settings file: {}", e);
-            eprintln!("{errmsg}");
-            Err(errmsg)
+            debug!("{errmsg}");
+            Err(DejacmdError::Config(errmsg))
          }
       }
    }
 
-   pub fn set_password(&mut self, password: &str, is_local: bool) -> Result<(), String>
+   pub fn set_password(&mut self, password: &str, is_local: bool) -> Result<(), DejacmdError>
    //----------------------------------------------------------------
    {
-      let encrypted_password: &mut Option<String>;
-      if is_local
+      let (kdf, key_file) = (self.passphrase_kdf.clone(), self.encryption_key_file.clone());
+      let cipher = self.get_cipher()?;
+      let encrypted_password: &mut Option<String> = if is_local
       {
-         encrypted_password = &mut self.local_encrypted_password;
+         &mut self.local_encrypted_password
       }
       else
       {
-         encrypted_password = &mut self.central_encrypted_password;
-      }
+         &mut self.central_encrypted_password
+      };
       if password.trim().is_empty()
       {
          *encrypted_password = None;
@@ -533,23 +1217,23 @@ impl Settings
             | Err(e) =>
             {
                let errmsg = format!("Failed to write settings file: {}", e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Config(errmsg));
             }
          }
          return Ok(());
       }
-      let key = match Settings::get_encryption_key(true)
+      let key = match Settings::get_encryption_key(kdf.as_deref(), key_file.as_deref(), true)
       {
          |  Ok(k) => k,
             Err(e) =>
             {
                let errmsg = format!("Encryption key is missing and failed to generate: {}", e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Crypto(errmsg));
             }
       };      
-      match crypt::encrypt(password, &key)
+      match crypt::encrypt_with(cipher, password, &key)
       {
          | Ok(encrypted_data) =>
          {
@@ -567,8 +1251,8 @@ impl Settings
                | Err(e) =>
                {
                   let errmsg = format!("Failed to write settings file: {}", e);
-                  eprintln!("{errmsg}");
-                  return Err(errmsg);
+                  debug!("{errmsg}");
+                  return Err(DejacmdError::Config(errmsg));
                }
             }
             Ok(())
@@ -576,16 +1260,18 @@ impl Settings
          | Err(e) =>
          {
             let errmsg = format!("Failed to encrypt database password: {}", e);
-            eprintln!("{errmsg}");
+            debug!("{errmsg}");
             // self.toast_manager.error(errmsg);
-            Err(errmsg)
+            Err(DejacmdError::Crypto(errmsg))
          }
       }
    }
 
-   pub fn set_user_password(&mut self, user: &str, password: &str, is_local: bool) -> Result<(), String>
+   pub fn set_user_password(&mut self, user: &str, password: &str, is_local: bool) -> Result<(), DejacmdError>
    //----------------------------------------------------------------
    {
+      let (kdf, key_file) = (self.passphrase_kdf.clone(), self.encryption_key_file.clone());
+      let cipher = self.get_cipher()?;
       let usr: &mut Option<String>;
       let encrypted_password: &mut Option<String>;
       if is_local
@@ -612,23 +1298,23 @@ impl Settings
             | Err(e) =>
             {
                let errmsg = format!("Failed to write settings file: {}", e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Config(errmsg));
             }
          }
          return Ok(());
       }
-      let key = match Settings::get_encryption_key(true)
+      let key = match Settings::get_encryption_key(kdf.as_deref(), key_file.as_deref(), true)
       {
          |  Ok(k) => k,
             Err(e) =>
             {
                let errmsg = format!("Encryption key is missing and failed to generate: {}", e);
-               eprintln!("{errmsg}");
-               return Err(errmsg);
+               debug!("{errmsg}");
+               return Err(DejacmdError::Crypto(errmsg));
             }
       };      
-      match crypt::encrypt(&password, &key)
+      match crypt::encrypt_with(cipher, password, &key)
       {
          | Ok(encrypted_data) =>
          {
@@ -644,8 +1330,8 @@ impl Settings
                | Err(e) =>
                {
                   let errmsg = format!("Failed to write settings file: {}", e);
-                  eprintln!("{errmsg}");
-                  return Err(errmsg);
+                  debug!("{errmsg}");
+                  return Err(DejacmdError::Config(errmsg));
                }
             }
             Ok(())
@@ -653,9 +1339,9 @@ impl Settings
          | Err(e) =>
          {
             let errmsg = format!("Failed to encrypt database password: {}", e);
-            eprintln!("{errmsg}");
+            debug!("{errmsg}");
             // self.toast_manager.error(errmsg);
-            Err(errmsg)
+            Err(DejacmdError::Crypto(errmsg))
          }
       }
    }
@@ -745,6 +1431,28 @@ impl Settings
       }
    }
 
+   /// The directory the default local database is kept in, DEJACMD_DATA_DIR if set or else dejacmd in the user's data
+   /// directory (e.g. ~/.local/share/dejacmd), which is created if needed
+   pub fn get_data_path() -> Result<PathBuf, DejacmdError>
+   //-----------------------------------------------
+   {
+      let path = match env::var(DATA_DIR_ENV).ok().filter(|d| !d.trim().is_empty())
+      {
+         | Some(dir) => match dir.trim().strip_prefix("~/")
+         {
+            | Some(rest) => Settings::get_home_dir().join(rest),
+            | None => PathBuf::from(dir.trim()),
+         },
+         | None => dirs::data_dir().ok_or_else(|| DejacmdError::Config("No data directory".to_string()))?.join(PROGRAM),
+      };
+      if !path.is_dir()
+      {
+         std::fs::create_dir_all(&path)
+            .map_err(|e| DejacmdError::Config(format!("Failed to create data directory {}: {}", path.display(), e)))?;
+      }
+      Ok(path)
+   }
+
    /// Get the path to the settings file for the program.
    pub fn get_settings_path() -> Result<PathBuf, std::io::Error>
    //-------------------------------------------------------------------
@@ -754,7 +1462,7 @@ impl Settings
          | Ok(p) => p,
          | Err(e) =>
          {
-            eprintln!("Error getting settings path: {}", e);
+            error!("Error getting settings path: {}", e);
             return Err(e);
          }
       };
@@ -768,9 +1476,9 @@ impl Settings
       let settings = Settings::default();
       let mut config_file = Settings::get_config_path()?;
       config_file.push("settings.json");
-      let mut file = File::create(&config_file)?;
+      let _lock = lock_settings(&config_file)?;
       let json = serde_json::to_string_pretty(&settings)?;
-      file.write_all(json.as_bytes())?;
+      write_atomically(&config_file, json.as_bytes())?;
       // let file = File::create(&config_file)?;
       // let mut writer = BufWriter::new(file);
       // serde_json::to_writer(&mut writer, &settings)?;
@@ -785,7 +1493,7 @@ impl Settings
          | Ok(p) => p,
          | Err(e) =>
          {
-            eprintln!("Error getting settings path: {}", e);
+            error!("Error getting settings path: {}", e);
             return Settings::default();
          }
       };
@@ -794,25 +1502,67 @@ impl Settings
       {
          return Settings::default();
       }
-      let file = match File::open(&config_file)
+      let text = match std::fs::read_to_string(&config_file)
       {
-         | Ok(f) => f,
+         | Ok(text) => text,
          | Err(e) =>
          {
-            eprintln!("Error opening settings file: {}", e);
+            warn!("Error opening settings file: {}", e);
             return Settings::default();
          }
       };
-      let settings: Settings = match serde_json::from_reader(file)
+      let settings = match Settings::parse_settings(&text)
       {
-         | Ok(s) => s,
-         | Err(e) =>
+         | (s, None) => s,
+         | (s, Some(e)) =>
          {
-            eprintln!("Error reading settings: {}", e);
-            Settings::default()
+            // Keep the file as it was before the settings that could be read are written over it
+            let backup = config_file.with_extension(format!("json.{}.bak", chrono::Local::now().format("%Y%m%d%H%M%S")));
+            match std::fs::copy(&config_file, &backup)
+            {
+               | Ok(_) => warn!("Error reading settings ({}), the settings file was copied to {}", e, backup.display()),
+               | Err(ee) => warn!("Error reading settings ({}) and copying the settings file to {}: {}", e, backup.display(), ee),
+            }
+            s
          }
       };
-      settings.clone()
+      debug!("Read settings version {} from {}", settings.version, config_file.display());
+      settings
+   }
+
+   /// Settings from the text of a settings file with the parse error if it doesn't parse, in which case the settings
+   /// are those that could be read, with defaults for invalid values, so one bad value doesn't reset the rest of the
+   /// configuration
+   fn parse_settings(text: &str) -> (Settings, Option<String>)
+   //---------------------------------------------------------
+   {
+      let error = match serde_json::from_str::<Settings>(text)
+      {
+         | Ok(settings) => return (settings, None),
+         | Err(e) => e.to_string(),
+      };
+      let Ok(serde_json::Value::Object(values)) = serde_json::from_str::<serde_json::Value>(text)
+      else
+      {
+         return (Settings::default(), Some(error));
+      };
+      // Start from a version 0 file so migrations for the version the file had still run if its version is invalid
+      let mut settings = Settings { version: 0, ..Settings::default() };
+      for (key, value) in values
+      {
+         let Ok(serde_json::Value::Object(mut candidate)) = serde_json::to_value(&settings)
+         else
+         {
+            break;
+         };
+         candidate.insert(key.clone(), value);
+         match serde_json::from_value::<Settings>(serde_json::Value::Object(candidate))
+         {
+            | Ok(s) => settings = s,
+            | Err(e) => warn!("Ignoring invalid setting {}: {}", key, e),
+         }
+      }
+      (settings, Some(error))
    }
 
    fn get_home_fallbacks() -> PathBuf
@@ -826,7 +1576,7 @@ impl Settings
       {
          return PathBuf::from("C:/Users/Public");
       }
-      return PathBuf::from("~/");
+      PathBuf::from("~/")
    }
 
    pub fn get_home_dir() -> PathBuf
@@ -854,23 +1604,473 @@ impl Settings
       }
    }
 
-   // Test helper - only available when running tests
+   /// Settings with a local database at local_url and, unless central_url is empty, a central one at central_url, as
+   /// Settings::builder().local_database_url(local_url).central_database_url(central_url).build()
    #[doc(hidden)]
    pub fn new_for_test(local_url: &str, central_url: &str) -> Self
    //----------------------------------------------------------------
    {
-      Self {
-         local_database_url: local_url.to_string(),
-         local_user: None,
-         local_encrypted_password: None,
-         central_database_url: if central_url.is_empty() { None } else { Some(central_url.to_string()) },
-         central_user: None,
-         central_encrypted_password: None,
-         encryption_key: None,
-         last_local_update_file: None,
-         last_central_update_file: None,
+      Settings::builder().local_database_url(local_url).central_database_url(central_url).build()
+         .expect("Settings without passwords always build")
+   }
+
+   /// A SettingsBuilder for settings made in code, e.g. by tests or a program embedding dejacmd, that don't depend on
+   /// the user's settings file or encryption key
+   pub fn builder() -> SettingsBuilder { SettingsBuilder::default() }
+}
+
+/// Builds in-memory Settings (see Settings::builder). Nothing is read from or written to the config directory: the
+/// settings start from the defaults rather than the settings file, can't be saved, and passwords are encrypted with a
+/// key kept in the Settings (given with encryption_key or generated) instead of the encryption-key file.
+#[derive(Debug, Default)]
+pub struct SettingsBuilder
+{
+   local_database_url: Option<String>,
+   central_database_url: Option<String>,
+   local_credentials: Option<(String, SecretString)>,
+   central_credentials: Option<(String, SecretString)>,
+   encryption_key: Option<SecretString>,
+   cipher: Option<String>,
+   values: Vec<(String, String)>,
+}
+
+impl SettingsBuilder
+{
+   /// The local database URL, the default local database if not set
+   pub fn local_database_url(mut self, url: &str) -> Self
+   //-----------------------------------------------------
+   {
+      self.local_database_url = Some(url.to_string());
+      self
+   }
+
+   /// The central database URL or dejacmd server, none if not set or empty
+   pub fn central_database_url(mut self, url: &str) -> Self
+   //-------------------------------------------------------
+   {
+      self.central_database_url = Some(url.to_string()).filter(|url| !url.trim().is_empty());
+      self
+   }
+
+   /// User and password for the {{user}} and {{password}} placeholders in the local database URL
+   pub fn local_credentials(mut self, user: &str, password: SecretString) -> Self
+   //----------------------------------------------------------------------------
+   {
+      self.local_credentials = Some((user.to_string(), password));
+      self
+   }
+
+   pub fn central_credentials(mut self, user: &str, password: SecretString) -> Self
+   //------------------------------------------------------------------------------
+   {
+      self.central_credentials = Some((user.to_string(), password));
+      self
+   }
+
+   /// The hex key (see crypt::generate_key) the passwords are encrypted with in the Settings
+   pub fn encryption_key(mut self, hex_key: SecretString) -> Self
+   //-------------------------------------------------------------
+   {
+      self.encryption_key = Some(hex_key);
+      self
+   }
+
+   /// Cipher the passwords are encrypted with (crypt::Cipher::NAMES)
+   pub fn cipher(mut self, name: &str) -> Self
+   //------------------------------------------
+   {
+      self.cipher = Some(name.to_string());
+      self
+   }
+
+   /// Any other setting, by its key in the settings file (SETTING_KEYS) with a value as for dejacmd config --set
+   pub fn setting(mut self, key: &str, value: &str) -> Self
+   //-------------------------------------------------------
+   {
+      self.values.push((key.to_string(), value.to_string()));
+      self
+   }
+
+   pub fn build(self) -> Result<Settings, DejacmdError>
+   //--------------------------------------------------
+   {
+      let mut settings = Settings { version: SETTINGS_VERSION, is_ephemeral: true, ..Settings::default() };
+      if let Some(url) = self.local_database_url
+      {
+         settings.local_database_url = url;
+      }
+      settings.central_database_url = self.central_database_url;
+      for (key, value) in &self.values
+      {
+         settings.set_value(key, value)?;
+      }
+      settings.cipher = self.cipher;
+      let cipher = settings.get_cipher()?;
+      let key = match self.encryption_key
+      {
+         | Some(key) =>
+         {
+            crypt::check_key(key.expose_secret())?;
+            key
+         },
+         | None => SecretString::from(generate_key()),
+      };
+      let encrypt = |password: &SecretString| -> Result<Option<String>, DejacmdError>
+      {
+         if password.expose_secret().is_empty()
+         {
+            return Ok(None);
+         }
+         crypt::encrypt_with(cipher, password.expose_secret(), key.expose_secret()).map(|data| Some(hex::encode(data)))
+            .map_err(|e| DejacmdError::Crypto(format!("Failed to encrypt database password: {}", e)))
+      };
+      if let Some((user, password)) = &self.local_credentials
+      {
+         settings.local_user = Some(user.clone()).filter(|user| !user.is_empty());
+         settings.local_encrypted_password = encrypt(password)?;
+      }
+      if let Some((user, password)) = &self.central_credentials
+      {
+         settings.central_user = Some(user.clone()).filter(|user| !user.is_empty());
+         settings.central_encrypted_password = encrypt(password)?;
       }
+      settings.memory_key = Some(key);
+      Ok(settings)
    }
 }
 
 unsafe impl Sync for Settings {}
+
+/// Lock the settings file at path against other dejacmd processes writing it (dejacmd-log writes the last update
+/// watermarks while dejacmd may be changing settings). The lock is on a separate settings.json.lock file as the
+/// settings file itself is replaced on every write, and is released when the returned file is dropped.
+fn lock_settings(path: &Path) -> Result<File, std::io::Error>
+//-----------------------------------------------------------
+{
+   lock_beside(path)
+}
+
+/// Lock a path.lock file beside path, waiting a little for another process holding it
+fn lock_beside(path: &Path) -> Result<File, std::io::Error>
+//---------------------------------------------------------
+{
+   let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+   let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+   for retry in 0..3
+   {
+      match file.try_lock()
+      {
+         | Ok(_) => break,
+         | Err(e) =>
+         {
+            if retry == 2
+            {
+               return Err(std::io::Error::other(format!("Failed to lock {}: {}", lock_path.display(), e)));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+         }
+      }
+   }
+   Ok(file)
+}
+
+/// Create dir only accessible by the user if it doesn't exist, and check an existing one is a directory (not a link)
+/// owned by the user that other users can't access
+fn private_dir(dir: &Path) -> Result<(), DejacmdError>
+//----------------------------------------------------
+{
+   let mut builder = std::fs::DirBuilder::new();
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::DirBuilderExt;
+      builder.mode(0o700);
+   }
+   match builder.create(dir)
+   {
+      | Ok(_) => {},
+      | Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {},
+      | Err(e) => return Err(DejacmdError::Config(format!("Failed to create {}: {}", dir.display(), e))),
+   }
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::{MetadataExt, PermissionsExt};
+      let metadata = std::fs::symlink_metadata(dir)
+         .map_err(|e| DejacmdError::Config(format!("Failed to read {}: {}", dir.display(), e)))?;
+      if !metadata.is_dir() || metadata.uid() != nix::unistd::getuid().as_raw() || metadata.permissions().mode() & 0o077 != 0
+      {
+         return Err(DejacmdError::Crypto(format!("{} must be a directory owned by you and only accessible by you", dir.display())));
+      }
+   }
+   Ok(())
+}
+
+/// Write data to a temporary file beside path and rename it over path, so readers see the old or the new contents
+/// and never a partly written file. The permissions of an existing file are kept, a new file is only accessible by
+/// the user.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), std::io::Error>
+//-------------------------------------------------------------------------
+{
+   let temp_path = PathBuf::from(format!("{}.{}.tmp", path.display(), std::process::id()));
+   // Left behind by an earlier process with the same id
+   let _ = std::fs::remove_file(&temp_path);
+   let result = (||
+   {
+      let mut options = std::fs::OpenOptions::new();
+      options.write(true).create_new(true);
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::OpenOptionsExt;
+         options.mode(0o600);
+      }
+      let mut file = options.open(&temp_path)?;
+      file.write_all(data)?;
+      file.sync_all()?;
+      if let Ok(metadata) = std::fs::metadata(path)
+      {
+         std::fs::set_permissions(&temp_path, metadata.permissions())?;
+      }
+      std::fs::rename(&temp_path, path)
+   })();
+   if result.is_err()
+   {
+      let _ = std::fs::remove_file(&temp_path);
+   }
+   result
+}
+
+/// Move the SQLite database from to to, with its -wal, -shm or -journal files. Returns false if both exist, so the
+/// database at to is never replaced, and otherwise true as to is where the database now is (or will be created).
+fn move_database(from: &Path, to: &Path) -> Result<bool, DejacmdError>
+//--------------------------------------------------------------
+{
+   if !from.exists()
+   {
+      return Ok(true);
+   }
+   if to.exists()
+   {
+      return Ok(false);
+   }
+   for suffix in ["", "-wal", "-shm", "-journal"]
+   {
+      let (from, to) = (PathBuf::from(format!("{}{}", from.display(), suffix)), PathBuf::from(format!("{}{}", to.display(), suffix)));
+      if !from.exists()
+      {
+         continue;
+      }
+      // rename fails across file systems, e.g. for a data directory on another mount
+      if std::fs::rename(&from, &to).is_err()
+      {
+         std::fs::copy(&from, &to)
+            .map_err(|e| DejacmdError::Config(format!("Error copying {} to {}: {}", from.display(), to.display(), e)))?;
+         std::fs::remove_file(&from).map_err(|e| DejacmdError::Config(format!("Error removing {}: {}", from.display(), e)))?;
+      }
+      debug!("Moved {} to {}", from.display(), to.display());
+   }
+   Ok(true)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_set_value()
+   {
+      let mut settings = Settings::new_for_test("sqlite:///tmp/unused.db", "");
+      settings.set_value("log_timeout_ms", "250").unwrap();
+      assert_eq!(settings.log_timeout_ms, Some(250));
+      settings.set_value("ignore_space", "false").unwrap();
+      assert_eq!(settings.ignore_space, Some(false));
+      settings.set_value("retention_older_than", "90d").unwrap();
+      assert_eq!(settings.retention_older_than.as_deref(), Some("90d"));
+      settings.set_value("ignore_commands", r#"["ls *", "cd *"]"#).unwrap();
+      assert_eq!(settings.ignore_commands, Some(vec!["ls *".to_string(), "cd *".to_string()]));
+      settings.set_value("central_exclude_dirs", "~/secret, /tmp").unwrap();
+      assert_eq!(settings.central_exclude_dirs, Some(vec!["~/secret".to_string(), "/tmp".to_string()]));
+      settings.set_value("hyperlink_template", "42").unwrap();
+      assert_eq!(settings.get_hyperlink_template(), "42");
+
+      assert!(settings.set_value("log_timeout_ms", "soon").is_err());
+      assert!(settings.set_value("no_such_setting", "1").is_err());
+      assert!(settings.set_value("encryption_key", "00").is_err());
+      assert!(settings.set_value("central_database_url", "sqlite:///tmp/c.db").is_err());
+      assert_eq!(settings.log_timeout_ms, Some(250), "A failed set leaves the settings unchanged");
+
+      settings.unset_value("log_timeout_ms").unwrap();
+      assert_eq!(settings.log_timeout_ms, None);
+      assert_eq!(settings.get_local_database_url(), "sqlite:///tmp/unused.db");
+
+      settings.local_encrypted_password = Some("abc".to_string());
+      let values = settings.list_values();
+      assert_eq!(values.len(), SETTING_KEYS.len());
+      assert!(values.contains(&("local_encrypted_password", Some("\"********\"".to_string()))));
+      assert!(values.contains(&("ignore_space", Some("false".to_string()))));
+      assert!(values.contains(&("log_timeout_ms", None)));
+      settings.set_value("encryption_key_file", "/media/usb/dejacmd.key").unwrap();
+      assert_eq!(settings.encryption_key_file.as_deref(), Some("/media/usb/dejacmd.key"));
+      let serialized = serde_json::to_value(&settings).unwrap();
+      assert!(serialized.as_object().unwrap().keys().all(|key| SETTING_KEYS.contains(&key.as_str())),
+              "Every setting is listed");
+   }
+
+   #[test]
+   fn test_builder()
+   {
+      let key = generate_key();
+      let settings = Settings::builder()
+         .local_database_url("sqlite:///tmp/built.db")
+         .central_database_url("postgres://{{user}}:{{password}}@db/dejacmd")
+         .central_credentials("dejacmd", SecretString::from("secret"))
+         .encryption_key(SecretString::from(key.clone()))
+         .cipher("chacha20-poly1305")
+         .setting("search_lines", "40")
+         .build().unwrap();
+      assert_eq!(settings.get_local_database_url(), "sqlite:///tmp/built.db");
+      assert_eq!(settings.search_lines, Some(40));
+      assert!(settings.is_ephemeral());
+      assert!(settings.write_settings().is_err(), "Built settings are never saved");
+      let (user, password) = settings.get_credentials(false).unwrap();
+      assert_eq!((user.as_str(), password.expose_secret()), ("dejacmd", "secret"));
+      assert_eq!(settings.get_password_key().unwrap(), key);
+      let encrypted = hex::decode(settings.central_encrypted_password.as_deref().unwrap()).unwrap();
+      assert_eq!(crypt::encrypted_cipher(&encrypted), Some(crypt::Cipher::ChaCha20Poly1305));
+      assert!(!format!("{:?}", settings).contains(&key), "The key isn't shown");
+
+      let (_, password) = Settings::new_for_test("sqlite:///tmp/unused.db", "").get_credentials(true).unwrap();
+      assert!(password.expose_secret().is_empty());
+      let encrypted = Settings::builder().local_database_url("sqlite+sqlcipher:///tmp/built.db")
+         .encryption_key(SecretString::from(key.clone())).build().unwrap();
+      let (_, password) = encrypted.get_credentials(true).unwrap();
+      assert_eq!(password.expose_secret(), crypt::derive_subkey(&key, "sqlcipher database").unwrap(),
+                 "A SQLCipher database without a password uses a key derived from the encryption key");
+      assert_ne!(password.expose_secret(), key);
+      assert!(Settings::builder().encryption_key(SecretString::from("00")).build().is_err());
+      assert!(Settings::builder().setting("no_such_setting", "1").build().is_err());
+   }
+
+   #[test]
+   fn test_key_file_path()
+   {
+      if env::var_os(KEY_FILE_ENV).is_some()
+      {
+         return;
+      }
+      let dir = env::temp_dir();
+      assert_eq!(Settings::key_file_path(Some(&dir.join("k").display().to_string())), Ok(dir.join("k")));
+      assert_eq!(Settings::key_file_path(Some("~/k")), Ok(Settings::get_home_dir().join("k")));
+      assert!(Settings::key_file_path(Some(&dir.join("not-mounted").join("k").display().to_string())).is_err());
+      assert_eq!(Settings::key_file_path(Some(" ")), Settings::key_file_path(None));
+   }
+
+   #[test]
+   fn test_move_database()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = env::temp_dir().join(format!("dejacmd_move_test_{}", nanos));
+      std::fs::create_dir_all(dir.join("data")).unwrap();
+      let (from, to) = (dir.join(".dejacmd.sqlite"), dir.join("data").join("history.sqlite"));
+      assert!(move_database(&from, &to).unwrap(), "Nothing to move so the new location is used");
+
+      std::fs::write(&from, "db").unwrap();
+      std::fs::write(dir.join(".dejacmd.sqlite-wal"), "wal").unwrap();
+      assert!(move_database(&from, &to).unwrap());
+      assert!(!from.exists());
+      assert_eq!(std::fs::read_to_string(&to).unwrap(), "db");
+      assert_eq!(std::fs::read_to_string(dir.join("data").join("history.sqlite-wal")).unwrap(), "wal");
+
+      std::fs::write(&from, "other db").unwrap();
+      assert!(!move_database(&from, &to).unwrap(), "An existing database isn't replaced");
+      assert_eq!(std::fs::read_to_string(&to).unwrap(), "db");
+
+      let lock = lock_beside(&to).unwrap();
+      assert!(lock_beside(&to).is_err(), "Only one process moves the database at a time");
+      drop(lock);
+      assert!(lock_beside(&to).is_ok());
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+
+   #[test]
+   fn test_settings_version()
+   {
+      let (settings, e) = Settings::parse_settings(r#"{"local_database_url": "sqlite:///tmp/h.db", "search_lines": 40}"#);
+      assert_eq!(e, None);
+      assert_eq!(settings.version, 0, "Files from before versioning");
+      let mut migrated = settings.clone();
+      migrated.migrate().unwrap();
+      assert_eq!(migrated.version, SETTINGS_VERSION);
+      assert_eq!((migrated.get_local_database_url(), migrated.search_lines), (settings.get_local_database_url(), Some(40)));
+
+      let (salvaged, e) = Settings::parse_settings(r#"{"version": 1, "local_database_url": "sqlite:///tmp/h.db",
+                                                      "search_lines": "lots", "timezone": "utc"}"#);
+      assert!(e.is_some());
+      assert_eq!(salvaged.version, 1);
+      assert_eq!(salvaged.get_local_database_url(), "sqlite:///tmp/h.db");
+      assert_eq!((salvaged.search_lines, salvaged.timezone.as_deref()), (None, Some("utc")), "Only the bad value is lost");
+      assert!(Settings::parse_settings("{ not json").1.is_some());
+      assert!(settings.clone().set_value("version", "5").is_err());
+
+      let (mut newer, _) = Settings::parse_settings(&format!(r#"{{"version": {}}}"#, SETTINGS_VERSION + 1));
+      newer.migrate().unwrap();
+      assert_eq!(newer.version, SETTINGS_VERSION + 1);
+      assert!(newer.write_settings().is_err(), "A newer settings file isn't overwritten");
+   }
+
+   #[cfg(unix)]
+   #[test]
+   fn test_private_dir()
+   {
+      use std::os::unix::fs::PermissionsExt;
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = env::temp_dir().join(format!("dejacmd_private_test_{}", nanos));
+      private_dir(&dir).unwrap();
+      assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+      private_dir(&dir).unwrap();
+      std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+      assert!(private_dir(&dir).is_err(), "A directory other users can read isn't used");
+      let link = env::temp_dir().join(format!("dejacmd_private_link_{}", nanos));
+      std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+      std::os::unix::fs::symlink(&dir, &link).unwrap();
+      assert!(private_dir(&link).is_err(), "A link isn't followed");
+      let _ = std::fs::remove_file(&link);
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+
+   #[test]
+   fn test_write_atomically()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = env::temp_dir().join(format!("dejacmd_write_test_{}", nanos));
+      std::fs::create_dir_all(&dir).unwrap();
+      let path = dir.join("settings.json");
+      std::fs::write(&path, "{}").unwrap();
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::PermissionsExt;
+         std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+      }
+      let lock = lock_settings(&path).unwrap();
+      assert!(lock_settings(&path).is_err(), "Only one writer at a time");
+      write_atomically(&path, b"{\"version\": 2}").unwrap();
+      drop(lock);
+      assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"version\": 2}");
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::PermissionsExt;
+         assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600, "Permissions are kept");
+      }
+      let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+      assert_eq!(files.len(), 2, "Only the settings and lock files are left: {:?}", files);
+      assert!(lock_settings(&path).is_ok());
+
+      let new_path = dir.join("dejacmd.toml");
+      write_atomically(&new_path, b"tag = \"x\"").unwrap();
+      #[cfg(unix)]
+      {
+         use std::os::unix::fs::PermissionsExt;
+         assert_eq!(std::fs::metadata(&new_path).unwrap().permissions().mode() & 0o777, 0o600, "A new file is only accessible by the user");
+      }
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
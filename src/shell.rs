@@ -0,0 +1,391 @@
+use std::path::{Path, PathBuf};
+
+use crate::settings::Settings;
+
+/// Shells dejacmd init can generate hooks for
+pub const SHELLS: [&str; 4] = ["bash", "zsh", "fish", "powershell"];
+
+/// Lines delimiting the hooks in an rc file, so installing again replaces them instead of adding a second copy
+pub const BEGIN_MARKER: &str = "# >>> dejacmd init >>>";
+pub const END_MARKER: &str = "# <<< dejacmd init <<<";
+
+const LOG_PLACEHOLDER: &str = "@DEJACMD_LOG@";
+const DEJACMD_PLACEHOLDER: &str = "@DEJACMD@";
+const SHELL_PATH_PLACEHOLDER: &str = "@SHELL_PATH@";
+
+// The DEBUG trap runs before every simple command, __dejacmd_ready limits it to the first command after a prompt
+// so the start time is that of the whole command line. It is cleared by __dejacmd_precmd so an empty command line
+// (where the only commands run are those in PROMPT_COMMAND) isn't logged again, and the hooks' own functions (such as
+// the Ctrl-R widget) are ignored.
+// Only the tmux server knows the session name ($TMUX has the session's number), so the hooks ask it for the pane's
+// location once when the shell starts instead of dejacmd-log running tmux for every command.
+const BASH_HOOKS: &str = r#"[[ -z "$TMUX" ]] || export DEJACMD_TMUX_LOCATION="$(tmux display-message -p -t "$TMUX_PANE" \
+   '#{session_name}:#{window_index}.#{pane_index}' 2> /dev/null)"
+__dejacmd_preexec()
+{
+   [[ -n "$__dejacmd_ready" && "$BASH_COMMAND" != __dejacmd_* ]] || return
+   __dejacmd_ready=
+   __dejacmd_ran=1
+   __dejacmd_start=${EPOCHREALTIME/[.,]/}
+   [[ -z "$DEJACMD_CAPTURE" ]] || { : > "$DEJACMD_CAPTURE"; __dejacmd_offset=0; }
+}
+__dejacmd_precmd()
+{
+   local exit_status=$? duration=-1 entry capture=()
+   __dejacmd_ready=
+   [[ -n "$__dejacmd_ran" ]] || return
+   __dejacmd_ran=
+   if [[ -n "$__dejacmd_start" && -n "$EPOCHREALTIME" ]]; then
+      duration=$(( (${EPOCHREALTIME/[.,]/} - __dejacmd_start) / 1000 ))
+   fi
+   entry="$(HISTTIMEFORMAT="%F %T " history 1)"
+   [[ "$entry" != "$__dejacmd_last" ]] || return
+   __dejacmd_last=$entry
+   if [[ -n "$DEJACMD_CAPTURE" && -n "$__dejacmd_offset" ]]; then
+      capture=(--output-file "$DEJACMD_CAPTURE" --output-offset "$__dejacmd_offset")
+   fi
+   @DEJACMD_LOG@ -s $exit_status -d $duration -p $$ "${capture[@]}" "$entry"
+}
+trap '__dejacmd_preexec' DEBUG
+PROMPT_COMMAND="__dejacmd_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND};__dejacmd_ready=1"
+"#;
+
+const ZSH_HOOKS: &str = r#"zmodload zsh/datetime
+[[ -z "$TMUX" ]] || export DEJACMD_TMUX_LOCATION="$(tmux display-message -p -t "$TMUX_PANE" \
+   '#{session_name}:#{window_index}.#{pane_index}' 2> /dev/null)"
+__dejacmd_preexec()
+{
+   __dejacmd_start=$EPOCHREALTIME
+   [[ -z "$DEJACMD_CAPTURE" ]] || { : > "$DEJACMD_CAPTURE"; __dejacmd_offset=0; }
+}
+__dejacmd_precmd()
+{
+   local exit_status=$? duration=-1 entry
+   local -a capture
+   [[ -n "$__dejacmd_start" ]] || return
+   printf -v duration '%.0f' $(( (EPOCHREALTIME - __dejacmd_start) * 1000 ))
+   __dejacmd_start=
+   entry="$(fc -t '%Y-%m-%d %T' -il -1)"
+   [[ "$entry" != "$__dejacmd_last" ]] || return
+   __dejacmd_last=$entry
+   if [[ -n "$DEJACMD_CAPTURE" && -n "$__dejacmd_offset" ]]; then
+      capture=(--output-file "$DEJACMD_CAPTURE" --output-offset "$__dejacmd_offset")
+   fi
+   @DEJACMD_LOG@ -s $exit_status -d $duration -p $$ "${capture[@]}" "$entry"
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __dejacmd_preexec
+add-zsh-hook precmd __dejacmd_precmd
+"#;
+
+// Runs an interactive shell under script, which records everything written to the terminal in the capture file.
+// The hooks empty the file when each command starts and pass it to dejacmd-log so it can summarize the command's
+// output. script runs $SHELL, the startup file is read again in the new shell where DEJACMD_CAPTURE is already set.
+// mktemp creates the file readable only by the user with a name that can't be guessed (a fixed name in a shared /tmp
+// could be a symlink planted by someone else). script appends (-a) so emptying the file doesn't leave it writing past
+// the end, and the outer shell removes the file when script exits. util-linux script flushes with -f and the
+// BSD/macOS script with -F.
+const CAPTURE_OUTPUT: &str = r#"if [[ -z "$DEJACMD_CAPTURE" && $- == *i* && -t 0 && -t 1 ]] && command -v script > /dev/null \
+   && DEJACMD_CAPTURE="$(mktemp "${XDG_RUNTIME_DIR:-${TMPDIR:-/tmp}}/dejacmd-capture.XXXXXXXXXX")"; then
+   export DEJACMD_CAPTURE
+   trap 'rm -f "$DEJACMD_CAPTURE"' EXIT
+   if script --version > /dev/null 2>&1; then
+      SHELL="@SHELL_PATH@" script -aqf "$DEJACMD_CAPTURE"
+   else
+      SHELL="@SHELL_PATH@" script -aqF "$DEJACMD_CAPTURE"
+   fi
+   exit
+fi
+"#;
+
+const BASH_WIDGET: &str = r#"__dejacmd_widget()
+{
+   local selected
+   selected="$(@DEJACMD@ widget --query="$READLINE_LINE")" || return
+   READLINE_LINE=$selected
+   READLINE_POINT=${#READLINE_LINE}
+}
+bind -m emacs-standard -x '"\C-r": __dejacmd_widget'
+bind -m vi-insert -x '"\C-r": __dejacmd_widget'
+"#;
+
+const ZSH_WIDGET: &str = r#"__dejacmd_widget()
+{
+   local selected
+   selected="$(@DEJACMD@ widget --query="$BUFFER")" && BUFFER=$selected && CURSOR=${#BUFFER}
+   zle reset-prompt
+}
+zle -N __dejacmd_widget
+bindkey -M emacs '^R' __dejacmd_widget
+bindkey -M viins '^R' __dejacmd_widget
+"#;
+
+const FISH_WIDGET: &str = r#"function __dejacmd_widget
+   set -l query (commandline | string collect)
+   set -l selected (@DEJACMD@ widget --query="$query" | string collect)
+   and commandline -r -- $selected
+   commandline -f repaint
+end
+bind \cr __dejacmd_widget
+bind -M insert \cr __dejacmd_widget
+"#;
+
+// The fzf bindings read dejacmd fzf's id<TAB>command entries, show only the command and strip the id from the selection
+const BASH_FZF: &str = r#"__dejacmd_fzf()
+{
+   local selected
+   selected="$(@DEJACMD@ fzf --print0 | fzf --read0 --delimiter='\t' --with-nth=2.. --scheme=history --height=40% --reverse \
+               --query="$READLINE_LINE" --preview="@DEJACMD@ fzf --preview {1}" --preview-window=down:4:wrap)" || return
+   READLINE_LINE=${selected#*$'\t'}
+   READLINE_POINT=${#READLINE_LINE}
+}
+bind -m emacs-standard -x '"\C-r": __dejacmd_fzf'
+bind -m vi-insert -x '"\C-r": __dejacmd_fzf'
+"#;
+
+const ZSH_FZF: &str = r#"__dejacmd_fzf()
+{
+   local selected
+   selected="$(@DEJACMD@ fzf --print0 | fzf --read0 --delimiter='\t' --with-nth=2.. --scheme=history --height=40% --reverse \
+               --query="$BUFFER" --preview="@DEJACMD@ fzf --preview {1}" --preview-window=down:4:wrap)" &&
+      BUFFER=${selected#*$'\t'} && CURSOR=${#BUFFER}
+   zle reset-prompt
+}
+zle -N __dejacmd_fzf
+bindkey -M emacs '^R' __dejacmd_fzf
+bindkey -M viins '^R' __dejacmd_fzf
+"#;
+
+const FISH_FZF: &str = r#"function __dejacmd_fzf
+   set -l selected (@DEJACMD@ fzf --print0 | fzf --read0 --delimiter=\t --with-nth=2.. --scheme=history --height=40% --reverse \
+                    --query=(commandline) --preview="@DEJACMD@ fzf --preview {1}" --preview-window=down:4:wrap | string collect)
+   and commandline -r -- (string replace -r '^[^\t]*\t' '' -- $selected | string collect)
+   commandline -f repaint
+end
+bind \cr __dejacmd_fzf
+bind -M insert \cr __dejacmd_fzf
+"#;
+
+// fish has no history numbers, the size of the history is used instead
+const FISH_HOOKS: &str = r#"set -q TMUX; and set -gx DEJACMD_TMUX_LOCATION (tmux display-message -p -t "$TMUX_PANE" \
+   '#{session_name}:#{window_index}.#{pane_index}' 2> /dev/null)
+function __dejacmd_postexec --on-event fish_postexec
+   set -l exit_status $status
+   string length -q -- (string trim -- $argv[1]); or return
+   @DEJACMD_LOG@ -s $exit_status -d $CMD_DURATION -p $fish_pid (count $history)"  "(date '+%Y-%m-%d %H:%M:%S')" $argv[1]"
+end
+"#;
+
+// Wraps the existing prompt function. The id of the last history item logged stops an empty command line logging
+// the previous command again.
+const POWERSHELL_HOOKS: &str = r#"$global:__DejacmdLastId = (Get-History -Count 1).Id
+$global:__DejacmdPrompt = $function:prompt
+function global:prompt {
+    $exitStatus = if ($?) { 0 } elseif ($LastExitCode) { $LastExitCode } else { 1 }
+    $item = Get-History -Count 1
+    if ($item -and $item.Id -ne $global:__DejacmdLastId) {
+        $global:__DejacmdLastId = $item.Id
+        $duration = [long]($item.EndExecutionTime - $item.StartExecutionTime).TotalMilliseconds
+        $timestamp = $item.StartExecutionTime.ToString("yyyy-MM-dd HH:mm:ss")
+        $cwd = (Get-Location -PSProvider FileSystem).Path
+        & @DEJACMD_LOG@ -s $exitStatus -d $duration -p $PID -n $item.Id -t $timestamp --cwd $cwd -c $item.CommandLine
+    }
+    & $global:__DejacmdPrompt
+}
+"#;
+
+/// The hooks that log each command with dejacmd-log (log_program), its exit status and how long it took for shell,
+/// delimited by BEGIN_MARKER and END_MARKER. With a widget_program (dejacmd) Ctrl-R is bound to dejacmd widget, or with
+/// is_fzf to fzf reading dejacmd fzf, except for PowerShell. is_capture_output (bash and zsh only) runs the shell under
+/// script so a summary of each command's output is logged too (see output::summarize).
+pub fn hook_script(shell: &str, log_program: &str, widget_program: Option<&str>, is_fzf: bool, is_capture_output: bool)
+   -> Result<String, String>
+//---------------------------------------------------------------------------------------------------------------------
+{
+   let quote = |program: &str| if shell == "powershell" { format!("'{}'", program.replace('\'', "''")) } else { posix_quote(program) };
+   let (hooks, widget, fzf) = match shell
+   {
+      | "bash" => (BASH_HOOKS, BASH_WIDGET, BASH_FZF),
+      | "zsh" => (ZSH_HOOKS, ZSH_WIDGET, ZSH_FZF),
+      | "fish" => (FISH_HOOKS, FISH_WIDGET, FISH_FZF),
+      | "powershell" => (POWERSHELL_HOOKS, "", ""),
+      | _ => return Err(format!("Unsupported shell {} (expected one of {})", shell, SHELLS.join(", "))),
+   };
+   let widget = if is_fzf { fzf } else { widget };
+   let capture = match (is_capture_output, shell)
+   {
+      | (false, _) => String::new(),
+      | (true, "bash") => format!("# Record the terminal so the output of commands can be logged\n{}",
+                                  CAPTURE_OUTPUT.replace(SHELL_PATH_PLACEHOLDER, "$BASH")),
+      | (true, "zsh") => format!("# Record the terminal so the output of commands can be logged\n{}",
+                                 CAPTURE_OUTPUT.replace(SHELL_PATH_PLACEHOLDER, "$(command -v zsh)")),
+      | (true, _) => return Err(format!("Capturing output isn't supported for {} (only bash and zsh)", shell)),
+   };
+   let mut script = format!("{}\n{}# Log commands to the dejacmd history (generated by dejacmd init {})\n{}", BEGIN_MARKER,
+                            capture, shell, hooks.replace(LOG_PLACEHOLDER, &quote(log_program)));
+   if let Some(program) = widget_program && !widget.is_empty()
+   {
+      script.push_str(if is_fzf { "# Search the history with fzf on Ctrl-R\n" } else { "# Search the history with Ctrl-R\n" });
+      script.push_str(&widget.replace(DEJACMD_PLACEHOLDER, &quote(program)));
+   }
+   script.push_str(END_MARKER);
+   script.push('\n');
+   Ok(script)
+}
+
+/// Single quoted for bash, zsh and fish
+fn posix_quote(s: &str) -> String
+//-------------------------------
+{
+   format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// dejacmd-log in the same directory as the running program, otherwise just the name so it is found on the PATH
+pub fn log_program() -> String
+//----------------------------
+{
+   program("dejacmd-log")
+}
+
+/// The program called name in the same directory as the running program, otherwise just the name
+pub fn program(name: &str) -> String
+//----------------------------------
+{
+   let name = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+   std::env::current_exe().ok()
+      .and_then(|exe| exe.parent().map(|dir| dir.join(&name)))
+      .filter(|path| path.is_file())
+      .map(|path| path.display().to_string())
+      .unwrap_or(name)
+}
+
+/// The startup file dejacmd init --install adds the hooks for shell to
+pub fn rc_file(shell: &str) -> Result<PathBuf, String>
+//----------------------------------------------------
+{
+   let home = Settings::get_home_dir();
+   let config_dir = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from).unwrap_or_else(|| home.join(".config"));
+   match shell
+   {
+      | "bash" => Ok(home.join(".bashrc")),
+      | "zsh" => Ok(std::env::var_os("ZDOTDIR").map(PathBuf::from).unwrap_or(home).join(".zshrc")),
+      | "fish" => Ok(config_dir.join("fish").join("conf.d").join("dejacmd.fish")),
+      | "powershell" =>
+      {
+         // The $PROFILE of PowerShell 7 for the current user and host
+         let dir = if cfg!(windows)
+         {
+            dirs::document_dir().unwrap_or_else(|| home.join("Documents")).join("PowerShell")
+         }
+         else
+         {
+            config_dir.join("powershell")
+         };
+         Ok(dir.join("Microsoft.PowerShell_profile.ps1"))
+      },
+      | _ => Err(format!("Unsupported shell {} (expected one of {})", shell, SHELLS.join(", "))),
+   }
+}
+
+/// Add script (from hook_script) to the end of an rc file, or replace the hooks a previous install added.
+/// Returns true if existing hooks were replaced.
+pub fn install(path: &Path, script: &str) -> Result<bool, String>
+//----------------------------------------------------------------
+{
+   let content = if path.exists()
+   {
+      std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?
+   }
+   else
+   {
+      String::new()
+   };
+   let existing = content.find(BEGIN_MARKER)
+      .and_then(|start| content[start..].find(END_MARKER).map(|end| (start, start + end + END_MARKER.len())));
+   let (updated, is_replaced) = match existing
+   {
+      | Some((start, end)) =>
+      {
+         let rest = content[end..].strip_prefix('\n').unwrap_or(&content[end..]);
+         (format!("{}{}{}", &content[..start], script, rest), true)
+      },
+      | None =>
+      {
+         let separator = if content.is_empty() || content.ends_with("\n\n") { "" }
+                         else if content.ends_with('\n') { "\n" }
+                         else { "\n\n" };
+         (format!("{}{}{}", content, separator, script), false)
+      },
+   };
+   if let Some(dir) = path.parent()
+   {
+      std::fs::create_dir_all(dir).map_err(|e| format!("Error creating {}: {}", dir.display(), e))?;
+   }
+   std::fs::write(path, updated).map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+   Ok(is_replaced)
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_hook_script()
+   {
+      for shell in SHELLS
+      {
+         let script = hook_script(shell, "/opt/it's/dejacmd-log", Some("/opt/dejacmd"), false, false).unwrap();
+         assert!(script.starts_with(BEGIN_MARKER) && script.trim_end().ends_with(END_MARKER), "{}", script);
+         assert!(!script.contains(LOG_PLACEHOLDER) && !script.contains(DEJACMD_PLACEHOLDER));
+         assert!(script.contains(" -d "), "{} hooks should pass the duration", shell);
+         assert_eq!(script.contains("'/opt/dejacmd' widget --query="), shell != "powershell", "{}", script);
+         assert!(!hook_script(shell, "dejacmd-log", None, false, false).unwrap().contains("widget"));
+         let fzf = hook_script(shell, "dejacmd-log", Some("/opt/dejacmd"), true, false).unwrap();
+         assert_eq!(fzf.contains("'/opt/dejacmd' fzf --print0 | fzf --read0"), shell != "powershell", "{}", fzf);
+         assert_eq!(fzf.contains("--preview=\"'/opt/dejacmd' fzf --preview {1}\""), shell != "powershell", "{}", fzf);
+         assert!(!fzf.contains("widget"));
+      }
+      assert!(hook_script("bash", "/opt/it's/dejacmd-log", None, false, false).unwrap().contains(r"'/opt/it'\''s/dejacmd-log' -s $exit_status"));
+      assert!(hook_script("powershell", "/opt/it's/dejacmd-log", None, false, false).unwrap().contains("& '/opt/it''s/dejacmd-log' -s"));
+      for shell in ["bash", "zsh", "fish"]
+      {
+         let script = hook_script(shell, "dejacmd-log", None, false, false).unwrap();
+         assert!(script.contains(crate::TMUX_LOCATION_ENV) && script.contains("tmux display-message -p"), "{}", script);
+      }
+      assert!(hook_script("tcsh", "dejacmd-log", None, false, false).is_err());
+      for shell in ["bash", "zsh"]
+      {
+         let script = hook_script(shell, "dejacmd-log", None, false, true).unwrap();
+         assert!(script.contains("script -aqf \"$DEJACMD_CAPTURE\"") && !script.contains(SHELL_PATH_PLACEHOLDER), "{}", script);
+         assert!(script.contains("mktemp") && script.contains("trap 'rm -f \"$DEJACMD_CAPTURE\"' EXIT"), "{}", script);
+         assert!(script.find("script -aqf").unwrap() < script.find("__dejacmd_precmd()").unwrap(), "The shell is restarted first");
+         assert!(script.contains(": > \"$DEJACMD_CAPTURE\""), "The capture file is emptied for each command");
+         assert!(!hook_script(shell, "dejacmd-log", None, false, false).unwrap().contains("script -aqf"));
+      }
+      assert!(hook_script("fish", "dejacmd-log", None, false, true).is_err());
+      assert!(rc_file("tcsh").is_err());
+      assert!(rc_file("bash").unwrap().ends_with(".bashrc"));
+   }
+
+   #[test]
+   fn test_install()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_shell_test_{}", nanos));
+      let rc = dir.join("conf.d").join(".bashrc");
+      assert!(!install(&rc, &hook_script("bash", "dejacmd-log", None, false, false).unwrap()).unwrap());
+      assert!(std::fs::read_to_string(&rc).unwrap().starts_with(BEGIN_MARKER), "Missing directories are created");
+
+      std::fs::write(&rc, "alias ll='ls -l'").unwrap();
+      assert!(!install(&rc, &hook_script("bash", "dejacmd-log", None, false, false).unwrap()).unwrap());
+      std::fs::write(&rc, std::fs::read_to_string(&rc).unwrap() + "export EDITOR=vi\n").unwrap();
+      assert!(install(&rc, &hook_script("bash", "/usr/local/bin/dejacmd-log", None, false, false).unwrap()).unwrap());
+      let content = std::fs::read_to_string(&rc).unwrap();
+      assert_eq!(content.matches(BEGIN_MARKER).count(), 1, "{}", content);
+      assert!(content.starts_with("alias ll='ls -l'\n\n"));
+      assert!(content.ends_with(&format!("{}\nexport EDITOR=vi\n", END_MARKER)), "{}", content);
+      assert!(content.contains("'/usr/local/bin/dejacmd-log'") && !content.contains("'dejacmd-log'"));
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+
+/// Directory in the config directory holding the snapshots saved by dejacmd snapshot save, one NAME.json file each
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+/// The state of a history table at a point in time. The ids of every row are kept as well as the row count and latest
+/// timestamp so rows added since by an import or sync with older timestamps are still found.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot
+{
+   pub name: String,
+   /// When the snapshot was saved, an RFC 3339 time
+   pub created: String,
+   /// local or central
+   pub database: String,
+   pub rows: usize,
+   pub latest: Option<String>,
+   pub ids: HashSet<String>,
+}
+
+impl Snapshot
+{
+   pub fn new(name: &str, database: &str, latest: Option<String>, ids: HashSet<String>) -> Self
+   //------------------------------------------------------------------------------------------
+   {
+      Snapshot { name: name.to_string(), created: Local::now().to_rfc3339(), database: database.to_string(), rows: ids.len(),
+                 latest, ids }
+   }
+}
+
+pub fn snapshot_dir() -> Result<PathBuf, String>
+//----------------------------------------------
+{
+   let mut path = Settings::get_config_path().map_err(|e| format!("Error getting snapshot directory: {}", e))?;
+   path.push(SNAPSHOT_DIR);
+   Ok(path)
+}
+
+/// The file a snapshot called name is saved in, names are used as file names so can't contain path separators
+pub fn snapshot_path(dir: &Path, name: &str) -> Result<PathBuf, String>
+//---------------------------------------------------------------------
+{
+   let name = name.trim();
+   if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\'])
+   {
+      return Err(format!("Invalid snapshot name '{}'", name));
+   }
+   Ok(dir.join(format!("{}.json", name)))
+}
+
+/// Save snapshot in dir, replacing any snapshot with the same name
+pub fn save_at(dir: &Path, snapshot: &Snapshot) -> Result<PathBuf, String>
+//------------------------------------------------------------------------
+{
+   let path = snapshot_path(dir, &snapshot.name)?;
+   std::fs::create_dir_all(dir).map_err(|e| format!("Error creating snapshot directory {}: {}", dir.display(), e))?;
+   let json = serde_json::to_string(snapshot).map_err(|e| format!("Error serializing snapshot: {}", e))?;
+   std::fs::write(&path, json).map_err(|e| format!("Error writing snapshot {}: {}", path.display(), e))?;
+   Ok(path)
+}
+
+pub fn load_at(dir: &Path, name: &str) -> Result<Snapshot, String>
+//----------------------------------------------------------------
+{
+   let path = snapshot_path(dir, name)?;
+   let text = match std::fs::read_to_string(&path)
+   {
+      | Ok(text) => text,
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(format!("No snapshot called {}", name.trim())),
+      | Err(e) => return Err(format!("Error reading snapshot {}: {}", path.display(), e)),
+   };
+   serde_json::from_str(&text).map_err(|e| format!("Error reading snapshot {}: {}", path.display(), e))
+}
+
+/// The snapshots in dir, oldest first
+pub fn list_at(dir: &Path) -> Result<Vec<Snapshot>, String>
+//---------------------------------------------------------
+{
+   let files = match std::fs::read_dir(dir)
+   {
+      | Ok(files) => files,
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+      | Err(e) => return Err(format!("Error reading snapshot directory {}: {}", dir.display(), e)),
+   };
+   let mut snapshots: Vec<Snapshot> = files.filter_map(|file| file.ok())
+      .filter(|file| file.path().extension().is_some_and(|e| e == "json"))
+      .filter_map(|file| std::fs::read_to_string(file.path()).ok())
+      .filter_map(|text| serde_json::from_str(&text).ok())
+      .collect();
+   snapshots.sort_by(|a, b| a.created.cmp(&b.created));
+   Ok(snapshots)
+}
+
+/// Delete a snapshot, returning false if there wasn't one called name
+pub fn remove_at(dir: &Path, name: &str) -> Result<bool, String>
+//--------------------------------------------------------------
+{
+   let path = snapshot_path(dir, name)?;
+   match std::fs::remove_file(&path)
+   {
+      | Ok(_) => Ok(true),
+      | Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+      | Err(e) => Err(format!("Error removing snapshot {}: {}", path.display(), e)),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn test_snapshots()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_snapshot_test_{}", nanos));
+      assert!(list_at(&dir).unwrap().is_empty());
+      assert!(load_at(&dir, "incident").is_err());
+
+      let ids = HashSet::from(["a".to_string(), "b".to_string()]);
+      let snapshot = Snapshot::new("incident", "local", Some("2026-01-11 04:33:25".to_string()), ids);
+      assert_eq!(snapshot.rows, 2);
+      save_at(&dir, &snapshot).unwrap();
+      assert_eq!(load_at(&dir, " incident ").unwrap(), snapshot);
+      let later = Snapshot { name: "later".to_string(), created: format!("{}z", snapshot.created), ..snapshot.clone() };
+      save_at(&dir, &later).unwrap();
+      assert_eq!(list_at(&dir).unwrap().iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["incident", "later"]);
+
+      assert!(snapshot_path(&dir, "../settings").is_err());
+      assert!(snapshot_path(&dir, "").is_err());
+      assert!(remove_at(&dir, "incident").unwrap());
+      assert!(!remove_at(&dir, "incident").unwrap());
+      let _ = std::fs::remove_dir_all(&dir);
+   }
+}
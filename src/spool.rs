@@ -0,0 +1,313 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sqlx::{Any, Pool};
+use tracing::{debug, warn};
+
+use crate::settings::Settings;
+use crate::HistoryEntry;
+use crate::remote::RemoteCentral;
+
+/// JSON lines file in the config directory holding entries that couldn't be written to the central database
+pub const SPOOL_FILE: &str = "central-spool.jsonl";
+
+/// JSON lines file in the config directory holding entries dejacmd-log didn't write to the local database in time
+pub const LOCAL_SPOOL_FILE: &str = "local-spool.jsonl";
+
+/// A flush renames the spool to <name>.<pid>.flushing while it works. One left behind for longer than this belongs to
+/// a process that died part way through and its entries are returned to the spool.
+const ORPHAN_AGE: Duration = Duration::from_secs(600);
+
+pub fn spool_path() -> Result<PathBuf, String>
+//--------------------------------------------
+{
+   config_file(SPOOL_FILE)
+}
+
+pub fn local_spool_path() -> Result<PathBuf, String>
+//--------------------------------------------------
+{
+   config_file(LOCAL_SPOOL_FILE)
+}
+
+fn config_file(name: &str) -> Result<PathBuf, String>
+//---------------------------------------------------
+{
+   let mut path = Settings::get_config_path().map_err(|e| format!("Error getting spool path: {}", e))?;
+   path.push(name);
+   Ok(path)
+}
+
+/// Append an entry to the spool file in the config directory
+pub fn append(entry: &HistoryEntry) -> Result<(), String>
+//--------------------------------------------------------
+{
+   append_to(&spool_path()?, entry)
+}
+
+pub fn append_to(path: &Path, entry: &HistoryEntry) -> Result<(), String>
+//------------------------------------------------------------------------
+{
+   let json = serde_json::to_string(entry).map_err(|e| format!("Error serializing spool entry {}: {}", entry.id, e))?;
+   append_lines(path, &[json.as_str()])
+}
+
+fn append_lines(path: &Path, lines: &[&str]) -> Result<(), String>
+//-----------------------------------------------------------------
+{
+   let _lock = lock_spool(path)?;
+   let mut file = OpenOptions::new().create(true).append(true).open(path)
+      .map_err(|e| format!("Error opening spool file {}: {}", path.display(), e))?;
+   let mut text = lines.join("\n");
+   text.push('\n');
+   file.write_all(text.as_bytes()).map_err(|e| format!("Error writing spool file {}: {}", path.display(), e))
+}
+
+/// Number of entries in a spool file waiting to be written to the central database
+pub fn backlog_at(path: &Path) -> usize
+//-------------------------------------
+{
+   fs::read_to_string(path).map(|content| content.lines().filter(|l| !l.trim().is_empty()).count()).unwrap_or(0)
+}
+
+/// Insert the entries in the spool file in the config directory into the central database
+pub async fn flush(pool: &Pool<Any>, scheme: &str) -> Result<usize, String>
+//--------------------------------------------------------------------------
+{
+   flush_from(&spool_path()?, pool, scheme).await
+}
+
+/// Append an entry to the local spool file in the config directory
+pub fn append_local(entry: &HistoryEntry) -> Result<(), String>
+//--------------------------------------------------------------
+{
+   append_to(&local_spool_path()?, entry)
+}
+
+/// Insert the entries in the local spool file in the config directory into the local database
+pub async fn flush_local(pool: &Pool<Any>, scheme: &str) -> Result<usize, String>
+//--------------------------------------------------------------------------------
+{
+   flush_from(&local_spool_path()?, pool, scheme).await
+}
+
+/// Insert the entries in the spool file in the config directory into a central dejacmd server
+pub async fn flush_remote(remote: &RemoteCentral) -> Result<usize, String>
+//------------------------------------------------------------------------
+{
+   flush_remote_from(&spool_path()?, remote).await
+}
+
+/// Insert spooled entries into a database, skipping any that are already present. If an insert fails the entries
+/// that haven't been written are returned to the spool. Returns the number of entries inserted.
+pub async fn flush_from(path: &Path, pool: &Pool<Any>, scheme: &str) -> Result<usize, String>
+//--------------------------------------------------------------------------------------------
+{
+   flush_with(path, async |entry: &HistoryEntry| entry.upsert(pool, scheme).await.map_err(|e| e.to_string())).await
+}
+
+pub async fn flush_remote_from(path: &Path, remote: &RemoteCentral) -> Result<usize, String>
+//-------------------------------------------------------------------------------------------
+{
+   flush_with(path, async |entry: &HistoryEntry|
+   {
+      remote.insert(std::slice::from_ref(entry)).await.map(|inserted| inserted > 0).map_err(|e| e.to_string())
+   }).await
+}
+
+/// Flush the spool using insert, which returns false for an entry that was already present
+async fn flush_with(path: &Path, mut insert: impl AsyncFnMut(&HistoryEntry) -> Result<bool, String>) -> Result<usize, String>
+//----------------------------------------------------------------------------------------------------------------------------
+{
+   recover_orphans(path);
+   let flushing = flushing_path(path, std::process::id());
+   {
+      // Appends hold the lock while writing, so none is part way through when the spool is moved aside
+      let _lock = lock_spool(path)?;
+      if !path.exists()
+      {
+         return Ok(0);
+      }
+      fs::rename(path, &flushing).map_err(|e| format!("Error moving spool file {}: {}", path.display(), e))?;
+   }
+   let content = fs::read_to_string(&flushing)
+      .map_err(|e| format!("Error reading spool file {}: {}", flushing.display(), e))?;
+
+   let mut flushed = 0;
+   let mut remaining: Vec<&str> = Vec::new();
+   let mut error: Option<String> = None;
+   for line in content.lines().filter(|l| !l.trim().is_empty())
+   {
+      if error.is_some()
+      {
+         remaining.push(line);
+         continue;
+      }
+      let entry: HistoryEntry = match serde_json::from_str(line)
+      {
+         | Ok(e) => e,
+         | Err(e) =>
+         {
+            warn!("Discarding invalid spool entry {}: {}", line, e);
+            continue;
+         }
+      };
+      match insert(&entry).await
+      {
+         | Ok(is_inserted) => if is_inserted { flushed += 1 },
+         | Err(e) =>
+         {
+            remaining.push(line);
+            error = Some(e);
+         }
+      }
+   }
+   if !remaining.is_empty()
+   {
+      append_lines(path, &remaining)?;
+   }
+   let _ = fs::remove_file(&flushing);
+   debug!("Flushed {} spooled entries from {}", flushed, path.display());
+   match error
+   {
+      | Some(e) => Err(format!("{} spooled entries written, {} returned to the spool: {}", flushed, remaining.len(), e)),
+      | None => Ok(flushed),
+   }
+}
+
+/// Exclusively lock the path.lock file beside the spool, waiting for another process holding it. The lock is on a
+/// separate file as a flush moves the spool itself aside, and is released when the returned file is dropped.
+fn lock_spool(path: &Path) -> Result<fs::File, String>
+//-----------------------------------------------------
+{
+   let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+   let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+      .map_err(|e| format!("Error opening spool lock {}: {}", lock_path.display(), e))?;
+   file.lock().map_err(|e| format!("Error locking spool lock {}: {}", lock_path.display(), e))?;
+   Ok(file)
+}
+
+fn flushing_path(path: &Path, pid: u32) -> PathBuf
+//-------------------------------------------------
+{
+   let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+   path.with_file_name(format!("{}.{}.flushing", name, pid))
+}
+
+/// Return the entries of flushes abandoned by a process that exited part way through to the spool
+fn recover_orphans(path: &Path)
+//-----------------------------
+{
+   let (Some(dir), Some(name)) = (path.parent(), path.file_name().map(|n| n.to_string_lossy().to_string())) else
+   {
+      return;
+   };
+   let Ok(files) = fs::read_dir(dir) else { return };
+   for file in files.flatten()
+   {
+      let file_name = file.file_name().to_string_lossy().to_string();
+      let is_old = file.metadata().and_then(|m| m.modified()).ok()
+         .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+         .is_some_and(|age| age > ORPHAN_AGE);
+      if file_name.starts_with(&format!("{}.", name)) && file_name.ends_with(".flushing") && is_old
+         && let Ok(content) = fs::read_to_string(file.path())
+      {
+         let lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+         if lines.is_empty() || append_lines(path, &lines).is_ok()
+         {
+            let _ = fs::remove_file(file.path());
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use secrecy::SecretString;
+
+   fn entry(id: &str) -> HistoryEntry
+   {
+      HistoryEntry
+      {
+         cwd: Some("/tmp".to_string()),
+         shell: Some("bash".to_string()),
+         user_id: Some(1000),
+         user_name: Some("me".to_string()),
+         os: Some("linux".to_string()),
+         history_no: Some(42),
+         hostname: Some("desktop".to_string()),
+         session_id: Some("4242-1768105965".to_string()),
+         git_repo: Some("/home/me/src/dejacmd".to_string()),
+         git_branch: Some("main".to_string()),
+         duration_ms: Some(1250),
+         ssh_origin: Some("192.168.1.20".to_string()),
+         tty: Some("/dev/pts/3".to_string()),
+         project: Some("website".to_string()),
+         multiplexer: Some("tmux:debug:1.0".to_string()),
+         ..HistoryEntry::new_for_test(id, "2026-01-11 04:33:25", &format!("echo {}", id))
+      }
+   }
+
+   #[tokio::test]
+   async fn test_spool_flush()
+   {
+      let nanos = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_spool_test_{}", nanos));
+      fs::create_dir_all(&dir).unwrap();
+      let spool = dir.join(SPOOL_FILE);
+      let url = format!("sqlite://{}", dir.join("central.db").display());
+      let (pool, scheme) = crate::get_database(&url, "", &SecretString::default()).await.unwrap();
+      let pool = pool.unwrap();
+      crate::create_schema(&pool, &scheme).await.unwrap();
+
+      assert_eq!(flush_from(&spool, &pool, &scheme).await.unwrap(), 0, "No spool is not an error");
+
+      append_to(&spool, &entry("a")).unwrap();
+      append_to(&spool, &entry("b")).unwrap();
+      assert_eq!(backlog_at(&spool), 2);
+      entry("b").insert(&pool, &scheme).await.unwrap(); // e.g already written by a flush that was interrupted
+      assert_eq!(flush_from(&spool, &pool, &scheme).await.unwrap(), 1);
+      assert!(!spool.exists(), "Spool should be removed once flushed");
+      assert_eq!(backlog_at(&spool), 0);
+
+      let row = sqlx::query(&format!("SELECT {} FROM history WHERE id = 'a'", crate::HISTORY_COLUMNS)).fetch_one(&pool).await.unwrap();
+      assert_eq!(HistoryEntry::from_row(&row), entry("a"));
+      assert!(!entry("a").upsert(&pool, &scheme).await.unwrap(), "Existing ids are skipped");
+
+      // Entries from an abandoned flush are recovered
+      let orphan = flushing_path(&spool, 1);
+      fs::write(&orphan, serde_json::to_string(&entry("c")).unwrap() + "\n").unwrap();
+      fs::File::options().write(true).open(&orphan).unwrap()
+         .set_modified(SystemTime::now() - ORPHAN_AGE * 2).unwrap();
+      assert_eq!(flush_from(&spool, &pool, &scheme).await.unwrap(), 1);
+      assert!(!orphan.exists());
+
+      pool.close().await;
+      let _ = fs::remove_dir_all(&dir);
+   }
+
+   #[test]
+   fn test_spool_lock()
+   {
+      let nanos = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let dir = std::env::temp_dir().join(format!("dejacmd_spool_lock_test_{}", nanos));
+      fs::create_dir_all(&dir).unwrap();
+      let spool = dir.join(SPOOL_FILE);
+
+      // An append waits for a flush holding the lock to move the spool aside
+      let lock = lock_spool(&spool).unwrap();
+      let appending = spool.clone();
+      let appender = std::thread::spawn(move || append_to(&appending, &entry("a")));
+      std::thread::sleep(Duration::from_millis(200));
+      assert_eq!(backlog_at(&spool), 0);
+      drop(lock);
+      appender.join().unwrap().unwrap();
+      assert_eq!(backlog_at(&spool), 1);
+
+      let _ = fs::remove_dir_all(&dir);
+   }
+}
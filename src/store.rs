@@ -0,0 +1,755 @@
+use std::sync::LazyLock;
+
+use chrono::TimeZone;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use secrecy::SecretString;
+use short_uuid::ShortUuid;
+use sqlx::{Any, Pool};
+
+use crate::error::DejacmdError;
+use crate::remote::{RemoteCentral, RemoteError, SEARCH_PATH, is_remote_url};
+use crate::settings::Settings;
+use crate::{HISTORY_COLUMNS, HistoryEntry, PoolSettings, create_schema, fix_placeholders, get_database_with_options,
+            insert_history_batch, shared_database};
+
+/// Number of entries HistoryStore::search_stream reads from the database at a time
+pub const SEARCH_PAGE_SIZE: u64 = 500;
+
+/// A --not-between exclusion of an absolute time range or, when is_daily, of the same times of day on every day
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TimeExclusion
+{
+   pub is_daily: bool,
+   pub start: String,
+   pub end: String,
+}
+
+impl TimeExclusion
+{
+   /// WHERE clause condition excluding the range, with the start and end bound in that order. Times of day are
+   /// compared against the HH:MM:SS part of the timestamp and a daily range that wraps midnight (22:00,06:00) works.
+   pub fn condition(&self) -> &'static str
+   //-------------------------------------
+   {
+      match (self.is_daily, self.start <= self.end)
+      {
+         | (false, _) => "NOT (command_timestamp >= ? AND command_timestamp <= ?)",
+         | (true, true) => "NOT (SUBSTR(command_timestamp, 12, 8) >= ? AND SUBSTR(command_timestamp, 12, 8) <= ?)",
+         | (true, false) => "NOT (SUBSTR(command_timestamp, 12, 8) >= ? OR SUBSTR(command_timestamp, 12, 8) <= ?)",
+      }
+   }
+}
+
+/// The escape clause for a LIKE pattern with text escaped by like_escape. ! rather than a backslash, which MySQL string
+/// literals would need doubled.
+pub const LIKE_ESCAPE: &str = "ESCAPE '!'";
+
+/// text with the LIKE wildcards % and _ (and the ! escape character) escaped so that it only matches itself
+pub fn like_escape(text: &str) -> String
+//-------------------------------------
+{
+   text.replace('!', "!!").replace('%', "!%").replace('_', "!_")
+}
+
+/// WHERE clause conditions for commands run in hours of the day (start, end), with the end hour excluded so (9, 17) is
+/// 09:00 to 16:59 and (17, 9) wraps midnight, and on weekdays (0 for Sunday to 6 for Saturday, any if empty). The
+/// hour and day are found with the database's own date functions and the values are validated integers so are
+/// written into the conditions rather than bound.
+pub fn calendar_conditions(scheme: &str, hours: Option<(u32, u32)>, weekdays: &[u32]) -> Vec<String>
+//--------------------------------------------------------------------------------------------------
+{
+   let (hour, weekday) = if scheme.starts_with("postgres")
+   {
+      ("CAST(EXTRACT(HOUR FROM CAST(command_timestamp AS TIMESTAMP)) AS INTEGER)",
+       "CAST(EXTRACT(DOW FROM CAST(command_timestamp AS TIMESTAMP)) AS INTEGER)")
+   }
+   else if scheme.starts_with("mysql") || scheme.starts_with("mariadb")
+   {
+      ("HOUR(command_timestamp)", "(DAYOFWEEK(command_timestamp) - 1)")
+   }
+   else
+   {
+      ("CAST(strftime('%H', command_timestamp) AS INTEGER)", "CAST(strftime('%w', command_timestamp) AS INTEGER)")
+   };
+   let mut conditions = Vec::new();
+   match hours
+   {
+      | Some((start, end)) if start < end => conditions.push(format!("({hour} >= {start} AND {hour} < {end})")),
+      | Some((start, end)) if start > end => conditions.push(format!("({hour} >= {start} OR {hour} < {end})")),
+      | _ => {},
+   }
+   let weekdays: Vec<String> = weekdays.iter().filter(|d| **d < 7).map(|d| d.to_string()).collect();
+   if !weekdays.is_empty()
+   {
+      conditions.push(format!("{} IN ({})", weekday, weekdays.join(", ")));
+   }
+   conditions
+}
+
+/// How search results are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort
+{
+   /// When the command was run
+   #[default]
+   Time,
+   /// How long the command took, commands without a recorded duration come last
+   Duration,
+   /// How often the command was run. Each command is found once, with the time it was last run.
+   Frequency,
+}
+
+impl SearchSort
+{
+   pub fn parse(name: &str) -> Result<SearchSort, String>
+   //-----------------------------------------------------
+   {
+      match name.trim().to_lowercase().as_str()
+      {
+         | "time" => Ok(SearchSort::Time),
+         | "duration" => Ok(SearchSort::Duration),
+         | "frequency" => Ok(SearchSort::Frequency),
+         | _ => Err(format!("Invalid sort order '{}'. Expected time, duration or frequency", name)),
+      }
+   }
+}
+
+/// Search parameters, also the body of a search request to a central dejacmd server (remote::SEARCH_PATH)
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SearchRequest
+{
+   /// Substring of the command, any command if empty
+   pub spec: String,
+   /// Maximum number of commands, 0 for no limit
+   pub limit: u64,
+   /// Ascending order (oldest, shortest or least frequent first) rather than descending
+   pub is_sort_reversed: bool,
+   pub is_ignore_case: bool,
+   pub is_unique: bool,
+   pub is_show_time: bool,
+   /// A command matches if it was run in any of the (start, end) ranges
+   pub ranges: Vec<(String, String)>,
+   pub exclusions: Vec<TimeExclusion>,
+   /// Only commands logged on these machines (any if empty)
+   #[serde(default)]
+   pub hosts: Vec<String>,
+   #[serde(default)]
+   pub is_show_host: bool,
+   /// Only commands from this shell session
+   #[serde(default)]
+   pub session: Option<String>,
+   /// Only commands run in this git repository (its top level directory or the name of that directory)
+   #[serde(default)]
+   pub repo: Option<String>,
+   #[serde(default)]
+   pub branch: Option<String>,
+   /// Only commands with this project tag
+   #[serde(default)]
+   pub project: Option<String>,
+   /// Only commands run in this tmux (or screen) session, by name
+   #[serde(default)]
+   pub tmux_session: Option<String>,
+   /// Only commands whose captured output summary contains this (see output::summarize)
+   #[serde(default)]
+   pub output: Option<String>,
+   /// Only commands run in exactly this directory
+   #[serde(default)]
+   pub cwd: Option<String>,
+   /// Only these entries
+   #[serde(default)]
+   pub ids: Vec<String>,
+   /// Number of matching entries to skip, for reading results a page at a time
+   #[serde(default)]
+   pub offset: u64,
+   /// Only commands run in these hours of the day, see calendar_conditions
+   #[serde(default)]
+   pub hours: Option<(u32, u32)>,
+   /// Only commands run on these days of the week, 0 for Sunday to 6 for Saturday (any if empty)
+   #[serde(default)]
+   pub weekdays: Vec<u32>,
+   #[serde(default)]
+   pub sort: SearchSort,
+}
+
+/// Somewhere history is kept, for programs embedding dejacmd as a library rather than running dejacmd and
+/// dejacmd-log. SqlHistoryStore is the implementation for the local and central databases.
+pub trait HistoryStore
+{
+   /// Insert an entry, failing if an entry with its id is already stored
+   fn insert(&self, entry: &HistoryEntry) -> impl Future<Output = Result<(), DejacmdError>> + Send;
+
+   /// Insert entries in one transaction, skipping those whose ids are already stored. Returns the number inserted.
+   fn insert_batch(&self, entries: &[HistoryEntry]) -> impl Future<Output = Result<u64, DejacmdError>> + Send;
+
+   /// Entries matching the search, most recent first unless is_sort_reversed. As for dejacmd search, only the
+   /// columns needed to show the results are filled in.
+   fn search(&self, request: &SearchRequest) -> impl Future<Output = Result<Vec<HistoryEntry>, DejacmdError>> + Send;
+
+   /// The search's entries as a stream, read SEARCH_PAGE_SIZE at a time so a large (or unlimited) search isn't all
+   /// held in memory
+   fn search_stream(&self, request: &SearchRequest) -> BoxStream<'_, Result<HistoryEntry, DejacmdError>>
+   where Self: Sync
+   //-------------------------------------------------------------------------------------------------
+   {
+      let limit = request.limit;
+      let page_limit = move |fetched: u64| if limit == 0 { SEARCH_PAGE_SIZE } else { SEARCH_PAGE_SIZE.min(limit - fetched) };
+      let first = SearchRequest { limit: page_limit(0), ..request.clone() };
+      stream::try_unfold(Some((first, 0)), move |state| async move
+      {
+         let Some((page, fetched)) = state
+         else
+         {
+            return Ok(None);
+         };
+         let entries = self.search(&page).await?;
+         let fetched = fetched + entries.len() as u64;
+         let next = if (entries.len() as u64) < page.limit || (limit > 0 && fetched >= limit)
+         {
+            None
+         }
+         else
+         {
+            Some((SearchRequest { offset: page.offset + page.limit, limit: page_limit(fetched), ..page }, fetched))
+         };
+         Ok(Some((stream::iter(entries.into_iter().map(Ok)), next)))
+      })
+      .try_flatten()
+      .boxed()
+   }
+
+   /// Every stored entry with all its columns, oldest first, without reading them all into memory
+   fn stream_all(&self) -> BoxStream<'_, Result<HistoryEntry, DejacmdError>>;
+}
+
+/// A history database reached through sqlx (SQLite, PostgreSQL or MySQL/MariaDB)
+#[derive(Debug, Clone)]
+pub struct SqlHistoryStore
+{
+   pool: Pool<Any>,
+   scheme: String,
+}
+
+impl SqlHistoryStore
+{
+   /// A store for a connected database whose schema is already up to date, e.g. from crate::connections
+   pub fn new(pool: Pool<Any>, scheme: &str) -> Self
+   //------------------------------------------------
+   {
+      SqlHistoryStore { pool, scheme: scheme.to_string() }
+   }
+
+   /// Connect to the database at url (with {{user}} and {{password}} replaced by user and password) and create or
+   /// update its history table
+   pub async fn connect(url: &str, user: &str, password: &SecretString, options: &PoolSettings) -> Result<Self, DejacmdError>
+   //-------------------------------------------------------------------------------------------------------------------
+   {
+      match get_database_with_options(url, user, password, options).await
+      {
+         | Ok((Some(pool), scheme)) =>
+         {
+            create_schema(&pool, &scheme).await?;
+            Ok(SqlHistoryStore::new(pool, &scheme))
+         },
+         | Ok((None, _)) => Err(DejacmdError::Config(format!("Unsupported database URL {}", url))),
+         | Err(e) => Err(e),
+      }
+   }
+
+   /// The local or central database configured in settings, None if it isn't configured. A central dejacmd server
+   /// (an http URL) or libSQL database is an error, use remote::RemoteCentral for it.
+   pub async fn from_settings(settings: &Settings, is_local: bool) -> Result<Option<Self>, DejacmdError>
+   //---------------------------------------------------------------------------------------------
+   {
+      let url = if is_local { settings.get_local_database_url() } else { settings.get_central_database_url() };
+      if url.trim().is_empty()
+      {
+         return Ok(None);
+      }
+      if is_remote_url(&url)
+      {
+         return Err(DejacmdError::Config(format!("{} is a dejacmd server or libSQL database rather than a database for sqlx", url)));
+      }
+      let (user, password) = settings.get_credentials(is_local)?;
+      SqlHistoryStore::connect(&url, &user, &password, &PoolSettings::from_settings(settings)).await.map(Some)
+   }
+
+   pub fn pool(&self) -> &Pool<Any> { &self.pool }
+
+   pub fn scheme(&self) -> &str { &self.scheme }
+}
+
+impl HistoryStore for SqlHistoryStore
+{
+   async fn insert(&self, entry: &HistoryEntry) -> Result<(), DejacmdError>
+   //-----------------------------------------------------------------
+   {
+      entry.insert(&self.pool, &self.scheme).await.map_err(|e| DejacmdError::Query(format!("Error inserting {}: {}", entry.id, e)))
+   }
+
+   async fn insert_batch(&self, entries: &[HistoryEntry]) -> Result<u64, DejacmdError>
+   //-----------------------------------------------------------------------------
+   {
+      insert_history_batch(&self.pool, &self.scheme, entries).await
+   }
+
+   async fn search(&self, request: &SearchRequest) -> Result<Vec<HistoryEntry>, DejacmdError>
+   //------------------------------------------------------------------------------------
+   {
+      search_rows(&self.pool, &self.scheme, request).await
+   }
+
+   fn stream_all(&self) -> BoxStream<'_, Result<HistoryEntry, DejacmdError>>
+   //-------------------------------------------------------------------
+   {
+      static SQL: LazyLock<String> =
+         LazyLock::new(|| format!("SELECT {} FROM history ORDER BY command_timestamp, id", HISTORY_COLUMNS));
+      sqlx::query(SQL.as_str()).fetch(&self.pool)
+         .map(|row| row.map(|row| HistoryEntry::from_row(&row)).map_err(|e| DejacmdError::Query(format!("Error reading history: {}", e))))
+         .boxed()
+   }
+}
+
+/// Search the local or central history configured in settings, which for the central history may be a dejacmd server.
+/// This is what dejacmd search shows, without the formatting.
+pub async fn search_history(settings: &Settings, is_central: bool, request: &SearchRequest)
+   -> Result<Vec<HistoryEntry>, DejacmdError>
+//--------------------------------------------------------------------------------------------------------------------
+{
+   if is_central && let Some(remote) = RemoteCentral::from_settings(settings).map_err(DejacmdError::Config)?
+   {
+      let post = async |request: &SearchRequest| remote.post::<_, Vec<HistoryEntry>>(SEARCH_PATH, request).await
+         .map_err(|e| match e
+         {
+            | RemoteError::Unreachable(_) => DejacmdError::Connection(e.to_string()),
+            | RemoteError::Failed(_) => DejacmdError::Query(e.to_string()),
+         });
+      if request.limit > 0
+      {
+         return post(request).await;
+      }
+      // Servers cap the number of entries returned so an unlimited search is read a page at a time
+      let mut entries = Vec::new();
+      let mut page = SearchRequest { limit: SEARCH_PAGE_SIZE, ..request.clone() };
+      loop
+      {
+         let found = post(&page).await?;
+         let is_last = (found.len() as u64) < page.limit;
+         entries.extend(found);
+         if is_last
+         {
+            return Ok(entries);
+         }
+         page.offset += page.limit;
+      }
+   }
+   let url = if is_central { settings.get_central_database_url() } else { settings.get_local_database_url() };
+   if url.trim().is_empty()
+   {
+      return Err(DejacmdError::Config("No database URL configured".to_string()));
+   }
+   let (user, password) = settings.get_credentials(!is_central).unwrap_or_else(|_| ("".to_string(), SecretString::default()));
+   let (pool, scheme) = match shared_database(&url, &user, &password, &PoolSettings::from_settings(settings), false).await
+   {
+      | Ok((Some(pool), scheme)) => (pool, scheme),
+      | Ok((None, _)) => return Err(DejacmdError::Connection("Failed to establish database connection".to_string())),
+      | Err(e) => return Err(DejacmdError::Connection(format!("Error connecting to {} database: {}",
+                                                               if is_central { "central" } else { "local" }, e))),
+   };
+   search_rows(&pool, &scheme, request).await
+}
+
+/// Run a search against a history database. Only the columns needed for the output are selected so, for example, the
+/// entries have no id or cwd for unique or frequency ordered searches.
+pub async fn search_rows(pool: &Pool<Any>, scheme: &str, request: &SearchRequest) -> Result<Vec<HistoryEntry>, DejacmdError>
+//--------------------------------------------------------------------------------------------------------------------
+{
+   let (query, values) = search_sql(scheme, request);
+   let mut query_builder = sqlx::query(&query);
+   for value in &values
+   {
+      query_builder = query_builder.bind(value);
+   }
+   let rows = query_builder.fetch_all(pool).await
+      .map_err(|e| DejacmdError::Query(format!("{} with {} [{}]", query, values.join(", "), e)))?;
+   Ok(rows.iter().map(HistoryEntry::from_row).collect())
+}
+
+/// The SELECT for a search with placeholders for the scheme, and the values bound to them in order
+pub(crate) fn search_sql(scheme: &str, request: &SearchRequest) -> (String, Vec<String>)
+//-------------------------------------------------------------------------------------
+{
+   let spec = &request.spec;
+   let term= if spec.trim().is_empty() {"".to_string()} else { format!("%{}%", like_escape(spec)) };
+   // Ordering by frequency, or unique commands by duration, needs a row per command so is a GROUP BY rather than DISTINCT
+   let is_grouped = request.sort == SearchSort::Frequency || (request.is_unique && request.sort == SearchSort::Duration);
+   let select = if is_grouped
+   {
+      format!("{} {} {} {} command ",
+         if request.sort == SearchSort::Frequency { "COUNT(*) AS runs," } else { "" },
+         if request.is_show_time { "MAX(command_timestamp) AS command_timestamp," } else { "" },
+         if request.sort == SearchSort::Duration { "MAX(duration_ms) AS duration_ms," } else { "" },
+         if request.is_show_host { "hostname," } else { "" })
+   }
+   else
+   {
+      // id and cwd would defeat DISTINCT so unique results can't be linked or show directories
+      format!("{} {} {} {} {} command ",
+         if request.is_unique { "DISTINCT" } else { "" },
+         if request.is_show_time { "command_timestamp," } else { "" },
+         if request.is_unique { "" } else { "id, cwd," },
+         if request.sort == SearchSort::Duration { "duration_ms," } else { "" },
+         if request.is_show_host || !request.is_unique { "hostname," } else { "" })
+   };
+   let from = "history";
+
+   // Build WHERE clause
+   let mut where_conditions = Vec::new();
+
+   if !spec.trim().is_empty()
+   {
+      if request.is_ignore_case
+      {
+         where_conditions.push(format!("LOWER(command) LIKE LOWER(?) {}", LIKE_ESCAPE));
+      } else {
+         where_conditions.push(format!("command LIKE ? {}", LIKE_ESCAPE));
+      }
+   }
+
+   if !request.ranges.is_empty()
+   {
+      let any_range = vec!["(command_timestamp >= ? AND command_timestamp <= ?)"; request.ranges.len()].join(" OR ");
+      where_conditions.push(format!("({})", any_range));
+   }
+
+   for exclusion in &request.exclusions
+   {
+      where_conditions.push(exclusion.condition().to_string());
+   }
+
+   where_conditions.extend(calendar_conditions(scheme, request.hours, &request.weekdays));
+
+   if !request.hosts.is_empty()
+   {
+      where_conditions.push(format!("hostname IN ({})", vec!["?"; request.hosts.len()].join(", ")));
+   }
+
+   if request.session.is_some()
+   {
+      where_conditions.push("session_id = ?".to_string());
+   }
+
+   if request.repo.is_some()
+   {
+      where_conditions.push(format!("(git_repo = ? OR git_repo LIKE ? {})", LIKE_ESCAPE));
+   }
+
+   if request.branch.is_some()
+   {
+      where_conditions.push("git_branch = ?".to_string());
+   }
+
+   if request.project.is_some()
+   {
+      where_conditions.push("project = ?".to_string());
+   }
+
+   if request.tmux_session.is_some()
+   {
+      where_conditions.push(format!("multiplexer LIKE ? {}", LIKE_ESCAPE));
+   }
+
+   if request.output.is_some()
+   {
+      where_conditions.push(format!("id IN (SELECT id FROM history_output WHERE {} {})",
+                                    if request.is_ignore_case { "LOWER(output) LIKE LOWER(?)" } else { "output LIKE ?" }, LIKE_ESCAPE));
+   }
+
+   if request.cwd.is_some()
+   {
+      where_conditions.push("cwd = ?".to_string());
+   }
+
+   if !request.ids.is_empty()
+   {
+      where_conditions.push(format!("id IN ({})", vec!["?"; request.ids.len()].join(", ")));
+   }
+
+   let wher = if where_conditions.is_empty()
+   {
+      "1=1".to_string()
+   }
+   else
+   {
+      where_conditions.join(" AND ")
+   };
+
+   // The id (the command for unique searches) breaks ties between commands logged in the same second so pages of a
+   // search don't overlap. NULL durations sort last in every database.
+   let d = if request.is_sort_reversed { "" } else { " DESC" };
+   let order = match (request.sort, is_grouped)
+   {
+      | (SearchSort::Time, _) if request.is_unique => format!("command_timestamp{d}, command{d}"),
+      | (SearchSort::Time, _) => format!("command_timestamp{d}, id{d}"),
+      | (SearchSort::Duration, false) => format!("duration_ms IS NULL, duration_ms{d}, command_timestamp{d}, id{d}"),
+      | (SearchSort::Duration, true) =>
+         format!("MAX(duration_ms) IS NULL, MAX(duration_ms){d}, MAX(command_timestamp){d}, command{d}"),
+      | (SearchSort::Frequency, _) => format!("COUNT(*){d}, MAX(command_timestamp){d}, command{d}"),
+   };
+   let group = match (is_grouped, request.is_show_host)
+   {
+      | (false, _) => "",
+      | (true, false) => " GROUP BY command",
+      | (true, true) => " GROUP BY command, hostname",
+   };
+   let limit = match (request.limit, request.offset)
+   {
+      | (0, 0) => "".to_string(),
+      // Only PostgreSQL allows an OFFSET without a LIMIT
+      | (0, offset) if scheme.starts_with("postgres") => format!("OFFSET {}", offset),
+      | (0, offset) if scheme.starts_with("mysql") || scheme.starts_with("mariadb") =>
+         format!("LIMIT {} OFFSET {}", u64::MAX, offset),
+      | (0, offset) => format!("LIMIT -1 OFFSET {}", offset),
+      | (limit, 0) => format!("LIMIT {}", limit),
+      | (limit, offset) => format!("LIMIT {} OFFSET {}", limit, offset),
+   };
+   let sql = format!("SELECT {} FROM {} WHERE {}{} ORDER BY {} {}", select, from, wher, group, order, limit);
+   let mut values = Vec::new();
+
+   if !term.is_empty()
+   {
+      values.push(term);
+   }
+
+   for (start, end) in &request.ranges
+   {
+      values.extend([start.clone(), end.clone()]);
+   }
+
+   for exclusion in &request.exclusions
+   {
+      values.extend([exclusion.start.clone(), exclusion.end.clone()]);
+   }
+
+   for host in &request.hosts
+   {
+      values.push(host.clone());
+   }
+
+   if let Some(session) = &request.session
+   {
+      values.push(session.clone());
+   }
+
+   if let Some(repo) = &request.repo
+   {
+      // A name matches the last component of the repository directory
+      values.extend([repo.clone(), format!("%/{}", like_escape(repo))]);
+   }
+
+   if let Some(branch) = &request.branch
+   {
+      values.push(branch.clone());
+   }
+
+   if let Some(project) = &request.project
+   {
+      values.push(project.clone());
+   }
+
+   if let Some(session) = &request.tmux_session
+   {
+      // tmux:SESSION:WINDOW.PANE or screen:SESSION:WINDOW
+      values.push(format!("%:{}:%", like_escape(session)));
+   }
+
+   if let Some(output) = &request.output
+   {
+      values.push(format!("%{}%", like_escape(output)));
+   }
+
+   if let Some(cwd) = &request.cwd
+   {
+      values.push(cwd.clone());
+   }
+
+   for id in &request.ids
+   {
+      values.push(id.clone());
+   }
+   (fix_placeholders(&sql, scheme), values)
+}
+
+/// An entry for a command imported from a shell history file, run at timestamp (Unix seconds) in cwd (the current
+/// directory if empty) by the current user on this machine. History files don't record sessions, git repositories
+/// or durations so those are None.
+pub fn imported_entry(command: &str, cwd: &str, timestamp: i64, shell: &str, exit_status: i64, history_no: Option<i64>)
+   -> Result<HistoryEntry, DejacmdError>
+//--------------------------------------------------------------------------------------------------------------------
+{
+   let command_timestamp = chrono::Utc.timestamp_opt(timestamp, 0)
+      .single()
+      .ok_or_else(|| DejacmdError::Parse(format!("Invalid timestamp {}", timestamp)))?
+      .format("%Y-%m-%d %H:%M:%S").to_string();
+   let cwd = if cwd.trim().is_empty()
+   {
+      std::env::current_dir().unwrap_or_default().display().to_string()
+   }
+   else
+   {
+      cwd.to_string()
+   };
+   Ok(HistoryEntry
+   {
+      id: ShortUuid::generate().to_string(),
+      command_timestamp,
+      cwd: Some(cwd),
+      shell: Some(shell.to_string()),
+      user_name: Some(crate::get_user_name()),
+      ip: Some(crate::get_local_ip()),
+      os: Some(crate::get_os()),
+      exit_status: Some(exit_status),
+      command: Some(command.to_string()),
+      history_no,
+      hostname: Some(crate::get_hostname()),
+      ..Default::default()
+   })
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[tokio::test]
+   async fn test_sql_history_store()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_store_test_{}.sqlite", nanos));
+      let url = format!("sqlite://{}?mode=rwc", path.display());
+      let store = SqlHistoryStore::connect(&url, "", &SecretString::default(), &PoolSettings::default()).await.unwrap();
+
+      let mut first = imported_entry("cargo build", "/src/dejacmd", 1767000000, "bash", 0, Some(1)).unwrap();
+      first.project = Some("dejacmd".to_string());
+      first.multiplexer = Some("tmux:debug:1.0".to_string());
+      store.insert(&first).await.unwrap();
+      assert!(store.insert(&first).await.is_err(), "Ids are unique");
+      let second = imported_entry("cargo test", "/src/dejacmd", 1767000060, "bash", 101, Some(2)).unwrap();
+      assert_eq!(store.insert_batch(&[first.clone(), second.clone()]).await.unwrap(), 1, "Stored entries are skipped");
+
+      let request = SearchRequest { spec: "cargo".to_string(), is_show_time: true, ..SearchRequest::default() };
+      let found: Vec<_> = store.search(&request).await.unwrap().into_iter().filter_map(|e| e.command).collect();
+      assert_eq!(found, vec!["cargo test", "cargo build"]);
+      let request = SearchRequest { project: Some("dejacmd".to_string()), ..SearchRequest::default() };
+      assert_eq!(store.search(&request).await.unwrap().len(), 1);
+      let request = SearchRequest { tmux_session: Some("debug".to_string()), ..SearchRequest::default() };
+      assert_eq!(store.search(&request).await.unwrap().len(), 1);
+      let request = SearchRequest { tmux_session: Some("1.0".to_string()), ..SearchRequest::default() };
+      assert!(store.search(&request).await.unwrap().is_empty(), "Only the session name matches");
+      let request = SearchRequest { tmux_session: Some("d_bug".to_string()), ..SearchRequest::default() };
+      assert!(store.search(&request).await.unwrap().is_empty(), "_ isn't a wildcard");
+      crate::output::insert_output(store.pool(), "sqlite", &first.id, "Compiling dejacmd\nFinished dev profile").await.unwrap();
+      let request = SearchRequest { output: Some("finished DEV".to_string()), is_ignore_case: true, ..SearchRequest::default() };
+      assert_eq!(store.search(&request).await.unwrap().first().map(|e| e.id.as_str()), Some(first.id.as_str()));
+      let request = SearchRequest { output: Some("error[E0308]".to_string()), ..request };
+      assert!(store.search(&request).await.unwrap().is_empty());
+      let request = SearchRequest { output: Some("dev_profile".to_string()), ..request };
+      assert!(store.search(&request).await.unwrap().is_empty(), "_ isn't a wildcard");
+      assert_eq!(like_escape("100%_done!"), "100!%!_done!!");
+
+      let more: Vec<_> = (0..SEARCH_PAGE_SIZE + 10)
+         .map(|i| imported_entry(&format!("ls {}", i), "/", 1767001000 + i as i64, "bash", 0, None).unwrap())
+         .collect();
+      store.insert_batch(&more).await.unwrap();
+      let request = SearchRequest { spec: "ls ".to_string(), is_sort_reversed: true, ..SearchRequest::default() };
+      let streamed: Vec<HistoryEntry> = store.search_stream(&request).map(|e| e.unwrap()).collect().await;
+      assert_eq!(streamed.len(), more.len(), "Unlimited searches read every page");
+      assert_eq!(streamed.last().and_then(|e| e.command.as_deref()), more.last().and_then(|e| e.command.as_deref()));
+      let request = SearchRequest { limit: SEARCH_PAGE_SIZE + 5, ..request };
+      assert_eq!(store.search_stream(&request).count().await, SEARCH_PAGE_SIZE as usize + 5);
+      let request = SearchRequest { limit: 2, offset: 3, ..request };
+      let page: Vec<_> = store.search(&request).await.unwrap().into_iter().filter_map(|e| e.command).collect();
+      assert_eq!(page, vec!["ls 3", "ls 4"]);
+      let request = SearchRequest { limit: 0, offset: SEARCH_PAGE_SIZE + 8, ..request };
+      let rest: Vec<_> = store.search(&request).await.unwrap().into_iter().filter_map(|e| e.command).collect();
+      assert_eq!(rest, [8, 9].map(|i| format!("ls {}", SEARCH_PAGE_SIZE + i)), "An offset without a limit skips to the end");
+
+      let all: Vec<HistoryEntry> = store.stream_all().map(|e| e.unwrap()).collect().await;
+      assert_eq!(all[..2], [first, second], "Every column is read back");
+      store.pool().close().await;
+      let _ = std::fs::remove_file(&path);
+   }
+
+   #[tokio::test]
+   async fn test_calendar_conditions()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_calendar_test_{}.sqlite", nanos));
+      let url = format!("sqlite://{}?mode=rwc", path.display());
+      let store = SqlHistoryStore::connect(&url, "", &SecretString::default(), &PoolSettings::default()).await.unwrap();
+      // 2026-02-02 is a Monday
+      let timestamps = ["2026-02-02 08:59:59", "2026-02-02 09:00:00", "2026-02-06 16:59:00", "2026-02-07 17:00:00", "2026-02-08 23:30:00"];
+      let entries: Vec<HistoryEntry> = timestamps.iter().enumerate()
+         .map(|(i, timestamp)|
+         {
+            let mut entry = imported_entry(&format!("cmd{}", i + 1), "/", 1767000000 + i as i64, "bash", 0, None).unwrap();
+            entry.command_timestamp = timestamp.to_string();
+            entry
+         })
+         .collect();
+      store.insert_batch(&entries).await.unwrap();
+      let found = async |hours: Option<(u32, u32)>, weekdays: Vec<u32>| -> Vec<String>
+      {
+         let request = SearchRequest { is_sort_reversed: true, hours, weekdays, ..SearchRequest::default() };
+         store.search(&request).await.unwrap().into_iter().filter_map(|e| e.command).collect()
+      };
+      assert_eq!(found(Some((9, 17)), vec![]).await, vec!["cmd2", "cmd3"]);
+      assert_eq!(found(Some((17, 9)), vec![]).await, vec!["cmd1", "cmd4", "cmd5"], "Hours can wrap midnight");
+      assert_eq!(found(None, vec![1, 2, 3, 4, 5]).await, vec!["cmd1", "cmd2", "cmd3"]);
+      assert_eq!(found(Some((17, 24)), vec![0, 6]).await, vec!["cmd4", "cmd5"]);
+      assert_eq!(calendar_conditions("mysql", Some((9, 17)), &[0, 9]),
+                 vec!["(HOUR(command_timestamp) >= 9 AND HOUR(command_timestamp) < 17)", "(DAYOFWEEK(command_timestamp) - 1) IN (0)"]);
+      store.pool().close().await;
+      let _ = std::fs::remove_file(&path);
+   }
+
+   #[tokio::test]
+   async fn test_search_sort()
+   {
+      let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+      let path = std::env::temp_dir().join(format!("dejacmd_sort_test_{}.sqlite", nanos));
+      let url = format!("sqlite://{}?mode=rwc", path.display());
+      let store = SqlHistoryStore::connect(&url, "", &SecretString::default(), &PoolSettings::default()).await.unwrap();
+      let entries: Vec<HistoryEntry> = [("make", Some(9000)), ("ls", Some(5)), ("make", Some(200)), ("vim", None), ("ls", Some(7)),
+                                        ("ls", None)]
+         .iter().enumerate()
+         .map(|(i, (command, duration))|
+         {
+            let mut entry = imported_entry(command, "/", 1767000000 + i as i64, "bash", 0, None).unwrap();
+            entry.duration_ms = *duration;
+            entry
+         })
+         .collect();
+      store.insert_batch(&entries).await.unwrap();
+      let found = async |sort: SearchSort, is_sort_reversed: bool, is_unique: bool| -> Vec<String>
+      {
+         let request = SearchRequest { sort, is_sort_reversed, is_unique, is_show_time: true, ..SearchRequest::default() };
+         store.search(&request).await.unwrap().into_iter().filter_map(|e| e.command).collect()
+      };
+      assert_eq!(found(SearchSort::Time, false, false).await, vec!["ls", "ls", "vim", "make", "ls", "make"]);
+      assert_eq!(found(SearchSort::Duration, false, false).await, vec!["make", "make", "ls", "ls", "ls", "vim"],
+                 "Commands without a duration come last");
+      assert_eq!(found(SearchSort::Duration, true, false).await, vec!["ls", "ls", "make", "make", "vim", "ls"]);
+      assert_eq!(found(SearchSort::Duration, false, true).await, vec!["make", "ls", "vim"]);
+      assert_eq!(found(SearchSort::Frequency, false, false).await, vec!["ls", "make", "vim"]);
+      assert_eq!(found(SearchSort::Frequency, true, false).await, vec!["vim", "make", "ls"]);
+      let request = SearchRequest { sort: SearchSort::Frequency, is_show_time: true, limit: 1, ..SearchRequest::default() };
+      let found = store.search(&request).await.unwrap();
+      assert_eq!(found[0].command_timestamp, entries[5].command_timestamp, "Frequency ordered searches show when the command was last run");
+      assert_eq!((found[0].command.as_deref(), found[0].runs), (Some("ls"), Some(3)));
+      assert_eq!(SearchSort::parse(" Duration"), Ok(SearchSort::Duration));
+      assert!(SearchSort::parse("size").is_err());
+      store.pool().close().await;
+      let _ = std::fs::remove_file(&path);
+   }
+}